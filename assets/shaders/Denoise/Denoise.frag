@@ -0,0 +1,109 @@
+#version 450
+#include <uniforms.glsl>
+
+// One iteration of path_tracer::denoise::atrous
+layout(set = 2, binding = 0) uniform texture2D Denoise_color;
+layout(set = 2, binding = 1) uniform sampler Denoise_color_sampler;
+layout(set = 2, binding = 2) uniform texture2D Denoise_normal_depth;
+layout(set = 2, binding = 3) uniform sampler Denoise_normal_depth_sampler;
+layout(set = 2, binding = 4) uniform texture2D Denoise_albedo;
+layout(set = 2, binding = 5) uniform sampler Denoise_albedo_sampler;
+layout(set = 2, binding = 6) uniform Denoise_inv_sample_counter {
+    float invSampleCounter;
+};
+layout(set = 2, binding = 7) uniform Denoise_step_width {
+    float stepWidth;
+};
+layout(set = 2, binding = 8) uniform Denoise_iteration {
+    int iteration;
+};
+layout(set = 2, binding = 9) uniform Denoise_iterations {
+    int iterations;
+};
+layout(set = 2, binding = 10) uniform Denoise_sigma_color {
+    float sigmaColor;
+};
+layout(set = 2, binding = 11) uniform Denoise_sigma_normal {
+    float sigmaNormal;
+};
+layout(set = 2, binding = 12) uniform Denoise_sigma_depth {
+    float sigmaDepth;
+};
+layout(set = 2, binding = 13) uniform Denoise_sigma_albedo {
+    float sigmaAlbedo;
+};
+layout(set = 2, binding = 14) uniform Denoise_render_scale {
+    float renderScale;
+};
+
+#define MIN_ALBEDO 1e-3
+
+const float kernel[5] = float[](1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+
+vec4 NormalDepth(ivec2 p)
+{
+    return texelFetch(sampler2D(Denoise_normal_depth, Denoise_normal_depth_sampler), p, 0);
+}
+
+vec3 Albedo(ivec2 p)
+{
+    return texelFetch(sampler2D(Denoise_albedo, Denoise_albedo_sampler), p, 0).rgb;
+}
+
+vec3 Demodulation(ivec2 p)
+{
+    return NormalDepth(p).w >= 0.0 ? max(Albedo(p), vec3(MIN_ALBEDO)) : vec3(1.0);
+}
+
+// The first iteration reads the accumulated sum, the next ones the demodulated lighting
+vec3 Color(ivec2 p)
+{
+    vec3 c = texelFetch(sampler2D(Denoise_color, Denoise_color_sampler), p, 0).rgb;
+    return iteration == 0 ? c * invSampleCounter / Demodulation(p) : c;
+}
+
+void main() {
+    ivec2 size = ivec2(vec2(textureSize(sampler2D(Denoise_color, Denoise_color_sampler), 0)) * renderScale);
+    ivec2 p = ivec2(gl_FragCoord.xy);
+    int step = int(stepWidth);
+    float sigma = sigmaColor * sigmaColor / stepWidth;
+
+    vec3 cp = Color(p);
+    vec4 np = NormalDepth(p);
+    vec3 ap = Albedo(p);
+    bool hitP = np.w >= 0.0;
+
+    vec3 sum = vec3(0.0);
+    float weights = 0.0;
+    for (int j = 0; j < 5; j++) {
+        for (int i = 0; i < 5; i++) {
+            ivec2 q = p + ivec2(i - 2, j - 2) * step;
+            if (q.x < 0 || q.y < 0 || q.x >= size.x || q.y >= size.y)
+                continue;
+
+            vec3 cq = Color(q);
+            vec4 nq = NormalDepth(q);
+            if (hitP != (nq.w >= 0.0))
+                continue;
+
+            vec3 dc = cq - cp;
+            float w = exp(-dot(dc, dc) / max(sigma, 1e-10));
+            if (hitP) {
+                vec3 da = Albedo(q) - ap;
+                w *= pow(max(dot(np.xyz, nq.xyz), 0.0), sigmaNormal);
+                w *= exp(-abs(nq.w - np.w) / (sigmaDepth * np.w * stepWidth + 1e-6));
+                w *= exp(-dot(da, da) / (sigmaAlbedo * sigmaAlbedo));
+            }
+            w *= kernel[i] * kernel[j];
+            sum += cq * w;
+            weights += w;
+        }
+    }
+    // The center always has a weight of 1
+    vec3 color = sum / weights;
+
+    if (iteration == iterations - 1)
+        color *= Demodulation(p);
+
+    o_Target = vec4(color, 1.0);
+}
@@ -0,0 +1,16 @@
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+layout(set = 2, binding = 14) uniform Denoise_render_scale {
+    float renderScale;
+};
+void main() {
+    // Same corner of the target as PathTracer.vert
+    vec2 position = vec2(Vertex_Position.x + 1.0, Vertex_Position.y - 1.0) * renderScale + vec2(-1.0, 1.0);
+    gl_Position = vec4(position, 0.0, 1.0);
+}
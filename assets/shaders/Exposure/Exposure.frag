@@ -0,0 +1,118 @@
+#version 450
+#include <uniforms.glsl>
+#include <color.glsl>
+
+// path_tracer::tonemapping metering, a single fragment writing the adapted EV100
+layout(set = 2, binding = 0) uniform texture2D Exposure_color;
+layout(set = 2, binding = 1) uniform sampler Exposure_color_sampler;
+layout(set = 2, binding = 2) uniform texture2D Exposure_previous;
+layout(set = 2, binding = 3) uniform sampler Exposure_previous_sampler;
+layout(set = 2, binding = 4) uniform Exposure_inv_sample_counter {
+    float invSampleCounter;
+};
+layout(set = 2, binding = 5) uniform Exposure_render_scale {
+    float renderScale;
+};
+layout(set = 2, binding = 6) uniform Exposure_reset {
+    int reset;
+};
+layout(set = 2, binding = 7) uniform Exposure_delta_time {
+    float deltaTime;
+};
+layout(set = 2, binding = 8) uniform Exposure_min_ev {
+    float minEv;
+};
+layout(set = 2, binding = 9) uniform Exposure_max_ev {
+    float maxEv;
+};
+layout(set = 2, binding = 10) uniform Exposure_low_percent {
+    float lowPercent;
+};
+layout(set = 2, binding = 11) uniform Exposure_high_percent {
+    float highPercent;
+};
+layout(set = 2, binding = 12) uniform Exposure_speed_up {
+    float speedUp;
+};
+layout(set = 2, binding = 13) uniform Exposure_speed_down {
+    float speedDown;
+};
+
+#define HISTOGRAM_BINS 64
+#define ISO 100.0
+#define CALIBRATION 12.5
+
+const ivec2 meteringGrid = ivec2(64, 36);
+
+float LuminanceToEV100(float luminance)
+{
+    return log2(luminance * ISO / CALIBRATION);
+}
+
+// The first bin gathers what is too dark to meter
+int HistogramBin(float luminance)
+{
+    float ev = LuminanceToEV100(luminance);
+    if (!(luminance > 0.0) || ev < minEv)
+        return 0;
+    float t = min((ev - minEv) / (maxEv - minEv), 1.0);
+    return 1 + min(int(t * float(HISTOGRAM_BINS - 1)), HISTOGRAM_BINS - 2);
+}
+
+float BinEV(int bin)
+{
+    return minEv + (float(bin) - 0.5) / float(HISTOGRAM_BINS - 1) * (maxEv - minEv);
+}
+
+void main() {
+    float histogram[HISTOGRAM_BINS];
+    for (int i = 0; i < HISTOGRAM_BINS; i++)
+        histogram[i] = 0.0;
+
+    // The traced part of the target
+    vec2 size = vec2(textureSize(sampler2D(Exposure_color, Exposure_color_sampler), 0)) * renderScale;
+    for (int y = 0; y < meteringGrid.y; y++)
+    {
+        for (int x = 0; x < meteringGrid.x; x++)
+        {
+            ivec2 p = ivec2((vec2(x, y) + 0.5) / vec2(meteringGrid) * size);
+            vec3 c = texelFetch(sampler2D(Exposure_color, Exposure_color_sampler), p, 0).rgb * invSampleCounter;
+            histogram[HistogramBin(RelativeLuminance(c))] += 1.0;
+        }
+    }
+
+    float total = 0.0;
+    for (int i = 1; i < HISTOGRAM_BINS; i++)
+        total += histogram[i];
+
+    float previous = texelFetch(sampler2D(Exposure_previous, Exposure_previous_sampler), ivec2(0), 0).x;
+    if (total <= 0.0)
+    {
+        // Nothing to meter, keeps the exposure
+        o_Target = vec4(reset != 0 ? 0.0 : previous, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    // Average of the part of the histogram between the percentiles
+    float low = total * lowPercent * 0.01;
+    float high = total * highPercent * 0.01;
+    float below = 0.0;
+    float sum = 0.0;
+    float weights = 0.0;
+    for (int i = 1; i < HISTOGRAM_BINS; i++)
+    {
+        float w = clamp(below + histogram[i], low, high) - clamp(below, low, high);
+        sum += w * BinEV(i);
+        weights += w;
+        below += histogram[i];
+    }
+    float target = clamp(weights > 0.0 ? sum / weights : BinEV(HISTOGRAM_BINS - 1), minEv, maxEv);
+
+    float ev = target;
+    if (reset == 0)
+    {
+        float speed = target > previous ? speedUp : speedDown;
+        ev = previous + (target - previous) * (1.0 - exp(-deltaTime * speed));
+    }
+    o_Target = vec4(ev, 0.0, 0.0, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,12 @@
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+void main() {
+    // Covers the 1x1 target
+    gl_Position = vec4(Vertex_Position.xy, 0.0, 1.0);
+}
\ No newline at end of file
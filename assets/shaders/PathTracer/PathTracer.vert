@@ -7,6 +7,12 @@ layout(set = 0, binding = 0) uniform CameraViewProj {
 layout(set = 1, binding = 0) uniform Transform {
     mat4 Model;
 };
+layout(set = 2, binding = 32) uniform PathTracer_render_scale {
+    float renderScale;
+};
 void main() {
-    gl_Position = ViewProj * Model * vec4(Vertex_Position, 1.0);
+    // Fullscreen quad over the accumulation target, the view of its pass doesn't matter
+    // Shrunk towards the top left corner, where the rows of the target start
+    vec2 position = vec2(Vertex_Position.x + 1.0, Vertex_Position.y - 1.0) * renderScale + vec2(-1.0, 1.0);
+    gl_Position = vec4(position, 0.0, 1.0);
 }
\ No newline at end of file
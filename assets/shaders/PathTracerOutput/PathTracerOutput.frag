@@ -0,0 +1,52 @@
+#version 450
+#include <uniforms.glsl>
+#include <color.glsl>
+
+layout(location = 0) in vec2 v_Uv;
+
+layout(set = 2, binding = 0) uniform PathTracerOutput_inv_sample_counter {
+    float invSampleCounter;
+};
+layout(set = 2, binding = 1) uniform texture2D PathTracerOutput_accum;
+layout(set = 2, binding = 2) uniform sampler PathTracerOutput_accum_sampler;
+layout(set = 2, binding = 3) uniform PathTracerOutput_render_scale {
+    float renderScale;
+};
+layout(set = 2, binding = 4) uniform texture2D PathTracerOutput_exposure;
+layout(set = 2, binding = 5) uniform sampler PathTracerOutput_exposure_sampler;
+layout(set = 2, binding = 6) uniform PathTracerOutput_auto_exposure {
+    int autoExposure;
+};
+layout(set = 2, binding = 7) uniform PathTracerOutput_exposure_ev {
+    float exposureEv;
+};
+layout(set = 2, binding = 8) uniform PathTracerOutput_tonemapper {
+    int tonemapper;
+};
+layout(set = 2, binding = 9) uniform PathTracerOutput_white_point {
+    float whitePoint;
+};
+layout(set = 2, binding = 10) uniform PathTracerOutput_white_balance {
+    mat4 whiteBalance;
+};
+
+// path_tracer::tonemapping::ev100_to_exposure
+float EV100ToExposure(float ev100)
+{
+    return 1.0 / (1.2 * exp2(ev100));
+}
+
+void main() {
+    vec3 color = texture(sampler2D(PathTracerOutput_accum, PathTracerOutput_accum_sampler), v_Uv * renderScale).rgb * invSampleCounter;
+
+    // exposureEv is the compensation in stops with auto exposure, the EV100 without
+    float ev100 = exposureEv;
+    if (autoExposure != 0)
+        ev100 = texelFetch(sampler2D(PathTracerOutput_exposure, PathTracerOutput_exposure_sampler), ivec2(0), 0).x - exposureEv;
+    color *= EV100ToExposure(ev100);
+
+    color = (whiteBalance * vec4(color, 0.0)).rgb;
+
+    // The swap chain is sRGB and does the transfer function
+    o_Target = vec4(Tonemap(color, tonemapper, whitePoint), 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,14 @@
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+layout(location = 0) out vec2 v_Uv;
+void main() {
+    // Fullscreen quad, the accumulation targets have their rows from the top
+    v_Uv = vec2(Vertex_Position.x, -Vertex_Position.y) * 0.5 + 0.5;
+    gl_Position = vec4(Vertex_Position.xy, 0.0, 1.0);
+}
\ No newline at end of file
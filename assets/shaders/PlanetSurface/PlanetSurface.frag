@@ -0,0 +1,17 @@
+#version 450
+#include <lib.glsl>
+
+layout(location = 0) in vec3 v_Position;
+layout(location = 1) in vec3 v_Normal;
+layout(location = 2) in vec4 v_Color;
+layout(set = 2, binding = 0) uniform PlanetSurface_ambient {
+    float ambient;
+};
+layout(set = 2, binding = 1) uniform PlanetSurface_light_position {
+    vec3 light_position;
+};
+void main() {
+    vec3 l = normalize(light_position - v_Position);
+    float diffuse = max(dot(normalize(v_Normal), l), 0.0);
+    o_Target = vec4(v_Color.rgb * (ambient + (1.0 - ambient) * diffuse), 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(location = 1) in vec3 Vertex_Normal;
+layout(location = 2) in vec4 Vertex_Color;
+layout(location = 0) out vec3 v_Position;
+layout(location = 1) out vec3 v_Normal;
+layout(location = 2) out vec4 v_Color;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+void main() {
+    vec4 position = Model * vec4(Vertex_Position, 1.0);
+    gl_Position = ViewProj * position;
+    v_Position = position.xyz;
+    v_Normal = mat3(Model) * Vertex_Normal;
+    v_Color = Vertex_Color;
+}
@@ -0,0 +1,7 @@
+#version 450
+#include <lib.glsl>
+
+layout(location = 0) in float v_Temperature;
+void main() {
+    o_Target = vec4(BlackBodyRGB(v_Temperature), 1.0);
+}
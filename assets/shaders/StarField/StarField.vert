@@ -0,0 +1,18 @@
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(location = 1) in float Vertex_Temperature;
+layout(location = 0) out float v_Temperature;
+layout(set = 0, binding = 0) uniform CameraViewProj {
+    mat4 ViewProj;
+};
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+layout(set = 2, binding = 0) uniform StarField_point_size {
+    float point_size;
+};
+void main() {
+    gl_Position = ViewProj * Model * vec4(Vertex_Position, 1.0);
+    gl_PointSize = point_size;
+    v_Temperature = Vertex_Temperature;
+}
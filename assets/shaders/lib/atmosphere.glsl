@@ -0,0 +1,27 @@
+#ifndef LIB_ATMOSPHERE
+#define LIB_ATMOSPHERE
+    // AtmosphereScattering from src/procedual/atmosphere.rs and the planet center, lengths in meters
+    struct AtmosphereScattering {
+        vec3 center;
+        float planet_radius;
+        float atmosphere_radius;
+        float rayleigh_scale_height;
+        float mie_scale_height;
+        vec3 rayleigh;
+        float mie;
+        float mie_g;
+    };
+
+    float atmosphere_height(AtmosphereScattering a, vec3 p) {
+        return max(length(p - a.center) - a.planet_radius, 0.0);
+    }
+
+    //Scattering coefficients at a point, both fall of exponentially with their scale height
+    vec3 rayleigh_scattering(AtmosphereScattering a, vec3 p) {
+        return a.rayleigh * exp(-atmosphere_height(a, p) / a.rayleigh_scale_height);
+    }
+
+    float mie_scattering(AtmosphereScattering a, vec3 p) {
+        return a.mie * exp(-atmosphere_height(a, p) / a.mie_scale_height);
+    }
+#endif
\ No newline at end of file
@@ -117,7 +117,7 @@
     vec3 WavelengthToXYZLinear( float fWavelength )
     {
         float fPos = ( fWavelength - standardObserver1931_w_min ) / (standardObserver1931_w_max - standardObserver1931_w_min);
-        float fIndex = fPos * float(standardObserver1931_length);
+        float fIndex = fPos * float(standardObserver1931_length - 1);
         float fFloorIndex = floor(fIndex);
         float fBlend = clamp( fIndex - fFloorIndex, 0.0, 1.0 );
         int iIndex0 = int(fFloorIndex);
@@ -221,4 +221,122 @@
         mat3 cat = M * d * inverse(M);
         return cat;
     }
+
+    // Bradford transform of colors seen under fromWhite to how they look under toWhite, XYZ * m
+    mat3 ChromaticAdaptation(vec3 fromWhite, vec3 toWhite)
+    {
+        vec3 s = (toWhite * mCAT_Bradford) / (fromWhite * mCAT_Bradford);
+        mat3 d = mat3(
+            s.x,	0,		0,
+            0,		s.y,	0,
+            0,		0,		s.z );
+        return mCAT_Bradford * d * inverse(mCAT_Bradford);
+    }
+
+    // Rec. 709 luminance of linear sRGB
+    float RelativeLuminance(vec3 c)
+    {
+        return dot(c, vec3(0.2126729, 0.7151522, 0.0721750));
+    }
+
+    // Black bodies, mirrored by src/color.rs for the stars on the CPU
+
+    #define C_SECOND_RADIATION 1.4387769e7 // nm K, hc/k
+    #define C_PLANCK_REFERENCE 555.0       // nm
+
+    // Spectral radiance relative to 555 nm, differences of exponents so cold bodies go to 0 instead of inf / inf
+    float PlanckRelative(float wavelength, float temperature)
+    {
+        float x = C_SECOND_RADIATION / (wavelength * temperature);
+        float x0 = C_SECOND_RADIATION / (C_PLANCK_REFERENCE * temperature);
+        return pow(C_PLANCK_REFERENCE / wavelength, 5.0) * exp(x0 - x) * (1.0 - exp(-x0)) / (1.0 - exp(-x));
+    }
+
+    // Y = 1
+    vec3 BlackBodyXYZ(float temperature)
+    {
+        float step = (standardObserver1931_w_max - standardObserver1931_w_min) / float(standardObserver1931_length - 1);
+        vec3 xyz = vec3(0.0);
+        for (int i = 0; i < standardObserver1931_length; i++)
+            xyz += standardObserver1931[i] * PlanckRelative(standardObserver1931_w_min + step * float(i), temperature);
+        return xyz / max(xyz.y, 1e-30);
+    }
+
+    // Linear sRGB with the brightest channel at 1, only the hue
+    vec3 BlackBodyRGB(float temperature)
+    {
+        vec3 c = max(XYZtosRGB(BlackBodyXYZ(temperature)), vec3(0.0));
+        return c / max(max(c.r, max(c.g, c.b)), 1e-30);
+    }
+
+    // Tonemappers, same order as Tonemapper in src/color.rs
+
+    #define TONEMAP_NONE 0
+    #define TONEMAP_REINHARD_EXTENDED 1
+    #define TONEMAP_ACES 2
+    #define TONEMAP_AGX 3
+
+    // Reinhard on the luminance, white and above map to 1
+    vec3 TonemapReinhardExtended(vec3 c, float white)
+    {
+        float l = RelativeLuminance(c);
+        if (l <= 0.0)
+            return vec3(0.0);
+        float mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+        return min(c * mapped / l, vec3(1.0));
+    }
+
+    // Stephen Hill's fit of the ACES RRT and sRGB ODT
+    vec3 TonemapACES(vec3 c)
+    {
+        const mat3 inputMat = mat3(
+            0.59719, 0.35458, 0.04823,
+            0.07600, 0.90834, 0.01566,
+            0.02840, 0.13383, 0.83777 );
+        const mat3 outputMat = mat3(
+            1.60475, -0.53108, -0.07367,
+            -0.10208,  1.10813, -0.00605,
+            -0.00327, -0.07276,  1.07602 );
+
+        vec3 v = c * inputMat;
+        vec3 a = v * (v + 0.0245786) - 0.000090537;
+        vec3 b = v * (0.983729 * v + 0.4329510) + 0.238081;
+        return clamp((a / b) * outputMat, 0.0, 1.0);
+    }
+
+    // Minimal AgX by Benjamin Wrensch with the default look, these two are column major
+    vec3 TonemapAgX(vec3 c)
+    {
+        const mat3 inset = mat3(
+            0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+            0.0784335999999992, 0.878468636469772, 0.0784336,
+            0.0792237451477643, 0.0791661274605434, 0.879142973793104 );
+        const mat3 outset = mat3(
+            1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+            -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+            -0.0990297440797205, -0.0989611768448433, 1.15107367264116 );
+        const float minEv = -12.47393;
+        const float maxEv = 4.026069;
+
+        vec3 v = inset * max(c, vec3(0.0));
+        vec3 x = (clamp(log2(max(v, vec3(1e-10))), minEv, maxEv) - minEv) / (maxEv - minEv);
+
+        vec3 x2 = x * x;
+        vec3 x4 = x2 * x2;
+        vec3 curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+
+        return min(pow(max(outset * curve, vec3(0.0)), vec3(2.2)), vec3(1.0));
+    }
+
+    // white is only used by Reinhard
+    vec3 Tonemap(vec3 c, int tonemapper, float white)
+    {
+        if (tonemapper == TONEMAP_REINHARD_EXTENDED)
+            return TonemapReinhardExtended(c, white);
+        if (tonemapper == TONEMAP_ACES)
+            return TonemapACES(c);
+        if (tonemapper == TONEMAP_AGX)
+            return TonemapAgX(c);
+        return clamp(c, 0.0, 1.0);
+    }
 #endif
\ No newline at end of file
@@ -34,4 +34,133 @@
     vec4 asymptotic_averaging_4d(vec4 current, vec4 target, float speed) {
         return current + (target - current) * speed;
     }
+
+    // Penner easing functions, the values are checked against easing_table.csv by the tests in src/easing.rs
+    #define EASING_PI 3.14159265359
+    #define EASING_BACK_C1 1.70158
+    #define EASING_BACK_C3 2.70158
+    #define EASING_ELASTIC_C4 2.09439510239
+
+    float ease_linear(float x) {
+        return x;
+    }
+
+    float bounce_curve(float x) {
+        const float n1 = 7.5625;
+        const float d1 = 2.75;
+        if (x < 1.0 / d1) {
+            return n1 * x * x;
+        } else if (x < 2.0 / d1) {
+            x -= 1.5 / d1;
+            return n1 * x * x + 0.75;
+        } else if (x < 2.5 / d1) {
+            x -= 2.25 / d1;
+            return n1 * x * x + 0.9375;
+        }
+        x -= 2.625 / d1;
+        return n1 * x * x + 0.984375;
+    }
+
+    float ease_sine_in(float x) {
+        return 1.0 - cos(x * EASING_PI / 2.0);
+    }
+    float ease_sine_out(float x) {
+        return 1.0 - ease_sine_in(1.0 - x);
+    }
+    float ease_sine_in_out(float x) {
+        return x < 0.5 ? ease_sine_in(2.0 * x) / 2.0 : 1.0 - ease_sine_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_quad_in(float x) {
+        return x * x;
+    }
+    float ease_quad_out(float x) {
+        return 1.0 - ease_quad_in(1.0 - x);
+    }
+    float ease_quad_in_out(float x) {
+        return x < 0.5 ? ease_quad_in(2.0 * x) / 2.0 : 1.0 - ease_quad_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_cubic_in(float x) {
+        return x * x * x;
+    }
+    float ease_cubic_out(float x) {
+        return 1.0 - ease_cubic_in(1.0 - x);
+    }
+    float ease_cubic_in_out(float x) {
+        return x < 0.5 ? ease_cubic_in(2.0 * x) / 2.0 : 1.0 - ease_cubic_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_quart_in(float x) {
+        return x * x * x * x;
+    }
+    float ease_quart_out(float x) {
+        return 1.0 - ease_quart_in(1.0 - x);
+    }
+    float ease_quart_in_out(float x) {
+        return x < 0.5 ? ease_quart_in(2.0 * x) / 2.0 : 1.0 - ease_quart_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_quint_in(float x) {
+        return x * x * x * x * x;
+    }
+    float ease_quint_out(float x) {
+        return 1.0 - ease_quint_in(1.0 - x);
+    }
+    float ease_quint_in_out(float x) {
+        return x < 0.5 ? ease_quint_in(2.0 * x) / 2.0 : 1.0 - ease_quint_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_expo_in(float x) {
+        return x == 0.0 ? 0.0 : pow(2.0, 10.0 * x - 10.0);
+    }
+    float ease_expo_out(float x) {
+        return 1.0 - ease_expo_in(1.0 - x);
+    }
+    float ease_expo_in_out(float x) {
+        return x < 0.5 ? ease_expo_in(2.0 * x) / 2.0 : 1.0 - ease_expo_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_circ_in(float x) {
+        return 1.0 - sqrt(1.0 - x * x);
+    }
+    float ease_circ_out(float x) {
+        return 1.0 - ease_circ_in(1.0 - x);
+    }
+    float ease_circ_in_out(float x) {
+        return x < 0.5 ? ease_circ_in(2.0 * x) / 2.0 : 1.0 - ease_circ_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_back_in(float x) {
+        return EASING_BACK_C3 * x * x * x - EASING_BACK_C1 * x * x;
+    }
+    float ease_back_out(float x) {
+        return 1.0 - ease_back_in(1.0 - x);
+    }
+    float ease_back_in_out(float x) {
+        return x < 0.5 ? ease_back_in(2.0 * x) / 2.0 : 1.0 - ease_back_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_elastic_in(float x) {
+        if (x == 0.0 || x == 1.0) {
+            return x;
+        }
+        return -pow(2.0, 10.0 * x - 10.0) * sin((10.0 * x - 10.75) * EASING_ELASTIC_C4);
+    }
+    float ease_elastic_out(float x) {
+        return 1.0 - ease_elastic_in(1.0 - x);
+    }
+    float ease_elastic_in_out(float x) {
+        return x < 0.5 ? ease_elastic_in(2.0 * x) / 2.0 : 1.0 - ease_elastic_in(2.0 - 2.0 * x) / 2.0;
+    }
+
+    float ease_bounce_in(float x) {
+        return 1.0 - bounce_curve(1.0 - x);
+    }
+    float ease_bounce_out(float x) {
+        return 1.0 - ease_bounce_in(1.0 - x);
+    }
+    float ease_bounce_in_out(float x) {
+        return x < 0.5 ? ease_bounce_in(2.0 * x) / 2.0 : 1.0 - ease_bounce_in(2.0 - 2.0 * x) / 2.0;
+    }
 #endif
\ No newline at end of file
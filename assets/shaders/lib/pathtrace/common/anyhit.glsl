@@ -81,7 +81,7 @@ bool AnyHit(Ray r, float maxDist)
 
     while (index != -1)
     {
-        ivec3 LRLeaf = ivec3(texelFetch(BVH, index * 3 + 2).xyz);
+        ivec3 LRLeaf = ivec3(texelFetchBuffer(BVH, index * 3 + 2).xyz);
 
         int leftIndex  = int(LRLeaf.x);
         int rightIndex = int(LRLeaf.y);
@@ -91,11 +91,11 @@ bool AnyHit(Ray r, float maxDist)
         {
             for (int i = 0; i < rightIndex; i++) // Loop through tris
             {
-                ivec3 vertIndices = ivec3(texelFetch(vertexIndicesTex, leftIndex + i).xyz);
+                ivec3 vertIndices = ivec3(texelFetchBuffer(vertexIndicesTex, leftIndex + i).xyz);
 
-                vec4 v0 = texelFetch(verticesTex, vertIndices.x);
-                vec4 v1 = texelFetch(verticesTex, vertIndices.y);
-                vec4 v2 = texelFetch(verticesTex, vertIndices.z);
+                vec4 v0 = texelFetchBuffer(verticesTex, vertIndices.x);
+                vec4 v1 = texelFetchBuffer(verticesTex, vertIndices.y);
+                vec4 v2 = texelFetchBuffer(verticesTex, vertIndices.z);
 
                 vec3 e0 = v1.xyz - v0.xyz;
                 vec3 e1 = v2.xyz - v0.xyz;
@@ -118,10 +118,10 @@ bool AnyHit(Ray r, float maxDist)
         }
         else if (leaf < 0) // Leaf node of TLAS
         {
-            vec4 r1 = texelFetch(transformsTex, ivec2((-leaf - 1) * 4 + 0, 0), 0).xyzw;
-            vec4 r2 = texelFetch(transformsTex, ivec2((-leaf - 1) * 4 + 1, 0), 0).xyzw;
-            vec4 r3 = texelFetch(transformsTex, ivec2((-leaf - 1) * 4 + 2, 0), 0).xyzw;
-            vec4 r4 = texelFetch(transformsTex, ivec2((-leaf - 1) * 4 + 3, 0), 0).xyzw;
+            vec4 r1 = texelFetchBuffer(transformsTex, (-leaf - 1) * 4 + 0).xyzw;
+            vec4 r2 = texelFetchBuffer(transformsTex, (-leaf - 1) * 4 + 1).xyzw;
+            vec4 r3 = texelFetchBuffer(transformsTex, (-leaf - 1) * 4 + 2).xyzw;
+            vec4 r4 = texelFetchBuffer(transformsTex, (-leaf - 1) * 4 + 3).xyzw;
 
             mat4 transform = mat4(r1, r2, r3, r4);
 
@@ -137,8 +137,8 @@ bool AnyHit(Ray r, float maxDist)
         }
         else
         {
-            leftHit =  AABBIntersect(texelFetch(BVH, leftIndex  * 3 + 0).xyz, texelFetch(BVH, leftIndex  * 3 + 1).xyz, rTrans);
-            rightHit = AABBIntersect(texelFetch(BVH, rightIndex * 3 + 0).xyz, texelFetch(BVH, rightIndex * 3 + 1).xyz, rTrans);
+            leftHit =  AABBIntersect(texelFetchBuffer(BVH, leftIndex  * 3 + 0).xyz, texelFetchBuffer(BVH, leftIndex  * 3 + 1).xyz, rTrans);
+            rightHit = AABBIntersect(texelFetchBuffer(BVH, rightIndex * 3 + 0).xyz, texelFetchBuffer(BVH, rightIndex * 3 + 1).xyz, rTrans);
 
             if (leftHit > 0.0 && rightHit > 0.0)
             {
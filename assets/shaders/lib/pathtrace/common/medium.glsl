@@ -0,0 +1,352 @@
+/*
+ * Participating media, mirrors path_tracer::medium. Every medium is a sphere or a box with a density in [0, 1]
+ * scaling its coefficients, rays are tracked against the summed majorants of the media they cross.
+ */
+
+#define MEDIUM_TEXELS 6
+#define MAX_TRACKING_STEPS 1024
+
+#define MEDIUM_SPHERE 0
+#define MEDIUM_BOX 1
+
+#define DENSITY_HOMOGENEOUS 0
+#define DENSITY_NOISE 1
+#define DENSITY_EXPONENTIAL 2
+
+#define PHASE_HG 0
+#define PHASE_RAYLEIGH 1
+
+#define COLLISION_PASSED 0
+#define COLLISION_SCATTERED 1
+#define COLLISION_ABSORBED 2
+
+struct Medium
+{
+    int shape;
+    int density;
+    int phase;
+    float g;
+    vec3 a;       // Center of spheres, min of boxes
+    float radius;
+    vec3 b;       // Max of boxes
+    vec3 params;  // Frequency, octaves and threshold of noise, surface radius and scale height of exponential densities
+    vec3 sigmaA;
+    vec3 sigmaS;
+    vec3 emission;
+    float majorant;
+};
+
+//-----------------------------------------------------------------------
+Medium GetMedium(int i)
+//-----------------------------------------------------------------------
+{
+    int index = i * MEDIUM_TEXELS;
+
+    vec4 param1 = texelFetchBuffer(mediaTex, index + 0);
+    vec4 param2 = texelFetchBuffer(mediaTex, index + 1);
+    vec4 param3 = texelFetchBuffer(mediaTex, index + 2);
+    vec4 param4 = texelFetchBuffer(mediaTex, index + 3);
+    vec4 param5 = texelFetchBuffer(mediaTex, index + 4);
+    vec4 param6 = texelFetchBuffer(mediaTex, index + 5);
+
+    Medium m;
+    m.shape    = int(param1.x);
+    m.density  = int(param1.y);
+    m.phase    = int(param1.z);
+    m.g        = param1.w;
+    m.a        = param2.xyz;
+    m.radius   = param2.w;
+    m.b        = param3.xyz;
+    m.params   = vec3(param3.w, param4.w, param5.w);
+    m.sigmaA   = param4.xyz;
+    m.sigmaS   = param5.xyz;
+    m.emission = param6.xyz;
+    m.majorant = param6.w;
+    return m;
+}
+
+//-----------------------------------------------------------------------
+float HenyeyGreenstein(float cosTheta, float g)
+//-----------------------------------------------------------------------
+{
+    float denom = 1.0 + g * g - 2.0 * g * cosTheta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+
+//-----------------------------------------------------------------------
+float SampleHenyeyGreenstein(float g, float r1)
+//-----------------------------------------------------------------------
+{
+    if (abs(g) < 1.0e-3)
+        return 1.0 - 2.0 * r1;
+
+    float s = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+    return clamp((1.0 + g * g - s * s) / (2.0 * g), -1.0, 1.0);
+}
+
+//-----------------------------------------------------------------------
+float Rayleigh(float cosTheta)
+//-----------------------------------------------------------------------
+{
+    return 3.0 / (16.0 * PI) * (1.0 + cosTheta * cosTheta);
+}
+
+//-----------------------------------------------------------------------
+float SampleRayleigh(float r1)
+//-----------------------------------------------------------------------
+{
+    // Cardano's formula for the cubic of the CDF
+    float z = 2.0 * (2.0 * r1 - 1.0);
+    float u = pow(z + sqrt(z * z + 1.0), 1.0 / 3.0);
+    return clamp(u - 1.0 / u, -1.0, 1.0);
+}
+
+// Also the pdf of PhaseSample, both phase functions are sampled exactly
+//-----------------------------------------------------------------------
+float PhaseEval(int phase, float g, float cosTheta)
+//-----------------------------------------------------------------------
+{
+    return phase == PHASE_RAYLEIGH ? Rayleigh(cosTheta) : HenyeyGreenstein(cosTheta, g);
+}
+
+//-----------------------------------------------------------------------
+vec3 PhaseSample(int phase, float g, vec3 direction)
+//-----------------------------------------------------------------------
+{
+    float r1 = rand();
+    float r2 = rand();
+
+    float cosTheta = phase == PHASE_RAYLEIGH ? SampleRayleigh(r1) : SampleHenyeyGreenstein(g, r1);
+    float sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+    float phi = TWO_PI * r2;
+
+    vec3 T, B;
+    Onb(direction, T, B);
+    return normalize(T * sinTheta * cos(phi) + B * sinTheta * sin(phi) + direction * cosTheta);
+}
+
+// pcg3d by Jarzynski and Olano
+//-----------------------------------------------------------------------
+float MediumHash(ivec3 p)
+//-----------------------------------------------------------------------
+{
+    uvec3 v = uvec3(p);
+    v = v * 1664525u + 1013904223u;
+    v.x += v.y * v.z; v.y += v.z * v.x; v.z += v.x * v.y;
+    v = v ^ (v >> 16u);
+    v.x += v.y * v.z;
+    return float(v.x) / float(0xffffffffu);
+}
+
+//-----------------------------------------------------------------------
+float ValueNoise(vec3 p)
+//-----------------------------------------------------------------------
+{
+    ivec3 c = ivec3(floor(p));
+    vec3 f = fract(p);
+    vec3 w = f * f * (3.0 - 2.0 * f);
+
+    float z0 = mix(mix(MediumHash(c), MediumHash(c + ivec3(1, 0, 0)), w.x),
+                   mix(MediumHash(c + ivec3(0, 1, 0)), MediumHash(c + ivec3(1, 1, 0)), w.x), w.y);
+    float z1 = mix(mix(MediumHash(c + ivec3(0, 0, 1)), MediumHash(c + ivec3(1, 0, 1)), w.x),
+                   mix(MediumHash(c + ivec3(0, 1, 1)), MediumHash(c + ivec3(1, 1, 1)), w.x), w.y);
+    return mix(z0, z1, w.z);
+}
+
+// Octaves of value noise halving in amplitude, normalized to [0, 1]
+//-----------------------------------------------------------------------
+float Fbm(vec3 p, int octaves)
+//-----------------------------------------------------------------------
+{
+    float sum = 0.0;
+    float amplitude = 1.0;
+    float total = 0.0;
+    for (int i = 0; i < max(octaves, 1); i++)
+    {
+        sum += amplitude * ValueNoise(p);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    return sum / total;
+}
+
+// Distances where the ray enters and leaves the medium, the entry is zero from the inside
+//-----------------------------------------------------------------------
+bool MediumInterval(in Medium m, in Ray r, out float t0, out float t1)
+//-----------------------------------------------------------------------
+{
+    if (m.shape == MEDIUM_SPHERE)
+    {
+        vec3 op = m.a - r.origin;
+        float b = dot(op, r.direction);
+        float det = b * b - dot(op, op) + m.radius * m.radius;
+        if (det < 0.0)
+            return false;
+
+        det = sqrt(det);
+        t0 = b - det;
+        t1 = b + det;
+    }
+    else
+    {
+        vec3 invDir = 1.0 / r.direction;
+        vec3 f = (m.a - r.origin) * invDir;
+        vec3 n = (m.b - r.origin) * invDir;
+        vec3 tmin = min(f, n);
+        vec3 tmax = max(f, n);
+        t0 = max(tmin.x, max(tmin.y, tmin.z));
+        t1 = min(tmax.x, min(tmax.y, tmax.z));
+    }
+
+    t0 = max(t0, 0.0);
+    return t1 > t0;
+}
+
+//-----------------------------------------------------------------------
+float MediumDensity(in Medium m, vec3 p)
+//-----------------------------------------------------------------------
+{
+    vec3 center = m.a;
+    if (m.shape == MEDIUM_SPHERE)
+    {
+        if (dot(p - m.a, p - m.a) > m.radius * m.radius)
+            return 0.0;
+    }
+    else
+    {
+        if (any(lessThan(p, m.a)) || any(greaterThan(p, m.b)))
+            return 0.0;
+        center = (m.a + m.b) * 0.5;
+    }
+
+    if (m.density == DENSITY_NOISE)
+        return clamp((Fbm(p * m.params.x, int(m.params.y)) - m.params.z) / max(1.0 - m.params.z, 1.0e-4), 0.0, 1.0);
+    if (m.density == DENSITY_EXPONENTIAL)
+        return exp(-max(length(p - center) - m.params.x, 0.0) / m.params.y);
+    return 1.0;
+}
+
+// Span of the ray inside any of the media before maxDist and the sum of their majorants
+//-----------------------------------------------------------------------
+bool MediaExtent(in Ray r, float maxDist, out float t0, out float t1, out float majorant)
+//-----------------------------------------------------------------------
+{
+    t0 = maxDist;
+    t1 = 0.0;
+    majorant = 0.0;
+
+    for (int i = 0; i < numOfMedia; i++)
+    {
+        Medium m = GetMedium(i);
+        float m0, m1;
+        if (MediumInterval(m, r, m0, m1) && m0 < maxDist)
+        {
+            t0 = min(t0, m0);
+            t1 = max(t1, min(m1, maxDist));
+            majorant += m.majorant;
+        }
+    }
+
+    return majorant > 0.0 && t0 < t1;
+}
+
+// Ratio tracking, the transmittance along the ray up to maxDist
+//-----------------------------------------------------------------------
+vec3 Transmittance(in Ray r, float maxDist)
+//-----------------------------------------------------------------------
+{
+    float t, tMax, majorant;
+    if (!MediaExtent(r, maxDist, t, tMax, majorant))
+        return vec3(1.0);
+
+    vec3 tr = vec3(1.0);
+    for (int k = 0; k < MAX_TRACKING_STEPS; k++)
+    {
+        t -= log(1.0 - rand()) / majorant;
+        if (t >= tMax)
+            break;
+
+        vec3 p = r.origin + r.direction * t;
+        vec3 sigmaT = vec3(0.0);
+        for (int i = 0; i < numOfMedia; i++)
+        {
+            Medium m = GetMedium(i);
+            sigmaT += (m.sigmaA + m.sigmaS) * MediumDensity(m, p);
+        }
+        tr *= 1.0 - sigmaT / majorant;
+    }
+    return tr;
+}
+
+// Delta tracking with the null collisions weighted by throughput like spectral tracking (Kutz et al. 2017),
+// emission is collected at every tentative collision
+//-----------------------------------------------------------------------
+int DeltaTrack(in Ray r, float maxDist, inout vec3 throughput, inout vec3 radiance, out vec3 position, out int phase, out float g)
+//-----------------------------------------------------------------------
+{
+    float t, tMax, majorant;
+    if (!MediaExtent(r, maxDist, t, tMax, majorant))
+        return COLLISION_PASSED;
+
+    for (int k = 0; k < MAX_TRACKING_STEPS; k++)
+    {
+        t -= log(1.0 - rand()) / majorant;
+        if (t >= tMax)
+            return COLLISION_PASSED;
+
+        vec3 p = r.origin + r.direction * t;
+        vec3 sigmaT = vec3(0.0);
+        vec3 emitted = vec3(0.0);
+        float pScatter = 0.0;
+        for (int i = 0; i < numOfMedia; i++)
+        {
+            Medium m = GetMedium(i);
+            float d = MediumDensity(m, p);
+            vec3 sigmaS = m.sigmaS * d * throughput;
+            sigmaT += (m.sigmaA + m.sigmaS) * d;
+            emitted += m.sigmaA * m.emission * d;
+            pScatter += max(sigmaS.x, max(sigmaS.y, sigmaS.z));
+        }
+        radiance += throughput * emitted / majorant;
+
+        // Each medium scatters with the share of its coefficient, the rest of the majorant are null collisions
+        vec3 sigmaN = vec3(majorant) - sigmaT;
+        vec3 n = sigmaN * throughput;
+        float pNull = max(n.x, max(n.y, n.z));
+        if (pNull + pScatter <= 0.0)
+            return COLLISION_ABSORBED;
+
+        float x = rand() * (pNull + pScatter);
+        if (x < pNull)
+        {
+            throughput *= sigmaN * (pNull + pScatter) / (majorant * pNull);
+            continue;
+        }
+        x -= pNull;
+
+        for (int i = 0; i < numOfMedia; i++)
+        {
+            Medium m = GetMedium(i);
+            vec3 sigmaS = m.sigmaS * MediumDensity(m, p);
+            vec3 s = sigmaS * throughput;
+            float pM = max(s.x, max(s.y, s.z));
+            if (pM <= 0.0)
+                continue;
+
+            if (x < pM)
+            {
+                throughput *= sigmaS * (pNull + pScatter) / (majorant * pM);
+                position = p;
+                phase = m.phase;
+                g = m.g;
+                return COLLISION_SCATTERED;
+            }
+            x -= pM;
+        }
+        // Rounding left x past the last medium
+        return COLLISION_ABSORBED;
+    }
+
+    return COLLISION_ABSORBED;
+}
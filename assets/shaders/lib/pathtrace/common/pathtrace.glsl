@@ -62,6 +62,7 @@ void GetMaterials(inout State state, in Ray r)
 
     vec3 texIDs        = param7.xyz;
 
+#ifdef TEXTURE_MAPS
     vec2 texUV = state.texCoord;
     texUV.y = 1.0 - texUV.y;
 
@@ -95,6 +96,7 @@ void GetMaterials(inout State state, in Ray r)
 
         Onb(state.normal, state.tangent, state.bitangent);
     }
+#endif
 
     // Commented out the following as anisotropic param is temporarily unused.
     // Calculate anisotropic roughness along the tangent and bitangent directions
@@ -117,7 +119,7 @@ vec3 DirectLight(in Ray r, in State state)
 
     // Environment Light
 #ifdef ENVMAP
-#ifndef CONSTANT_BG
+    if (useEnvMap != 0)
     {
         vec3 color;
         vec4 dirPdf = EnvSample(color);
@@ -136,14 +138,17 @@ vec3 DirectLight(in Ray r, in State state)
                 float misWeight = powerHeuristic(lightPdf, bsdfSampleRec.pdf);
                 if (misWeight > 0.0)
                     Li += misWeight * bsdfSampleRec.f * abs(dot(lightDir, state.ffnormal)) * color / lightPdf;
+#ifdef MEDIA
+                Li *= Transmittance(shadowRay, INFINITY - EPS);
+#endif
             }
         }
     }
-#endif
 #endif
 
     // Analytic Lights 
 #ifdef LIGHTS
+    if (numOfLights > 0)
     {
         LightSampleRec lightSampleRec;
         Light light;
@@ -178,7 +183,13 @@ vec3 DirectLight(in Ray r, in State state)
                     weight = powerHeuristic(lightSampleRec.pdf, bsdfSampleRec.pdf);
 
                 if (bsdfSampleRec.pdf > 0.0)
-                    Li += weight * bsdfSampleRec.f * abs(dot(state.ffnormal, lightSampleRec.direction)) * lightSampleRec.emission / lightSampleRec.pdf;
+                {
+                    vec3 tr = vec3(1.0);
+#ifdef MEDIA
+                    tr = Transmittance(shadowRay, lightSampleRec.dist - EPS);
+#endif
+                    Li += weight * bsdfSampleRec.f * abs(dot(state.ffnormal, lightSampleRec.direction)) * tr * lightSampleRec.emission / lightSampleRec.pdf;
+                }
             }
         }
     }
@@ -187,6 +198,65 @@ vec3 DirectLight(in Ray r, in State state)
     return Li;
 }
 
+#ifdef MEDIA
+// DirectLight for a scattering event inside a medium, the phase function takes the place of the BSDF
+//-----------------------------------------------------------------------
+vec3 MediumLight(in Ray r, vec3 position, int phase, float g)
+//-----------------------------------------------------------------------
+{
+    vec3 Li = vec3(0.0);
+
+#ifdef ENVMAP
+    if (useEnvMap != 0)
+    {
+        vec3 color;
+        vec4 dirPdf = EnvSample(color);
+        vec3 lightDir = dirPdf.xyz;
+        float lightPdf = dirPdf.w;
+
+        Ray shadowRay = Ray(position, lightDir);
+        if (!AnyHit(shadowRay, INFINITY - EPS))
+        {
+            float p = PhaseEval(phase, g, dot(r.direction, lightDir));
+            Li += powerHeuristic(lightPdf, p) * p * color * Transmittance(shadowRay, INFINITY - EPS) / lightPdf;
+        }
+    }
+#endif
+
+#ifdef LIGHTS
+    if (numOfLights > 0)
+    {
+        LightSampleRec lightSampleRec;
+
+        int index = int(rand() * float(numOfLights)) * 5;
+
+        vec3 params = texelFetch(lightsTex, ivec2(index + 4, 0), 0).xyz;
+        Light light = Light(
+            texelFetch(lightsTex, ivec2(index + 0, 0), 0).xyz,
+            texelFetch(lightsTex, ivec2(index + 1, 0), 0).xyz,
+            texelFetch(lightsTex, ivec2(index + 2, 0), 0).xyz,
+            texelFetch(lightsTex, ivec2(index + 3, 0), 0).xyz,
+            params.x, params.y, params.z
+        );
+        sampleOneLight(light, position, lightSampleRec);
+
+        if (dot(lightSampleRec.direction, lightSampleRec.normal) < 0.0)
+        {
+            Ray shadowRay = Ray(position, lightSampleRec.direction);
+            if (!AnyHit(shadowRay, lightSampleRec.dist - EPS))
+            {
+                float p = PhaseEval(phase, g, dot(r.direction, lightSampleRec.direction));
+                float weight = light.area > 0.0 ? powerHeuristic(lightSampleRec.pdf, p) : 1.0;
+                Li += weight * p * Transmittance(shadowRay, lightSampleRec.dist - EPS) * lightSampleRec.emission / lightSampleRec.pdf;
+            }
+        }
+    }
+#endif
+
+    return Li;
+}
+#endif
+
 
 //-----------------------------------------------------------------------
 vec3 PathTrace(Ray r)
@@ -204,15 +274,38 @@ vec3 PathTrace(Ray r)
         state.depth = depth;
         bool hit = ClosestHit(r, state, lightSampleRec);
 
+#ifdef MEDIA
+        // Media in front of the surface, or of the background when nothing was hit
+        if (numOfMedia > 0)
+        {
+            vec3 scatterPos;
+            int phase;
+            float g;
+            int collision = DeltaTrack(r, hit ? state.hitDist : INFINITY, throughput, radiance, scatterPos, phase, g);
+
+            if (collision == COLLISION_ABSORBED)
+                break;
+
+            if (collision == COLLISION_SCATTERED)
+            {
+                radiance += MediumLight(r, scatterPos, phase, g) * throughput;
+
+                // The phase function is sampled exactly, its value and pdf cancel
+                vec3 L = PhaseSample(phase, g, r.direction);
+                bsdfSampleRec.pdf = PhaseEval(phase, g, dot(r.direction, L));
+                r = Ray(scatterPos, L);
+                continue;
+            }
+        }
+#endif
+
         if (!hit)
         {
-#ifdef CONSTANT_BG
-            radiance += bgColor * throughput;
-#else
 #ifdef ENVMAP
+            if (useEnvMap != 0)
             {
                 float misWeight = 1.0f;
-                vec2 uv = vec2((PI + atan(r.direction.z, r.direction.x)) * (1.0 / TWO_PI), acos(r.direction.y) * (1.0 / PI));
+                vec2 uv = vec2((PI + atan(r.direction.z, r.direction.x)) * (1.0 / TWO_PI), acos(clamp(r.direction.y, -1.0, 1.0)) * (1.0 / PI));
 
                 if (depth > 0)
                 {
@@ -222,8 +315,9 @@ vec3 PathTrace(Ray r)
                 }
                 radiance += misWeight * texture(hdrTex, uv).xyz * throughput * hdrMultiplier;
             }
+            else
 #endif
-#endif
+            radiance += bgColor * throughput;
             return radiance;
         }
 
@@ -269,8 +269,8 @@ void sampleOneLight(in Light light, in vec3 surfacePos, inout LightSampleRec lig
 }
 
 #ifdef ENVMAP
-#ifndef CONSTANT_BG
 
+// Tables built by path_tracer::environment::EnvironmentMap, read with nearest filtering
 //-----------------------------------------------------------------------
 float EnvPdf(in Ray r)
 //-----------------------------------------------------------------------
@@ -291,6 +291,11 @@ vec4 EnvSample(inout vec3 color)
     float v = texture(hdrMarginalDistTex, vec2(r1, 0.)).x;
     float u = texture(hdrCondDistTex, vec2(r2, v)).x;
 
+    // The tables hold texel centers, anywhere in the texel has the same pdf
+    vec2 size = vec2(textureSize(hdrCondDistTex, 0));
+    u += (rand() - 0.5) / size.x;
+    v += (rand() - 0.5) / size.y;
+
     color = texture(hdrTex, vec2(u, v)).xyz * hdrMultiplier;
     float pdf = texture(hdrCondDistTex, vec2(u, v)).y * texture(hdrMarginalDistTex, vec2(v, 0.)).y;
 
@@ -303,7 +308,6 @@ vec4 EnvSample(inout vec3 color)
     return vec4(-sin(theta) * cos(phi), cos(theta), -sin(theta) * sin(phi), (pdf * hdrResolution) / (2.0 * PI * PI * sin(theta)));
 }
 
-#endif
 #endif
 
 //-----------------------------------------------------------------------
@@ -23,32 +23,121 @@
  */
 
 // uniform bool isCameraMoving;
-// uniform bool useEnvMap;
 // uniform vec3 randomVector;
 // uniform vec2 screenResolution;
 // uniform float hdrTexSize;
-// uniform vec2 tileOffset;
-// uniform vec2 invNumTiles;
-
-// uniform sampler2D accumTexture;
-// uniform samplerBuffer BVH;
-// uniform isamplerBuffer vertexIndicesTex;
-// uniform samplerBuffer verticesTex;
-// uniform samplerBuffer normalsTex;
-// uniform sampler2D materialsTex;
-// uniform sampler2D transformsTex;
-// uniform sampler2D lightsTex;
+
 // uniform sampler2DArray textureMapsArrayTex;
 
-// uniform sampler2D hdrTex;
-// uniform sampler2D hdrMarginalDistTex;
-// uniform sampler2D hdrCondDistTex;
-
-// uniform float hdrResolution;
-// uniform float hdrMultiplier;
-// uniform vec3 bgColor;
-// uniform int numOfLights;
-// uniform int maxDepth;
-// uniform int topBVHIndex;
-// uniform int vertIndicesSize;
-// uniform int frame;
\ No newline at end of file
+// Scene buffers uploaded by path_tracer::gpu_scene, rows of BUFFER_WIDTH texels
+#define BUFFER_WIDTH 4096
+#define texelFetchBuffer(tex, i) texelFetch(tex, ivec2((i) % BUFFER_WIDTH, (i) / BUFFER_WIDTH), 0)
+
+layout(set = 2, binding = 5) uniform PathTracer_camera_position {
+    vec3 cameraPosition;
+};
+layout(set = 2, binding = 6) uniform PathTracer_camera_right {
+    vec3 cameraRight;
+};
+layout(set = 2, binding = 7) uniform PathTracer_camera_up {
+    vec3 cameraUp;
+};
+layout(set = 2, binding = 8) uniform PathTracer_camera_forward {
+    vec3 cameraForward;
+};
+layout(set = 2, binding = 9) uniform PathTracer_fov {
+    float cameraFov;
+};
+layout(set = 2, binding = 10) uniform PathTracer_focal_dist {
+    float cameraFocalDist;
+};
+layout(set = 2, binding = 11) uniform PathTracer_aperture {
+    float cameraAperture;
+};
+layout(set = 2, binding = 12) uniform PathTracer_bg_color {
+    vec3 bgColor;
+};
+layout(set = 2, binding = 13) uniform PathTracer_num_of_lights {
+    int numOfLights;
+};
+layout(set = 2, binding = 14) uniform PathTracer_top_bvh_index {
+    int topBVHIndex;
+};
+
+layout(set = 2, binding = 15) uniform texture2D PathTracer_bvh;
+layout(set = 2, binding = 16) uniform sampler PathTracer_bvh_sampler;
+layout(set = 2, binding = 17) uniform texture2D PathTracer_vertex_indices;
+layout(set = 2, binding = 18) uniform sampler PathTracer_vertex_indices_sampler;
+layout(set = 2, binding = 19) uniform texture2D PathTracer_vertices;
+layout(set = 2, binding = 20) uniform sampler PathTracer_vertices_sampler;
+layout(set = 2, binding = 21) uniform texture2D PathTracer_normals;
+layout(set = 2, binding = 22) uniform sampler PathTracer_normals_sampler;
+layout(set = 2, binding = 23) uniform texture2D PathTracer_materials;
+layout(set = 2, binding = 24) uniform sampler PathTracer_materials_sampler;
+layout(set = 2, binding = 25) uniform texture2D PathTracer_transforms;
+layout(set = 2, binding = 26) uniform sampler PathTracer_transforms_sampler;
+layout(set = 2, binding = 27) uniform texture2D PathTracer_lights;
+layout(set = 2, binding = 28) uniform sampler PathTracer_lights_sampler;
+
+// Progressive accumulation, frame counts from the last reset and accumTexture holds the sum so far
+layout(set = 2, binding = 29) uniform PathTracer_frame {
+    int frame;
+};
+layout(set = 2, binding = 30) uniform texture2D PathTracer_accum;
+layout(set = 2, binding = 31) uniform sampler PathTracer_accum_sampler;
+
+// Share of the targets traced, from their top left corner, set by path_tracer::quality
+layout(set = 2, binding = 32) uniform PathTracer_render_scale {
+    float renderScale;
+};
+
+// Environment map and its sampling tables, uploaded by path_tracer::environment, bgColor when useEnvMap is 0
+layout(set = 2, binding = 33) uniform PathTracer_use_env_map {
+    int useEnvMap;
+};
+layout(set = 2, binding = 34) uniform PathTracer_hdr_multiplier {
+    float hdrMultiplier;
+};
+layout(set = 2, binding = 35) uniform PathTracer_hdr_resolution {
+    float hdrResolution;
+};
+layout(set = 2, binding = 36) uniform texture2D PathTracer_hdr;
+layout(set = 2, binding = 37) uniform sampler PathTracer_hdr_sampler;
+layout(set = 2, binding = 38) uniform texture2D PathTracer_hdr_marginal_dist;
+layout(set = 2, binding = 39) uniform sampler PathTracer_hdr_marginal_dist_sampler;
+layout(set = 2, binding = 40) uniform texture2D PathTracer_hdr_cond_dist;
+layout(set = 2, binding = 41) uniform sampler PathTracer_hdr_cond_dist_sampler;
+
+// Offline renders trace the image a tile at a time, see path_tracer::offline
+// Bottom left corner of the tile and its size, both relative to the image, (0, 0) and (1, 1) otherwise
+layout(set = 2, binding = 42) uniform PathTracer_tile_offset {
+    vec2 tileOffset;
+};
+layout(set = 2, binding = 43) uniform PathTracer_inv_num_tiles {
+    vec2 invNumTiles;
+};
+
+// Participating media, MEDIUM_TEXELS texels each, see common/medium.glsl
+layout(set = 2, binding = 44) uniform PathTracer_num_of_media {
+    int numOfMedia;
+};
+layout(set = 2, binding = 45) uniform texture2D PathTracer_media;
+layout(set = 2, binding = 46) uniform sampler PathTracer_media_sampler;
+
+#define BVH                sampler2D(PathTracer_bvh, PathTracer_bvh_sampler)
+#define vertexIndicesTex   sampler2D(PathTracer_vertex_indices, PathTracer_vertex_indices_sampler)
+#define verticesTex        sampler2D(PathTracer_vertices, PathTracer_vertices_sampler)
+#define normalsTex         sampler2D(PathTracer_normals, PathTracer_normals_sampler)
+#define materialsTex       sampler2D(PathTracer_materials, PathTracer_materials_sampler)
+#define transformsTex      sampler2D(PathTracer_transforms, PathTracer_transforms_sampler)
+#define lightsTex          sampler2D(PathTracer_lights, PathTracer_lights_sampler)
+#define accumTexture       sampler2D(PathTracer_accum, PathTracer_accum_sampler)
+#define hdrTex             sampler2D(PathTracer_hdr, PathTracer_hdr_sampler)
+#define hdrMarginalDistTex sampler2D(PathTracer_hdr_marginal_dist, PathTracer_hdr_marginal_dist_sampler)
+#define hdrCondDistTex     sampler2D(PathTracer_hdr_cond_dist, PathTracer_hdr_cond_dist_sampler)
+#define mediaTex           sampler2D(PathTracer_media, PathTracer_media_sampler)
+
+#define maxDepth pathlenght
+#define LIGHTS
+#define ENVMAP
+#define MEDIA
@@ -2,6 +2,7 @@
 #include <pathtrace/common/globals.glsl>
 #include <pathtrace/common/intersection.glsl>
 #include <pathtrace/common/sampling.glsl>
+#include <pathtrace/common/medium.glsl>
 #include <pathtrace/common/anyhit.glsl>
 #include <pathtrace/common/closest_hit.glsl>
 #include <pathtrace/common/disney.glsl>
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::fs;
+
+use bevy::input::mouse::MouseMotion;
+use bevy::input::{Axis, Input};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use crate::input::GamepadLobby;
+
+static BINDINGS_PATH: &str = "assets/input/bindings.toml";
+static MOUSE_MOTION_SCALE: f32 = 0.05; // Pixels of mouse motion per frame to stick units
+static REBIND_AXIS_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+    Key(String),
+    MouseButton(String),
+    MouseMotion(String),
+    GamepadButton(String),
+    GamepadButtonAxis(String),
+    GamepadAxis(String),
+}
+
+impl Binding {
+    fn is_gamepad_analog(&self) -> bool {
+        matches!(self, Binding::GamepadAxis(_) | Binding::GamepadButtonAxis(_))
+    }
+
+    /*
+     * The name resolves back to an input, unknown bindings are never triggered
+     */
+    pub fn is_known(&self) -> bool {
+        match self {
+            Binding::Key(k) => key_code(k).is_some(),
+            Binding::MouseButton(m) => mouse_button(m).is_some(),
+            Binding::MouseMotion(a) => a == "X" || a == "Y",
+            Binding::GamepadButton(n) | Binding::GamepadButtonAxis(n) => gamepad_button_type(n).is_some(),
+            Binding::GamepadAxis(n) => gamepad_axis_type(n).is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ActionConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bindings: Vec<Binding>,
+}
+
+fn default_sensitivity() -> f32 {
+    1.0
+}
+
+// TOML needs plain values before arrays of tables, so the bindings are the last fields
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AxisConfig {
+    #[serde(default)]
+    pub deadzone: f32,      // Applies to gamepad sticks and triggers
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity: f32,
+    #[serde(default)]
+    pub invert: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub positive: Vec<Binding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub negative: Vec<Binding>,
+}
+
+impl Default for AxisConfig {
+    fn default() -> Self {
+        Self { deadzone: 0.0, sensitivity: 1.0, invert: false, positive: Vec::new(), negative: Vec::new() }
+    }
+}
+
+/*
+ * Removes the deadzone and rescales the rest so the output still reaches 1
+ */
+pub fn apply_deadzone(v: f32, deadzone: f32) -> f32 {
+    if v.abs() <= deadzone {
+        0.0
+    } else {
+        v.signum() * (v.abs() - deadzone) / (1.0 - deadzone).max(f32::EPSILON)
+    }
+}
+
+impl AxisConfig {
+    /*
+     * Value of the axis given the raw value of every binding
+     */
+    pub fn value(&self, raw: &dyn Fn(&Binding) -> f32) -> f32 {
+        let read = |b: &Binding| if b.is_gamepad_analog() { apply_deadzone(raw(b), self.deadzone) } else { raw(b) };
+
+        let v: f32 = self.positive.iter().map(read).sum::<f32>() - self.negative.iter().map(read).sum::<f32>();
+        let v = v * self.sensitivity;
+
+        if self.invert { -v } else { v }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebindSlot {
+    Action,
+    Positive,
+    Negative,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ActionMap {
+    #[serde(default)]
+    pub actions: HashMap<String, ActionConfig>,
+    #[serde(default)]
+    pub axes: HashMap<String, AxisConfig>,
+}
+
+impl ActionMap {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&contents)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let contents = toml::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(path, contents).map_err(|e| e.to_string())
+    }
+
+    /*
+     * Replaces the binding at index of an action or axis side, appends it if index is past the end
+     */
+    pub fn rebind(&mut self, name: &str, slot: RebindSlot, index: usize, binding: Binding) -> Result<(), String> {
+        if !binding.is_known() {
+            return Err(format!("Can not bind {:?}", binding));
+        }
+
+        let bindings = match slot {
+            RebindSlot::Action => self.actions.get_mut(name).map(|a| &mut a.bindings),
+            RebindSlot::Positive => self.axes.get_mut(name).map(|a| &mut a.positive),
+            RebindSlot::Negative => self.axes.get_mut(name).map(|a| &mut a.negative),
+        }.ok_or(format!("Unknown action or axis {}", name))?;
+
+        if index < bindings.len() {
+            bindings[index] = binding;
+        } else {
+            bindings.push(binding);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * The slot an existing binding is in, used to pick what to rebind by pressing its current input
+     */
+    pub fn find_slot(&self, binding: &Binding) -> Option<PendingRebind> {
+        let find = |bindings: &Vec<Binding>| bindings.iter().position(|b| b == binding);
+        let slot = |name: &String, slot: RebindSlot, index: usize| PendingRebind { name: name.clone(), slot, index };
+
+        self.actions.iter().find_map(|(name, a)| find(&a.bindings).map(|i| slot(name, RebindSlot::Action, i)))
+            .or_else(|| self.axes.iter().find_map(|(name, a)| find(&a.positive).map(|i| slot(name, RebindSlot::Positive, i))))
+            .or_else(|| self.axes.iter().find_map(|(name, a)| find(&a.negative).map(|i| slot(name, RebindSlot::Negative, i))))
+    }
+}
+
+/*
+ * Actions and axes evaluated for the current frame
+ */
+#[derive(Debug, Default)]
+pub struct ActionState {
+    pub(crate) pressed: HashSet<String>,
+    pub(crate) just_pressed: HashSet<String>,
+    pub(crate) axes: HashMap<String, f32>,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: &str) -> bool {
+        self.pressed.contains(action)
+    }
+
+    pub fn just_pressed(&self, action: &str) -> bool {
+        self.just_pressed.contains(action)
+    }
+
+    pub fn axis(&self, axis: &str) -> f32 {
+        self.axes.get(axis).cloned().unwrap_or(0.0)
+    }
+}
+
+/*
+ * Inserted by the rebind action, the next input pressed selects the slot it is bound to
+ */
+pub struct SelectRebind;
+
+/*
+ * The next input pressed is bound to the slot
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRebind {
+    pub name: String,
+    pub slot: RebindSlot,
+    pub index: usize,
+}
+
+pub fn key_code(name: &str) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match name {
+        "A" => A, "B" => B, "C" => C, "D" => D, "E" => E, "F" => F, "G" => G, "H" => H, "I" => I,
+        "J" => J, "K" => K, "L" => L, "M" => M, "N" => N, "O" => O, "P" => P, "Q" => Q, "R" => R,
+        "S" => S, "T" => T, "U" => U, "V" => V, "W" => W, "X" => X, "Y" => Y, "Z" => Z,
+        "Key0" => Key0, "Key1" => Key1, "Key2" => Key2, "Key3" => Key3, "Key4" => Key4,
+        "Key5" => Key5, "Key6" => Key6, "Key7" => Key7, "Key8" => Key8, "Key9" => Key9,
+        "F1" => F1, "F2" => F2, "F3" => F3, "F4" => F4, "F5" => F5, "F6" => F6,
+        "F7" => F7, "F8" => F8, "F9" => F9, "F10" => F10, "F11" => F11, "F12" => F12,
+        "Up" => Up, "Down" => Down, "Left" => Left, "Right" => Right,
+        "Space" => Space, "Return" => Return, "Tab" => Tab, "Escape" => Escape, "Back" => Back,
+        "LShift" => LShift, "RShift" => RShift, "LControl" => LControl, "RControl" => RControl,
+        "LAlt" => LAlt, "RAlt" => RAlt,
+        _ => return None
+    })
+}
+
+pub fn mouse_button(name: &str) -> Option<MouseButton> {
+    match name {
+        "Left" => Some(MouseButton::Left),
+        "Right" => Some(MouseButton::Right),
+        "Middle" => Some(MouseButton::Middle),
+        _ => name.parse::<u16>().ok().map(MouseButton::Other)
+    }
+}
+
+pub fn gamepad_button_type(name: &str) -> Option<GamepadButtonType> {
+    use GamepadButtonType::*;
+    Some(match name {
+        "South" => South, "East" => East, "North" => North, "West" => West, "C" => C, "Z" => Z,
+        "LeftTrigger" => LeftTrigger, "LeftTrigger2" => LeftTrigger2,
+        "RightTrigger" => RightTrigger, "RightTrigger2" => RightTrigger2,
+        "Select" => Select, "Start" => Start, "Mode" => Mode,
+        "LeftThumb" => LeftThumb, "RightThumb" => RightThumb,
+        "DPadUp" => DPadUp, "DPadDown" => DPadDown, "DPadLeft" => DPadLeft, "DPadRight" => DPadRight,
+        _ => return None
+    })
+}
+
+pub fn gamepad_axis_type(name: &str) -> Option<GamepadAxisType> {
+    use GamepadAxisType::*;
+    Some(match name {
+        "LeftStickX" => LeftStickX, "LeftStickY" => LeftStickY, "LeftZ" => LeftZ,
+        "RightStickX" => RightStickX, "RightStickY" => RightStickY, "RightZ" => RightZ,
+        "DPadX" => DPadX, "DPadY" => DPadY,
+        _ => return None
+    })
+}
+
+pub fn setup_actions(mut commands: Commands) {
+    let cwd = std::env::current_dir().unwrap().display().to_string();
+    let path = format!("{0}/{1}", cwd, BINDINGS_PATH);
+
+    let map = ActionMap::load(&path).unwrap_or_else(|e| {
+        warn!("Could not load {}: {}", path, e);
+        ActionMap::default()
+    });
+
+    commands.insert_resource(map);
+    commands.insert_resource(ActionState::default());
+}
+
+/*
+ * Evaluates every action and axis from the raw devices
+ */
+pub fn update_actions(
+    map: Res<ActionMap>,
+    mut state: ResMut<ActionState>,
+    windows: Res<Windows>,
+    select: Option<Res<SelectRebind>>,
+    pending: Option<Res<PendingRebind>>,
+    lobby: Res<GamepadLobby>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    button_inputs: Res<Input<GamepadButton>>,
+    button_axes: Res<Axis<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut mouse_motion_events: EventReader<MouseMotion>,
+) {
+    // A free cursor moves over the window instead of looking around
+    let motion = mouse_motion_events.iter().fold(Vec2::ZERO, |m, e| m + e.delta) * MOUSE_MOTION_SCALE;
+    let motion = if windows.get_primary().map_or(false, |w| w.cursor_locked()) { motion } else { Vec2::ZERO };
+    let gamepads: Vec<Gamepad> = lobby.gamepads.iter().cloned().collect();
+
+    let raw = |b: &Binding| -> f32 {
+        let held = |pressed: bool| if pressed { 1.0 } else { 0.0 };
+        match b {
+            Binding::Key(k) => key_code(k).map_or(0.0, |k| held(keyboard_input.pressed(k))),
+            Binding::MouseButton(m) => mouse_button(m).map_or(0.0, |m| held(mouse_input.pressed(m))),
+            Binding::MouseMotion(a) => match a.as_str() {
+                "X" => motion.x,
+                "Y" => -motion.y,
+                _ => 0.0
+            },
+            Binding::GamepadButton(n) => gamepad_button_type(n).map_or(0.0, |t| {
+                held(gamepads.iter().any(|g| button_inputs.pressed(GamepadButton(*g, t))))
+            }),
+            Binding::GamepadButtonAxis(n) => gamepad_button_type(n).map_or(0.0, |t| {
+                gamepads.iter().filter_map(|g| button_axes.get(GamepadButton(*g, t))).sum()
+            }),
+            Binding::GamepadAxis(n) => gamepad_axis_type(n).map_or(0.0, |t| {
+                gamepads.iter().filter_map(|g| axes.get(GamepadAxis(*g, t))).sum()
+            }),
+        }
+    };
+
+    let just_pressed = |b: &Binding| -> bool {
+        match b {
+            Binding::Key(k) => key_code(k).map_or(false, |k| keyboard_input.just_pressed(k)),
+            Binding::MouseButton(m) => mouse_button(m).map_or(false, |m| mouse_input.just_pressed(m)),
+            Binding::GamepadButton(n) => gamepad_button_type(n).map_or(false, |t| {
+                gamepads.iter().any(|g| button_inputs.just_pressed(GamepadButton(*g, t)))
+            }),
+            _ => false
+        }
+    };
+
+    state.pressed.clear();
+    state.just_pressed.clear();
+
+    // Nothing reaches the game while an input is captured for rebinding
+    if select.is_some() || pending.is_some() {
+        state.axes.clear();
+        return;
+    }
+
+    for (name, action) in map.actions.iter() {
+        if action.bindings.iter().any(|b| raw(b) > 0.5) {
+            state.pressed.insert(name.clone());
+        }
+        if action.bindings.iter().any(|b| just_pressed(b)) {
+            state.just_pressed.insert(name.clone());
+        }
+    }
+
+    state.axes = map.axes.iter().map(|(name, axis)| (name.clone(), axis.value(&raw))).collect();
+}
+
+/*
+ * The rebind action waits for the current input of an action or axis side, then binds the next key, button or stick
+ * movement to that slot and saves the bindings
+ */
+pub fn rebind_actions(
+    mut commands: Commands,
+    state: Res<ActionState>,
+    select: Option<Res<SelectRebind>>,
+    pending: Option<Res<PendingRebind>>,
+    mut map: ResMut<ActionMap>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    button_inputs: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    lobby: Res<GamepadLobby>,
+) {
+    if select.is_none() && pending.is_none() {
+        if state.just_pressed("rebind") {
+            info!("Press the input to rebind");
+            commands.insert_resource(SelectRebind);
+        }
+        return;
+    }
+
+    // Keys without a name in key_code are skipped, the slot waits for the next input
+    let binding = keyboard_input.get_just_pressed().map(|k| Binding::Key(format!("{:?}", k))).find(Binding::is_known)
+        .or_else(|| mouse_input.get_just_pressed().next().map(|m| Binding::MouseButton(match m {
+            MouseButton::Other(n) => n.to_string(),
+            _ => format!("{:?}", m)
+        })))
+        .or_else(|| button_inputs.get_just_pressed().next().map(|b| Binding::GamepadButton(format!("{:?}", b.1))))
+        .or_else(|| {
+            lobby.gamepads.iter().flat_map(|g| {
+                [GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY, GamepadAxisType::RightStickX, GamepadAxisType::RightStickY]
+                    .iter()
+                    .filter(|t| axes.get(GamepadAxis(*g, **t)).map_or(false, |v| v.abs() > REBIND_AXIS_THRESHOLD))
+                    .map(|t| Binding::GamepadAxis(format!("{:?}", t)))
+                    .collect::<Vec<_>>()
+            }).next()
+        });
+
+    let binding = match binding {
+        Some(b) => b,
+        None => return
+    };
+
+    if let Some(pending) = pending {
+        info!("Binding {:?} to {} {:?}", binding, pending.name, pending.slot);
+        if let Err(e) = map.rebind(&pending.name, pending.slot, pending.index, binding) {
+            warn!("{}", e);
+        } else if let Err(e) = map.save(BINDINGS_PATH) {
+            warn!("Could not save bindings: {}", e);
+        }
+        commands.remove_resource::<PendingRebind>();
+    } else {
+        match map.find_slot(&binding) {
+            Some(slot) => {
+                info!("Press the new input for {} {:?}", slot.name, slot.slot);
+                commands.insert_resource(slot);
+            },
+            None => warn!("{:?} is not bound", binding)
+        }
+        commands.remove_resource::<SelectRebind>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_map() -> ActionMap {
+        ActionMap::parse(include_str!("../assets/input/bindings.toml")).unwrap()
+    }
+
+    #[test]
+    fn default_bindings_parse() {
+        let map = default_map();
+        assert!(map.actions.contains_key("jump"));
+        assert_eq!(Binding::Key("W".to_string()), map.axes["move_y"].positive[0]);
+        assert_eq!(1.0, map.axes["roll"].sensitivity);
+    }
+
+    #[test]
+    fn every_default_binding_is_known() {
+        let map = default_map();
+        let bindings = map.actions.values().flat_map(|a| a.bindings.iter())
+            .chain(map.axes.values().flat_map(|a| a.positive.iter().chain(a.negative.iter())));
+
+        for b in bindings {
+            assert!(b.is_known(), "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn deadzone_is_rescaled() {
+        assert_eq!(0.0, apply_deadzone(0.05, 0.1));
+        assert!((apply_deadzone(1.0, 0.1) - 1.0).abs() < 1.0e-6);
+        assert!((apply_deadzone(-0.55, 0.1) + 0.5).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn axis_applies_sensitivity_and_inversion() {
+        let stick = Binding::GamepadAxis("LeftStickX".to_string());
+        let key = Binding::Key("A".to_string());
+        let axis = AxisConfig { deadzone: 0.2, sensitivity: 2.0, invert: true, positive: vec![stick.clone()], negative: vec![key.clone()] };
+
+        let raw = |b: &Binding| if *b == stick { 0.6 } else { 0.0 };
+        assert!((axis.value(&raw) + 1.0).abs() < 1.0e-6);
+
+        let raw = |b: &Binding| if *b == key { 1.0 } else { 0.1 };
+        assert!((axis.value(&raw) - 2.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn rebinding_replaces_and_appends() {
+        let mut map = default_map();
+        map.rebind("jump", RebindSlot::Action, 0, Binding::Key("J".to_string())).unwrap();
+        map.rebind("move_x", RebindSlot::Negative, 10, Binding::Key("Left".to_string())).unwrap();
+
+        assert_eq!(Binding::Key("J".to_string()), map.actions["jump"].bindings[0]);
+        assert_eq!(Some(&Binding::Key("Left".to_string())), map.axes["move_x"].negative.last());
+        assert!(map.rebind("missing", RebindSlot::Action, 0, Binding::Key("J".to_string())).is_err());
+        assert!(map.rebind("jump", RebindSlot::Action, 0, Binding::Key(format!("{:?}", KeyCode::Numpad5))).is_err());
+        assert_eq!(Binding::Key("J".to_string()), map.actions["jump"].bindings[0]);
+
+        let saved = toml::to_string(&map).unwrap();
+        assert_eq!(map, ActionMap::parse(&saved).unwrap());
+    }
+
+    #[test]
+    fn current_input_selects_the_slot() {
+        let map = default_map();
+        let slot = |name: &str, slot: RebindSlot, index: usize| Some(PendingRebind { name: name.to_string(), slot, index });
+
+        assert_eq!(slot("jump", RebindSlot::Action, 1), map.find_slot(&Binding::GamepadButton("South".to_string())));
+        assert_eq!(slot("move_x", RebindSlot::Negative, 0), map.find_slot(&Binding::Key("A".to_string())));
+        assert_eq!(None, map.find_slot(&Binding::Key("F12".to_string())));
+    }
+}
@@ -1,29 +1,39 @@
 use bevy::prelude::*;
 
-use bevy::render::camera::Camera;
+use bevy::render::camera::{Camera, PerspectiveProjection};
 use bevy::{math::Vec3};
 use bevy_frustum_culling::FrustumCulling;
 
 use crate::constants::GLOBAL_SCALE;
+use crate::easing::{damp_3d, damp_rot, slerp, smooth_step};
+
+static TRANSITION_TIME: f32 = 1.5;   //s
+static ORBIT_SPEED: f32 = 0.04;      //rad per unit of the look axes
+static ZOOM_SPEED: f32 = 1.0;        //Doublings of the orbit distance per second
+static MIN_ORBIT_DISTANCE: f32 = 1.0e-4;
+static MAX_ORBIT_PITCH: f32 = 1.55;  //rad
+static KEYFRAME_SPACING: f32 = 2.0;  //s between captured keyframes
 
 pub fn setup_camera(mut commands: Commands) {
     let mut t = Transform::from_translation(Vec3::new(0.0, 0.0, 0.0))
         .looking_at(Vec3::new(1.0, 0.0, 0.0), Vec3::Y);
     commands
         .spawn()
-        .insert_bundle(OrthographicCameraBundle {
+        .insert_bundle(PerspectiveCameraBundle {
             transform: t,
-            orthographic_projection: bevy::render::camera::OrthographicProjection {
-                scale: 0.01,
+            perspective_projection: PerspectiveProjection {
+                near: 1.0e-4,
+                far: 1.0e4,
                 ..Default::default()
             },
-            ..OrthographicCameraBundle::new_3d()
+            ..Default::default()
         })
         .insert(PlayerCamera {
             position: t.translation,
             rotation: t.rotation,
             ..Default::default()
         })
+        .insert(CameraRig::new(t.translation, t.rotation))
         .insert(FrustumCulling);
 }
 
@@ -40,34 +50,310 @@ pub struct PlayerCamera {
 }
 impl Default for PlayerCamera {
     fn default() -> Self {
-        Self { 
+        Self {
             position: Default::default(),
             rotation: Default::default(),
-            
+
             rotation_easing: 10.0,
             position_easing: 2.0,
-            
+
             rotation_speed: 0.1,
             position_speed: 1.0
         }
     }
 }
 
+/*
+ * Entities the orbit and follow modes can be pointed at
+ */
+pub struct CameraTarget;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,  //s
+    pub position: Vec3,
+    pub rotation: Quat,
+}
+
+/*
+ * Catmull-Rom spline through the keyframe positions, rotations are eased between neighbouring keyframes
+ */
+#[derive(Debug, Clone, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+    pub looping: bool,
+}
+
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+impl CameraPath {
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+
+    /*
+     * Appends a pose KEYFRAME_SPACING after the last keyframe
+     */
+    pub fn push(&mut self, position: Vec3, rotation: Quat) {
+        let time = if self.keyframes.is_empty() { 0.0 } else { self.duration() + KEYFRAME_SPACING };
+        self.keyframes.push(Keyframe { time, position, rotation });
+    }
+
+    pub fn sample(&self, time: f32) -> Option<(Vec3, Quat)> {
+        let n = self.keyframes.len();
+        let (first, last) = (self.keyframes.first()?, self.keyframes.last()?);
+
+        let time = if self.looping && self.duration() > 0.0 { time.rem_euclid(self.duration()) } else { time };
+        if n == 1 || time <= first.time {
+            return Some((first.position, first.rotation));
+        }
+        if time >= last.time {
+            return Some((last.position, last.rotation));
+        }
+
+        let i = self.keyframes.iter().rposition(|k| k.time <= time).unwrap_or(0).min(n - 2);
+        let (k1, k2) = (&self.keyframes[i], &self.keyframes[i + 1]);
+        let k0 = &self.keyframes[i.saturating_sub(1)];
+        let k3 = &self.keyframes[(i + 2).min(n - 1)];
+
+        let t = ((time - k1.time) / (k2.time - k1.time).max(f32::EPSILON)).clamp(0.0, 1.0);
+        let position = catmull_rom(k0.position, k1.position, k2.position, k3.position, t);
+        let rotation = slerp(k1.rotation, k2.rotation, smooth_step::<2>(t));
+
+        Some((position, rotation))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CameraMode {
+    FreeFly,
+    Orbit { center: Vec3, distance: f32, yaw: f32, pitch: f32 },
+    Follow { offset: Vec3, lag: f32 },
+    Cinematic { time: f32 },
+}
+
+impl CameraMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CameraMode::FreeFly => "free-fly",
+            CameraMode::Orbit { .. } => "orbit",
+            CameraMode::Follow { .. } => "follow",
+            CameraMode::Cinematic { .. } => "cinematic",
+        }
+    }
+}
+
+/*
+ * Pose on a sphere around the center looking at it
+ */
+pub fn orbit_pose(center: Vec3, distance: f32, yaw: f32, pitch: f32) -> (Vec3, Quat) {
+    let rotation = Quat::from_rotation_y(yaw) * Quat::from_rotation_x(-pitch);
+    (center + rotation * Vec3::Z * distance, rotation)
+}
+
+/*
+ * Places the camera by its mode, switching modes blends from the pose the camera had over TRANSITION_TIME
+ */
+pub struct CameraRig {
+    pub mode: CameraMode,
+    pub path: CameraPath,
+    pub transition_time: f32,
+
+    from: (Vec3, Quat),
+    blend: f32,
+    lagged: Vec3,  // Follow position trailing the target
+}
+
+impl CameraRig {
+    pub fn new(position: Vec3, rotation: Quat) -> Self {
+        Self {
+            mode: CameraMode::FreeFly,
+            path: CameraPath::default(),
+            transition_time: TRANSITION_TIME,
+            from: (position, rotation),
+            blend: 1.0,
+            lagged: position,
+        }
+    }
+
+    pub fn switch(&mut self, mode: CameraMode, current: &Transform) {
+        self.from = (current.translation, current.rotation);
+        self.lagged = current.translation;
+        self.blend = 0.0;
+        self.mode = mode;
+    }
+
+    /*
+     * Free-fly, orbit around the target, follow the target, the cinematic path if there is one
+     */
+    pub fn next_mode(&self, target: Option<&GlobalTransform>, current: &Transform) -> CameraMode {
+        match self.mode {
+            CameraMode::FreeFly => {
+                let center = target.map_or(current.translation + current.rotation * -Vec3::Z, |t| t.translation);
+                let distance = (current.translation - center).length().max(MIN_ORBIT_DISTANCE);
+                CameraMode::Orbit { center, distance, yaw: 0.0, pitch: 0.3 }
+            }
+            CameraMode::Orbit { .. } if target.is_some() => CameraMode::Follow { offset: Vec3::new(0.0, 0.2, 1.0), lag: 0.3 },
+            CameraMode::Orbit { .. } | CameraMode::Follow { .. } if !self.path.keyframes.is_empty() => CameraMode::Cinematic { time: 0.0 },
+            _ => CameraMode::FreeFly,
+        }
+    }
+
+    pub fn in_transition(&self) -> bool {
+        self.blend < 1.0
+    }
+
+    /*
+     * Where the current mode wants the camera this frame
+     */
+    fn desired(&mut self, current: &Transform, pc: &PlayerCamera, target: Option<&GlobalTransform>, actions: Option<&crate::actions::ActionState>, dt: f32) -> (Vec3, Quat) {
+        let axis = |name: &str| actions.map_or(0.0, |a| a.axis(name));
+
+        match &mut self.mode {
+            CameraMode::FreeFly => (
+                damp_3d(current.translation, pc.position, pc.position_easing, dt),
+                damp_rot(current.rotation, pc.rotation, pc.rotation_easing, dt),
+            ),
+            CameraMode::Orbit { center, distance, yaw, pitch } => {
+                if let Some(t) = target {
+                    *center = t.translation;
+                }
+                *yaw -= axis("look_x") * ORBIT_SPEED;
+                *pitch = (*pitch + axis("look_y") * ORBIT_SPEED).clamp(-MAX_ORBIT_PITCH, MAX_ORBIT_PITCH);
+                *distance = (*distance * (-axis("move_y") * ZOOM_SPEED * dt).exp2()).max(MIN_ORBIT_DISTANCE);
+                orbit_pose(*center, *distance, *yaw, *pitch)
+            }
+            CameraMode::Follow { offset, lag } => match target {
+                Some(t) => {
+                    let goal = t.translation + t.rotation * *offset;
+                    self.lagged = damp_3d(self.lagged, goal, 1.0 / lag.max(f32::EPSILON), dt);
+                    (self.lagged, Transform::from_translation(self.lagged).looking_at(t.translation, t.rotation * Vec3::Y).rotation)
+                }
+                None => (current.translation, current.rotation)
+            },
+            CameraMode::Cinematic { time } => {
+                *time += dt;
+                self.path.sample(*time).unwrap_or((current.translation, current.rotation))
+            }
+        }
+    }
+
+    pub fn update(&mut self, current: &Transform, pc: &PlayerCamera, target: Option<&GlobalTransform>, actions: Option<&crate::actions::ActionState>, dt: f32) -> (Vec3, Quat) {
+        let (position, rotation) = self.desired(current, pc, target, actions, dt);
+        if !self.in_transition() {
+            return (position, rotation);
+        }
+
+        self.blend = (self.blend + dt / self.transition_time.max(f32::EPSILON)).min(1.0);
+        let s = smooth_step::<2>(self.blend);
+        (self.from.0.lerp(position, s), slerp(self.from.1, rotation, s))
+    }
+}
+
 pub fn update_camera(
-    mut query: Query<(&mut Transform, &PlayerCamera), Changed<PlayerCamera>>,
+    mut query: Query<(&mut Transform, &PlayerCamera, &mut CameraRig)>,
+    targets: Query<&GlobalTransform, With<CameraTarget>>,
+    actions: Option<Res<crate::actions::ActionState>>,
     time: Res<Time>,
 ) {
-    for (mut t, pc) in query.iter_mut() {
-        t.rotation = crate::easing::asymptotic_averaging_rot(
-            t.rotation,
-            pc.rotation,
-            pc.rotation_easing * (time.delta_seconds() as f32)
-        );
-
-        t.translation = crate::easing::asymptotic_averaging_3d(
-            t.translation, 
-            pc.position, 
-            pc.position_easing * (time.delta_seconds() as f32)
-        );
-    }
-}
\ No newline at end of file
+    let dt = time.delta_seconds();
+    let target = targets.iter().next();
+    let actions = actions.as_ref().map(|a| &**a);
+
+    for (mut t, pc, mut rig) in query.iter_mut() {
+        if actions.map_or(false, |a| a.just_pressed("camera_mode")) {
+            let mode = rig.next_mode(target, &t);
+            info!("Camera mode {}", mode.name());
+            rig.switch(mode, &t);
+        }
+
+        // The cinematic mode plays the poses captured here
+        if actions.map_or(false, |a| a.just_pressed("camera_keyframe")) && !matches!(rig.mode, CameraMode::Cinematic { .. }) {
+            rig.path.push(t.translation, t.rotation);
+            info!("Camera keyframe {} at {}s", rig.path.keyframes.len(), rig.path.duration());
+        }
+
+        let (position, rotation) = rig.update(&t, pc, target, actions, dt);
+        t.translation = position;
+        t.rotation = rotation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path() -> CameraPath {
+        CameraPath {
+            keyframes: vec![
+                Keyframe { time: 0.0, position: Vec3::ZERO, rotation: Quat::IDENTITY },
+                Keyframe { time: 1.0, position: Vec3::X, rotation: Quat::from_rotation_y(1.0) },
+                Keyframe { time: 3.0, position: Vec3::new(1.0, 1.0, 0.0), rotation: Quat::from_rotation_y(2.0) },
+            ],
+            looping: false,
+        }
+    }
+
+    #[test]
+    fn paths_pass_through_their_keyframes() {
+        let path = path();
+        for k in path.keyframes.iter() {
+            let (p, r) = path.sample(k.time).unwrap();
+            assert!(p.distance(k.position) < 1.0e-5, "{:?} at {}", p, k.time);
+            assert!(r.dot(k.rotation).abs() > 0.9999);
+        }
+        assert_eq!(path.sample(10.0).unwrap().0, Vec3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn paths_are_continuous() {
+        let path = path();
+        let mut previous = path.sample(0.0).unwrap().0;
+        for i in 1..=300 {
+            let p = path.sample(i as f32 * 0.01).unwrap().0;
+            assert!(p.distance(previous) < 0.05);
+            previous = p;
+        }
+    }
+
+    #[test]
+    fn captured_keyframes_enable_cinematic() {
+        let t = Transform::from_translation(Vec3::new(0.0, 0.0, 10.0));
+        let mut rig = CameraRig::new(t.translation, t.rotation);
+        rig.mode = CameraMode::Orbit { center: Vec3::ZERO, distance: 10.0, yaw: 0.0, pitch: 0.0 };
+        assert!(matches!(rig.next_mode(None, &t), CameraMode::FreeFly));
+
+        rig.path.push(t.translation, t.rotation);
+        rig.path.push(Vec3::ZERO, t.rotation);
+        assert_eq!(KEYFRAME_SPACING, rig.path.duration());
+        assert!(matches!(rig.next_mode(None, &t), CameraMode::Cinematic { .. }));
+    }
+
+    #[test]
+    fn orbit_looks_at_the_center() {
+        let center = Vec3::new(1.0, 2.0, 3.0);
+        let (p, r) = orbit_pose(center, 5.0, 0.7, 0.4);
+        assert!((p.distance(center) - 5.0).abs() < 1.0e-5);
+        assert!((r * -Vec3::Z).dot((center - p).normalize()) > 0.9999);
+    }
+
+    #[test]
+    fn transitions_end_at_the_new_mode() {
+        let t = Transform::from_translation(Vec3::new(0.0, 0.0, 10.0));
+        let mut rig = CameraRig::new(t.translation, t.rotation);
+        rig.switch(CameraMode::Orbit { center: Vec3::ZERO, distance: 2.0, yaw: 0.0, pitch: 0.0 }, &t);
+
+        let first = rig.update(&t, &PlayerCamera::default(), None, None, 0.1);
+        assert!(first.0.z > 8.5);
+
+        for _ in 0..20 {
+            rig.update(&t, &PlayerCamera::default(), None, None, 0.1);
+        }
+        assert!(!rig.in_transition());
+        assert!(rig.update(&t, &PlayerCamera::default(), None, None, 0.1).0.distance(Vec3::new(0.0, 0.0, 2.0)) < 1.0e-5);
+    }
+}
@@ -59,7 +59,8 @@ pub fn load_chunk(
 	mut commands: Commands,
 	camera_query: Query<&Transform, With<crate::camera::PlayerCamera>>,
 	thread_pool: Res<AsyncComputeTaskPool>,
-	state: Local<crate::state::GameState>,
+	state: Res<crate::state::GameState>,
+	surface: Option<Res<crate::procedual::terrain::ActiveSurface>>,
 
     chunk_query: Query<&VoxelChunk, With<VoxelChunk>>
 	// mut loaded: ResMut<Option<LoadedChunks>>,
@@ -71,11 +72,12 @@ pub fn load_chunk(
         if !chunk_query.iter().any(|x| x.position == ft) {
             println!("Generating chunk {:?}, {:?}", ft, t.translation);
             let seed = state.seed;
+            let surface = surface.map(|s| *s);
             
             let len = 2u64;//materials_mappings.as_ref().unwrap().map.len() as u64;
 
             commands.spawn().insert(thread_pool.spawn(async move {
-                return generate_chunk(seed, ft, CHUNK_SIZE as u64, len);
+                return generate_chunk(seed, ft, CHUNK_SIZE as u64, len, surface);
             }));
         }
 	}
@@ -95,7 +97,10 @@ fn is_loaded(vc: &VoxelChunk, pos: Vec3, len: f32) -> bool {
     return true;
 }
 
-fn generate_chunk(seed: u64, pos: Vec3, size: u64, number_of_materials: u64) -> VoxelChunk {
+/*
+ * On a planet surface the voxels follow its heightfield, otherwise the chunk is a sphere
+ */
+pub(crate) fn generate_chunk(seed: u64, pos: Vec3, size: u64, number_of_materials: u64, surface: Option<crate::procedual::terrain::ActiveSurface>) -> VoxelChunk {
     let mut chunk = VoxelChunk::default();
     chunk.position = pos;
 
@@ -107,9 +112,15 @@ fn generate_chunk(seed: u64, pos: Vec3, size: u64, number_of_materials: u64) ->
             for z in 0..size {
                 i += 1;
 
-                if Vec3::new(cs/2.0, cs/2.0, cs/2.0).distance(Vec3::new(x as f32, y as f32, z as f32)) <=  cs/2.0 {
-                    let pbr_id = noise::noise_3d(x, y, z, seed) % number_of_materials;
+                let pbr_id = match surface {
+                    Some(s) => s.voxel(pos + Vec3::new(x as f32, y as f32, z as f32)).map(|biome| biome as u64 % number_of_materials),
+                    None if Vec3::new(cs/2.0, cs/2.0, cs/2.0).distance(Vec3::new(x as f32, y as f32, z as f32)) <=  cs/2.0 => {
+                        Some(noise::noise_3d(x, y, z, seed) % number_of_materials)
+                    }
+                    None => None
+                };
 
+                if let Some(pbr_id) = pbr_id {
                     chunk.voxels.push(Voxel {
                         id: noise::noise_1d(i, seed),
                         position:  Vec3::new(x as f32, y as f32, z as f32),
@@ -0,0 +1,276 @@
+use bevy::math::{DVec3, Vec3A};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_rapier3d::prelude::*;
+
+use crate::constants::{GLOBAL_SCALE, PHYSICS_GRAVITY};
+use crate::physics::{Identity, Mass};
+use crate::procedual::solar_system::{planet_radius, Asteroid, Moon, Planet};
+
+static MAX_DEBRIS: usize = 8;              // Always even, debris is ejected in opposite pairs
+static MERGE_ENERGY_FRACTION: f64 = 0.1;   // Impacts below this fraction of the binding energy merge without debris
+static MIN_REMNANT_FRACTION: f64 = 0.1;
+static DEBRIS_EJECTION_SPEED: f64 = 1.1;   // Times the escape velocity of the remnant
+
+/*
+ * Mass in Kg, radius in m, position and veclocity in render units like the rigid bodies
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionBody {
+    pub mass: f64,
+    pub radius: f64,
+    pub position: DVec3,
+    pub veclocity: DVec3,
+}
+
+#[derive(Debug, Clone)]
+pub enum CollisionOutcome {
+    Merge(CollisionBody),
+    Fragment {
+        remnant: CollisionBody,
+        debris: Vec<CollisionBody>
+    }
+}
+
+/*
+ * Kinetic energy of the relative motion in the center of mass frame, in Joule
+ */
+pub fn impact_energy(a: &CollisionBody, b: &CollisionBody) -> f64 {
+    let μ = a.mass * b.mass / (a.mass + b.mass);
+    let v = (a.veclocity - b.veclocity).length() * GLOBAL_SCALE as f64;
+    0.5 * μ * v * v
+}
+
+/*
+ * Energy needed to disperse a uniform sphere
+ */
+pub fn binding_energy(m: f64, r: f64) -> f64 {
+    0.6 * PHYSICS_GRAVITY * m * m / r
+}
+
+fn center_of_mass(a: &CollisionBody, b: &CollisionBody) -> (DVec3, DVec3) {
+    let m = a.mass + b.mass;
+    (
+        (a.position * a.mass + b.position * b.mass) / m,
+        (a.veclocity * a.mass + b.veclocity * b.mass) / m,
+    )
+}
+
+/*
+ * Mass of the largest remnant falls linearly with the impact energy (Leinhardt & Stewart 2012)
+ */
+fn remnant_fraction(energy: f64, binding: f64) -> f64 {
+    (1.0 - 0.5 * energy / binding).clamp(MIN_REMNANT_FRACTION, 1.0)
+}
+
+/*
+ * Resolves an impact into a single merged body or a remnant surrounded by debris.
+ * Mass and momentum are conserved in both cases.
+ */
+pub fn resolve(a: &CollisionBody, b: &CollisionBody, seed: u64) -> CollisionOutcome {
+    let mass = a.mass + b.mass;
+    let (position, veclocity) = center_of_mass(a, b);
+    let radius = planet_radius(mass);
+
+    let energy = impact_energy(a, b);
+    let binding = binding_energy(mass, radius);
+
+    if energy < MERGE_ENERGY_FRACTION * binding {
+        return CollisionOutcome::Merge(CollisionBody { mass, radius, position, veclocity });
+    }
+
+    let remnant_mass = mass * remnant_fraction(energy, binding);
+    let remnant_radius = planet_radius(remnant_mass);
+    let debris_mass = (mass - remnant_mass) / MAX_DEBRIS as f64;
+
+    // Debris keeps the mean density of the impactors
+    let density = mass / (4.0 / 3.0 * std::f64::consts::PI * (a.radius.powi(3) + b.radius.powi(3)));
+    let debris_radius = (3.0 * debris_mass / (4.0 * std::f64::consts::PI * density)).cbrt();
+
+    let distance = 1.5 * (remnant_radius + debris_radius);
+    let speed = DEBRIS_EJECTION_SPEED * crate::procedual::atmosphere::escape_velocity(remnant_mass, distance);
+
+    let mut debris = Vec::with_capacity(MAX_DEBRIS);
+    for i in 0..(MAX_DEBRIS / 2) as u64 {
+        let θ = 2.0 * std::f64::consts::PI * crate::noise::noise_3d_f64_normalized(i, 0, 0, seed);
+        let z = 2.0 * crate::noise::noise_3d_f64_normalized(i, 1, 0, seed) - 1.0;
+        let s = (1.0 - z * z).sqrt();
+        let dir = DVec3::new(s * θ.cos(), z, s * θ.sin());
+
+        // Opposite pairs keep the center of mass and the momentum of the remnant unchanged
+        for d in [dir, -dir].iter() {
+            debris.push(CollisionBody {
+                mass: debris_mass,
+                radius: debris_radius,
+                position: position + *d * distance / GLOBAL_SCALE as f64,
+                veclocity: veclocity + *d * speed / GLOBAL_SCALE as f64,
+            });
+        }
+    }
+
+    CollisionOutcome::Fragment {
+        remnant: CollisionBody { mass: remnant_mass, radius: remnant_radius, position, veclocity },
+        debris
+    }
+}
+
+fn to_vec3(v: DVec3) -> Vec3 {
+    Vec3::new(v.x as f32, v.y as f32, v.z as f32)
+}
+
+fn volume(radius: f64) -> f64 {
+    4.0 / 3.0 * std::f64::consts::PI * radius.powi(3)
+}
+
+fn collision_body(mass: &Mass, position: &RigidBodyPosition, veclocity: &RigidBodyVelocity, shape: &ColliderShape) -> CollisionBody {
+    let (p, _): (Vec3, Quat) = position.position.into();
+    CollisionBody {
+        mass: mass.mass,
+        radius: shape.as_ball().map_or(0.0, |b| b.radius as f64) * GLOBAL_SCALE as f64,
+        position: DVec3::new(p.x as f64, p.y as f64, p.z as f64),
+        veclocity: DVec3::new(veclocity.linvel.x as f64, veclocity.linvel.y as f64, veclocity.linvel.z as f64),
+    }
+}
+
+/*
+ * The heavier body survives as the remnant, the lighter one is despawned
+ */
+pub fn collisions(
+    mut commands: Commands,
+    mut events: EventReader<ContactEvent>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut bodies: Query<(&mut Mass, &mut RigidBodyPosition, &mut RigidBodyVelocity, &mut ColliderShape, &mut Transform)>,
+    mut generated: Query<(Option<&mut Planet>, Option<&mut Moon>, Option<&mut Asteroid>)>,
+    identities: Query<&Identity>,
+) {
+    let mut consumed = HashSet::default();
+
+    for event in events.iter() {
+        let (e1, e2) = match event {
+            ContactEvent::Started(h1, h2) => (h1.entity(), h2.entity()),
+            _ => continue
+        };
+
+        if consumed.contains(&e1) || consumed.contains(&e2) {
+            continue;
+        }
+
+        let (a, b) = match (bodies.get_mut(e1).map(|(m, p, v, s, _)| collision_body(&m, &p, &v, &s)), bodies.get_mut(e2).map(|(m, p, v, s, _)| collision_body(&m, &p, &v, &s))) {
+            (Ok(a), Ok(b)) => (a, b),
+            _ => continue
+        };
+
+        let (survivor, lost, old_radius) = if a.mass >= b.mass { (e1, e2, a.radius) } else { (e2, e1, b.radius) };
+
+        let seed = e1.id() as u64 ^ ((e2.id() as u64) << 32);
+        let (remnant, debris) = match resolve(&a, &b, seed) {
+            CollisionOutcome::Merge(remnant) => (remnant, Vec::new()),
+            CollisionOutcome::Fragment { remnant, debris } => (remnant, debris),
+        };
+
+        if let Ok((mut m, mut p, mut v, mut s, mut t)) = bodies.get_mut(survivor) {
+            m.mass = remnant.mass;
+            p.position = (to_vec3(remnant.position), Quat::IDENTITY).into();
+            v.linvel = to_vec3(remnant.veclocity).into();
+            *s = ColliderShape::ball((remnant.radius / GLOBAL_SCALE as f64) as f32);
+            t.scale *= (remnant.radius / old_radius) as f32;
+        }
+
+        // Keep the generated data in sync, the planet density is in Kg/m3, the others in g/cm3
+        if let Ok((planet, moon, asteroid)) = generated.get_mut(survivor) {
+            if let Some(mut planet) = planet {
+                planet.mass = remnant.mass;
+                planet.radius = remnant.radius;
+                planet.density = remnant.mass / volume(remnant.radius);
+            }
+            if let Some(mut moon) = moon {
+                moon.mass = remnant.mass;
+                moon.radius = remnant.radius;
+                moon.density = remnant.mass / volume(remnant.radius) / 1000.0;
+            }
+            if let Some(mut asteroid) = asteroid {
+                asteroid.mass = remnant.mass;
+                asteroid.radius = remnant.radius;
+                asteroid.density = remnant.mass / volume(remnant.radius) / 1000.0;
+            }
+        }
+
+        // Debris ids are unique per collision so they don't clash with the generated asteroids or earlier debris
+        let parent_id = |e: Entity| identities.get(e).map(|identity| identity.id.as_u128() as u64).unwrap_or(e.to_bits());
+        let parents = parent_id(e1) ^ parent_id(e2).rotate_left(32);
+
+        commands.entity(lost).despawn_recursive();
+        consumed.insert(survivor);
+        consumed.insert(lost);
+
+        for (i, d) in debris.iter().enumerate() {
+            let asteroid = Asteroid {
+                id: crate::noise::noise_1d(i as u64, seed.wrapping_add(parents)),
+                position: Vec3A::from(to_vec3(d.position * GLOBAL_SCALE as f64)),
+                mass: d.mass,
+                veclocity: Vec3A::from(to_vec3(d.veclocity * GLOBAL_SCALE as f64)),
+                radius: d.radius,
+                density: d.mass / volume(d.radius) / 1000.0,
+            };
+            crate::procedual::solar_system::spawn_asteroid(&mut commands, &mut materials, &mut meshes, asteroid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EARTH_MASS: f64 = 5.9722e24;
+
+    fn body(mass: f64, position: DVec3, veclocity: DVec3) -> CollisionBody {
+        CollisionBody { mass, radius: planet_radius(mass), position, veclocity }
+    }
+
+    fn totals(bodies: &[CollisionBody]) -> (f64, DVec3) {
+        (
+            bodies.iter().map(|b| b.mass).sum(),
+            bodies.iter().fold(DVec3::ZERO, |p, b| p + b.veclocity * b.mass),
+        )
+    }
+
+    fn outcome_bodies(outcome: CollisionOutcome) -> Vec<CollisionBody> {
+        match outcome {
+            CollisionOutcome::Merge(b) => vec![b],
+            CollisionOutcome::Fragment { remnant, debris } => std::iter::once(remnant).chain(debris).collect(),
+        }
+    }
+
+    #[test]
+    fn slow_impacts_merge_and_conserve_momentum() {
+        let a = body(EARTH_MASS, DVec3::ZERO, DVec3::new(1.0e-8, 0.0, 0.0));
+        let b = body(0.1 * EARTH_MASS, DVec3::new(1.0e-4, 0.0, 0.0), DVec3::new(-2.0e-8, 1.0e-8, 0.0));
+
+        let outcome = resolve(&a, &b, 42);
+        assert!(matches!(outcome, CollisionOutcome::Merge(_)));
+
+        let (m0, p0) = totals(&[a, b]);
+        let merged = outcome_bodies(outcome);
+        let (m1, p1) = totals(&merged);
+
+        assert!((m1 - m0).abs() / m0 < 1.0e-12);
+        assert!((p1 - p0).length() / p0.length() < 1.0e-12);
+        assert!((merged[0].radius - planet_radius(m0)).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn violent_impacts_fragment_and_conserve_momentum() {
+        let a = body(EARTH_MASS, DVec3::ZERO, DVec3::ZERO);
+        let b = body(0.5 * EARTH_MASS, DVec3::new(1.0e-4, 0.0, 0.0), DVec3::new(-1.0e-6, 0.0, 0.0));
+
+        let outcome = resolve(&a, &b, 42);
+        assert!(matches!(outcome, CollisionOutcome::Fragment { .. }));
+
+        let (m0, p0) = totals(&[a, b]);
+        let (m1, p1) = totals(&outcome_bodies(outcome));
+
+        assert!((m1 - m0).abs() / m0 < 1.0e-12);
+        assert!((p1 - p0).length() / p0.length() < 1.0e-9);
+    }
+}
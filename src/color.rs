@@ -0,0 +1,364 @@
+use bevy::math::{Mat3, Mat4, Vec3, Vec4};
+
+/*
+ * Rust side of lib/color.glsl, so stars, UI and offline renders get the same colors as the shaders.
+ * GLSL writes its matrices row by row and multiplies on the left (v * m), here they are regular column vectors.
+ */
+
+// CIE 1931 2° standard observer from 380 to 780 nm every 5 nm, standardObserver1931 in color.glsl
+pub static STANDARD_OBSERVER_1931: [[f32; 3]; 81] = [
+    [0.001368, 0.000039, 0.006450],  // 380 nm
+    [0.002236, 0.000064, 0.010550],  // 385 nm
+    [0.004243, 0.000120, 0.020050],  // 390 nm
+    [0.007650, 0.000217, 0.036210],  // 395 nm
+    [0.014310, 0.000396, 0.067850],  // 400 nm
+    [0.023190, 0.000640, 0.110200],  // 405 nm
+    [0.043510, 0.001210, 0.207400],  // 410 nm
+    [0.077630, 0.002180, 0.371300],  // 415 nm
+    [0.134380, 0.004000, 0.645600],  // 420 nm
+    [0.214770, 0.007300, 1.039050],  // 425 nm
+    [0.283900, 0.011600, 1.385600],  // 430 nm
+    [0.328500, 0.016840, 1.622960],  // 435 nm
+    [0.348280, 0.023000, 1.747060],  // 440 nm
+    [0.348060, 0.029800, 1.782600],  // 445 nm
+    [0.336200, 0.038000, 1.772110],  // 450 nm
+    [0.318700, 0.048000, 1.744100],  // 455 nm
+    [0.290800, 0.060000, 1.669200],  // 460 nm
+    [0.251100, 0.073900, 1.528100],  // 465 nm
+    [0.195360, 0.090980, 1.287640],  // 470 nm
+    [0.142100, 0.112600, 1.041900],  // 475 nm
+    [0.095640, 0.139020, 0.812950],  // 480 nm
+    [0.057950, 0.169300, 0.616200],  // 485 nm
+    [0.032010, 0.208020, 0.465180],  // 490 nm
+    [0.014700, 0.258600, 0.353300],  // 495 nm
+    [0.004900, 0.323000, 0.272000],  // 500 nm
+    [0.002400, 0.407300, 0.212300],  // 505 nm
+    [0.009300, 0.503000, 0.158200],  // 510 nm
+    [0.029100, 0.608200, 0.111700],  // 515 nm
+    [0.063270, 0.710000, 0.078250],  // 520 nm
+    [0.109600, 0.793200, 0.057250],  // 525 nm
+    [0.165500, 0.862000, 0.042160],  // 530 nm
+    [0.225750, 0.914850, 0.029840],  // 535 nm
+    [0.290400, 0.954000, 0.020300],  // 540 nm
+    [0.359700, 0.980300, 0.013400],  // 545 nm
+    [0.433450, 0.994950, 0.008750],  // 550 nm
+    [0.512050, 1.000000, 0.005750],  // 555 nm
+    [0.594500, 0.995000, 0.003900],  // 560 nm
+    [0.678400, 0.978600, 0.002750],  // 565 nm
+    [0.762100, 0.952000, 0.002100],  // 570 nm
+    [0.842500, 0.915400, 0.001800],  // 575 nm
+    [0.916300, 0.870000, 0.001650],  // 580 nm
+    [0.978600, 0.816300, 0.001400],  // 585 nm
+    [1.026300, 0.757000, 0.001100],  // 590 nm
+    [1.056700, 0.694900, 0.001000],  // 595 nm
+    [1.062200, 0.631000, 0.000800],  // 600 nm
+    [1.045600, 0.566800, 0.000600],  // 605 nm
+    [1.002600, 0.503000, 0.000340],  // 610 nm
+    [0.938400, 0.441200, 0.000240],  // 615 nm
+    [0.854450, 0.381000, 0.000190],  // 620 nm
+    [0.751400, 0.321000, 0.000100],  // 625 nm
+    [0.642400, 0.265000, 0.000050],  // 630 nm
+    [0.541900, 0.217000, 0.000030],  // 635 nm
+    [0.447900, 0.175000, 0.000020],  // 640 nm
+    [0.360800, 0.138200, 0.000010],  // 645 nm
+    [0.283500, 0.107000, 0.000000],  // 650 nm
+    [0.218700, 0.081600, 0.000000],  // 655 nm
+    [0.164900, 0.061000, 0.000000],  // 660 nm
+    [0.121200, 0.044580, 0.000000],  // 665 nm
+    [0.087400, 0.032000, 0.000000],  // 670 nm
+    [0.063600, 0.023200, 0.000000],  // 675 nm
+    [0.046770, 0.017000, 0.000000],  // 680 nm
+    [0.032900, 0.011920, 0.000000],  // 685 nm
+    [0.022700, 0.008210, 0.000000],  // 690 nm
+    [0.015840, 0.005723, 0.000000],  // 695 nm
+    [0.011359, 0.004102, 0.000000],  // 700 nm
+    [0.008111, 0.002929, 0.000000],  // 705 nm
+    [0.005790, 0.002091, 0.000000],  // 710 nm
+    [0.004109, 0.001484, 0.000000],  // 715 nm
+    [0.002899, 0.001047, 0.000000],  // 720 nm
+    [0.002049, 0.000740, 0.000000],  // 725 nm
+    [0.001440, 0.000520, 0.000000],  // 730 nm
+    [0.001000, 0.000361, 0.000000],  // 735 nm
+    [0.000690, 0.000249, 0.000000],  // 740 nm
+    [0.000476, 0.000172, 0.000000],  // 745 nm
+    [0.000332, 0.000120, 0.000000],  // 750 nm
+    [0.000235, 0.000085, 0.000000],  // 755 nm
+    [0.000166, 0.000060, 0.000000],  // 760 nm
+    [0.000117, 0.000042, 0.000000],  // 765 nm
+    [0.000083, 0.000030, 0.000000],  // 770 nm
+    [0.000059, 0.000021, 0.000000],  // 775 nm
+    [0.000042, 0.000015, 0.000000],  // 780 nm
+];
+pub static STANDARD_OBSERVER_1931_W_MIN: f32 = 380.0;
+pub static STANDARD_OBSERVER_1931_W_MAX: f32 = 780.0;
+
+static SECOND_RADIATION_CONSTANT: f32 = 1.4387769e7;  //nm K, hc/k
+static PLANCK_REFERENCE: f32 = 555.0;                  //nm
+
+pub static D65_WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];  //XYZ_wr
+
+// Same order as the TONEMAP_ defines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemapper {
+    None,
+    ReinhardExtended,
+    Aces,
+    AgX,
+}
+
+impl Tonemapper {
+    pub const ALL: [Tonemapper; 4] = [Tonemapper::None, Tonemapper::ReinhardExtended, Tonemapper::Aces, Tonemapper::AgX];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+fn rows(m: [f32; 9]) -> Mat3 {
+    Mat3::from_cols_array(&m).transpose()
+}
+
+fn srgb_to_xyz_matrix() -> Mat3 {
+    rows([0.4124564, 0.3575761, 0.1804375,
+          0.2126729, 0.7151522, 0.0721750,
+          0.0193339, 0.1191920, 0.9503041])
+}
+
+fn bradford() -> Mat3 {
+    rows([0.8951000, 0.2664000, -0.1614000,
+          -0.7502000, 1.7135000, 0.0367000,
+          0.0389000, -0.0685000, 1.0296000])
+}
+
+/*
+ * WavelengthToXYZLinear
+ */
+pub fn wavelength_to_xyz(wavelength: f32) -> Vec3 {
+    let n = STANDARD_OBSERVER_1931.len();
+    let pos = (wavelength - STANDARD_OBSERVER_1931_W_MIN) / (STANDARD_OBSERVER_1931_W_MAX - STANDARD_OBSERVER_1931_W_MIN);
+    let index = (pos * (n - 1) as f32).max(0.0).min((n - 1) as f32);
+    let i0 = index.floor() as usize;
+    let i1 = (i0 + 1).min(n - 1);
+    Vec3::from(STANDARD_OBSERVER_1931[i0]).lerp(Vec3::from(STANDARD_OBSERVER_1931[i1]), index - i0 as f32)
+}
+
+pub fn xyz_to_srgb(xyz: Vec3) -> Vec3 {
+    rows([3.2404542, -1.5371385, -0.4985314,
+          -0.9692660, 1.8760108, 0.0415560,
+          0.0556434, -0.2040259, 1.0572252]) * xyz
+}
+
+pub fn srgb_to_xyz(rgb: Vec3) -> Vec3 {
+    srgb_to_xyz_matrix() * rgb
+}
+
+/*
+ * Rec. 709 luminance of linear sRGB, RelativeLuminance
+ */
+pub fn relative_luminance(rgb: Vec3) -> f32 {
+    rgb.dot(Vec3::new(0.2126729, 0.7151522, 0.0721750))
+}
+
+/*
+ * Spectral radiance of a black body relative to 555 nm, PlanckRelative.
+ * Written with differences of exponents so cold bodies go to 0 instead of inf / inf.
+ */
+pub fn planck_relative(wavelength: f32, temperature: f32) -> f32 {
+    let x = SECOND_RADIATION_CONSTANT / (wavelength * temperature);
+    let x0 = SECOND_RADIATION_CONSTANT / (PLANCK_REFERENCE * temperature);
+    (PLANCK_REFERENCE / wavelength).powi(5) * (x0 - x).exp() * (1.0 - (-x0).exp()) / (1.0 - (-x).exp())
+}
+
+/*
+ * Color of a black body with Y = 1, BlackBodyXYZ
+ */
+pub fn blackbody_xyz(temperature: f32) -> Vec3 {
+    let step = (STANDARD_OBSERVER_1931_W_MAX - STANDARD_OBSERVER_1931_W_MIN) / (STANDARD_OBSERVER_1931.len() - 1) as f32;
+    let xyz = STANDARD_OBSERVER_1931.iter().enumerate().fold(Vec3::ZERO, |sum, (i, cmf)| {
+        sum + Vec3::from(*cmf) * planck_relative(STANDARD_OBSERVER_1931_W_MIN + step * i as f32, temperature)
+    });
+    xyz / xyz.y.max(1.0e-30)
+}
+
+/*
+ * Linear sRGB color of a black body with its brightest channel at 1, BlackBodyRGB.
+ * Only the hue, how bright a star is comes from its luminosity.
+ */
+pub fn blackbody_rgb(temperature: f32) -> Vec3 {
+    let c = xyz_to_srgb(blackbody_xyz(temperature)).max(Vec3::ZERO);
+    c / c.max_element().max(1.0e-30)
+}
+
+/*
+ * Bradford transform of XYZ colors seen under `from` to how they look under `to`, ChromaticAdaptation
+ */
+pub fn chromatic_adaptation(from: Vec3, to: Vec3) -> Mat3 {
+    let m = bradford();
+    m.inverse() * Mat3::from_diagonal((m * to) / (m * from)) * m
+}
+
+/*
+ * Linear sRGB matrix making a black body at `temperature` white, the white_balance uniform of PathTracerOutput
+ */
+pub fn white_balance(temperature: f32) -> Mat4 {
+    let m = srgb_to_xyz_matrix();
+    let balance = m.inverse() * chromatic_adaptation(blackbody_xyz(temperature), Vec3::from(D65_WHITE)) * m;
+    Mat4::from_cols(balance.x_axis.extend(0.0), balance.y_axis.extend(0.0), balance.z_axis.extend(0.0), Vec4::W)
+}
+
+/*
+ * Reinhard on the luminance, `white` and above map to 1, TonemapReinhardExtended
+ */
+pub fn reinhard_extended(c: Vec3, white: f32) -> Vec3 {
+    let l = relative_luminance(c);
+    if l <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+    (c * mapped / l).min(Vec3::ONE)
+}
+
+/*
+ * Stephen Hill's fit of the ACES RRT and sRGB ODT, TonemapACES
+ */
+pub fn aces(c: Vec3) -> Vec3 {
+    let input = rows([0.59719, 0.35458, 0.04823,
+                      0.07600, 0.90834, 0.01566,
+                      0.02840, 0.13383, 0.83777]);
+    let output = rows([1.60475, -0.53108, -0.07367,
+                       -0.10208, 1.10813, -0.00605,
+                       -0.00327, -0.07276, 1.07602]);
+    let v = input * c;
+    let a = v * (v + Vec3::splat(0.0245786)) - Vec3::splat(0.000090537);
+    let b = v * (0.983729 * v + Vec3::splat(0.4329510)) + Vec3::splat(0.238081);
+    (output * (a / b)).max(Vec3::ZERO).min(Vec3::ONE)
+}
+
+/*
+ * Minimal AgX by Benjamin Wrensch with the default look, TonemapAgX
+ */
+pub fn agx(c: Vec3) -> Vec3 {
+    static MIN_EV: f32 = -12.47393;
+    static MAX_EV: f32 = 4.026069;
+    let inset = Mat3::from_cols_array(&[0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+                                        0.0784335999999992, 0.878468636469772, 0.0784336,
+                                        0.0792237451477643, 0.0791661274605434, 0.879142973793104]);
+    let outset = Mat3::from_cols_array(&[1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+                                         -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+                                         -0.0990297440797205, -0.0989611768448433, 1.15107367264116]);
+
+    let v = (inset * c.max(Vec3::ZERO)).max(Vec3::splat(1.0e-10));
+    let v = Vec3::new(v.x.log2(), v.y.log2(), v.z.log2()).max(Vec3::splat(MIN_EV)).min(Vec3::splat(MAX_EV));
+    let x = (v - Vec3::splat(MIN_EV)) / (MAX_EV - MIN_EV);
+
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - Vec3::splat(0.00232);
+
+    let v = (outset * curve).max(Vec3::ZERO);
+    Vec3::new(v.x.powf(2.2), v.y.powf(2.2), v.z.powf(2.2)).min(Vec3::ONE)
+}
+
+/*
+ * Tonemap, `white` is only used by Reinhard
+ */
+pub fn tonemap(c: Vec3, tonemapper: Tonemapper, white: f32) -> Vec3 {
+    match tonemapper {
+        Tonemapper::None => c.max(Vec3::ZERO).min(Vec3::ONE),
+        Tonemapper::ReinhardExtended => reinhard_extended(c, white),
+        Tonemapper::Aces => aces(c),
+        Tonemapper::AgX => agx(c),
+    }
+}
+
+/*
+ * sRGB transfer function, done by the swap chain on the GPU
+ */
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { 12.92 * c.max(0.0) } else { 1.055 * c.min(1.0).powf(1.0 / 2.4) - 0.055 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static COLOR_GLSL: &str = include_str!("../assets/shaders/lib/color.glsl");
+
+    #[test]
+    fn observer_table_matches_glsl() {
+        let rows: Vec<[f32; 3]> = COLOR_GLSL.lines()
+            .filter(|l| l.contains(" nm"))
+            .filter_map(|l| {
+                let inner = l.split('(').nth(1)?.split(')').next()?;
+                let v: Vec<f32> = inner.split(',').map(|x| x.trim().parse().unwrap()).collect();
+                Some([v[0], v[1], v[2]])
+            })
+            .collect();
+        assert_eq!(rows.as_slice(), &STANDARD_OBSERVER_1931[..]);
+    }
+
+    #[test]
+    fn every_function_is_in_glsl() {
+        for name in ["WavelengthToXYZLinear", "XYZtosRGB", "sRGBtoXYZ", "RelativeLuminance", "PlanckRelative", "BlackBodyXYZ", "BlackBodyRGB",
+                     "ChromaticAdaptation", "TonemapReinhardExtended", "TonemapACES", "TonemapAgX", "Tonemap"].iter() {
+            assert!(COLOR_GLSL.contains(&format!(" {}(", name)), "{} missing from color.glsl", name);
+        }
+        for (i, name) in ["TONEMAP_NONE", "TONEMAP_REINHARD_EXTENDED", "TONEMAP_ACES", "TONEMAP_AGX"].iter().enumerate() {
+            assert!(COLOR_GLSL.contains(&format!("#define {} {}", name, i)), "{} isn't {}", name, i);
+        }
+    }
+
+    #[test]
+    fn black_bodies_go_from_red_to_blue() {
+        let red = blackbody_rgb(2000.0);
+        assert!(red.x == 1.0 && red.z < 0.2 * red.x, "{:?}", red);
+        let blue = blackbody_rgb(30000.0);
+        assert!(blue.z == 1.0 && blue.x < 0.8, "{:?}", blue);
+
+        // D65 is close to the Planckian locus at 6504 K
+        let white = blackbody_rgb(6504.0);
+        assert!(white.min_element() > 0.9, "{:?}", white);
+
+        // Too cold to glow or hot enough to overflow the Planck law, but never NaN
+        for t in [1.0, 100.0, 1.0e7, 1.0e9].iter() {
+            assert!(!blackbody_rgb(*t).is_nan(), "{}", t);
+        }
+    }
+
+    #[test]
+    fn white_balance_neutralizes_the_illuminant() {
+        let warm = xyz_to_srgb(blackbody_xyz(3200.0));
+        let balanced = white_balance(3200.0) * warm.extend(0.0);
+        assert!((balanced.truncate() - Vec3::ONE).abs().max_element() < 1.0e-3, "{:?}", balanced);
+
+        let round_trip = srgb_to_xyz(xyz_to_srgb(Vec3::new(0.3, 0.5, 0.2)));
+        assert!((round_trip - Vec3::new(0.3, 0.5, 0.2)).abs().max_element() < 1.0e-5, "{:?}", round_trip);
+    }
+
+    #[test]
+    fn tonemappers_are_monotonic_and_bounded() {
+        for tonemapper in Tonemapper::ALL.iter() {
+            let mut previous = -1.0;
+            for i in 0..200 {
+                let x = 0.001 * 1.08f32.powi(i);
+                let y = tonemap(Vec3::splat(x), *tonemapper, 4.0);
+                assert!(y.max_element() <= 1.0 && y.min_element() >= 0.0, "{:?} {} {:?}", tonemapper, x, y);
+                assert!(y.y >= previous - 1.0e-5, "{:?} {} {} {}", tonemapper, x, y.y, previous);
+                previous = y.y;
+            }
+            assert!(tonemap(Vec3::ZERO, *tonemapper, 4.0).max_element() < 1.0e-3, "{:?}", tonemapper);
+        }
+
+        assert!((reinhard_extended(Vec3::splat(4.0), 4.0) - Vec3::ONE).abs().max_element() < 1.0e-5);
+        assert!(aces(Vec3::splat(100.0)).min_element() > 0.99);
+        assert!(agx(Vec3::splat(0.18)).x > 0.1 && agx(Vec3::splat(0.18)).x < 0.3);
+    }
+
+    #[test]
+    fn srgb_transfer_is_continuous() {
+        let a = linear_to_srgb(0.0031308);
+        let b = linear_to_srgb(0.0031309);
+        assert!((a - b).abs() < 1.0e-4);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1.0e-6);
+    }
+}
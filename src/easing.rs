@@ -1,4 +1,9 @@
 use bevy::math::{Quat, Vec2, Vec3};
+use bevy::render::color::Color;
+
+static BACK_C1: f32 = 1.70158;
+static BACK_C3: f32 = 2.70158;
+static ELASTIC_C4: f32 = 2.0 * std::f32::consts::PI / 3.0;
 
 pub fn scale(y: f32, a: f32) -> f32 {
     y * a
@@ -20,8 +25,9 @@ pub fn smooth_stop<const N: i32>(x: f32 ) -> f32 {
     flip(flip(x).powi(N))
 }
 
+// Same as mix in GLSL
 pub fn mix(y1: f32, y2: f32, blend: f32) -> f32 {
-    scale(y1, blend) + scale(y2, flip(blend))
+    scale(y1, flip(blend)) + scale(y2, blend)
 }
 
 pub fn smooth_step<const N: i32>(x: f32) -> f32 {
@@ -46,5 +52,927 @@ pub fn asymptotic_averaging_3d(current: Vec3, target: Vec3, speed: f32) -> Vec3
 }
 
 pub fn asymptotic_averaging_rot(current: Quat, target: Quat, speed: f32) -> Quat {
-    current + (target - current) * speed
-}
\ No newline at end of file
+    nlerp(current, target, speed)
+}
+
+/*
+ * q and -q are the same rotation, picks the one closer to from so interpolation takes the short way
+ */
+pub fn shortest(from: Quat, to: Quat) -> Quat {
+    if from.dot(to) < 0.0 { -to } else { to }
+}
+
+pub fn nlerp(from: Quat, to: Quat, x: f32) -> Quat {
+    let to = shortest(from, to);
+    (from * (1.0 - x) + to * x).normalize()
+}
+
+pub fn slerp(from: Quat, to: Quat, x: f32) -> Quat {
+    let to = shortest(from, to);
+    let d = from.dot(to).min(1.0);
+
+    // Nearly parallel, sin θ vanishes
+    if d > 0.9995 {
+        return nlerp(from, to, x);
+    }
+
+    let θ = d.acos();
+    ((from * ((1.0 - x) * θ).sin() + to * (x * θ).sin()) * (1.0 / θ.sin())).normalize()
+}
+
+/*
+ * Fraction of the remaining distance covered in dt when closing in at rate k,
+ * n steps of dt/n cover the same distance as one step of dt
+ */
+pub fn damp_factor(k: f32, dt: f32) -> f32 {
+    1.0 - (-k * dt).exp()
+}
+
+pub fn damp(current: f32, target: f32, k: f32, dt: f32) -> f32 {
+    asymptotic_averaging(current, target, damp_factor(k, dt))
+}
+
+pub fn damp_3d(current: Vec3, target: Vec3, k: f32, dt: f32) -> Vec3 {
+    asymptotic_averaging_3d(current, target, damp_factor(k, dt))
+}
+
+pub fn damp_rot(current: Quat, target: Quat, k: f32, dt: f32) -> Quat {
+    slerp(current, target, damp_factor(k, dt))
+}
+
+/*
+ * Axis times angle of a unit quaternion, the short way around
+ */
+pub fn to_rotation_vector(q: Quat) -> Vec3 {
+    let q = shortest(Quat::IDENTITY, q);
+    let v = Vec3::new(q.x, q.y, q.z);
+    let s = v.length();
+    if s < 1.0e-6 {
+        return v * 2.0;
+    }
+    v * (2.0 * s.atan2(q.w) / s)
+}
+
+pub fn from_rotation_vector(v: Vec3) -> Quat {
+    let θ = v.length();
+    if θ < 1.0e-6 {
+        return Quat::from_xyzw(v.x * 0.5, v.y * 0.5, v.z * 0.5, 1.0).normalize();
+    }
+    Quat::from_axis_angle(v / θ, θ)
+}
+
+/*
+ * Exact step of a critically damped spring, reaches the target as fast as possible without overshooting.
+ * ω is the angular frequency, larger is stiffer.
+ */
+fn critically_damped(x: Vec3, v: Vec3, ω: f32, dt: f32) -> (Vec3, Vec3) {
+    let e = (-ω * dt).exp();
+    let temp = (v + x * ω) * dt;
+    ((x + temp) * e, (v - temp * ω) * e)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Spring3 {
+    pub position: Vec3,
+    pub veclocity: Vec3,
+}
+
+impl Spring3 {
+    pub fn new(position: Vec3) -> Self {
+        Self { position, veclocity: Vec3::ZERO }
+    }
+
+    pub fn step(&mut self, target: Vec3, ω: f32, dt: f32) -> Vec3 {
+        let (x, v) = critically_damped(self.position - target, self.veclocity, ω, dt);
+        self.position = target + x;
+        self.veclocity = v;
+        self.position
+    }
+}
+
+/*
+ * The spring acts on the rotation vector from the target to the current rotation, veclocity is angular
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpringRot {
+    pub rotation: Quat,
+    pub veclocity: Vec3,
+}
+
+impl SpringRot {
+    pub fn new(rotation: Quat) -> Self {
+        Self { rotation, veclocity: Vec3::ZERO }
+    }
+
+    pub fn step(&mut self, target: Quat, ω: f32, dt: f32) -> Quat {
+        let error = to_rotation_vector(self.rotation * target.conjugate());
+        let (x, v) = critically_damped(error, self.veclocity, ω, dt);
+        self.rotation = (from_rotation_vector(x) * target).normalize();
+        self.veclocity = v;
+        self.rotation
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EaseMode {
+    In,
+    Out,
+    InOut,
+}
+
+/*
+ * Robert Penner's easing functions, out and in-out are built from in like easing.glsl does
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    Linear,
+    Sine(EaseMode),
+    Quad(EaseMode),
+    Cubic(EaseMode),
+    Quart(EaseMode),
+    Quint(EaseMode),
+    Expo(EaseMode),
+    Circ(EaseMode),
+    Back(EaseMode),
+    Elastic(EaseMode),
+    Bounce(EaseMode),
+    CubicBezier(CubicBezier),
+}
+
+fn bounce_curve(mut x: f32) -> f32 {
+    let (n1, d1) = (7.5625, 2.75);
+    if x < 1.0 / d1 {
+        n1 * x * x
+    } else if x < 2.0 / d1 {
+        x -= 1.5 / d1;
+        n1 * x * x + 0.75
+    } else if x < 2.5 / d1 {
+        x -= 2.25 / d1;
+        n1 * x * x + 0.9375
+    } else {
+        x -= 2.625 / d1;
+        n1 * x * x + 0.984375
+    }
+}
+
+impl Easing {
+    pub fn penner() -> Vec<Easing> {
+        let kinds: [fn(EaseMode) -> Easing; 10] = [
+            Easing::Sine, Easing::Quad, Easing::Cubic, Easing::Quart, Easing::Quint,
+            Easing::Expo, Easing::Circ, Easing::Back, Easing::Elastic, Easing::Bounce,
+        ];
+        std::iter::once(Easing::Linear)
+            .chain(kinds.iter().flat_map(|k| [EaseMode::In, EaseMode::Out, EaseMode::InOut].iter().map(move |m| k(*m))))
+            .collect()
+    }
+
+    fn mode(&self) -> Option<EaseMode> {
+        use Easing::*;
+        match *self {
+            Sine(m) | Quad(m) | Cubic(m) | Quart(m) | Quint(m) | Expo(m) | Circ(m) | Back(m) | Elastic(m) | Bounce(m) => Some(m),
+            Linear | CubicBezier(_) => None,
+        }
+    }
+
+    fn ease_in(&self, x: f32) -> f32 {
+        use Easing::*;
+        match self {
+            Sine(_) => 1.0 - (x * std::f32::consts::FRAC_PI_2).cos(),
+            Quad(_) => smooth_start::<2>(x),
+            Cubic(_) => smooth_start::<3>(x),
+            Quart(_) => smooth_start::<4>(x),
+            Quint(_) => smooth_start::<5>(x),
+            Expo(_) => if x == 0.0 { 0.0 } else { 2f32.powf(10.0 * x - 10.0) },
+            Circ(_) => 1.0 - (1.0 - x * x).max(0.0).sqrt(),
+            Back(_) => BACK_C3 * x * x * x - BACK_C1 * x * x,
+            Elastic(_) => if x == 0.0 || x == 1.0 { x } else { -2f32.powf(10.0 * x - 10.0) * ((10.0 * x - 10.75) * ELASTIC_C4).sin() },
+            Bounce(_) => 1.0 - bounce_curve(1.0 - x),
+            Linear => x,
+            CubicBezier(b) => b.ease(x),
+        }
+    }
+
+    /*
+     * Maps 0..1 to 0..1, back and elastic overshoot in between
+     */
+    pub fn ease(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self.mode() {
+            None | Some(EaseMode::In) => self.ease_in(x),
+            Some(EaseMode::Out) => flip(self.ease_in(flip(x))),
+            Some(EaseMode::InOut) if x < 0.5 => self.ease_in(2.0 * x) / 2.0,
+            Some(EaseMode::InOut) => flip(self.ease_in(2.0 - 2.0 * x) / 2.0),
+        }
+    }
+
+    /*
+     * The names of the functions in easing.glsl without the ease_ prefix, like quad_in_out
+     */
+    pub fn name(&self) -> Option<String> {
+        use Easing::*;
+        let kind = match self {
+            Linear => return Some("linear".to_string()),
+            CubicBezier(_) => return None,
+            Sine(_) => "sine", Quad(_) => "quad", Cubic(_) => "cubic", Quart(_) => "quart", Quint(_) => "quint",
+            Expo(_) => "expo", Circ(_) => "circ", Back(_) => "back", Elastic(_) => "elastic", Bounce(_) => "bounce",
+        };
+        let mode = match self.mode()? {
+            EaseMode::In => "in",
+            EaseMode::Out => "out",
+            EaseMode::InOut => "in_out",
+        };
+        Some(format!("{}_{}", kind, mode))
+    }
+
+    pub fn parse(name: &str) -> Option<Easing> {
+        Easing::penner().into_iter().find(|e| e.name().as_deref() == Some(name))
+    }
+}
+
+/*
+ * Timing curve from (0, 0) to (1, 1) with two control points like CSS cubic-bezier
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CubicBezier {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+}
+
+impl CubicBezier {
+    pub fn new(x1: f32, y1: f32, x2: f32, y2: f32) -> Self {
+        // x has to be monotonic for the curve to be a function of it
+        Self { x1: x1.clamp(0.0, 1.0), y1, x2: x2.clamp(0.0, 1.0), y2 }
+    }
+
+    fn component(a1: f32, a2: f32, t: f32) -> f32 {
+        let u = 1.0 - t;
+        3.0 * u * u * t * a1 + 3.0 * u * t * t * a2 + t * t * t
+    }
+
+    fn derivative(a1: f32, a2: f32, t: f32) -> f32 {
+        let u = 1.0 - t;
+        3.0 * u * u * a1 + 6.0 * u * t * (a2 - a1) + 3.0 * t * t * (1.0 - a2)
+    }
+
+    /*
+     * Newton's method for the parameter at x, bisection when the slope vanishes
+     */
+    pub fn ease(&self, x: f32) -> f32 {
+        let mut t = x;
+        for _ in 0..8 {
+            let error = Self::component(self.x1, self.x2, t) - x;
+            let slope = Self::derivative(self.x1, self.x2, t);
+            if error.abs() < 1.0e-6 {
+                return Self::component(self.y1, self.y2, t);
+            }
+            if slope.abs() < 1.0e-6 {
+                break;
+            }
+            t = (t - error / slope).clamp(0.0, 1.0);
+        }
+
+        let (mut lo, mut hi) = (0.0, 1.0);
+        for _ in 0..32 {
+            t = 0.5 * (lo + hi);
+            if Self::component(self.x1, self.x2, t) < x { lo = t } else { hi = t }
+        }
+        Self::component(self.y1, self.y2, t)
+    }
+}
+
+pub trait Interpolate: Clone {
+    fn interpolate(&self, other: &Self, x: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, x: f32) -> Self {
+        mix(*self, *other, x)
+    }
+}
+
+impl Interpolate for Vec2 {
+    fn interpolate(&self, other: &Self, x: f32) -> Self {
+        self.lerp(*other, x)
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Self, x: f32) -> Self {
+        self.lerp(*other, x)
+    }
+}
+
+impl Interpolate for Quat {
+    fn interpolate(&self, other: &Self, x: f32) -> Self {
+        slerp(*self, *other, x)
+    }
+}
+
+// Blends in linear space, the stored color stays sRGB
+impl Interpolate for Color {
+    fn interpolate(&self, other: &Self, x: f32) -> Self {
+        let (a, b) = (self.as_linear_rgba_f32(), other.as_linear_rgba_f32());
+        Color::rgba_linear(mix(a[0], b[0], x), mix(a[1], b[1], x), mix(a[2], b[2], x), mix(a[3], b[3], x)).as_rgba()
+    }
+}
+
+/*
+ * De Casteljau's construction, works for anything that interpolates
+ */
+pub fn cubic_bezier<T: Interpolate>(p0: &T, p1: &T, p2: &T, p3: &T, t: f32) -> T {
+    let (a, b, c) = (p0.interpolate(p1, t), p1.interpolate(p2, t), p2.interpolate(p3, t));
+    let (d, e) = (a.interpolate(&b, t), b.interpolate(&c, t));
+    d.interpolate(&e, t)
+}
+
+/*
+ * The easing of a key shapes the way to the next key
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurveKey<T> {
+    pub time: f32,
+    pub value: T,
+    pub easing: Easing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve<T> {
+    keys: Vec<CurveKey<T>>,
+}
+
+impl<T> Default for Curve<T> {
+    fn default() -> Self {
+        Self { keys: Vec::new() }
+    }
+}
+
+impl<T: Interpolate> Curve<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, time: f32, value: T, easing: Easing) -> Self {
+        self.insert(time, value, easing);
+        self
+    }
+
+    /*
+     * Keys stay sorted by time, a key at the same time as an existing one replaces it
+     */
+    pub fn insert(&mut self, time: f32, value: T, easing: Easing) {
+        let key = CurveKey { time, value, easing };
+        match self.keys.iter().position(|k| k.time >= time) {
+            Some(i) if self.keys[i].time == time => self.keys[i] = key,
+            Some(i) => self.keys.insert(i, key),
+            None => self.keys.push(key),
+        }
+    }
+
+    pub fn keys(&self) -> &[CurveKey<T>] {
+        &self.keys
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map_or(0.0, |k| k.time)
+    }
+
+    /*
+     * Holds the first and last values outside the keys
+     */
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        if time <= first.time {
+            return Some(first.value.clone());
+        }
+        if time >= last.time {
+            return Some(last.value.clone());
+        }
+
+        let i = self.keys.iter().rposition(|k| k.time <= time)?;
+        let (a, b) = (&self.keys[i], &self.keys[i + 1]);
+        let x = (time - a.time) / (b.time - a.time);
+        Some(a.value.interpolate(&b.value, a.easing.ease(x)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, enough to scatter samples over the sphere of quaternions
+    fn samples(n: usize) -> Vec<Quat> {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+        };
+        (0..n).map(|_| Quat::from_xyzw(next(), next(), next(), next()).normalize()).collect()
+    }
+
+    fn is_unit(q: Quat) -> bool {
+        (q.length() - 1.0).abs() < 1.0e-5
+    }
+
+    fn angle_between(a: Quat, b: Quat) -> f32 {
+        2.0 * a.dot(b).abs().min(1.0).acos()
+    }
+
+    #[test]
+    fn interpolation_stays_unit_length() {
+        let qs = samples(256);
+        for pair in qs.windows(2) {
+            for i in 0..=10 {
+                let x = i as f32 / 10.0;
+                assert!(is_unit(slerp(pair[0], pair[1], x)));
+                assert!(is_unit(nlerp(pair[0], pair[1], x)));
+                assert!(is_unit(asymptotic_averaging_rot(pair[0], pair[1], x)));
+                assert!(is_unit(damp_rot(pair[0], pair[1], 10.0, x)));
+            }
+        }
+    }
+
+    #[test]
+    fn interpolation_takes_the_short_way() {
+        for q in samples(64) {
+            // -q is the same rotation, nothing should move
+            assert!(angle_between(slerp(q, -q, 0.5), q) < 1.0e-3);
+            assert!(angle_between(nlerp(q, -q, 0.5), q) < 1.0e-3);
+        }
+
+        let a = Quat::from_rotation_y(0.1);
+        let b = -Quat::from_rotation_y(0.5);
+        let half = slerp(a, b, 0.5);
+        assert!(angle_between(half, Quat::from_rotation_y(0.3)) < 1.0e-4);
+    }
+
+    #[test]
+    fn damping_is_frame_rate_independent() {
+        let coarse = damp_3d(Vec3::ZERO, Vec3::ONE, 5.0, 0.1);
+        let mut fine = Vec3::ZERO;
+        for _ in 0..10 {
+            fine = damp_3d(fine, Vec3::ONE, 5.0, 0.01);
+        }
+        assert!(coarse.distance(fine) < 1.0e-5);
+
+        let (a, b) = (Quat::IDENTITY, Quat::from_rotation_x(2.0));
+        let coarse = damp_rot(a, b, 5.0, 0.1);
+        let mut fine = a;
+        for _ in 0..10 {
+            fine = damp_rot(fine, b, 5.0, 0.01);
+        }
+        assert!(angle_between(coarse, fine) < 1.0e-4);
+    }
+
+    #[test]
+    fn rotation_vectors_round_trip() {
+        for q in samples(64) {
+            assert!(angle_between(from_rotation_vector(to_rotation_vector(q)), q) < 1.0e-3);
+            assert!(to_rotation_vector(q).length() <= std::f32::consts::PI + 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn springs_settle_without_overshoot() {
+        let mut spring = Spring3::new(Vec3::ZERO);
+        let target = Vec3::new(1.0, -2.0, 0.5);
+        for _ in 0..300 {
+            spring.step(target, 8.0, 1.0 / 60.0);
+            // Critically damped, never passes the target
+            assert!((spring.position - Vec3::ZERO).dot(target) <= target.length_squared() + 1.0e-4);
+        }
+        assert!(spring.position.distance(target) < 1.0e-3);
+    }
+
+    #[test]
+    fn rotation_springs_stay_unit_length_and_settle() {
+        let qs = samples(32);
+        for pair in qs.windows(2) {
+            let mut spring = SpringRot::new(pair[0]);
+            for _ in 0..300 {
+                assert!(is_unit(spring.step(pair[1], 8.0, 1.0 / 60.0)));
+            }
+            assert!(angle_between(spring.rotation, pair[1]) < 1.0e-3);
+        }
+    }
+
+    /*
+     * Interpreter for the subset of GLSL used by easing.glsl, floats, branches, ternaries and the builtins it calls.
+     * Runs the shader source itself so the table checks what the GPU computes, not a Rust copy of it
+     */
+    mod glsl {
+        use std::collections::HashMap;
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Token {
+            Number(f32),
+            Ident(String),
+            Symbol(&'static str),
+        }
+
+        static SYMBOLS: [&str; 25] = [
+            "+=", "-=", "*=", "/=", "==", "!=", "<=", ">=", "||", "&&",
+            "(", ")", "{", "}", ",", ";", "?", ":", "+", "-", "*", "/", "<", ">", "=",
+        ];
+
+        #[derive(Debug, Clone)]
+        enum Expr {
+            Number(f32),
+            Var(String),
+            Call(String, Vec<Expr>),
+            Neg(Box<Expr>),
+            Binary(&'static str, Box<Expr>, Box<Expr>),
+            Select(Box<Expr>, Box<Expr>, Box<Expr>),
+        }
+
+        #[derive(Debug, Clone)]
+        enum Stmt {
+            Return(Expr),
+            Assign(String, Option<&'static str>, Expr),
+            If(Expr, Vec<Stmt>, Vec<Stmt>),
+        }
+
+        struct Function {
+            params: Vec<String>,
+            body: Vec<Stmt>,
+        }
+
+        pub struct Program {
+            functions: HashMap<String, Function>,
+        }
+
+        /*
+         * Drops comments and preprocessor lines, #define constants are substituted as tokens
+         */
+        fn tokenize(source: &str) -> Vec<Token> {
+            let mut defines: HashMap<String, Vec<Token>> = HashMap::new();
+            let mut tokens = Vec::new();
+
+            for line in source.lines() {
+                let line = line.split("//").next().unwrap().trim();
+                if let Some(define) = line.strip_prefix("#define") {
+                    let mut parts = define.trim().splitn(2, char::is_whitespace);
+                    let name = parts.next().unwrap().to_string();
+                    defines.insert(name, tokenize(parts.next().unwrap_or("")));
+                    continue;
+                }
+                if line.starts_with('#') {
+                    continue;
+                }
+
+                let mut rest = line;
+                while let Some(c) = rest.chars().next() {
+                    if c.is_whitespace() {
+                        rest = &rest[1..];
+                    } else if c.is_ascii_digit() || c == '.' {
+                        let end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+                        tokens.push(Token::Number(rest[..end].parse().unwrap()));
+                        rest = &rest[end..];
+                    } else if c.is_alphabetic() || c == '_' {
+                        let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                        match defines.get(&rest[..end]) {
+                            Some(value) => tokens.extend(value.iter().cloned()),
+                            None => tokens.push(Token::Ident(rest[..end].to_string())),
+                        }
+                        rest = &rest[end..];
+                    } else {
+                        let symbol = SYMBOLS.iter().find(|s| rest.starts_with(**s)).unwrap_or_else(|| panic!("Unexpected {}", rest));
+                        tokens.push(Token::Symbol(symbol));
+                        rest = &rest[symbol.len()..];
+                    }
+                }
+            }
+
+            tokens
+        }
+
+        struct Parser {
+            tokens: Vec<Token>,
+            i: usize,
+        }
+
+        impl Parser {
+            fn peek(&self) -> Option<&Token> {
+                self.tokens.get(self.i)
+            }
+
+            fn next(&mut self) -> Token {
+                self.i += 1;
+                self.tokens[self.i - 1].clone()
+            }
+
+            fn is(&self, symbol: &str) -> bool {
+                self.peek() == Some(&Token::Symbol(SYMBOLS.iter().find(|s| **s == symbol).unwrap()))
+            }
+
+            fn eat(&mut self, symbol: &str) -> bool {
+                let found = self.is(symbol);
+                if found {
+                    self.i += 1;
+                }
+                found
+            }
+
+            fn expect(&mut self, symbol: &str) {
+                assert!(self.eat(symbol), "Expected {} at {:?}", symbol, self.peek());
+            }
+
+            fn ident(&mut self) -> String {
+                match self.next() {
+                    Token::Ident(name) => name,
+                    t => panic!("Expected a name, got {:?}", t)
+                }
+            }
+
+            fn function(&mut self) -> (String, Function) {
+                let _return_type = self.ident();
+                let name = self.ident();
+                self.expect("(");
+                let mut params = Vec::new();
+                while !self.eat(")") {
+                    let _type = self.ident();
+                    params.push(self.ident());
+                    self.eat(",");
+                }
+                (name, Function { params, body: self.block() })
+            }
+
+            fn block(&mut self) -> Vec<Stmt> {
+                if !self.eat("{") {
+                    return vec![self.statement()];
+                }
+                let mut body = Vec::new();
+                while !self.eat("}") {
+                    body.push(self.statement());
+                }
+                body
+            }
+
+            fn statement(&mut self) -> Stmt {
+                let first = self.ident();
+                let stmt = match first.as_str() {
+                    "return" => Stmt::Return(self.expr()),
+                    "if" => {
+                        self.expect("(");
+                        let condition = self.expr();
+                        self.expect(")");
+                        let then = self.block();
+                        let otherwise = if self.peek() == Some(&Token::Ident("else".to_string())) {
+                            self.i += 1;
+                            self.block()
+                        } else {
+                            Vec::new()
+                        };
+                        return Stmt::If(condition, then, otherwise);
+                    },
+                    _ => {
+                        // Declarations start with const and or the type, assignments with the variable
+                        let mut name = first;
+                        while let Some(Token::Ident(_)) = self.peek() {
+                            name = self.ident();
+                        }
+                        let op = ["+=", "-=", "*=", "/="].iter().copied().find(|op| self.is(op)).map(|op| &op[..1]);
+                        if op.is_some() {
+                            self.i += 1;
+                        } else {
+                            self.expect("=");
+                        }
+                        let op = op.map(|op| *SYMBOLS.iter().find(|s| **s == op).unwrap());
+                        Stmt::Assign(name, op, self.expr())
+                    }
+                };
+                self.expect(";");
+                stmt
+            }
+
+            fn expr(&mut self) -> Expr {
+                let condition = self.binary(0);
+                if self.eat("?") {
+                    let a = self.expr();
+                    self.expect(":");
+                    let b = self.expr();
+                    return Expr::Select(Box::new(condition), Box::new(a), Box::new(b));
+                }
+                condition
+            }
+
+            fn binary(&mut self, level: usize) -> Expr {
+                static LEVELS: [&[&str]; 6] = [&["||"], &["&&"], &["==", "!="], &["<", ">", "<=", ">="], &["+", "-"], &["*", "/"]];
+                if level == LEVELS.len() {
+                    return self.unary();
+                }
+
+                let mut lhs = self.binary(level + 1);
+                while let Some(op) = LEVELS[level].iter().copied().find(|op| self.is(op)) {
+                    self.i += 1;
+                    let op = *SYMBOLS.iter().find(|s| **s == op).unwrap();
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.binary(level + 1)));
+                }
+                lhs
+            }
+
+            fn unary(&mut self) -> Expr {
+                if self.eat("-") {
+                    return Expr::Neg(Box::new(self.unary()));
+                }
+                if self.eat("(") {
+                    let e = self.expr();
+                    self.expect(")");
+                    return e;
+                }
+                match self.next() {
+                    Token::Number(v) => Expr::Number(v),
+                    Token::Ident(name) if self.eat("(") => {
+                        let mut args = Vec::new();
+                        while !self.eat(")") {
+                            args.push(self.expr());
+                            self.eat(",");
+                        }
+                        Expr::Call(name, args)
+                    },
+                    Token::Ident(name) => Expr::Var(name),
+                    t => panic!("Unexpected {:?}", t)
+                }
+            }
+        }
+
+        impl Program {
+            pub fn parse(source: &str) -> Self {
+                let mut parser = Parser { tokens: tokenize(source), i: 0 };
+                let mut functions = HashMap::new();
+                while parser.peek().is_some() {
+                    let (name, function) = parser.function();
+                    functions.insert(name, function);
+                }
+                Self { functions }
+            }
+
+            pub fn call(&self, name: &str, args: &[f32]) -> f32 {
+                let f = self.functions.get(name).unwrap_or_else(|| panic!("{} is not in the shader", name));
+                let mut locals: HashMap<String, f32> = f.params.iter().cloned().zip(args.iter().copied()).collect();
+                self.run(&f.body, &mut locals).unwrap_or_else(|| panic!("{} does not return", name))
+            }
+
+            fn run(&self, body: &[Stmt], locals: &mut HashMap<String, f32>) -> Option<f32> {
+                for stmt in body {
+                    match stmt {
+                        Stmt::Return(e) => return Some(self.eval(e, locals)),
+                        Stmt::Assign(name, op, e) => {
+                            let v = self.eval(e, locals);
+                            let v = match op {
+                                Some(op) => binary(op, locals[name], v),
+                                None => v
+                            };
+                            locals.insert(name.clone(), v);
+                        },
+                        Stmt::If(condition, then, otherwise) => {
+                            let branch = if self.eval(condition, locals) != 0.0 { then } else { otherwise };
+                            if let Some(v) = self.run(branch, locals) {
+                                return Some(v);
+                            }
+                        },
+                    }
+                }
+                None
+            }
+
+            fn eval(&self, e: &Expr, locals: &HashMap<String, f32>) -> f32 {
+                match e {
+                    Expr::Number(v) => *v,
+                    Expr::Var(name) => *locals.get(name).unwrap_or_else(|| panic!("Unknown variable {}", name)),
+                    Expr::Neg(e) => -self.eval(e, locals),
+                    Expr::Binary(op, a, b) => binary(op, self.eval(a, locals), self.eval(b, locals)),
+                    Expr::Select(c, a, b) => if self.eval(c, locals) != 0.0 { self.eval(a, locals) } else { self.eval(b, locals) },
+                    Expr::Call(name, args) => {
+                        let args: Vec<f32> = args.iter().map(|a| self.eval(a, locals)).collect();
+                        match (name.as_str(), args.as_slice()) {
+                            ("pow", [x, y]) => x.powf(*y),
+                            ("sin", [x]) => x.sin(),
+                            ("cos", [x]) => x.cos(),
+                            ("sqrt", [x]) => x.sqrt(),
+                            ("mix", [x, y, a]) => x + (y - x) * a,
+                            _ => self.call(name, &args)
+                        }
+                    }
+                }
+            }
+        }
+
+        fn binary(op: &str, a: f32, b: f32) -> f32 {
+            let truth = |v: bool| if v { 1.0 } else { 0.0 };
+            match op {
+                "+" => a + b,
+                "-" => a - b,
+                "*" => a * b,
+                "/" => a / b,
+                "<" => truth(a < b),
+                ">" => truth(a > b),
+                "<=" => truth(a <= b),
+                ">=" => truth(a >= b),
+                "==" => truth(a == b),
+                "!=" => truth(a != b),
+                "||" => truth(a != 0.0 || b != 0.0),
+                "&&" => truth(a != 0.0 && b != 0.0),
+                _ => unreachable!()
+            }
+        }
+    }
+
+    static EASING_TABLE: &str = include_str!("../assets/shaders/lib/easing_table.csv");
+    static EASING_GLSL: &str = include_str!("../assets/shaders/lib/easing.glsl");
+
+    #[test]
+    fn easing_matches_the_shared_table() {
+        let rows = EASING_TABLE.lines().filter(|l| !l.starts_with('#') && !l.trim().is_empty());
+        for row in rows {
+            let fields: Vec<&str> = row.split(',').map(|f| f.trim()).collect();
+            let (name, x, y): (&str, f32, f32) = (fields[0], fields[1].parse().unwrap(), fields[2].parse().unwrap());
+
+            let value = match name {
+                "smooth_step2" => smooth_step::<2>(x),
+                "smooth_step3" => smooth_step::<3>(x),
+                _ => Easing::parse(name).unwrap_or_else(|| panic!("Unknown easing {}", name)).ease(x),
+            };
+            assert!((value - y).abs() < 1.0e-5, "{}({}) = {}, expected {}", name, x, value, y);
+        }
+    }
+
+    #[test]
+    fn glsl_matches_the_shared_table() {
+        let shader = glsl::Program::parse(EASING_GLSL);
+        let rows = EASING_TABLE.lines().filter(|l| !l.starts_with('#') && !l.trim().is_empty());
+        for row in rows {
+            let fields: Vec<&str> = row.split(',').map(|f| f.trim()).collect();
+            let (name, x, y): (&str, f32, f32) = (fields[0], fields[1].parse().unwrap(), fields[2].parse().unwrap());
+
+            let function = if name.starts_with("smooth_") { name.to_string() } else { format!("ease_{}", name) };
+            let value = shader.call(&function, &[x]);
+            assert!((value - y).abs() < 1.0e-5, "{}({}) = {} in easing.glsl, expected {}", function, x, value, y);
+        }
+    }
+
+    #[test]
+    fn every_easing_is_in_glsl_and_the_table() {
+        for e in Easing::penner() {
+            let name = e.name().unwrap();
+            assert!(EASING_GLSL.contains(&format!("float ease_{}(float x)", name)), "ease_{} missing from easing.glsl", name);
+            assert!(EASING_TABLE.lines().any(|l| l.starts_with(&format!("{},", name))), "{} missing from the table", name);
+            assert_eq!(Easing::parse(&name), Some(e));
+            assert!(e.ease(0.0).abs() < 1.0e-6 && (e.ease(1.0) - 1.0).abs() < 1.0e-6, "{}", name);
+        }
+    }
+
+    #[test]
+    fn bezier_timing_matches_css() {
+        let linear = CubicBezier::new(0.0, 0.0, 1.0, 1.0);
+        let ease = CubicBezier::new(0.25, 0.1, 0.25, 1.0);
+        for i in 0..=10 {
+            let x = i as f32 / 10.0;
+            assert!((linear.ease(x) - x).abs() < 1.0e-4);
+        }
+        assert!((ease.ease(0.5) - 0.8024034).abs() < 1.0e-4);
+        assert!((Easing::CubicBezier(ease).ease(1.0) - 1.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn bezier_curves_pass_through_their_ends() {
+        let (p0, p1, p2, p3) = (Vec3::ZERO, Vec3::Y, Vec3::new(1.0, 1.0, 0.0), Vec3::X);
+        assert_eq!(cubic_bezier(&p0, &p1, &p2, &p3, 0.0), p0);
+        assert_eq!(cubic_bezier(&p0, &p1, &p2, &p3, 1.0), p3);
+        assert!(cubic_bezier(&p0, &p1, &p2, &p3, 0.5).distance(Vec3::new(0.5, 0.75, 0.0)) < 1.0e-6);
+    }
+
+    #[test]
+    fn curves_interpolate_between_keys() {
+        let curve = Curve::new()
+            .with(2.0, Vec3::X, Easing::Linear)
+            .with(0.0, Vec3::ZERO, Easing::Linear)
+            .with(1.0, Vec3::Y, Easing::Quad(EaseMode::In));
+
+        assert_eq!(curve.keys().iter().map(|k| k.time).collect::<Vec<_>>(), vec![0.0, 1.0, 2.0]);
+        assert_eq!(curve.sample(-1.0), Some(Vec3::ZERO));
+        assert_eq!(curve.sample(0.5), Some(Vec3::new(0.0, 0.5, 0.0)));
+        assert!(curve.sample(1.5).unwrap().distance(Vec3::Y.lerp(Vec3::X, 0.25)) < 1.0e-6);
+        assert_eq!(curve.sample(3.0), Some(Vec3::X));
+        assert_eq!(Curve::<f32>::new().sample(0.0), None);
+
+        let rotations = Curve::new()
+            .with(0.0, Quat::IDENTITY, Easing::Sine(EaseMode::InOut))
+            .with(1.0, Quat::from_rotation_z(3.0), Easing::Linear);
+        for i in 0..=10 {
+            assert!(is_unit(rotations.sample(i as f32 / 10.0).unwrap()));
+        }
+
+        let colors = Curve::new()
+            .with(0.0, Color::BLACK, Easing::Linear)
+            .with(1.0, Color::WHITE, Easing::Linear);
+        let mid = colors.sample(0.5).unwrap();
+        // Half the light is brighter than half the sRGB value
+        assert!(mid.r() > 0.7 && mid.r() < 0.75);
+    }
+}
@@ -3,11 +3,11 @@
 use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
 use bevy::utils::HashSet;
-use bevy::{input::{Axis, Input}};
+use bevy::input::Input;
 
 #[derive(Default)]
 pub struct GamepadLobby {
-    gamepads: HashSet<Gamepad>,
+    pub gamepads: HashSet<Gamepad>,
 }
 
 pub fn gamepad_connection_system(
@@ -29,71 +29,31 @@ pub fn gamepad_connection_system(
     }
 }
 
+/*
+ * Flies the camera from the move and look axes of the action map
+ */
 pub fn gamepad_system(
-    lobby: Res<GamepadLobby>,
-    _button_inputs: Res<Input<GamepadButton>>,
-    button_axes: Res<Axis<GamepadButton>>,
-    axes: Res<Axis<GamepadAxis>>,
+    actions: Res<crate::actions::ActionState>,
     mut camera_query: Query<&mut crate::camera::PlayerCamera, With<crate::camera::PlayerCamera>>
 ) {
-    for gamepad in lobby.gamepads.iter().cloned() {
-        match camera_query.single_mut() {
-            Ok(mut pc) => {
-                let pos_speed = pc.position_speed;
-                let rot_speed = pc.rotation_speed;
-
-                let mut xr = Quat::IDENTITY;
-                let mut yr = Quat::IDENTITY;
-
-                if let Some(right_stick_x) = axes.get(GamepadAxis(gamepad, GamepadAxisType::RightStickX)) {
-                    if right_stick_x.abs() > 0.1 {
-                        xr = Quat::from_rotation_y( - right_stick_x * rot_speed);
-                    }
-                }
-
-                if let Some(right_stick_y) = axes.get(GamepadAxis(gamepad, GamepadAxisType::RightStickY)) {
-                    if right_stick_y.abs() > 0.1 {
-                        yr = Quat::from_rotation_x(right_stick_y * rot_speed);
-                    }
-                }
-            
-                let mut xp = 0f32;
-                if let Some(left_stick_x) = axes.get(GamepadAxis(gamepad, GamepadAxisType::LeftStickX)) {
-                    if left_stick_x.abs() > 0.1 {
-                        xp = left_stick_x * pos_speed;
-                    }
-                }
-
-                let mut yp = 0f32;
-                if let Some(left_stick_y) = axes.get(GamepadAxis(gamepad, GamepadAxisType::LeftStickY)) {
-                    if left_stick_y.abs() > 0.1 {
-                        yp = left_stick_y * pos_speed;
-                    }
-                }
-
-                let mut zp = 0.0f32;
-                if let Some(mut right_trigger) = button_axes.get(GamepadButton(gamepad, GamepadButtonType::RightTrigger2)) {
-                    if right_trigger.abs() < 0.1 {
-                        right_trigger = 0.0f32;
-                    }
-                    
-
-                    if let Some(mut left_trigger) = button_axes.get(GamepadButton(gamepad, GamepadButtonType::LeftTrigger2)) {
-                        if left_trigger.abs() < 0.1 {
-                            left_trigger = 0.0f32;
-                        }
-
-                        zp = (right_trigger - left_trigger) * pos_speed;
-                    }
-                }
-
-                pc.rotation *= xr * yr;
-                let rotation = pc.rotation;
-                pc.position += rotation * Vec3::new(xp, zp, -yp);
-            }
-            Err(e) => {
-                println!("{:?}", e);                
-            }
+    match camera_query.single_mut() {
+        Ok(mut pc) => {
+            let pos_speed = pc.position_speed;
+            let rot_speed = pc.rotation_speed;
+
+            let xr = Quat::from_rotation_y(-actions.axis("look_x") * rot_speed);
+            let yr = Quat::from_rotation_x(actions.axis("look_y") * rot_speed);
+
+            let xp = actions.axis("move_x") * pos_speed;
+            let yp = actions.axis("move_y") * pos_speed;
+            let zp = actions.axis("move_z") * pos_speed;
+
+            pc.rotation *= xr * yr;
+            let rotation = pc.rotation;
+            pc.position += rotation * Vec3::new(xp, zp, -yp);
+        }
+        Err(e) => {
+            println!("{:?}", e);
         }
     }
 }
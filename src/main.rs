@@ -46,6 +46,7 @@ mod state;
 mod chunks;
 mod pbr;
 mod easing;
+mod color;
 mod projections;
 mod bsp;
 mod procedual;
@@ -53,10 +54,54 @@ mod shaders;
 mod utils;
 mod path_tracer;
 mod window;
+mod trajectory;
+mod collisions;
+mod ship;
+mod player;
+mod actions;
+mod replay;
+
+/*
+ * The command line tools exit before DefaultPlugins would set up logging
+ */
+fn setup_log() {
+    App::build().add_plugin(bevy::log::LogPlugin);
+}
 
 fn main() {
-    App::build()
-        .insert_resource(Msaa { samples: 4 })
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.iter().position(|a| a == "--replay").and_then(|i| args.get(i + 1)) {
+        setup_log();
+        match replay::run_headless_replay(path) {
+            Ok(hash) => info!("Replay matches after {} ticks ({:016x})", hash.tick, hash.hash),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(path) = args.iter().position(|a| a == "--reference").and_then(|i| args.get(i + 1)) {
+        setup_log();
+        let image = path_tracer::reference::render(&path_tracer::scene::Scene::cornell(0.0), 640, 360, 256);
+        if let Err(e) = path_tracer::offline::save_image(&image, path, &path_tracer::tonemapping::Tonemapping::default()) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let offline = match path_tracer::offline::OfflineRender::from_args(&args) {
+        Ok(offline) => offline,
+        Err(e) => {
+            setup_log();
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut app = App::build();
+    app.insert_resource(Msaa { samples: 4 })
         .insert_resource(WindowDescriptor {
             title: "I am a window!".to_string(),
             width: 1920.,
@@ -74,6 +119,12 @@ fn main() {
         // .add_plugin(bevy_rapier3d::physics::RapierPhysicsPlugin::<bevy_rapier3d::physics::NoUserData>::default())
         // .add_system(physics::gravity.system())
         // .add_system(physics::impulse.system())
+        // .add_system(collisions::collisions.system())
+        // .init_resource::<physics::Relativity>()
+        // .add_system(physics::limits.system())
+        // .add_system(physics::proper_time.system())
+        // .add_startup_system(ship::spawn_ship.system())
+        // .add_system(ship::ship_control.system())
 
         //Camera
         .add_startup_system(camera::setup_camera.system())
@@ -84,7 +135,10 @@ fn main() {
         //Input register
         .init_resource::<input::GamepadLobby>()
         // .add_system_to_stage(CoreStage::PreUpdate, input::gamepad_connection_system.system())
-        // .add_system(input::gamepad_system.system().label("gamepad"))
+        .add_startup_system(actions::setup_actions.system())
+        .add_system(actions::update_actions.system().label("actions"))
+        .add_system(actions::rebind_actions.system().after("actions"))
+        // .add_system(input::gamepad_system.system().label("gamepad").after("actions"))
         .add_system(input::mouse_keyboard_system.system())
 
         .add_system(camera::update_camera.system())
@@ -94,7 +148,31 @@ fn main() {
         .add_startup_system(window::setup_window.system().label("setup_window"))
 
         .add_startup_system(crate::path_tracer::path_trace.system().after("setup_window"))
-        .add_system(path_tracer::update_pt.system())
+        .add_startup_system(path_tracer::accumulation::setup_accumulation.system().label("pt_setup_accumulation").after("setup_window"))
+        .add_startup_system(path_tracer::denoise::setup_denoiser.system().label("pt_setup_denoiser").after("pt_setup_accumulation"))
+        .add_startup_system(path_tracer::tonemapping::setup_exposure.system().after("pt_setup_denoiser"))
+        .add_startup_system(path_tracer::offline::setup_offline_render.system().after("pt_setup_accumulation"))
+        .init_resource::<path_tracer::quality::QualityController>()
+        .add_system(path_tracer::update_pt.system().label("pt_quality"))
+        .add_system(path_tracer::update_pt_camera.system().label("pt_camera"))
+        .init_resource::<path_tracer::gpu_scene::GpuScene>()
+        .add_system(path_tracer::gpu_scene::upload_scene.system().label("pt_scene"))
+        .init_resource::<path_tracer::environment::Environment>()
+        .insert_resource(offline)
+        .add_system(path_tracer::offline::request_still.system())
+        .add_system(path_tracer::offline::trace_still.system().label("pt_still").after("pt_quality").after("pt_camera").after("pt_scene"))
+        .add_system(path_tracer::environment::upload_environment.system().label("pt_environment").after("pt_camera").after("pt_still"))
+        .init_resource::<path_tracer::accumulation::Accumulation>()
+        .add_system(path_tracer::accumulation::accumulate.system().label("pt_accumulate").after("pt_quality").after("pt_camera").after("pt_scene").after("pt_environment").after("pt_still"))
+        .add_stage_after(bevy::render::RenderStage::Draw, path_tracer::accumulation::TARGET_DRAW_STAGE, SystemStage::parallel())
+        .add_system_to_stage(path_tracer::accumulation::TARGET_DRAW_STAGE, path_tracer::accumulation::draw_target_quads.system())
+        .add_system(path_tracer::offline::capture_still.system().after("pt_accumulate"))
+        .init_resource::<path_tracer::denoise::Denoiser>()
+        .add_system(path_tracer::denoise::toggle_denoiser.system())
+        .add_system(path_tracer::denoise::denoise.system().label("pt_denoise").after("pt_accumulate"))
+        .init_resource::<path_tracer::tonemapping::Tonemapping>()
+        .add_system(path_tracer::tonemapping::cycle_tonemapper.system())
+        .add_system(path_tracer::tonemapping::expose.system().after("pt_denoise"))
         // .add_system(crate::path_tracer::update_pt.system())
         
 		// .add_system(load_chunk.system())
@@ -109,8 +187,23 @@ fn main() {
         // .add_system(physics::black_body.system())
         // .add_startup_system(procedual::solar_system::render_solar_system.system())
 
-        //Start game
-        .run();
+        .init_resource::<state::GameState>()
+        // .add_startup_system(procedual::galaxy::setup_star_field.system())
+        // .add_system(procedual::galaxy::stream_sectors.system())
+        // .add_system(procedual::galaxy::create_sectors.system())
+        // .add_system(procedual::galaxy::instantiate_star_systems.system())
+        // .add_startup_system(procedual::terrain::setup_terrain.system())
+        // .add_system(procedual::terrain::update_terrain.system())
+        // .init_resource::<trajectory::TrajectoryPredictor>()
+        // .add_system(trajectory::predict_trajectories.system())
+        // .add_system(trajectory::render_trajectories.system())
+        ;
+
+    //Deterministic simulation, the same schedule records input with --record <path>
+    app.add_plugin(replay::SimulationPlugin::default());
+
+    //Start game
+    app.run();
 }
 
 
@@ -0,0 +1,339 @@
+use bevy::math::{Mat4, Vec3, Vec4};
+use ::bvh::{Point3, aabb::{AABB, Bounded}, bounding_hierarchy::BHShape, bvh::{BVH, BVHNode}};
+
+use super::gpu_scene::PackedScene;
+use super::sampling::INFINITY;
+
+/*
+ * Two level BVH flattened like closest_hit.glsl and anyhit.glsl walk it, three texels per node:
+ * min, max and (left, right, leaf). Interior nodes have leaf 0 and point at their children,
+ * BLAS leaves have leaf 1 and a range of triangles, TLAS leaves have leaf -(transform + 1),
+ * the BLAS root on the left and the material on the right
+ */
+
+struct Primitive {
+    bounds: AABB,
+    node_index: usize,
+}
+
+impl Bounded for Primitive {
+    fn aabb(&self) -> AABB {
+        self.bounds
+    }
+}
+
+impl BHShape for Primitive {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+}
+
+fn point(v: Vec3) -> Point3 {
+    Point3::new(v.x, v.y, v.z)
+}
+
+fn vec3(p: &Point3) -> Vec3 {
+    Vec3::new(p.x, p.y, p.z)
+}
+
+pub fn aabb((min, max): (Vec3, Vec3)) -> AABB {
+    AABB::with_bounds(point(min), point(max))
+}
+
+/*
+ * Flat triangles have boxes without thickness, a little slack keeps the slab test from missing them
+ */
+fn padded(bounds: &AABB) -> (Vec3, Vec3) {
+    let (min, max) = (vec3(&bounds.min), vec3(&bounds.max));
+    let pad = Vec3::splat(1.0e-5 * (1.0 + min.abs().max(max.abs()).max_element()));
+    (min - pad, max + pad)
+}
+
+fn write_node(out: &mut Vec<[f32; 4]>, index: usize, (min, max): (Vec3, Vec3), left: i32, right: i32, leaf: i32) {
+    out[index * 3] = [min.x, min.y, min.z, 0.0];
+    out[index * 3 + 1] = [max.x, max.y, max.z, 0.0];
+    out[index * 3 + 2] = [left as f32, right as f32, leaf as f32, 0.0];
+}
+
+/*
+ * Depth first, a node always comes before its children
+ */
+fn flatten(tree: &BVH, index: usize, bounds: AABB, out: &mut Vec<[f32; 4]>, leaf: &mut dyn FnMut(usize) -> (i32, i32, i32)) -> i32 {
+    let flat = out.len() / 3;
+    out.extend_from_slice(&[[0.0; 4]; 3]);
+
+    let (left, right, kind) = match tree.nodes[index] {
+        BVHNode::Leaf { shape_index, .. } => leaf(shape_index),
+        BVHNode::Node { child_l_index, child_l_aabb, child_r_index, child_r_aabb, .. } => {
+            let left = flatten(tree, child_l_index, child_l_aabb, out, leaf);
+            let right = flatten(tree, child_r_index, child_r_aabb, out, leaf);
+            (left, right, 0)
+        }
+    };
+
+    write_node(out, flat, padded(&bounds), left, right, kind);
+    flat as i32
+}
+
+fn build(bounds: &[AABB], out: &mut Vec<[f32; 4]>, leaf: &mut dyn FnMut(usize) -> (i32, i32, i32)) -> i32 {
+    if bounds.is_empty() {
+        return -1;
+    }
+
+    let mut primitives: Vec<Primitive> = bounds.iter().map(|b| Primitive { bounds: *b, node_index: 0 }).collect();
+    let tree = BVH::build(&mut primitives);
+    let root = bounds.iter().fold(AABB::empty(), |a, b| a.join(b));
+
+    flatten(&tree, 0, root, out, leaf)
+}
+
+/*
+ * Bottom level of one mesh, its triangles are appended to vertex_indices in leaf order
+ * so every leaf covers a single triangle
+ */
+pub fn build_blas(packed: &mut PackedScene, positions: &[Vec3], indices: &[[u32; 3]], vertex_offset: u32) -> i32 {
+    let bounds: Vec<AABB> = indices.iter().map(|t| {
+        let [a, b, c] = [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]];
+        aabb((a.min(b).min(c), a.max(b).max(c)))
+    }).collect();
+
+    let vertex_indices = &mut packed.vertex_indices;
+    build(&bounds, &mut packed.bvh, &mut |i| {
+        let t = indices[i];
+        vertex_indices.push([(t[0] + vertex_offset) as f32, (t[1] + vertex_offset) as f32, (t[2] + vertex_offset) as f32, 0.0]);
+        (vertex_indices.len() as i32 - 1, 1, 1)
+    })
+}
+
+/*
+ * A placed BLAS, bounds in world space
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct TlasInstance {
+    pub bounds: (Vec3, Vec3),
+    pub blas_root: i32,
+    pub material: i32,
+    pub transform: i32,
+}
+
+pub fn build_tlas(packed: &mut PackedScene, instances: &[TlasInstance]) -> i32 {
+    let bounds: Vec<AABB> = instances.iter().map(|i| aabb(i.bounds)).collect();
+    build(&bounds, &mut packed.bvh, &mut |i| {
+        let instance = instances[i];
+        (instance.blas_root, instance.material, -instance.transform - 1)
+    })
+}
+
+fn aabb_intersect(min: Vec3, max: Vec3, origin: Vec3, direction: Vec3) -> f32 {
+    let invdir = Vec3::ONE / direction;
+
+    let f = (max - origin) * invdir;
+    let n = (min - origin) * invdir;
+
+    let tmax = f.max(n);
+    let tmin = f.min(n);
+
+    let t1 = tmax.min_element();
+    let t0 = tmin.max_element();
+
+    if t1 >= t0 { if t0 > 0.0 { t0 } else { t1 } } else { -1.0 }
+}
+
+pub fn triangle_intersect(v0: Vec3, v1: Vec3, v2: Vec3, origin: Vec3, direction: Vec3) -> Option<f32> {
+    let e0 = v1 - v0;
+    let e1 = v2 - v0;
+    let pv = direction.cross(e1);
+    let det = e0.dot(pv);
+
+    let tv = origin - v0;
+    let qv = tv.cross(e0);
+
+    let u = tv.dot(pv) / det;
+    let v = direction.dot(qv) / det;
+    let t = e1.dot(qv) / det;
+
+    if u >= 0.0 && v >= 0.0 && t >= 0.0 && 1.0 - u - v >= 0.0 { Some(t) } else { None }
+}
+
+fn fetch(texels: &[[f32; 4]], i: i32) -> Vec3 {
+    let t = texels[i as usize];
+    Vec3::new(t[0], t[1], t[2])
+}
+
+pub fn instance_transform(packed: &PackedScene, transform: i32) -> Mat4 {
+    let c = |k: i32| Vec4::from(packed.transforms[(transform * 4 + k) as usize]);
+    Mat4::from_cols(c(0), c(1), c(2), c(3))
+}
+
+/*
+ * Object space ray the same way the shaders compute it
+ */
+pub fn object_ray(transform: &Mat4, origin: Vec3, direction: Vec3) -> (Vec3, Vec3) {
+    let inverse = transform.inverse();
+    (inverse.transform_point3(origin), inverse.transform_vector3(direction))
+}
+
+/*
+ * ClosestHit without the lights, the distance and the index into vertex_indices of the nearest triangle
+ */
+pub fn closest_hit(packed: &PackedScene, origin: Vec3, direction: Vec3) -> Option<(f32, usize)> {
+    let mut t = INFINITY;
+    let mut hit = None;
+
+    let mut stack = vec![-1];
+    let mut index = packed.top_bvh_index;
+    let mut blas = false;
+    let (mut r_origin, mut r_direction) = (origin, direction);
+
+    while index != -1 {
+        let lr_leaf = packed.bvh[(index * 3 + 2) as usize];
+        let (left, right, leaf) = (lr_leaf[0] as i32, lr_leaf[1] as i32, lr_leaf[2] as i32);
+
+        if leaf > 0 {
+            for i in 0..right {
+                let v = fetch(&packed.vertex_indices, left + i);
+                let [a, b, c] = [v.x as i32, v.y as i32, v.z as i32];
+                if let Some(d) = triangle_intersect(fetch(&packed.vertices, a), fetch(&packed.vertices, b), fetch(&packed.vertices, c), r_origin, r_direction) {
+                    if d < t {
+                        t = d;
+                        hit = Some((d, (left + i) as usize));
+                    }
+                }
+            }
+        } else if leaf < 0 {
+            let ray = object_ray(&instance_transform(packed, -leaf - 1), origin, direction);
+            r_origin = ray.0;
+            r_direction = ray.1;
+
+            stack.push(-1);
+            index = left;
+            blas = true;
+            continue;
+        } else {
+            let left_hit = aabb_intersect(fetch(&packed.bvh, left * 3), fetch(&packed.bvh, left * 3 + 1), r_origin, r_direction);
+            let right_hit = aabb_intersect(fetch(&packed.bvh, right * 3), fetch(&packed.bvh, right * 3 + 1), r_origin, r_direction);
+
+            if left_hit > 0.0 && right_hit > 0.0 {
+                let (first, deferred) = if left_hit > right_hit { (right, left) } else { (left, right) };
+                index = first;
+                stack.push(deferred);
+                continue;
+            } else if left_hit > 0.0 {
+                index = left;
+                continue;
+            } else if right_hit > 0.0 {
+                index = right;
+                continue;
+            }
+        }
+        index = stack.pop().unwrap();
+
+        // Done with a BLAS, back to the TLAS
+        if blas && index == -1 {
+            blas = false;
+            index = stack.pop().unwrap();
+            r_origin = origin;
+            r_direction = direction;
+        }
+    }
+
+    hit
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{shape, Mesh, Quat};
+
+    use super::*;
+    use super::super::gpu_scene::{Instance, MeshData, SceneData};
+    use super::super::sampling::{uniform_sample_sphere, Rng};
+    use super::super::scene::Material;
+    use crate::chunks::Voxel;
+
+    fn scene() -> SceneData {
+        let voxels: Vec<Voxel> = (0..6).map(|i| Voxel { position: Vec3::new(i as f32, (i % 2) as f32, 0.0), ..Default::default() }).collect();
+
+        let meshes = vec![
+            MeshData::from_mesh(&Mesh::from(shape::Cube { size: 1.0 })).unwrap(),
+            MeshData::from_mesh(&Mesh::from(shape::Icosphere { radius: 1.0, subdivisions: 2 })).unwrap(),
+            MeshData::from_voxels(voxels.iter()),
+        ];
+
+        let mut rng = Rng::new(4, 5, 6);
+        let instances = (0..12).map(|i| {
+            let position = (Vec3::new(rng.rand(), rng.rand(), rng.rand()) - Vec3::splat(0.5)) * 10.0;
+            let rotation = Quat::from_axis_angle(uniform_sample_sphere(rng.rand(), rng.rand()), rng.rand() * 6.0);
+            let scale = Vec3::splat(0.5 + rng.rand());
+            Instance { mesh: i % 3, material: i % 2, transform: Mat4::from_scale_rotation_translation(scale, rotation, position) }
+        }).collect();
+
+        SceneData { meshes, instances, materials: vec![Material::default(); 2], lights: vec![], media: vec![] }
+    }
+
+    /*
+     * Every triangle of every instance, in object space like the traversal
+     */
+    fn brute_force(scene: &SceneData, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let mut closest: Option<f32> = None;
+        for instance in scene.instances.iter() {
+            let (o, d) = object_ray(&instance.transform, origin, direction);
+            let mesh = &scene.meshes[instance.mesh];
+            for t in mesh.indices.iter() {
+                let [a, b, c] = [mesh.positions[t[0] as usize], mesh.positions[t[1] as usize], mesh.positions[t[2] as usize]];
+                if let Some(h) = triangle_intersect(a, b, c, o, d) {
+                    closest = Some(closest.map_or(h, |c: f32| c.min(h)));
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn every_triangle_has_one_leaf() {
+        let scene = scene();
+        let packed = scene.pack();
+
+        let triangles: usize = scene.meshes.iter().map(|m| m.indices.len()).sum();
+        assert_eq!(packed.vertex_indices.len(), triangles);
+
+        // Binary trees, 2n - 1 nodes per level
+        let blas_nodes: usize = scene.meshes.iter().map(|m| 2 * m.indices.len() - 1).sum();
+        assert_eq!(packed.bvh.len() / 3, blas_nodes + 2 * scene.instances.len() - 1);
+
+        let leaves: Vec<i32> = packed.bvh.chunks(3).filter(|n| n[2][2] > 0.0).map(|n| n[2][0] as i32).collect();
+        let mut sorted = leaves.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..triangles as i32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let scene = scene();
+        let packed = scene.pack();
+
+        let mut rng = Rng::new(1, 2, 3);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = (Vec3::new(rng.rand(), rng.rand(), rng.rand()) - Vec3::splat(0.5)) * 30.0;
+            let target = (Vec3::new(rng.rand(), rng.rand(), rng.rand()) - Vec3::splat(0.5)) * 10.0;
+            let direction = (target - origin).normalize();
+
+            let expected = brute_force(&scene, origin, direction);
+            let found = closest_hit(&packed, origin, direction).map(|(t, _)| t);
+            assert_eq!(found, expected, "{:?} {:?}", origin, direction);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 200, "{}", hits);
+    }
+
+    #[test]
+    fn empty_scene_has_no_root() {
+        let packed = SceneData::default().pack();
+        assert_eq!(packed.top_bvh_index, -1);
+        assert!(closest_hit(&packed, Vec3::ZERO, Vec3::X).is_none());
+    }
+}
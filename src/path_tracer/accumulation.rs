@@ -0,0 +1,498 @@
+use std::borrow::Cow;
+
+use bevy::ecs::{component::Component, system::EntityCommands};
+use bevy::{app::{Events, ManualEventReader}, prelude::*, reflect::TypeUuid, render::{camera::{ActiveCameras, Camera}, draw::DrawContext, mesh::Indices, pass::{LoadOp, Operations, PassDescriptor, RenderPassColorAttachmentDescriptor, TextureAttachment}, pipeline::{BlendState, ColorTargetState, ColorWrite, PipelineDescriptor, RenderPipeline, RenderPipelines}, render_graph::{base::node::MAIN_PASS, CameraNode, Node, PassNode, RenderGraph, RenderResourcesNode, ResourceSlotInfo, ResourceSlots}, renderer::{RenderContext, RenderResourceBindings, RenderResourceId, RenderResourceType, SamplerId}, shader::ShaderStages, texture::{Extent3d, FilterMode, SamplerDescriptor, TextureDescriptor, TextureDimension, TextureFormat, TextureUsage, SAMPLER_ASSET_INDEX, TEXTURE_ASSET_INDEX}}, window::{WindowCreated, WindowId, WindowResized}};
+
+use crate::shaders::loader;
+use super::{environment::Environment, gpu_scene::GpuScene, PathTracer, PathTracerOutput};
+
+/*
+ * Progressive accumulation: the trace pass adds this frame's samples to the sum of the previous frames.
+ * A texture can't be read and written by the same pass, so two float targets take turns.
+ */
+
+static DEFAULT_MAX_SAMPLES: u32 = 4096;
+
+pub const ACCUM_TEXTURE_A: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d15);
+pub const ACCUM_TEXTURE_B: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d16);
+
+// Normal and distance, albedo of the primary hits for the denoiser
+pub const AUX_NORMAL_DEPTH: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d17);
+pub const AUX_ALBEDO: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d18);
+
+pub const ACCUM_TEXTURE_A_NODE: &str = "path_tracer_accum_a";
+pub const ACCUM_TEXTURE_B_NODE: &str = "path_tracer_accum_b";
+pub const AUX_NORMAL_DEPTH_NODE: &str = "path_tracer_aux_normal_depth";
+pub const AUX_ALBEDO_NODE: &str = "path_tracer_aux_albedo";
+pub const ACCUM_PASS_A: &str = "path_tracer_pass_a";
+pub const ACCUM_PASS_B: &str = "path_tracer_pass_b";
+pub const ACCUM_CAMERA_A: &str = "path_tracer_camera_a";
+pub const ACCUM_CAMERA_B: &str = "path_tracer_camera_b";
+
+pub const TARGET_DRAW_STAGE: &str = "path_tracer_target_draw";
+pub static TARGET_SAMPLE_COUNT: u32 = 1;  // The float targets are never multisampled, whatever Msaa the main pass uses
+
+#[derive(Default)]
+pub struct AccumulatePassA;
+#[derive(Default)]
+pub struct AccumulatePassB;
+
+/*
+ * Quad drawn by an offscreen pass into float targets, see draw_target_quads
+ */
+pub struct TargetQuad;
+
+/*
+ * What the accumulated frames were traced with, any change starts over
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub position: Vec3,
+    pub forward: Vec3,
+    pub up: Vec3,
+    pub fov: f32,
+    pub focal_dist: f32,
+    pub aperture: f32,
+    pub width: f32,
+    pub height: f32,
+    pub pathlenght: i32,
+    pub render_scale: f32,
+    pub tile_offset: Vec2,
+    pub inv_num_tiles: Vec2,
+}
+
+impl View {
+    pub fn of(pt: &PathTracer) -> Self {
+        Self {
+            position: pt.camera_position,
+            forward: pt.camera_forward,
+            up: pt.camera_up,
+            fov: pt.fov,
+            focal_dist: pt.focal_dist,
+            aperture: pt.aperture,
+            width: pt.width,
+            height: pt.height,
+            pathlenght: pt.pathlenght,
+            render_scale: pt.render_scale,
+            tile_offset: pt.tile_offset,
+            inv_num_tiles: pt.inv_num_tiles,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Accumulation {
+    pub max_samples: u32,   //0 keeps tracing forever
+    pub frame: u32,         //Frames summed since the last reset
+    pub samples: u32,       //Samples per pixel summed since the last reset
+    view: Option<View>,
+    revision: u64,
+}
+
+impl Default for Accumulation {
+    fn default() -> Self {
+        Self {
+            max_samples: DEFAULT_MAX_SAMPLES,
+            frame: 0,
+            samples: 0,
+            view: None,
+            revision: 0,
+        }
+    }
+}
+
+impl Accumulation {
+    pub fn reset(&mut self) {
+        self.frame = 0;
+        self.samples = 0;
+    }
+
+    pub fn done(&self) -> bool {
+        self.max_samples > 0 && self.samples >= self.max_samples
+    }
+
+    /*
+     * Index of the frame to trace with `samples` more samples, None once the cap is reached
+     */
+    pub fn advance(&mut self, view: View, revision: u64, samples: u32) -> Option<u32> {
+        if self.view != Some(view) || self.revision != revision {
+            self.view = Some(view);
+            self.revision = revision;
+            self.reset();
+        }
+
+        if self.done() {
+            return None;
+        }
+
+        let frame = self.frame;
+        self.frame += 1;
+        self.samples += samples;
+        Some(frame)
+    }
+
+    /*
+     * Target holding the latest sum, A for even frames
+     */
+    pub fn latest(&self) -> &'static HandleUntyped {
+        if self.frame % 2 == 1 { &ACCUM_TEXTURE_A } else { &ACCUM_TEXTURE_B }
+    }
+}
+
+/*
+ * Float render target registered under a texture handle so shaders can read it, recreated with the window like WindowTextureNode
+ * unless it has a fixed size
+ */
+pub struct TargetTextureNode {
+    descriptor: TextureDescriptor,
+    sampler: SamplerDescriptor,
+    sampler_id: Option<SamplerId>,
+    handle: HandleUntyped,
+    size: Option<(u32, u32)>,
+    window_created_event_reader: ManualEventReader<WindowCreated>,
+    window_resized_event_reader: ManualEventReader<WindowResized>,
+}
+
+impl TargetTextureNode {
+    pub const TEXTURE: &'static str = "texture";
+
+    pub fn new(handle: HandleUntyped) -> Self {
+        Self {
+            descriptor: TextureDescriptor {
+                size: Extent3d::new(1, 1, 1),
+                mip_level_count: 1,
+                sample_count: TARGET_SAMPLE_COUNT,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba32Float,
+                usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED | TextureUsage::COPY_SRC,  //Copied by offline renders
+            },
+            sampler: SamplerDescriptor {
+                mag_filter: FilterMode::Nearest,
+                min_filter: FilterMode::Nearest,
+                mipmap_filter: FilterMode::Nearest,
+                ..Default::default()
+            },
+            sampler_id: None,
+            handle,
+            size: None,
+            window_created_event_reader: Default::default(),
+            window_resized_event_reader: Default::default(),
+        }
+    }
+
+    pub fn fixed(handle: HandleUntyped, width: u32, height: u32) -> Self {
+        Self { size: Some((width, height)), ..Self::new(handle) }
+    }
+}
+
+impl Node for TargetTextureNode {
+    fn output(&self) -> &[ResourceSlotInfo] {
+        static OUTPUT: &[ResourceSlotInfo] = &[ResourceSlotInfo {
+            name: Cow::Borrowed(TargetTextureNode::TEXTURE),
+            resource_type: RenderResourceType::Texture,
+        }];
+        OUTPUT
+    }
+
+    fn update(
+        &mut self,
+        world: &World,
+        render_context: &mut dyn RenderContext,
+        _input: &ResourceSlots,
+        output: &mut ResourceSlots,
+    ) {
+        let (width, height) = match self.size {
+            Some(_) if output.get(0).is_some() => return,
+            Some(size) => size,
+            None => {
+                let window_created_events = world.get_resource::<Events<WindowCreated>>().unwrap();
+                let window_resized_events = world.get_resource::<Events<WindowResized>>().unwrap();
+                let window = match world.get_resource::<Windows>().unwrap().get_primary() {
+                    Some(window) => window,
+                    None => return,
+                };
+
+                let created = self.window_created_event_reader.iter(&window_created_events).any(|e| e.id == window.id());
+                let resized = self.window_resized_event_reader.iter(&window_resized_events).any(|e| e.id == window.id());
+                if !created && !resized && output.get(0).is_some() {
+                    return;
+                }
+                (window.physical_width(), window.physical_height())
+            }
+        };
+
+        let render_resource_context = render_context.resources_mut();
+        if let Some(RenderResourceId::Texture(old_texture)) = output.get(0) {
+            render_resource_context.remove_texture(old_texture);
+        }
+
+        self.descriptor.size.width = width.max(1);
+        self.descriptor.size.height = height.max(1);
+        let texture = render_resource_context.create_texture(self.descriptor);
+        let sampler = match self.sampler_id {
+            Some(sampler) => sampler,
+            None => render_resource_context.create_sampler(&self.sampler),
+        };
+        self.sampler_id = Some(sampler);
+
+        render_resource_context.set_asset_resource_untyped(self.handle.clone_weak(), RenderResourceId::Texture(texture), TEXTURE_ASSET_INDEX);
+        render_resource_context.set_asset_resource_untyped(self.handle.clone_weak(), RenderResourceId::Sampler(sampler), SAMPLER_ASSET_INDEX);
+        output.set(0, RenderResourceId::Texture(texture));
+    }
+}
+
+/*
+ * Pipeline drawing a quad into float targets, without depth
+ */
+pub fn target_pipeline(name: &str, targets: usize, shaders: ResMut<Assets<Shader>>, asset_server: ResMut<AssetServer>) -> PipelineDescriptor {
+    let shader_bundle = loader::load_shader(name, shaders, asset_server);
+    PipelineDescriptor {
+        depth_stencil: None,
+        color_target_states: vec![ColorTargetState {
+            format: TextureFormat::Rgba32Float,
+            color_blend: BlendState::REPLACE,
+            alpha_blend: BlendState::REPLACE,
+            write_mask: ColorWrite::ALL,
+        }; targets],
+        ..PipelineDescriptor::new(ShaderStages {
+            vertex: shader_bundle.vertex.unwrap().shader,
+            fragment: shader_bundle.fragment.map(|x| x.shader),
+        })
+    }
+}
+
+/*
+ * Pass drawing into the float targets plugged into its slots, loaded and not cleared
+ */
+pub fn target_pass(attachments: &[&str]) -> PassDescriptor {
+    PassDescriptor {
+        color_attachments: attachments.iter().map(|name| RenderPassColorAttachmentDescriptor {
+            attachment: TextureAttachment::Input(name.to_string()),
+            resolve_target: None,
+            ops: Operations { load: LoadOp::Load, store: true },
+        }).collect(),
+        depth_stencil_attachment: None,
+        sample_count: TARGET_SAMPLE_COUNT,
+    }
+}
+
+/*
+ * Camera only binding the view of an offscreen pass, the quads cover their target whatever it is
+ */
+pub fn target_camera(commands: &mut Commands, active_cameras: &mut ActiveCameras, render_graph: &mut RenderGraph, name: &'static str) {
+    render_graph.add_system_node(name, CameraNode::new(name));
+    active_cameras.add(name);
+    commands.spawn_bundle(OrthographicCameraBundle {
+        camera: Camera {
+            name: Some(name.to_string()),
+            window: WindowId::new(),
+            ..Default::default()
+        },
+        ..OrthographicCameraBundle::new_2d()
+    });
+}
+
+/*
+ * Offscreen pass of the target camera drawing the quad marked with P into the float targets plugged into its slots.
+ * The quad is hidden so the MainPass doesn't draw it, the render resources of the pass go on the returned entity.
+ */
+pub fn add_target_pass<'a, 'b, P: Component + Default>(
+    commands: &'b mut Commands<'a>,
+    render_graph: &mut RenderGraph,
+    name: &'static str,
+    camera: &'static str,
+    attachments: &[&str],
+    quad: Handle<Mesh>,
+    pipeline: Handle<PipelineDescriptor>,
+) -> EntityCommands<'a, 'b> {
+    let mut pass_node = PassNode::<&P>::new(target_pass(attachments));
+    pass_node.add_camera(camera);
+    render_graph.add_node(name, pass_node);
+    render_graph.add_node_edge(camera, name).unwrap();
+    render_graph.add_node_edge(name, MAIN_PASS).unwrap();
+
+    commands.spawn_bundle((
+        quad,
+        Draw::default(),
+        Visible { is_visible: false, is_transparent: false },
+        RenderPipelines::from_pipelines(vec![RenderPipeline::new(pipeline)]),
+        Transform::default(),
+        GlobalTransform::default(),
+        P::default(),
+        TargetQuad,
+    ))
+}
+
+/*
+ * Two offscreen passes into float targets the size of the window, each reading the other one
+ */
+pub fn setup_accumulation(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pipelines: ResMut<Assets<PipelineDescriptor>>,
+    mut render_graph: ResMut<RenderGraph>,
+    mut active_cameras: ResMut<ActiveCameras>,
+    shaders: ResMut<Assets<Shader>>,
+    windows: Res<Windows>,
+    time: Res<Time>,
+) {
+    let pipeline = pipelines.add(target_pipeline("PathTracer", 3, shaders, asset_server));
+
+    render_graph.add_system_node("PathTracer", RenderResourcesNode::<PathTracer>::new(true));
+    render_graph.add_node(AUX_NORMAL_DEPTH_NODE, TargetTextureNode::new(AUX_NORMAL_DEPTH.clone_weak()));
+    render_graph.add_node(AUX_ALBEDO_NODE, TargetTextureNode::new(AUX_ALBEDO.clone_weak()));
+
+    let quad = meshes.add(Mesh::from(shape::Quad { size: Vec2::new(2.0, 2.0), flip: false }));
+    let window = windows.get_primary().unwrap();
+    let path_tracer = PathTracer {
+        width: window.physical_width() as f32,
+        height: window.physical_height() as f32,
+        time: time.seconds_since_startup() as f32,
+        samples: 10,
+        pathlenght: 10,
+        fov: std::f32::consts::FRAC_PI_2,
+        focal_dist: 1.0,
+        top_bvh_index: -1,
+        render_scale: 1.0,
+        inv_num_tiles: Vec2::ONE,
+        ..Default::default()
+    };
+
+    // The fragments of the first frame ignore what the target holds
+    let attachments = ["color_attachment", "normal_depth_attachment", "albedo_attachment"];
+    target_camera(&mut commands, &mut active_cameras, &mut render_graph, ACCUM_CAMERA_A);
+    add_target_pass::<AccumulatePassA>(&mut commands, &mut render_graph, ACCUM_PASS_A, ACCUM_CAMERA_A, &attachments, quad.clone(), pipeline.clone())
+        .insert(PathTracer { accum: ACCUM_TEXTURE_B.typed(), ..path_tracer.clone() });
+    target_camera(&mut commands, &mut active_cameras, &mut render_graph, ACCUM_CAMERA_B);
+    add_target_pass::<AccumulatePassB>(&mut commands, &mut render_graph, ACCUM_PASS_B, ACCUM_CAMERA_B, &attachments, quad, pipeline)
+        .insert(PathTracer { accum: ACCUM_TEXTURE_A.typed(), ..path_tracer });
+
+    let targets = [
+        (ACCUM_TEXTURE_A_NODE, ACCUM_PASS_A, ACCUM_TEXTURE_A),
+        (ACCUM_TEXTURE_B_NODE, ACCUM_PASS_B, ACCUM_TEXTURE_B),
+    ];
+    for (texture, pass, handle) in targets.iter() {
+        render_graph.add_node(*texture, TargetTextureNode::new(handle.clone_weak()));
+        render_graph.add_node_edge(*texture, "PathTracer").unwrap();
+        render_graph.add_node_edge("PathTracer", *pass).unwrap();
+        render_graph.add_slot_edge(*texture, TargetTextureNode::TEXTURE, *pass, "color_attachment").unwrap();
+        render_graph.add_slot_edge(AUX_NORMAL_DEPTH_NODE, TargetTextureNode::TEXTURE, *pass, "normal_depth_attachment").unwrap();
+        render_graph.add_slot_edge(AUX_ALBEDO_NODE, TargetTextureNode::TEXTURE, *pass, "albedo_attachment").unwrap();
+    }
+}
+
+/*
+ * draw_render_pipelines_system specializes every pipeline with the sample count of Msaa.
+ * Runs after it and draws the target quads again with the sample count of their targets.
+ */
+pub fn draw_target_quads(
+    mut draw_context: DrawContext,
+    mut render_resource_bindings: ResMut<RenderResourceBindings>,
+    meshes: Res<Assets<Mesh>>,
+    mut query: Query<(&mut Draw, &mut RenderPipelines, &Handle<Mesh>, &Visible), With<TargetQuad>>,
+) {
+    for (mut draw, mut render_pipelines, mesh, visible) in query.iter_mut() {
+        let mesh = match meshes.get(mesh) {
+            Some(mesh) if visible.is_visible => mesh,
+            _ => continue,
+        };
+        draw.clear_render_commands();
+
+        let render_pipelines = &mut *render_pipelines;
+        for render_pipeline in render_pipelines.pipelines.iter_mut() {
+            render_pipeline.specialization.sample_count = TARGET_SAMPLE_COUNT;
+
+            let bindings = &mut [&mut render_pipelines.bindings, &mut render_resource_bindings];
+            draw_context.set_pipeline(&mut draw, &render_pipeline.pipeline, &render_pipeline.specialization).unwrap();
+            draw_context.set_bind_groups_from_bindings(&mut draw, bindings).unwrap();
+            draw_context.set_vertex_buffers_from_bindings(&mut draw, &[&render_pipelines.bindings]).unwrap();
+
+            match mesh.indices() {
+                Some(Indices::U32(indices)) => draw.draw_indexed(0..indices.len() as u32, 0, 0..1),
+                Some(Indices::U16(indices)) => draw.draw_indexed(0..indices.len() as u32, 0, 0..1),
+                None => draw.draw(0..mesh.count_vertices() as u32, 0..1),
+            }
+        }
+    }
+}
+
+/*
+ * Runs after the camera, the scene and the environment are updated, shows the pass of this frame and hides the other one
+ */
+pub fn accumulate(
+    mut accumulation: ResMut<Accumulation>,
+    gpu_scene: Res<GpuScene>,
+    environment: Res<Environment>,
+    mut tracers: Query<(&mut PathTracer, &mut Visible, Option<&AccumulatePassA>)>,
+    mut outputs: Query<&mut PathTracerOutput>,
+) {
+    let request = tracers.iter_mut().next().map(|(pt, _, _)| (View::of(&pt), pt.samples.max(1) as u32));
+    let frame = match request {
+        Some((view, samples)) => accumulation.advance(view, gpu_scene.revision + environment.revision, samples),
+        None => return,
+    };
+
+    for (mut pt, mut visible, pass_a) in tracers.iter_mut() {
+        match frame {
+            Some(frame) => {
+                pt.frame = frame as i32;
+                visible.is_visible = pass_a.is_some() == (frame % 2 == 0);
+            }
+            None => visible.is_visible = false,
+        }
+    }
+
+    for mut output in outputs.iter_mut() {
+        output.inv_sample_counter = if accumulation.samples > 0 { 1.0 / accumulation.samples as f32 } else { 0.0 };
+        output.accum = accumulation.latest().clone_weak().typed();
+        if let Some((view, _)) = request {
+            output.render_scale = view.render_scale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(x: f32) -> View {
+        View { position: Vec3::new(x, 0.0, 0.0), forward: -Vec3::Z, up: Vec3::Y, fov: 1.0, focal_dist: 1.0, aperture: 0.0, width: 64.0, height: 64.0, pathlenght: 4, render_scale: 1.0, tile_offset: Vec2::ZERO, inv_num_tiles: Vec2::ONE }
+    }
+
+    #[test]
+    fn frames_alternate_between_targets() {
+        let mut acc = Accumulation::default();
+        assert_eq!(acc.advance(view(0.0), 1, 4), Some(0));
+        assert_eq!(acc.latest().id, ACCUM_TEXTURE_A.id);
+        assert_eq!(acc.advance(view(0.0), 1, 4), Some(1));
+        assert_eq!(acc.latest().id, ACCUM_TEXTURE_B.id);
+        assert_eq!(acc.samples, 8);
+    }
+
+    #[test]
+    fn camera_and_scene_changes_reset() {
+        let mut acc = Accumulation::default();
+        for _ in 0..5 {
+            acc.advance(view(0.0), 1, 4);
+        }
+        assert_eq!(acc.advance(view(0.5), 1, 4), Some(0));
+        acc.advance(view(0.5), 1, 4);
+        assert_eq!(acc.advance(view(0.5), 2, 4), Some(0));
+        assert_eq!(acc.samples, 4);
+    }
+
+    #[test]
+    fn tracing_stops_at_the_cap() {
+        let mut acc = Accumulation { max_samples: 10, ..Default::default() };
+        let traced = (0..10).filter_map(|_| acc.advance(view(0.0), 1, 4)).count();
+        assert_eq!(traced, 3);
+        assert!(acc.done());
+
+        // Moving again resumes
+        assert_eq!(acc.advance(view(1.0), 1, 4), Some(0));
+
+        let mut unbounded = Accumulation { max_samples: 0, ..Default::default() };
+        assert!((0..100).all(|_| unbounded.advance(view(0.0), 1, 4).is_some()));
+    }
+}
@@ -0,0 +1,372 @@
+use bevy::{prelude::*, reflect::TypeUuid, render::{camera::ActiveCameras, pipeline::PipelineDescriptor, render_graph::{RenderGraph, RenderResourcesNode}, renderer::{RenderResource, RenderResources}}};
+use rayon::prelude::*;
+
+use crate::{actions::ActionState, utils::reflection::Reflectable};
+use super::{accumulation::{self, Accumulation, TargetTextureNode}, reference::Image, sampling::INFINITY, scene::{Camera, LightSampleRec, Ray, Scene, State}, PathTracerOutput};
+
+/*
+ * Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010): five taps of the B3 spline per axis, spread
+ * twice as far every iteration, weighted down across color, normal, depth and albedo edges.
+ * The lighting is divided by the albedo of the primary hit while filtering so textures stay sharp.
+ * Denoise/Denoise.frag is the same filter, one pass per iteration.
+ */
+
+static KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+static MIN_ALBEDO: f32 = 1.0e-3;
+pub const MAX_ITERATIONS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Atrous {
+    pub iterations: u32,
+    pub sigma_color: f32,   //Halved every iteration, the noise left is lower
+    pub sigma_normal: f32,  //Exponent of the cosine between normals
+    pub sigma_depth: f32,   //Relative to the distance and the step
+    pub sigma_albedo: f32,
+}
+
+impl Default for Atrous {
+    fn default() -> Self {
+        Self {
+            iterations: 4,
+            sigma_color: 1.0,
+            sigma_normal: 64.0,
+            sigma_depth: 0.05,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+/*
+ * Primary hits through the pixel centers, rows from the top like Image.
+ * Misses have a negative depth, lights face the camera with a white albedo.
+ */
+#[derive(Debug, Clone)]
+pub struct GBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub normal: Vec<Vec3>,
+    pub depth: Vec<f32>,
+    pub albedo: Vec<Vec3>,
+}
+
+impl GBuffer {
+    pub fn trace(scene: &Scene, width: u32, height: u32) -> Self {
+        let texels: Vec<(Vec3, f32, Vec3)> = (0..width * height).into_par_iter().map(|i| {
+            let (x, row) = (i % width, i / width);
+            let r = pixel_ray(&scene.camera, x, height - 1 - row, width, height);
+
+            let mut state = State::default();
+            let mut light_sample = LightSampleRec::default();
+            if !scene.closest_hit(&r, &mut state, &mut light_sample) || state.hit_dist >= INFINITY {
+                (Vec3::ZERO, -1.0, Vec3::ONE)
+            } else if state.is_emitter {
+                (-r.direction, state.hit_dist, Vec3::ONE)
+            } else {
+                (state.ffnormal, state.hit_dist, scene.materials[state.mat_id].albedo)
+            }
+        }).collect();
+
+        Self {
+            width,
+            height,
+            normal: texels.iter().map(|t| t.0).collect(),
+            depth: texels.iter().map(|t| t.1).collect(),
+            albedo: texels.iter().map(|t| t.2).collect(),
+        }
+    }
+
+    fn hit(&self, i: usize) -> bool {
+        self.depth[i] >= 0.0
+    }
+
+    fn demodulation(&self, i: usize) -> Vec3 {
+        if self.hit(i) { self.albedo[i].max(Vec3::splat(MIN_ALBEDO)) } else { Vec3::ONE }
+    }
+}
+
+/*
+ * camera_ray without the jitter and the aperture
+ */
+fn pixel_ray(camera: &Camera, x: u32, y: u32, width: u32, height: u32) -> Ray {
+    let scale = (camera.fov * 0.5).tan();
+    let dx = ((x as f32 + 0.5) / width as f32 * 2.0 - 1.0) * scale;
+    let dy = ((y as f32 + 0.5) / height as f32 * 2.0 - 1.0) * height as f32 / width as f32 * scale;
+    Ray { origin: camera.position, direction: (dx * camera.right + dy * camera.up + camera.forward).normalize() }
+}
+
+fn edge_weight(g: &GBuffer, settings: &Atrous, p: usize, q: usize, cp: Vec3, cq: Vec3, step: f32, sigma_color: f32) -> f32 {
+    if g.hit(p) != g.hit(q) {
+        return 0.0;
+    }
+
+    let w_color = (-(cq - cp).length_squared() / sigma_color.max(1.0e-10)).exp();
+    if !g.hit(p) {
+        return w_color;
+    }
+
+    let w_normal = g.normal[p].dot(g.normal[q]).max(0.0).powf(settings.sigma_normal);
+    let w_depth = (-(g.depth[q] - g.depth[p]).abs() / (settings.sigma_depth * g.depth[p] * step + 1.0e-6)).exp();
+    let w_albedo = (-(g.albedo[q] - g.albedo[p]).length_squared() / (settings.sigma_albedo * settings.sigma_albedo)).exp();
+    w_color * w_normal * w_depth * w_albedo
+}
+
+pub fn atrous(image: &Image, g: &GBuffer, settings: &Atrous) -> Image {
+    let (width, height) = (image.width as i32, image.height as i32);
+    let mut color: Vec<Vec3> = image.pixels.iter().enumerate().map(|(i, c)| *c / g.demodulation(i)).collect();
+
+    for iteration in 0..settings.iterations {
+        let step = (1 << iteration) as i32;
+        let sigma_color = settings.sigma_color * settings.sigma_color / step as f32;
+
+        color = (0..width * height).into_par_iter().map(|p| {
+            let (px, py) = (p % width, p / width);
+            let cp = color[p as usize];
+
+            let mut sum = Vec3::ZERO;
+            let mut weights = 0.0;
+            for (j, ky) in KERNEL.iter().enumerate() {
+                for (i, kx) in KERNEL.iter().enumerate() {
+                    let qx = px + (i as i32 - 2) * step;
+                    let qy = py + (j as i32 - 2) * step;
+                    if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        continue;
+                    }
+                    let q = (qy * width + qx) as usize;
+                    let w = kx * ky * edge_weight(g, settings, p as usize, q, cp, color[q], step as f32, sigma_color);
+                    sum += color[q] * w;
+                    weights += w;
+                }
+            }
+            // The center always has a weight of 1
+            sum / weights
+        }).collect();
+    }
+
+    Image { width: image.width, height: image.height, pixels: color.iter().enumerate().map(|(i, c)| *c * g.demodulation(i)).collect() }
+}
+
+/*
+ * GPU side: an entity and a pass per iteration, ping-ponging between two float targets.
+ * The first iteration reads the latest accumulation target, the output quad shows the last one.
+ */
+#[derive(Debug)]
+pub struct Denoiser {
+    pub enabled: bool,
+    pub filter: Atrous,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self { enabled: true, filter: Atrous::default() }
+    }
+}
+
+pub const DENOISE_TEXTURE_A: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d19);
+pub const DENOISE_TEXTURE_B: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d1a);
+
+pub const DENOISE_TEXTURE_A_NODE: &str = "denoise_target_a";
+pub const DENOISE_TEXTURE_B_NODE: &str = "denoise_target_b";
+pub const DENOISE_CAMERA: &str = "denoise_camera";
+pub static DENOISE_PASSES: [&str; MAX_ITERATIONS as usize] = ["denoise_pass_0", "denoise_pass_1", "denoise_pass_2", "denoise_pass_3", "denoise_pass_4"];
+
+#[derive(Default)]
+pub struct DenoisePass<const I: u32>;
+
+crate::resource!{
+    #[uuid = "9d3f6a1e-27c4-4b8e-b5a0-6c1e8f2d7b93"]
+    struct Denoise {
+        color: Handle<Texture>,
+        normal_depth: Handle<Texture>,
+        albedo: Handle<Texture>,
+        inv_sample_counter: f32,
+        step_width: f32,
+        iteration: i32,
+        iterations: i32,
+        sigma_color: f32,
+        sigma_normal: f32,
+        sigma_depth: f32,
+        sigma_albedo: f32,
+        render_scale: f32
+    }
+}
+
+fn target(iteration: u32) -> &'static HandleUntyped {
+    if iteration % 2 == 0 { &DENOISE_TEXTURE_A } else { &DENOISE_TEXTURE_B }
+}
+
+fn add_pass<const I: u32>(commands: &mut Commands, render_graph: &mut RenderGraph, quad: Handle<Mesh>, pipeline: Handle<PipelineDescriptor>) {
+    let (node, previous) = if I % 2 == 0 { (DENOISE_TEXTURE_A_NODE, DENOISE_TEXTURE_B_NODE) } else { (DENOISE_TEXTURE_B_NODE, DENOISE_TEXTURE_A_NODE) };
+    let name = DENOISE_PASSES[I as usize];
+
+    accumulation::add_target_pass::<DenoisePass<I>>(commands, render_graph, name, DENOISE_CAMERA, &["color_attachment"], quad, pipeline)
+        .insert(Denoise {
+            color: if I == 0 { accumulation::ACCUM_TEXTURE_A.typed() } else { target(I - 1).clone_weak().typed() },
+            normal_depth: accumulation::AUX_NORMAL_DEPTH.typed(),
+            albedo: accumulation::AUX_ALBEDO.typed(),
+            step_width: (1 << I) as f32,
+            iteration: I as i32,
+            ..Default::default()
+        });
+    render_graph.add_node_edge("Denoise", name).unwrap();
+    render_graph.add_slot_edge(node, TargetTextureNode::TEXTURE, name, "color_attachment").unwrap();
+
+    if I == 0 {
+        render_graph.add_node_edge(accumulation::ACCUM_PASS_A, name).unwrap();
+        render_graph.add_node_edge(accumulation::ACCUM_PASS_B, name).unwrap();
+    } else {
+        // Reads the target of the previous iteration
+        render_graph.add_node_edge(DENOISE_PASSES[I as usize - 1], name).unwrap();
+        render_graph.add_node_edge(previous, name).unwrap();
+    }
+}
+
+pub fn setup_denoiser(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pipelines: ResMut<Assets<PipelineDescriptor>>,
+    mut render_graph: ResMut<RenderGraph>,
+    mut active_cameras: ResMut<ActiveCameras>,
+    shaders: ResMut<Assets<Shader>>,
+) {
+    let pipeline = pipelines.add(accumulation::target_pipeline("Denoise", 1, shaders, asset_server));
+    let quad = meshes.add(Mesh::from(shape::Quad { size: Vec2::new(2.0, 2.0), flip: false }));
+
+    render_graph.add_system_node("Denoise", RenderResourcesNode::<Denoise>::new(true));
+    render_graph.add_node(DENOISE_TEXTURE_A_NODE, TargetTextureNode::new(DENOISE_TEXTURE_A.clone_weak()));
+    render_graph.add_node(DENOISE_TEXTURE_B_NODE, TargetTextureNode::new(DENOISE_TEXTURE_B.clone_weak()));
+    render_graph.add_node_edge(DENOISE_TEXTURE_A_NODE, "Denoise").unwrap();
+    render_graph.add_node_edge(DENOISE_TEXTURE_B_NODE, "Denoise").unwrap();
+    render_graph.add_node_edge(accumulation::AUX_NORMAL_DEPTH_NODE, "Denoise").unwrap();
+    render_graph.add_node_edge(accumulation::AUX_ALBEDO_NODE, "Denoise").unwrap();
+    accumulation::target_camera(&mut commands, &mut active_cameras, &mut render_graph, DENOISE_CAMERA);
+
+    add_pass::<0>(&mut commands, &mut render_graph, quad.clone(), pipeline.clone());
+    add_pass::<1>(&mut commands, &mut render_graph, quad.clone(), pipeline.clone());
+    add_pass::<2>(&mut commands, &mut render_graph, quad.clone(), pipeline.clone());
+    add_pass::<3>(&mut commands, &mut render_graph, quad.clone(), pipeline.clone());
+    add_pass::<4>(&mut commands, &mut render_graph, quad, pipeline);
+}
+
+pub fn toggle_denoiser(
+    actions: Res<ActionState>,
+    mut denoiser: ResMut<Denoiser>,
+) {
+    if actions.just_pressed("toggle_denoiser") {
+        denoiser.enabled = !denoiser.enabled;
+        info!("Denoiser {}", if denoiser.enabled { "on" } else { "off" });
+    }
+}
+
+/*
+ * Runs after accumulation::accumulate and shows the filtered image instead when enabled
+ */
+pub fn denoise(
+    denoiser: Res<Denoiser>,
+    accumulation: Res<Accumulation>,
+    mut passes: Query<(&mut Denoise, &mut Visible)>,
+    mut outputs: Query<&mut PathTracerOutput>,
+) {
+    let filter = denoiser.filter;
+    let iterations = filter.iterations.min(MAX_ITERATIONS);
+    let enabled = denoiser.enabled && iterations > 0 && accumulation.samples > 0;
+    let inv_sample_counter = if accumulation.samples > 0 { 1.0 / accumulation.samples as f32 } else { 0.0 };
+
+    let mut render_scale = 1.0;
+    for output in outputs.iter_mut() {
+        render_scale = output.render_scale;
+    }
+
+    for (mut pass, mut visible) in passes.iter_mut() {
+        let iteration = pass.iteration as u32;
+        visible.is_visible = enabled && iteration < iterations;
+
+        if iteration == 0 {
+            pass.color = accumulation.latest().clone_weak().typed();
+        }
+        pass.inv_sample_counter = inv_sample_counter;
+        pass.iterations = iterations as i32;
+        pass.sigma_color = filter.sigma_color;
+        pass.sigma_normal = filter.sigma_normal;
+        pass.sigma_depth = filter.sigma_depth;
+        pass.sigma_albedo = filter.sigma_albedo;
+        pass.render_scale = render_scale;
+    }
+
+    if enabled {
+        for mut output in outputs.iter_mut() {
+            output.accum = target(iterations - 1).clone_weak().typed();
+            output.inv_sample_counter = 1.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::reference::render;
+    use super::super::scene::{Bsdf, Material, Object, Shape};
+
+    fn mse(a: &Image, b: &Image) -> f32 {
+        a.pixels.iter().zip(b.pixels.iter()).map(|(x, y)| (*x - *y).length_squared()).sum::<f32>() / a.pixels.len() as f32
+    }
+
+    #[test]
+    fn flat_image_is_left_alone() {
+        let scene = Scene::cornell(0.0);
+        let g = GBuffer::trace(&scene, 24, 16);
+        let mut image = Image::new(24, 16);
+        for (i, p) in image.pixels.iter_mut().enumerate() {
+            *p = g.demodulation(i) * 0.25;
+        }
+
+        let filtered = atrous(&image, &g, &Atrous::default());
+        for (a, b) in filtered.pixels.iter().zip(image.pixels.iter()) {
+            assert!((*a - *b).abs().max_element() < 1.0e-5, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn gbuffer_matches_the_primary_hits() {
+        let scene = Scene::cornell(0.0);
+        let g = GBuffer::trace(&scene, 16, 12);
+        // The camera looks at the back wall through the center of the box
+        let center = (6 * 16 + 8) as usize;
+        assert!(g.hit(center));
+        assert!((g.depth[center] - 16.0).abs() < 0.5, "{}", g.depth[center]);
+        assert!(g.normal[center].dot(-Vec3::Z) > 0.99, "{:?}", g.normal[center]);
+    }
+
+    #[test]
+    fn denoised_image_is_closer_to_the_reference() {
+        // A sphere on a floor under a white sky, soft shadows and no fireflies
+        let scene = Scene {
+            camera: Camera::look_at(Vec3::new(0.0, 1.5, -4.0), Vec3::new(0.0, 0.5, 0.0), 1.0),
+            objects: vec![
+                Object { shape: Shape::Rect { position: Vec3::new(-50.0, 0.0, -50.0), u: Vec3::new(0.0, 0.0, 100.0), v: Vec3::new(100.0, 0.0, 0.0) }, material: 0 },
+                Object { shape: Shape::Sphere { center: Vec3::new(0.0, 1.0, 0.0), radius: 1.0 }, material: 1 },
+            ],
+            materials: vec![Material::diffuse(Vec3::splat(0.5)), Material::diffuse(Vec3::new(0.8, 0.2, 0.2))],
+            lights: vec![],
+            media: vec![],
+            background: Vec3::ONE,
+            max_depth: 4,
+            rr_depth: None,
+            bsdf: Bsdf::Lambert,
+        };
+        let (width, height) = (48, 32);
+
+        let reference = render(&scene, width, height, 1024);
+        let noisy = render(&scene, width, height, 4);
+        let g = GBuffer::trace(&scene, width, height);
+        // The footprint of the last iteration is a fraction of the image
+        let denoised = atrous(&noisy, &g, &Atrous { iterations: 3, ..Default::default() });
+
+        let (before, after) = (mse(&noisy, &reference), mse(&denoised, &reference));
+        assert!(after < 0.5 * before, "{} -> {}", before, after);
+
+        // Filtering moves light around but doesn't make or lose much of it
+        let (mean, expected) = (denoised.mean(), reference.mean());
+        assert!((mean - expected).abs().max_element() < 0.05 * expected.max_element(), "{:?} {:?}", mean, expected);
+    }
+}
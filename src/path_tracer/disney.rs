@@ -0,0 +1,266 @@
+use bevy::math::Vec3;
+
+use crate::easing::mix;
+use super::sampling::*;
+use super::scene::State;
+
+/*
+ * Mirrors lib/pathtrace/common/disney.glsl, the functions return f and the pdf instead of writing an inout pdf
+ */
+
+fn lobe(state: &State, h: Vec3, n: Vec3) -> Vec3 {
+    state.tangent * h.x + state.bitangent * h.y + n * h.z
+}
+
+pub fn eval_dielectric_reflection(state: &State, v: Vec3, n: Vec3, l: Vec3, h: Vec3) -> (Vec3, f32) {
+    if n.dot(l) <= 0.0 {
+        return (Vec3::ZERO, 0.0);
+    }
+
+    let f = dielectric_fresnel(v.dot(h), state.eta);
+    let d = gtr2(n.dot(h), state.mat.roughness);
+
+    let pdf = d * n.dot(h) * f / (4.0 * v.dot(h).abs());
+
+    let g = smith_g_ggx(n.dot(l).abs(), state.mat.roughness) * smith_g_ggx(n.dot(v).abs(), state.mat.roughness);
+    (state.mat.albedo * f * d * g, pdf)
+}
+
+pub fn eval_dielectric_refraction(state: &State, v: Vec3, n: Vec3, l: Vec3, h: Vec3) -> (Vec3, f32) {
+    if n.dot(l) >= 0.0 {
+        return (Vec3::ZERO, 0.0);
+    }
+
+    let f = dielectric_fresnel(v.dot(h).abs(), state.eta);
+    let d = gtr2(n.dot(h), state.mat.roughness);
+
+    let denom_sqrt = l.dot(h) + v.dot(h) * state.eta;
+    let pdf = d * n.dot(h) * (1.0 - f) * l.dot(h).abs() / (denom_sqrt * denom_sqrt);
+
+    let g = smith_g_ggx(n.dot(l).abs(), state.mat.roughness) * smith_g_ggx(n.dot(v).abs(), state.mat.roughness);
+    let eta2 = state.eta * state.eta;
+    (state.mat.albedo * (1.0 - f) * d * g * v.dot(h).abs() * l.dot(h).abs() * 4.0 * eta2 / (denom_sqrt * denom_sqrt), pdf)
+}
+
+pub fn eval_specular(state: &State, cspec0: Vec3, v: Vec3, n: Vec3, l: Vec3, h: Vec3) -> (Vec3, f32) {
+    if n.dot(l) <= 0.0 {
+        return (Vec3::ZERO, 0.0);
+    }
+
+    let d = gtr2(n.dot(h), state.mat.roughness);
+    let pdf = d * n.dot(h) / (4.0 * v.dot(h));
+
+    let fh = schlick_fresnel(l.dot(h));
+    let f = mix3(cspec0, Vec3::ONE, fh);
+    let g = smith_g_ggx(n.dot(l).abs(), state.mat.roughness) * smith_g_ggx(n.dot(v).abs(), state.mat.roughness);
+    (f * d * g, pdf)
+}
+
+pub fn eval_clearcoat(state: &State, v: Vec3, n: Vec3, l: Vec3, h: Vec3) -> (Vec3, f32) {
+    if n.dot(l) <= 0.0 {
+        return (Vec3::ZERO, 0.0);
+    }
+
+    let d = gtr1(n.dot(h), mix(0.1, 0.001, state.mat.clearcoat_gloss));
+    let pdf = d * n.dot(h) / (4.0 * v.dot(h));
+
+    let fh = schlick_fresnel(l.dot(h));
+    let f = mix(0.04, 1.0, fh);
+    let g = smith_g_ggx(n.dot(l), 0.25) * smith_g_ggx(n.dot(v), 0.25);
+    (Vec3::splat(0.25 * state.mat.clearcoat * f * d * g), pdf)
+}
+
+pub fn eval_diffuse(state: &State, csheen: Vec3, v: Vec3, n: Vec3, l: Vec3, h: Vec3) -> (Vec3, f32) {
+    if n.dot(l) <= 0.0 {
+        return (Vec3::ZERO, 0.0);
+    }
+
+    let pdf = n.dot(l) * (1.0 / PI);
+
+    // Diffuse
+    let fl = schlick_fresnel(n.dot(l));
+    let fv = schlick_fresnel(n.dot(v));
+    let fh = schlick_fresnel(l.dot(h));
+    let fd90 = 0.5 + 2.0 * l.dot(h) * l.dot(h) * state.mat.roughness;
+    let fd = mix(1.0, fd90, fl) * mix(1.0, fd90, fv);
+
+    // Fake subsurface
+    let fss90 = l.dot(h) * l.dot(h) * state.mat.roughness;
+    let fss = mix(1.0, fss90, fl) * mix(1.0, fss90, fv);
+    let ss = 1.25 * (fss * (1.0 / (n.dot(l) + n.dot(v)) - 0.5) + 0.5);
+
+    let fsheen = fh * state.mat.sheen * csheen;
+    (((1.0 / PI) * mix(fd, ss, state.mat.subsurface) * state.mat.albedo + fsheen) * (1.0 - state.mat.metallic), pdf)
+}
+
+/*
+ * Tint colors shared by sampling and evaluation
+ */
+fn tints(state: &State) -> (Vec3, Vec3) {
+    let cdlin = state.mat.albedo;
+    let cdlum = 0.3 * cdlin.x + 0.6 * cdlin.y + 0.1 * cdlin.z; // Luminance approximation
+
+    let ctint = if cdlum > 0.0 { cdlin / cdlum } else { Vec3::ONE }; // Isolates hue and saturation
+    let cspec0 = mix3(state.mat.specular * 0.08 * mix3(Vec3::ONE, ctint, state.mat.specular_tint), cdlin, state.mat.metallic);
+    let csheen = mix3(Vec3::ONE, ctint, state.mat.sheen_tint);
+    (cspec0, csheen)
+}
+
+/*
+ * Returns f, L and the pdf of the lobe that was picked
+ */
+pub fn disney_sample(state: &State, v: Vec3, n: Vec3, rng: &mut Rng) -> (Vec3, Vec3, f32) {
+    let r1 = rng.rand();
+    let r2 = rng.rand();
+
+    let diffuse_ratio = 0.5 * (1.0 - state.mat.metallic);
+    let trans_weight = (1.0 - state.mat.metallic) * state.mat.spec_trans;
+
+    let (cspec0, csheen) = tints(state);
+
+    if rng.rand() < trans_weight {
+        let mut h = lobe(state, importance_sample_gtr2(state.mat.roughness, r1, r2), n);
+        if v.dot(h) < 0.0 {
+            h = -h;
+        }
+
+        let r = reflect(-v, h);
+        let f = dielectric_fresnel(r.dot(h).abs(), state.eta);
+
+        // Reflection or total internal reflection, otherwise transmission
+        let (l, (f, pdf)) = if rng.rand() < f {
+            let l = r.normalize();
+            (l, eval_dielectric_reflection(state, v, n, l, h))
+        } else {
+            let l = refract(-v, h, state.eta).normalize();
+            (l, eval_dielectric_refraction(state, v, n, l, h))
+        };
+
+        return (f * trans_weight, l, pdf * trans_weight);
+    }
+
+    let (f, l, pdf) = if rng.rand() < diffuse_ratio {
+        let l = lobe(state, cosine_sample_hemisphere(r1, r2), n);
+        let h = (l + v).normalize();
+
+        let (f, pdf) = eval_diffuse(state, csheen, v, n, l, h);
+        (f, l, pdf * diffuse_ratio)
+    } else {
+        let primary_spec_ratio = 1.0 / (1.0 + state.mat.clearcoat);
+
+        if rng.rand() < primary_spec_ratio {
+            let mut h = lobe(state, importance_sample_gtr2(state.mat.roughness, r1, r2), n);
+            if v.dot(h) < 0.0 {
+                h = -h;
+            }
+            let l = reflect(-v, h).normalize();
+
+            let (f, pdf) = eval_specular(state, cspec0, v, n, l, h);
+            (f, l, pdf * primary_spec_ratio * (1.0 - diffuse_ratio))
+        } else {
+            let mut h = lobe(state, importance_sample_gtr1(mix(0.1, 0.001, state.mat.clearcoat_gloss), r1, r2), n);
+            if v.dot(h) < 0.0 {
+                h = -h;
+            }
+            let l = reflect(-v, h).normalize();
+
+            let (f, pdf) = eval_clearcoat(state, v, n, l, h);
+            (f, l, pdf * (1.0 - primary_spec_ratio) * (1.0 - diffuse_ratio))
+        }
+    };
+
+    (f * (1.0 - trans_weight), l, pdf * (1.0 - trans_weight))
+}
+
+pub fn disney_eval(state: &State, v: Vec3, n: Vec3, l: Vec3) -> (Vec3, f32) {
+    let refl = n.dot(l) > 0.0;
+
+    let mut h = if refl { (l + v).normalize() } else { (l + v * state.eta).normalize() };
+    if v.dot(h) < 0.0 {
+        h = -h;
+    }
+
+    let diffuse_ratio = 0.5 * (1.0 - state.mat.metallic);
+    let primary_spec_ratio = 1.0 / (1.0 + state.mat.clearcoat);
+    let trans_weight = (1.0 - state.mat.metallic) * state.mat.spec_trans;
+
+    let (mut bsdf, mut bsdf_pdf) = (Vec3::ZERO, 0.0);
+    if trans_weight > 0.0 {
+        let (f, pdf) = if refl { eval_dielectric_reflection(state, v, n, l, h) } else { eval_dielectric_refraction(state, v, n, l, h) };
+        bsdf = f;
+        bsdf_pdf = pdf;
+    }
+
+    let (mut brdf, mut brdf_pdf) = (Vec3::ZERO, 0.0);
+    if trans_weight < 1.0 {
+        let (cspec0, csheen) = tints(state);
+
+        let (f, pdf) = eval_diffuse(state, csheen, v, n, l, h);
+        brdf += f;
+        brdf_pdf += pdf * diffuse_ratio;
+
+        let (f, pdf) = eval_specular(state, cspec0, v, n, l, h);
+        brdf += f;
+        brdf_pdf += pdf * primary_spec_ratio * (1.0 - diffuse_ratio);
+
+        let (f, pdf) = eval_clearcoat(state, v, n, l, h);
+        brdf += f;
+        brdf_pdf += pdf * (1.0 - primary_spec_ratio) * (1.0 - diffuse_ratio);
+    }
+
+    (mix3(brdf, bsdf, trans_weight), mix(brdf_pdf, bsdf_pdf, trans_weight))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scene::Material;
+
+    fn state(mat: Material) -> State {
+        let n = Vec3::Y;
+        let (tangent, bitangent) = onb(n);
+        State { normal: n, ffnormal: n, tangent, bitangent, eta: 1.0 / mat.ior, mat, ..Default::default() }
+    }
+
+    #[test]
+    fn sampled_and_evaluated_specular_agree() {
+        let s = state(Material { metallic: 1.0, roughness: 0.3, ..Default::default() });
+        let v = Vec3::new(0.3, 1.0, 0.2).normalize();
+        let mut rng = Rng::new(1, 2, 3);
+
+        for _ in 0..256 {
+            let (f, l, pdf) = disney_sample(&s, v, s.ffnormal, &mut rng);
+            if pdf <= 0.0 {
+                continue;
+            }
+            let (f2, pdf2) = disney_eval(&s, v, s.ffnormal, l);
+            assert!((f - f2).length() <= 1.0e-3 * f.length().max(1.0), "{:?} {:?}", f, f2);
+            assert!((pdf - pdf2).abs() <= 1.0e-3 * pdf.max(1.0), "{} {}", pdf, pdf2);
+        }
+    }
+
+    #[test]
+    fn reflectance_stays_below_one() {
+        let materials = [
+            Material { albedo: Vec3::splat(0.5), roughness: 1.0, ..Default::default() },
+            Material { albedo: Vec3::splat(0.9), metallic: 1.0, roughness: 0.2, ..Default::default() },
+            Material { albedo: Vec3::splat(0.5), clearcoat: 1.0, clearcoat_gloss: 0.5, roughness: 0.5, ..Default::default() },
+        ];
+        for m in materials.iter() {
+            let s = state(*m);
+            let v = Vec3::new(0.5, 1.0, 0.0).normalize();
+            let mut rng = Rng::new(7, 11, 0);
+
+            let n = 20000;
+            let mut albedo = Vec3::ZERO;
+            for _ in 0..n {
+                let (f, l, pdf) = disney_sample(&s, v, s.ffnormal, &mut rng);
+                if pdf > 0.0 {
+                    albedo += f * s.ffnormal.dot(l).abs() / pdf;
+                }
+            }
+            albedo /= n as f32;
+            assert!(albedo.max_element() < 1.0 && albedo.min_element() > 0.0, "{:?} {:?}", m, albedo);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::{fs, path::Path};
+
+use bevy::{prelude::*, render::texture::{Extent3d, FilterMode, SamplerDescriptor, TextureDimension, TextureFormat}};
+use image::codecs::hdr::HdrDecoder;
+
+use crate::{constants::GLOBAL_SCALE, procedual::solar_system::{Star, AU, SUN_LUMINOSITY}};
+use super::{reference::Image, sampling::{PI, TWO_PI}, PathTracer};
+
+/*
+ * Environment lighting of lib/pathtrace/common/sampling.glsl: an equirectangular map, +Y up, rows from the top,
+ * and the tables EnvSample walks through to pick its texels in proportion to their luminance.
+ */
+
+/*
+ * Radiance .hdr through the decoder of the image crate, rows from the top like the shaders expect
+ */
+pub fn parse_hdr(bytes: &[u8]) -> Result<Image, String> {
+    let decoder = HdrDecoder::new(bytes).map_err(|e| e.to_string())?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_native().map_err(|e| e.to_string())?;
+
+    Ok(Image {
+        width: metadata.width,
+        height: metadata.height,
+        pixels: pixels.into_iter().map(|p| Vec3::from(p.to_hdr().0)).collect(),
+    })
+}
+
+pub fn load_hdr<P: AsRef<Path>>(path: P) -> Result<Image, String> {
+    let bytes = fs::read(path.as_ref()).map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+    parse_hdr(&bytes)
+}
+
+fn luminance(c: Vec3) -> f32 {
+    0.3 * c.x + 0.6 * c.y + 0.1 * c.z
+}
+
+/*
+ * Entry i holds the center of the bin picked by the random numbers in [i / n, (i + 1) / n) and the share of the
+ * entries picking bin i. The shaders read these tables with nearest filtering, so that share is the actual
+ * probability of a bin: bins too dim to get an entry are left to the BSDF samples instead of biasing the image.
+ */
+fn inverse_cdf(weights: &[f32]) -> Vec<[f32; 2]> {
+    let n = weights.len();
+    let total: f32 = weights.iter().sum();
+    let mut cdf = Vec::with_capacity(n);
+    let mut sum = 0.0;
+    for (i, w) in weights.iter().enumerate() {
+        sum += w;
+        cdf.push(if total > 0.0 { sum / total } else { (i + 1) as f32 / n as f32 });
+    }
+
+    let mut table = vec![[0.0; 2]; n];
+    for i in 0..n {
+        let r = (i as f32 + 0.5) / n as f32;
+        // First bin whose cdf goes past r, never one without weight
+        let bin = cdf.partition_point(|c| *c <= r).min(n - 1);
+        table[i][0] = (bin as f32 + 0.5) / n as f32;
+        table[bin][1] += 1.0 / n as f32;
+    }
+    table
+}
+
+pub fn direction_to_uv(d: Vec3) -> Vec2 {
+    Vec2::new((PI + d.z.atan2(d.x)) / TWO_PI, d.y.max(-1.0).min(1.0).acos() / PI)
+}
+
+pub fn uv_to_direction(uv: Vec2) -> Vec3 {
+    let (phi, theta) = (uv.x * TWO_PI, uv.y * PI);
+    Vec3::new(-theta.sin() * phi.cos(), theta.cos(), -theta.sin() * phi.sin())
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvironmentMap {
+    pub image: Image,
+    pub marginal: Vec<[f32; 2]>,     //A row per entry, hdrMarginalDistTex
+    pub conditional: Vec<[f32; 2]>,  //A column per entry for every row, hdrCondDistTex
+}
+
+impl EnvironmentMap {
+    pub fn new(image: Image) -> Self {
+        let (w, h) = (image.width as usize, image.height as usize);
+        let mut conditional = Vec::with_capacity(w * h);
+        let mut row_weights = Vec::with_capacity(h);
+        for (y, row) in image.pixels.chunks(w).enumerate() {
+            let weights: Vec<f32> = row.iter().map(|c| luminance(*c).max(0.0)).collect();
+            // Rows near the poles cover less of the sphere
+            let sin_theta = (PI * (y as f32 + 0.5) / h as f32).sin();
+            row_weights.push(weights.iter().sum::<f32>() * sin_theta);
+            conditional.extend(inverse_cdf(&weights));
+        }
+        let marginal = inverse_cdf(&row_weights);
+        Self { image, marginal, conditional }
+    }
+
+    fn texel(&self, uv: Vec2) -> (usize, usize) {
+        let x = ((uv.x * self.image.width as f32) as usize).min(self.image.width as usize - 1);
+        let y = ((uv.y * self.image.height as f32) as usize).min(self.image.height as usize - 1);
+        (x, y)
+    }
+
+    fn texel_pdf(&self, uv: Vec2) -> f32 {
+        let (x, y) = self.texel(uv);
+        let w = self.image.width as usize;
+        let resolution = (self.image.width * self.image.height) as f32;
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.conditional[y * w + x][1] * self.marginal[y][1] * resolution / (2.0 * PI * PI * sin_theta)
+    }
+
+    pub fn radiance(&self, d: Vec3) -> Vec3 {
+        let (x, y) = self.texel(direction_to_uv(d));
+        self.image.get(x as u32, y as u32)
+    }
+
+    /*
+     * EnvPdf, per solid angle
+     */
+    pub fn pdf(&self, d: Vec3) -> f32 {
+        self.texel_pdf(direction_to_uv(d))
+    }
+
+    /*
+     * EnvSample, a direction anywhere inside the picked texel with its radiance and pdf
+     */
+    pub fn sample(&self, r1: f32, r2: f32, r3: f32, r4: f32) -> (Vec3, Vec3, f32) {
+        let (w, h) = (self.image.width as usize, self.image.height as usize);
+        let v = self.marginal[((r1 * h as f32) as usize).min(h - 1)][0];
+        let row = ((v * h as f32) as usize).min(h - 1);
+        let u = self.conditional[row * w + ((r2 * w as f32) as usize).min(w - 1)][0];
+
+        let uv = Vec2::new(u + (r3 - 0.5) / w as f32, v + (r4 - 0.5) / h as f32);
+        let d = uv_to_direction(uv);
+        let (x, y) = self.texel(uv);
+        (d, self.image.get(x as u32, y as u32), self.texel_pdf(uv))
+    }
+}
+
+/*
+ * Light scattered around a star, when there is no map. The disk itself is the sphere light of gpu_scene,
+ * the sky only holds a dim ambient term and a Henyey-Greenstein glow around it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProceduralSky {
+    pub width: u32,
+    pub height: u32,
+    pub ambient: f32,
+    pub glow: f32,
+    pub anisotropy: f32,
+}
+
+impl Default for ProceduralSky {
+    fn default() -> Self {
+        Self { width: 256, height: 128, ambient: 0.002, glow: 0.05, anisotropy: 0.95 }
+    }
+}
+
+impl ProceduralSky {
+    /*
+     * `towards` points at the star, `irradiance` is its color scaled by the flux it receives relative to the Earth
+     */
+    pub fn render(&self, towards: Vec3, irradiance: Vec3) -> Image {
+        let g = self.anisotropy;
+        let mut image = Image::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let d = uv_to_direction(Vec2::new((x as f32 + 0.5) / self.width as f32, (y as f32 + 0.5) / self.height as f32));
+                let phase = (1.0 - g * g) / (4.0 * PI * (1.0 + g * g - 2.0 * g * d.dot(towards)).powf(1.5));
+                image.pixels[(y * self.width + x) as usize] = irradiance * (self.ambient + self.glow * phase);
+            }
+        }
+        image
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvironmentSource {
+    Background(Vec3),  //bgColor, no environment map
+    Map(String),       //Path of a .hdr file
+    Sky,               //ProceduralSky around the nearest star, black without one
+}
+
+/*
+ * Revision grows whenever the uploaded environment changed, like GpuScene
+ */
+#[derive(Debug)]
+pub struct Environment {
+    pub source: EnvironmentSource,
+    pub multiplier: f32,
+    pub sky: ProceduralSky,
+    pub revision: u64,
+    uploaded: Option<(EnvironmentSource, Vec3, Vec3)>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self { source: EnvironmentSource::Sky, multiplier: 1.0, sky: ProceduralSky::default(), revision: 0, uploaded: None }
+    }
+}
+
+static SKY_REBUILD_ANGLE: f32 = 0.01;     //rad, less than a texel of the default sky
+static SKY_REBUILD_CHANGE: f32 = 0.01;    //Relative change of the irradiance
+
+fn float_texture(width: u32, height: u32, texels: impl Iterator<Item = [f32; 4]>) -> Texture {
+    let mut data = Vec::with_capacity((width * height * 16) as usize);
+    for t in texels {
+        for c in t.iter() {
+            data.extend_from_slice(&c.to_ne_bytes());
+        }
+    }
+
+    let mut texture = Texture::new(Extent3d::new(width, height, 1), TextureDimension::D2, data, TextureFormat::Rgba32Float);
+    texture.sampler = SamplerDescriptor {
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        mipmap_filter: FilterMode::Nearest,
+        ..Default::default()
+    };
+    texture
+}
+
+pub fn upload_environment(
+    mut environment: ResMut<Environment>,
+    mut textures: ResMut<Assets<Texture>>,
+    stars: Query<(&Star, &GlobalTransform)>,
+    mut pt: Query<&mut PathTracer>,
+) {
+    let camera = match pt.iter_mut().next() {
+        Some(pt) => pt.camera_position,
+        None => return,
+    };
+
+    // Direction and irradiance of the nearest star, in the units of the scene
+    let star = stars.iter().min_by(|a, b| a.1.translation.distance_squared(camera).total_cmp(&b.1.translation.distance_squared(camera)));
+    let (towards, irradiance) = match star {
+        Some((star, transform)) => {
+            let d = (transform.translation - camera).length().max(1.0e-6);
+            let flux = (star.luminosity / SUN_LUMINOSITY) as f32 / (d * GLOBAL_SCALE / AU as f32).powi(2);
+            let color = star.color().as_rgba_linear();
+            ((transform.translation - camera) / d, Vec3::new(color.r(), color.g(), color.b()) * flux)
+        }
+        None => (Vec3::Y, Vec3::ZERO),
+    };
+
+    let up_to_date = match &environment.uploaded {
+        None => false,
+        Some((source, _, _)) if *source != environment.source => false,
+        Some((EnvironmentSource::Sky, t, i)) => {
+            t.angle_between(towards) < SKY_REBUILD_ANGLE && (irradiance - *i).length() <= SKY_REBUILD_CHANGE * i.length()
+        }
+        Some(_) => true,
+    };
+    if up_to_date {
+        let multiplier = environment.multiplier;
+        let mut changed = false;
+        for mut pt in pt.iter_mut() {
+            if pt.hdr_multiplier != multiplier {
+                pt.hdr_multiplier = multiplier;
+                changed = true;
+            }
+        }
+        if changed {
+            environment.revision += 1;
+        }
+        return;
+    }
+
+    let map = match &environment.source {
+        EnvironmentSource::Background(_) => None,
+        EnvironmentSource::Map(path) => match load_hdr(path) {
+            Ok(image) => Some(EnvironmentMap::new(image)),
+            Err(e) => {
+                warn!("Environment map not loaded, {}", e);
+                None
+            }
+        },
+        EnvironmentSource::Sky if irradiance == Vec3::ZERO => None,
+        EnvironmentSource::Sky => Some(EnvironmentMap::new(environment.sky.render(towards, irradiance))),
+    };
+    let background = match environment.source {
+        EnvironmentSource::Background(color) => color,
+        _ => Vec3::ZERO,
+    };
+
+    // One black texel when not used, so the bindings stay valid
+    let map = map.unwrap_or_else(|| EnvironmentMap::new(Image::new(1, 1)));
+    let use_env_map = map.image.pixels.iter().any(|p| *p != Vec3::ZERO);
+    let (width, height) = (map.image.width, map.image.height);
+    let hdr_tex = textures.add(float_texture(width, height, map.image.pixels.iter().map(|p| [p.x, p.y, p.z, 1.0])));
+    let marginal_tex = textures.add(float_texture(height, 1, map.marginal.iter().map(|m| [m[0], m[1], 0.0, 0.0])));
+    let conditional_tex = textures.add(float_texture(width, height, map.conditional.iter().map(|c| [c[0], c[1], 0.0, 0.0])));
+
+    for mut pt in pt.iter_mut() {
+        pt.use_env_map = use_env_map as i32;
+        pt.bg_color = background;
+        pt.hdr_multiplier = environment.multiplier;
+        pt.hdr_resolution = (width * height) as f32;
+        pt.hdr = hdr_tex.clone();
+        pt.hdr_marginal_dist = marginal_tex.clone();
+        pt.hdr_cond_dist = conditional_tex.clone();
+    }
+
+    environment.uploaded = Some((environment.source.clone(), towards, irradiance));
+    environment.revision += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sampling::Rng;
+
+    fn header(width: u32, height: u32) -> Vec<u8> {
+        format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=1.0\n\n-Y {} +X {}\n", height, width).into_bytes()
+    }
+
+    #[test]
+    fn flat_and_run_length_scanlines_are_decoded() {
+        // 2 = 128 * 2^(129 - 136) * 2, below 8 pixels there is no run length encoding
+        let mut flat = header(2, 1);
+        flat.extend_from_slice(&[128, 64, 0, 130, 0, 0, 0, 0]);
+        let image = parse_hdr(&flat).unwrap();
+        assert_eq!(image.pixels, vec![Vec3::new(2.0, 1.0, 0.0), Vec3::ZERO]);
+
+        // A run of 8 for red, literals for green, runs for blue and the exponent
+        let mut rle = header(8, 1);
+        rle.extend_from_slice(&[2, 2, 0, 8]);
+        rle.extend_from_slice(&[128 + 8, 128]);
+        rle.extend_from_slice(&[8, 0, 16, 32, 48, 64, 80, 96, 112]);
+        rle.extend_from_slice(&[128 + 4, 0, 128 + 4, 64]);
+        rle.extend_from_slice(&[128 + 8, 129]);
+        let image = parse_hdr(&rle).unwrap();
+        for (x, p) in image.pixels.iter().enumerate() {
+            let expected = Vec3::new(1.0, x as f32 / 8.0, if x < 4 { 0.0 } else { 0.5 });
+            assert!((*p - expected).abs().max_element() < 1.0e-6, "{} {:?}", x, p);
+        }
+
+        assert!(parse_hdr(b"P6\n").is_err());
+        assert!(parse_hdr(&rle[..rle.len() - 1]).is_err());
+    }
+
+    fn bright_spot() -> EnvironmentMap {
+        let mut image = Image::new(32, 16);
+        for (i, p) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = (i % 32, i / 32);
+            *p = if (10..13).contains(&x) && (4..6).contains(&y) { Vec3::new(50.0, 40.0, 30.0) } else { Vec3::splat(0.2) };
+        }
+        EnvironmentMap::new(image)
+    }
+
+    #[test]
+    fn directions_and_texels_agree() {
+        for uv in [Vec2::new(0.1, 0.3), Vec2::new(0.6, 0.9), Vec2::new(0.95, 0.5)].iter() {
+            let back = direction_to_uv(uv_to_direction(*uv));
+            assert!((back - *uv).abs().max_element() < 1.0e-5, "{:?} {:?}", uv, back);
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let map = bright_spot();
+        let (n, m) = (512, 256);
+        let mut sum = 0.0;
+        for j in 0..m {
+            for i in 0..n {
+                let uv = Vec2::new((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / m as f32);
+                let solid_angle = TWO_PI / n as f32 * PI / m as f32 * (uv.y * PI).sin();
+                sum += map.pdf(uv_to_direction(uv)) * solid_angle;
+            }
+        }
+        assert!((sum - 1.0).abs() < 0.01, "{}", sum);
+    }
+
+    #[test]
+    fn importance_sampling_estimates_the_irradiance() {
+        let map = bright_spot();
+        let n = Vec3::Y;
+
+        // Cosine weighted irradiance by quadrature over the texels
+        let (w, h) = (map.image.width, map.image.height);
+        let mut expected = Vec3::ZERO;
+        for y in 0..h * 8 {
+            for x in 0..w * 8 {
+                let uv = Vec2::new((x as f32 + 0.5) / (w * 8) as f32, (y as f32 + 0.5) / (h * 8) as f32);
+                let d = uv_to_direction(uv);
+                let solid_angle = TWO_PI / (w * 8) as f32 * PI / (h * 8) as f32 * (uv.y * PI).sin();
+                expected += map.radiance(d) * d.dot(n).max(0.0) * solid_angle;
+            }
+        }
+
+        // Half of the samples from the map, half uniform like a BSDF would, mixed with the balance heuristic
+        let mut rng = Rng::new(3, 5, 7);
+        let samples = 200000;
+        let mut estimate = Vec3::ZERO;
+        for i in 0..samples {
+            let (d, color) = if i % 2 == 0 {
+                let (d, color, pdf) = map.sample(rng.rand(), rng.rand(), rng.rand(), rng.rand());
+                assert!(pdf > 0.0);
+                (d, color)
+            } else {
+                let d = uv_to_direction(Vec2::new(rng.rand(), (1.0 - 2.0 * rng.rand()).acos() / PI));
+                (d, map.radiance(d))
+            };
+            estimate += color * d.dot(n).max(0.0) / (0.5 * map.pdf(d) + 0.5 / (4.0 * PI));
+        }
+        estimate /= samples as f32;
+        assert!((estimate - expected).abs().max_element() < 0.02 * expected.max_element(), "{:?} {:?}", estimate, expected);
+
+        // Most of the light comes from the spot, most samples go there
+        let spot = (0..1000).filter(|_| map.sample(rng.rand(), rng.rand(), rng.rand(), rng.rand()).1.x > 1.0).count();
+        assert!(spot > 500, "{}", spot);
+    }
+
+    #[test]
+    fn sky_glows_around_the_star() {
+        let sky = ProceduralSky { width: 64, height: 32, ..Default::default() };
+        let towards = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let image = sky.render(towards, Vec3::ONE);
+        let map = EnvironmentMap::new(image);
+
+        assert!(map.radiance(towards).x > 100.0 * map.radiance(-towards).x);
+        let mut rng = Rng::new(1, 2, 3);
+        let near = (0..1000).filter(|_| map.sample(rng.rand(), rng.rand(), rng.rand(), rng.rand()).0.dot(towards) > 0.9).count();
+        assert!(near > 500, "{}", near);
+    }
+}
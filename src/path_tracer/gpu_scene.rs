@@ -0,0 +1,728 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{prelude::*, render::{mesh::{Indices, VertexAttributeValues}, pipeline::PrimitiveTopology, texture::{Extent3d, FilterMode, SamplerDescriptor, TextureDimension, TextureFormat}}};
+
+use crate::{chunks::{Voxel, VoxelChunk}, constants::GLOBAL_SCALE, pbr::MaterialsMapping, procedual::{atmosphere::AtmosphereScattering, solar_system::Star, terrain::PLANET_VISUAL_SCALE}};
+use super::{acceleration, medium, scene, PathTraceScreen, PathTracer};
+
+/*
+ * Packs the ECS world into the sampler buffers of lib/pathtrace/common/uniforms.glsl
+ */
+
+pub static BUFFER_WIDTH: usize = 4096;  // Same as BUFFER_WIDTH in uniforms.glsl
+pub static POINT_LIGHT_RADIUS: f32 = 0.05;
+pub static MIE_ABSORPTION: f32 = 0.11;  // Mie extinction is 1.11 times its scattering
+
+/*
+ * Triangles of a single mesh in its own space, shared by every instance of it
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl MeshData {
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+
+        let positions: Vec<Vec3> = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float3(p)) => p.iter().map(|p| Vec3::from(*p)).collect(),
+            _ => return None,
+        };
+
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float2(uv)) => uv.iter().map(|uv| Vec2::from(*uv)).collect(),
+            _ => vec![Vec2::ZERO; positions.len()],
+        };
+
+        let flat: Vec<u32> = match mesh.indices() {
+            Some(Indices::U16(i)) => i.iter().map(|i| *i as u32).collect(),
+            Some(Indices::U32(i)) => i.clone(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        let indices: Vec<[u32; 3]> = flat.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float3(n)) => n.iter().map(|n| Vec3::from(*n)).collect(),
+            _ => smooth_normals(&positions, &indices),
+        };
+
+        Some(Self { positions, normals, uvs, indices })
+    }
+
+    /*
+     * Cubes centered on the voxel positions, faces between two voxels are left out
+     */
+    pub fn from_voxels<'a>(voxels: impl Iterator<Item = &'a Voxel> + Clone) -> Self {
+        let cell = |p: Vec3| IVec3::new(p.x.round() as i32, p.y.round() as i32, p.z.round() as i32);
+        let occupied: HashSet<IVec3> = voxels.clone().map(|v| cell(v.position)).collect();
+
+        let mut mesh = Self::default();
+        for voxel in voxels {
+            for &(x, y, z) in [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)].iter() {
+                if !occupied.contains(&(cell(voxel.position) + IVec3::new(x, y, z))) {
+                    mesh.push_face(voxel.position, Vec3::new(x as f32, y as f32, z as f32));
+                }
+            }
+        }
+        mesh
+    }
+
+    fn push_face(&mut self, center: Vec3, normal: Vec3) {
+        let u = if normal.x.abs() > 0.5 { Vec3::Y } else { Vec3::X };
+        let v = normal.cross(u);
+        let first = self.positions.len() as u32;
+
+        for &(a, b) in [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)].iter() {
+            self.positions.push(center + normal * 0.5 + u * a + v * b);
+            self.normals.push(normal);
+            self.uvs.push(Vec2::new(a + 0.5, b + 0.5));
+        }
+        // u × v = normal, counter clockwise seen from outside
+        self.indices.push([first, first + 1, first + 2]);
+        self.indices.push([first, first + 2, first + 3]);
+    }
+
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        self.positions.iter().fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), p| (min.min(*p), max.max(*p)))
+    }
+}
+
+fn smooth_normals(positions: &[Vec3], indices: &[[u32; 3]]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for t in indices {
+        let [a, b, c] = *t;
+        let n = (positions[b as usize] - positions[a as usize]).cross(positions[c as usize] - positions[a as usize]);
+        for i in t.iter() {
+            normals[*i as usize] += n;
+        }
+    }
+    normals.iter().map(|n| if n.length_squared() > 0.0 { n.normalize() } else { Vec3::Y }).collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    pub mesh: usize,
+    pub material: usize,
+    pub transform: Mat4,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SceneData {
+    pub meshes: Vec<MeshData>,
+    pub instances: Vec<Instance>,
+    pub materials: Vec<scene::Material>,
+    pub lights: Vec<scene::Light>,
+    pub media: Vec<medium::Medium>,
+}
+
+/*
+ * Texel arrays in the order the shaders fetch them
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PackedScene {
+    pub bvh: Vec<[f32; 4]>,
+    pub vertex_indices: Vec<[f32; 4]>,
+    pub vertices: Vec<[f32; 4]>,
+    pub normals: Vec<[f32; 4]>,
+    pub materials: Vec<[f32; 4]>,
+    pub transforms: Vec<[f32; 4]>,
+    pub lights: Vec<[f32; 4]>,
+    pub media: Vec<[f32; 4]>,
+    pub top_bvh_index: i32,
+    pub blas_roots: Vec<i32>,  // Per mesh, the TLAS is rebuilt on top of them when only instances move
+    pub blas_texels: usize,    // The BLAS come first in bvh, the TLAS after them
+}
+
+fn texel(v: Vec3, w: f32) -> [f32; 4] {
+    [v.x, v.y, v.z, w]
+}
+
+fn transform_aabb(transform: &Mat4, (min, max): (Vec3, Vec3)) -> (Vec3, Vec3) {
+    let mut bounds = (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN));
+    for i in 0..8 {
+        let corner = Vec3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        );
+        let p = transform.transform_point3(corner);
+        bounds = (bounds.0.min(p), bounds.1.max(p));
+    }
+    bounds
+}
+
+pub fn pack_material(m: &scene::Material) -> [[f32; 4]; 7] {
+    [
+        texel(m.albedo, m.specular),
+        texel(m.emission, m.anisotropic),
+        [m.metallic, m.roughness, m.subsurface, m.specular_tint],
+        [m.sheen, m.sheen_tint, m.clearcoat, m.clearcoat_gloss],
+        [m.spec_trans, m.ior, m.at_distance, 0.0],
+        texel(m.extinction, 0.0),
+        [-1.0, -1.0, -1.0, 0.0],  // No texture maps
+    ]
+}
+
+pub fn pack_light(l: &scene::Light) -> [[f32; 4]; 5] {
+    [
+        texel(l.position, 0.0),
+        texel(l.emission, 0.0),
+        texel(l.u, 0.0),
+        texel(l.v, 0.0),
+        [l.radius, l.area, l.kind as i32 as f32, 0.0],
+    ]
+}
+
+/*
+ * Shape, density and phase with their parameters in the w components, the majorant last
+ */
+pub fn pack_medium(m: &medium::Medium) -> [[f32; 4]; 6] {
+    let (shape, a, b, radius) = match m.shape {
+        medium::MediumShape::Sphere { center, radius } => (0.0, center, Vec3::ZERO, radius),
+        medium::MediumShape::Box { min, max } => (1.0, min, max, 0.0),
+    };
+    let (density, params) = match m.density {
+        medium::Density::Homogeneous => (0.0, [0.0; 3]),
+        medium::Density::Noise { frequency, octaves, threshold } => (1.0, [frequency, octaves as f32, threshold]),
+        medium::Density::Exponential { surface_radius, scale_height } => (2.0, [surface_radius, scale_height, 0.0]),
+    };
+    let (phase, g) = match m.phase {
+        medium::Phase::HenyeyGreenstein(g) => (0.0, g),
+        medium::Phase::Rayleigh => (1.0, 0.0),
+    };
+
+    [
+        [shape, density, phase, g],
+        texel(a, radius),
+        texel(b, params[0]),
+        texel(m.sigma_a, params[1]),
+        texel(m.sigma_s, params[2]),
+        texel(m.emission, m.majorant()),
+    ]
+}
+
+/*
+ * Rayleigh and Mie shells around a planet, shrunk like the planet meshes of solar_system so the coefficients grow by as much
+ */
+pub fn atmosphere_media(atmosphere: &AtmosphereScattering, center: Vec3) -> [medium::Medium; 2] {
+    let scale = PLANET_VISUAL_SCALE / GLOBAL_SCALE;
+    let shape = medium::MediumShape::Sphere { center, radius: atmosphere.atmosphere_radius * scale };
+    let surface_radius = atmosphere.planet_radius * scale;
+
+    [
+        medium::Medium {
+            shape,
+            sigma_a: Vec3::ZERO,
+            sigma_s: atmosphere.rayleigh / scale,
+            emission: Vec3::ZERO,
+            phase: medium::Phase::Rayleigh,
+            density: medium::Density::Exponential { surface_radius, scale_height: atmosphere.rayleigh_scale_height * scale },
+        },
+        medium::Medium {
+            shape,
+            sigma_a: Vec3::splat(atmosphere.mie * MIE_ABSORPTION / scale),
+            sigma_s: Vec3::splat(atmosphere.mie / scale),
+            emission: Vec3::ZERO,
+            phase: medium::Phase::HenyeyGreenstein(atmosphere.mie_g),
+            density: medium::Density::Exponential { surface_radius, scale_height: atmosphere.mie_scale_height * scale },
+        },
+    ]
+}
+
+impl SceneData {
+    /*
+     * The instances as world space triangles for path_tracer::reference, which has no BVH
+     */
+    pub fn to_scene(&self, camera: scene::Camera, background: Vec3, max_depth: usize) -> scene::Scene {
+        let mut objects = Vec::new();
+        for instance in self.instances.iter() {
+            let mesh = &self.meshes[instance.mesh];
+            let p = |i: u32| instance.transform.transform_point3(mesh.positions[i as usize]);
+            objects.extend(mesh.indices.iter().map(|t| scene::Object {
+                shape: scene::Shape::Triangle { a: p(t[0]), b: p(t[1]), c: p(t[2]) },
+                material: instance.material,
+            }));
+        }
+
+        scene::Scene {
+            camera,
+            objects,
+            materials: self.materials.clone(),
+            lights: self.lights.clone(),
+            media: self.media.clone(),
+            background,
+            max_depth,
+            rr_depth: None,
+            bsdf: scene::Bsdf::Disney,
+        }
+    }
+
+    pub fn pack(&self) -> PackedScene {
+        let mut packed = PackedScene { top_bvh_index: -1, ..Default::default() };
+
+        for m in self.materials.iter() {
+            packed.materials.extend_from_slice(&pack_material(m));
+        }
+        self.pack_lights(&mut packed);
+
+        // Geometry and a BLAS per mesh
+        for mesh in self.meshes.iter() {
+            let vertex_offset = packed.vertices.len() as u32;
+
+            for i in 0..mesh.positions.len() {
+                let uv = mesh.uvs.get(i).copied().unwrap_or(Vec2::ZERO);
+                packed.vertices.push(texel(mesh.positions[i], uv.x));
+                packed.normals.push(texel(mesh.normals[i], uv.y));
+            }
+            let root = acceleration::build_blas(&mut packed, &mesh.positions, &mesh.indices, vertex_offset);
+            packed.blas_roots.push(root);
+        }
+        packed.blas_texels = packed.bvh.len();
+
+        self.pack_instances(&mut packed);
+        packed
+    }
+
+    pub fn pack_lights(&self, packed: &mut PackedScene) {
+        packed.lights.clear();
+        packed.media.clear();
+        for l in self.lights.iter() {
+            packed.lights.extend_from_slice(&pack_light(l));
+        }
+        for m in self.media.iter() {
+            packed.media.extend_from_slice(&pack_medium(m));
+        }
+    }
+
+    /*
+     * Replaces the TLAS and the transforms of a packed scene with the same meshes, the BLAS are kept
+     */
+    pub fn pack_instances(&self, packed: &mut PackedScene) {
+        packed.bvh.truncate(packed.blas_texels);
+        packed.transforms.clear();
+
+        // Instances of the non empty meshes in the TLAS
+        let mut instances = Vec::with_capacity(self.instances.len());
+        for instance in self.instances.iter() {
+            let mesh = &self.meshes[instance.mesh];
+            if mesh.indices.is_empty() {
+                continue;
+            }
+            packed.transforms.extend_from_slice(&instance.transform.to_cols_array_2d());
+
+            instances.push(acceleration::TlasInstance {
+                bounds: transform_aabb(&instance.transform, mesh.aabb()),
+                blas_root: packed.blas_roots[instance.mesh],
+                material: instance.material as i32,
+                transform: (packed.transforms.len() / 4 - 1) as i32,
+            });
+        }
+        packed.top_bvh_index = acceleration::build_tlas(packed, &instances);
+    }
+}
+
+/*
+ * Bevy's PBR parameters expressed with the Disney ones
+ */
+pub fn material_from_standard(m: &StandardMaterial) -> scene::Material {
+    let albedo = m.base_color.as_rgba_linear();
+    let emission = m.emissive.as_rgba_linear();
+
+    scene::Material {
+        albedo: Vec3::new(albedo.r(), albedo.g(), albedo.b()),
+        emission: Vec3::new(emission.r(), emission.g(), emission.b()),
+        metallic: m.metallic,
+        roughness: m.roughness * m.roughness,  // Perceptual roughness to α
+        specular: 2.0 * m.reflectance * m.reflectance,  // Same f0, 0.16 reflectance² = 0.08 specular
+        ..Default::default()
+    }
+}
+
+/*
+ * Rows of BUFFER_WIDTH texels, never empty so the bindings stay valid
+ */
+pub fn buffer_texture(texels: &[[f32; 4]]) -> Texture {
+    let width = texels.len().min(BUFFER_WIDTH).max(1);
+    let height = ((texels.len() + width - 1) / width).max(1);
+
+    let mut data = Vec::with_capacity(width * height * 16);
+    for i in 0..width * height {
+        for c in texels.get(i).copied().unwrap_or([0.0; 4]).iter() {
+            data.extend_from_slice(&c.to_ne_bytes());
+        }
+    }
+
+    let mut texture = Texture::new(Extent3d::new(width as u32, height as u32, 1), TextureDimension::D2, data, TextureFormat::Rgba32Float);
+    texture.sampler = SamplerDescriptor {
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        mipmap_filter: FilterMode::Nearest,
+        ..Default::default()
+    };
+    texture
+}
+
+/*
+ * Revision grows whenever the uploaded scene changed, data is what was uploaded for the CPU renderer.
+ * Moving bodies change the picture too, so while the simulation moves them every frame the accumulation restarts
+ * every frame and the denoiser carries the image, only the geometry upload is cheaper than for a structural change
+ */
+#[derive(Debug, Default)]
+pub struct GpuScene {
+    pub revision: u64,
+    pub triangles: usize,
+    pub instances: usize,
+    pub data: SceneData,
+    pub packed: PackedScene,
+    entities: Vec<Entity>,  // Owner of each instance in data
+}
+
+fn light_from_bevy(light: &Light, transform: &GlobalTransform) -> scene::Light {
+    let color = light.color.as_rgba_linear();
+    let area = std::f32::consts::PI * POINT_LIGHT_RADIUS * POINT_LIGHT_RADIUS;
+    // Intensity spread over all directions, radiance of a small sphere emitting it
+    let emission = Vec3::new(color.r(), color.g(), color.b()) * light.intensity / (4.0 * std::f32::consts::PI * area);
+    scene::Light::sphere(transform.translation, POINT_LIGHT_RADIUS, emission)
+}
+
+fn collect_lights(
+    data: &mut SceneData,
+    materials: &Assets<StandardMaterial>,
+    lights: &Query<(&Light, &GlobalTransform)>,
+    stars: &Query<(&Star, &GlobalTransform, &Handle<StandardMaterial>)>,
+    atmospheres: &Query<(&AtmosphereScattering, &GlobalTransform)>,
+    volumes: &Query<&medium::Medium>,
+) {
+    data.lights.clear();
+    data.media.clear();
+
+    for (light, transform) in lights.iter() {
+        data.lights.push(light_from_bevy(light, transform));
+    }
+    // Stars are traced as analytic sphere lights instead of their icospheres
+    for (star, transform, material) in stars.iter() {
+        let emission = materials.get(material).map(|m| m.emissive.as_rgba_linear()).unwrap_or(Color::BLACK);
+        data.lights.push(scene::Light::sphere(transform.translation, star.radius as f32 / GLOBAL_SCALE, Vec3::new(emission.r(), emission.g(), emission.b())));
+    }
+
+    for (atmosphere, transform) in atmospheres.iter() {
+        data.media.extend_from_slice(&atmosphere_media(atmosphere, transform.translation));
+    }
+    // Nebulae and other free volumes are entities with a medium in world space
+    data.media.extend(volumes.iter().copied());
+}
+
+/*
+ * New meshes, materials, lights or media repack everything, when things only moved the BLAS are kept
+ * and the TLAS, the transforms and the lights are uploaded again
+ */
+pub fn upload_scene(
+    mut gpu_scene: ResMut<GpuScene>,
+    mut textures: ResMut<Assets<Texture>>,
+    meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<StandardMaterial>>,
+    material_mapping: Option<Res<MaterialsMapping>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut material_events: EventReader<AssetEvent<StandardMaterial>>,
+    changed: Query<(), (Or<(With<Handle<Mesh>>, With<Light>, With<VoxelChunk>, With<AtmosphereScattering>, With<medium::Medium>)>, Or<(Changed<Handle<Mesh>>, Changed<Handle<StandardMaterial>>, Changed<Light>, Changed<VoxelChunk>, Changed<AtmosphereScattering>, Changed<medium::Medium>)>, Without<PathTraceScreen>, Without<PathTracer>)>,
+    moved: Query<(), (Or<(With<Handle<Mesh>>, With<Light>, With<VoxelChunk>, With<AtmosphereScattering>)>, Changed<GlobalTransform>, Without<PathTraceScreen>, Without<PathTracer>)>,
+    removed: RemovedComponents<Handle<Mesh>>,
+    objects: Query<(Entity, &Handle<Mesh>, &Handle<StandardMaterial>, &GlobalTransform, &Visible), (Without<PathTraceScreen>, Without<Voxel>, Without<Star>)>,
+    chunks: Query<(Entity, &VoxelChunk, &GlobalTransform)>,
+    transforms: Query<&GlobalTransform>,
+    lights: Query<(&Light, &GlobalTransform)>,
+    stars: Query<(&Star, &GlobalTransform, &Handle<StandardMaterial>)>,
+    atmospheres: Query<(&AtmosphereScattering, &GlobalTransform)>,
+    volumes: Query<&medium::Medium>,
+    mut pt: Query<&mut PathTracer>,
+) {
+    let events = mesh_events.iter().count() + material_events.iter().count();
+    let rebuild = gpu_scene.revision == 0 || events > 0 || changed.iter().next().is_some() || removed.iter().next().is_some();
+    if !rebuild && moved.iter().next().is_none() {
+        return;
+    }
+
+    let gpu_scene = &mut *gpu_scene;
+    if !rebuild {
+        for (instance, entity) in gpu_scene.data.instances.iter_mut().zip(gpu_scene.entities.iter()) {
+            if let Ok(transform) = transforms.get(*entity) {
+                instance.transform = transform.compute_matrix();
+            }
+        }
+        collect_lights(&mut gpu_scene.data, &materials, &lights, &stars, &atmospheres, &volumes);
+
+        let (data, packed) = (&gpu_scene.data, &mut gpu_scene.packed);
+        data.pack_instances(packed);
+        data.pack_lights(packed);
+        gpu_scene.revision += 1;
+
+        let bvh_tex = textures.add(buffer_texture(&packed.bvh));
+        let transforms_tex = textures.add(buffer_texture(&packed.transforms));
+        let lights_tex = textures.add(buffer_texture(&packed.lights));
+        let media_tex = textures.add(buffer_texture(&packed.media));
+
+        for mut pt in pt.iter_mut() {
+            pt.bvh = bvh_tex.clone();
+            pt.transforms = transforms_tex.clone();
+            pt.lights = lights_tex.clone();
+            pt.top_bvh_index = packed.top_bvh_index;
+            pt.num_of_lights = data.lights.len() as i32;
+            pt.media = media_tex.clone();
+            pt.num_of_media = data.media.len() as i32;
+        }
+        return;
+    }
+
+    let mut data = SceneData::default();
+    let mut entities = Vec::new();
+    let mut mesh_ids: HashMap<Handle<Mesh>, usize> = HashMap::new();
+    let mut material_ids: HashMap<Handle<StandardMaterial>, usize> = HashMap::new();
+
+    // Index 0 is the fallback for missing materials
+    data.materials.push(scene::Material::default());
+
+    let mut material_id = |data: &mut SceneData, handle: &Handle<StandardMaterial>| -> usize {
+        if let Some(id) = material_ids.get(handle) {
+            return *id;
+        }
+        let id = match materials.get(handle) {
+            Some(m) => {
+                data.materials.push(material_from_standard(m));
+                data.materials.len() - 1
+            }
+            None => 0,
+        };
+        material_ids.insert(handle.clone_weak(), id);
+        id
+    };
+
+    for (entity, mesh, material, transform, visible) in objects.iter() {
+        if !visible.is_visible {
+            continue;
+        }
+        let mesh_id = match mesh_ids.get(mesh) {
+            Some(id) => *id,
+            None => match meshes.get(mesh).and_then(MeshData::from_mesh) {
+                Some(m) => {
+                    data.meshes.push(m);
+                    mesh_ids.insert(mesh.clone_weak(), data.meshes.len() - 1);
+                    data.meshes.len() - 1
+                }
+                None => continue,
+            },
+        };
+        let material = material_id(&mut data, material);
+        data.instances.push(Instance { mesh: mesh_id, material, transform: transform.compute_matrix() });
+        entities.push(entity);
+    }
+
+    // A mesh per chunk and material, the voxels are not traced one by one
+    for (entity, chunk, transform) in chunks.iter() {
+        let pbr_ids: HashSet<u64> = chunk.voxels.iter().map(|v| v.pbr_id).collect();
+        for pbr_id in pbr_ids {
+            let material = match material_mapping.as_ref().and_then(|m| m.map.get(&pbr_id).map(|h| h.value().clone())) {
+                Some(handle) => material_id(&mut data, &handle),
+                None => 0,
+            };
+            data.meshes.push(MeshData::from_voxels(chunk.voxels.iter().filter(|v| v.pbr_id == pbr_id)));
+            data.instances.push(Instance { mesh: data.meshes.len() - 1, material, transform: transform.compute_matrix() });
+            entities.push(entity);
+        }
+    }
+
+    collect_lights(&mut data, &materials, &lights, &stars, &atmospheres, &volumes);
+
+    let packed = data.pack();
+
+    gpu_scene.revision += 1;
+    gpu_scene.triangles = packed.vertex_indices.len();
+    gpu_scene.instances = packed.transforms.len() / 4;
+
+    let bvh_tex = textures.add(buffer_texture(&packed.bvh));
+    let vertex_indices_tex = textures.add(buffer_texture(&packed.vertex_indices));
+    let vertices_tex = textures.add(buffer_texture(&packed.vertices));
+    let normals_tex = textures.add(buffer_texture(&packed.normals));
+    let materials_tex = textures.add(buffer_texture(&packed.materials));
+    let transforms_tex = textures.add(buffer_texture(&packed.transforms));
+    let lights_tex = textures.add(buffer_texture(&packed.lights));
+    let media_tex = textures.add(buffer_texture(&packed.media));
+
+    // Both accumulation passes trace the same scene
+    for mut pt in pt.iter_mut() {
+        pt.bvh = bvh_tex.clone();
+        pt.vertex_indices = vertex_indices_tex.clone();
+        pt.vertices = vertices_tex.clone();
+        pt.normals = normals_tex.clone();
+        pt.materials = materials_tex.clone();
+        pt.transforms = transforms_tex.clone();
+        pt.lights = lights_tex.clone();
+        pt.top_bvh_index = packed.top_bvh_index;
+        pt.num_of_lights = data.lights.len() as i32;
+        pt.media = media_tex.clone();
+        pt.num_of_media = data.media.len() as i32;
+    }
+    gpu_scene.data = data;
+    gpu_scene.packed = packed;
+    gpu_scene.entities = entities;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube() -> MeshData {
+        MeshData::from_mesh(&Mesh::from(shape::Cube { size: 1.0 })).unwrap()
+    }
+
+    #[test]
+    fn cube_mesh_is_extracted() {
+        let mesh = cube();
+        assert_eq!(mesh.indices.len(), 12);
+        assert_eq!(mesh.aabb(), (Vec3::splat(-0.5), Vec3::splat(0.5)));
+        assert!(mesh.normals.iter().all(|n| (n.length() - 1.0).abs() < 1.0e-5));
+    }
+
+    #[test]
+    fn faces_between_voxels_are_culled() {
+        let voxels = vec![
+            Voxel { position: Vec3::ZERO, ..Default::default() },
+            Voxel { position: Vec3::X, ..Default::default() },
+        ];
+        let mesh = MeshData::from_voxels(voxels.iter());
+        assert_eq!(mesh.indices.len(), 2 * 10);
+        assert_eq!(mesh.aabb(), (Vec3::splat(-0.5), Vec3::new(1.5, 0.5, 0.5)));
+
+        // Winding agrees with the normals
+        for t in mesh.indices.iter() {
+            let [a, b, c] = [mesh.positions[t[0] as usize], mesh.positions[t[1] as usize], mesh.positions[t[2] as usize]];
+            assert!((b - a).cross(c - a).dot(mesh.normals[t[0] as usize]) > 0.0);
+        }
+    }
+
+    #[test]
+    fn instances_become_world_space_triangles() {
+        let data = SceneData {
+            meshes: vec![cube()],
+            instances: vec![Instance { mesh: 0, material: 1, transform: Mat4::from_translation(Vec3::X * 3.0) }],
+            materials: vec![scene::Material::default(), scene::Material::glass(1.5)],
+            lights: vec![scene::Light::sphere(Vec3::Y, 0.5, Vec3::ONE)],
+            media: vec![],
+        };
+        let camera = scene::Camera::look_at(Vec3::new(3.0, 0.0, 5.0), Vec3::X * 3.0, 1.0);
+        let scene = data.to_scene(camera, Vec3::ZERO, 8);
+
+        assert_eq!(scene.objects.len(), 12);
+        assert!(scene.objects.iter().all(|o| o.material == 1));
+        assert_eq!(scene.lights.len(), 1);
+
+        // The cube in front of the camera
+        let ray = scene::Ray { origin: camera.position, direction: camera.forward };
+        let mut state = scene::State::default();
+        let mut light_sample = scene::LightSampleRec::default();
+        assert!(scene.closest_hit(&ray, &mut state, &mut light_sample));
+        assert!((state.hit_dist - 4.5).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn packed_layout_matches_the_shaders() {
+        let scene = SceneData {
+            meshes: vec![cube(), cube()],
+            instances: vec![
+                Instance { mesh: 0, material: 1, transform: Mat4::from_translation(Vec3::X * 3.0) },
+                Instance { mesh: 1, material: 0, transform: Mat4::IDENTITY },
+            ],
+            materials: vec![scene::Material::default(), scene::Material::glass(1.5)],
+            lights: vec![scene::Light::sphere(Vec3::Y, 0.5, Vec3::ONE)],
+            media: vec![],
+        };
+        let packed = scene.pack();
+
+        assert_eq!(packed.materials.len(), 2 * 7);
+        assert_eq!(packed.materials[7 + 4][1], 1.5);
+        assert_eq!(packed.materials[6], [-1.0, -1.0, -1.0, 0.0]);
+        assert_eq!(packed.lights[4], [0.5, std::f32::consts::PI, 1.0, 0.0]);
+
+        // Two BLAS of 23 nodes, the TLAS comes after them
+        assert_eq!(packed.bvh.len(), (2 * 23 + 3) * 3);
+        assert_eq!(packed.top_bvh_index, 2 * 23);
+        assert_eq!(packed.bvh[2 * 23 * 3 + 2][2], 0.0);
+        assert!(packed.bvh[2 * 23 * 3 + 1][0] >= 3.5);
+
+        // The second mesh points past the vertices of the first one
+        assert!(packed.vertex_indices[12..].iter().all(|t| t[0] >= 24.0));
+
+        // Instance leaves: BLAS root, material and the transform
+        let mut leaves: Vec<[f32; 4]> = packed.bvh.chunks(3).filter(|n| n[2][2] < 0.0).map(|n| n[2]).collect();
+        leaves.sort_by(|a, b| b[2].partial_cmp(&a[2]).unwrap());
+        assert_eq!(leaves, vec![[0.0, 1.0, -1.0, 0.0], [23.0, 0.0, -2.0, 0.0]]);
+        assert_eq!(packed.transforms[3], [3.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn moved_instances_keep_the_blas() {
+        let mut scene = SceneData {
+            meshes: vec![cube(), cube()],
+            instances: vec![
+                Instance { mesh: 0, material: 0, transform: Mat4::IDENTITY },
+                Instance { mesh: 1, material: 0, transform: Mat4::from_translation(Vec3::X * 3.0) },
+            ],
+            materials: vec![scene::Material::default()],
+            ..Default::default()
+        };
+        let mut packed = scene.pack();
+
+        scene.instances[0].transform = Mat4::from_translation(Vec3::Y * 5.0);
+        scene.pack_instances(&mut packed);
+        let fresh = scene.pack();
+
+        assert_eq!(packed.bvh, fresh.bvh);
+        assert_eq!(packed.transforms, fresh.transforms);
+        assert_eq!(packed.top_bvh_index, fresh.top_bvh_index);
+        assert_eq!(packed.transforms[3], [0.0, 5.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn atmospheres_become_two_shells() {
+        let atmosphere = AtmosphereScattering {
+            planet_radius: 6.4e6,
+            atmosphere_radius: 6.485e6,
+            rayleigh_scale_height: 8.5e3,
+            mie_scale_height: 1.2e3,
+            rayleigh: Vec3::new(5.802e-6, 13.558e-6, 33.1e-6),
+            mie: 3.996e-6,
+            mie_g: 0.76,
+        };
+        let [rayleigh, mie] = atmosphere_media(&atmosphere, Vec3::X);
+
+        // Shrinking the shells keeps their optical depth
+        let depth = |m: &medium::Medium| match m.density {
+            medium::Density::Exponential { scale_height, .. } => m.sigma_s.x * scale_height,
+            _ => 0.0,
+        };
+        assert!((depth(&rayleigh) / (5.802e-6 * 8.5e3) - 1.0).abs() < 1.0e-4);
+        assert!((depth(&mie) / (3.996e-6 * 1.2e3) - 1.0).abs() < 1.0e-4);
+
+        let packed = pack_medium(&mie);
+        assert_eq!(packed[0], [0.0, 2.0, 0.0, 0.76]);
+        assert_eq!(packed[1][0], 1.0);
+        assert_eq!(packed[5][3], mie.majorant());
+        assert_eq!(pack_medium(&rayleigh)[0][2], 1.0);
+    }
+
+    #[test]
+    fn buffers_fill_whole_rows() {
+        let texels = vec![[1.0; 4]; BUFFER_WIDTH + 1];
+        let texture = buffer_texture(&texels);
+        assert_eq!((texture.size.width, texture.size.height), (BUFFER_WIDTH as u32, 2));
+        assert_eq!(texture.data.len(), BUFFER_WIDTH * 2 * 16);
+
+        let empty = buffer_texture(&[]);
+        assert_eq!((empty.size.width, empty.size.height), (1, 1));
+    }
+}
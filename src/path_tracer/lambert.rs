@@ -0,0 +1,21 @@
+use bevy::math::Vec3;
+
+use super::sampling::{cosine_sample_hemisphere, Rng, PI};
+use super::scene::State;
+
+/*
+ * Mirrors lib/pathtrace/common/lambert.glsl, returns f, L and the pdf
+ */
+pub fn lambert_sample(state: &State, _v: Vec3, n: Vec3, rng: &mut Rng) -> (Vec3, Vec3, f32) {
+    let r1 = rng.rand();
+    let r2 = rng.rand();
+
+    let l = cosine_sample_hemisphere(r1, r2);
+    let l = state.tangent * l.x + state.bitangent * l.y + n * l.z;
+
+    ((1.0 / PI) * state.mat.albedo, l, n.dot(l) * (1.0 / PI))
+}
+
+pub fn lambert_eval(state: &State, _v: Vec3, n: Vec3, l: Vec3) -> (Vec3, f32) {
+    ((1.0 / PI) * state.mat.albedo, n.dot(l) * (1.0 / PI))
+}
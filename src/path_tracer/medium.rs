@@ -0,0 +1,401 @@
+use bevy::math::Vec3;
+
+use super::sampling::{onb, Rng, PI, TWO_PI};
+use super::scene::Ray;
+
+/*
+ * Mirrors lib/pathtrace/common/medium.glsl, participating media tracked against the summed majorant of the media a ray crosses
+ */
+
+pub static MEDIUM_TEXELS: usize = 6;  // Same as MEDIUM_TEXELS in medium.glsl
+pub static MAX_TRACKING_STEPS: usize = 1024;  // Bounds the loops of the shaders, a ray is cut off after that many collisions
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediumShape {
+    Sphere { center: Vec3, radius: f32 },
+    Box { min: Vec3, max: Vec3 },
+}
+
+/*
+ * Evaluated with the cosine between the travelling ray and the scattered one, positive g scatters forward
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    HenyeyGreenstein(f32),
+    Rayleigh,
+}
+
+/*
+ * Scales the coefficients and never leaves [0, 1], so they are the majorant of the medium
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Density {
+    Homogeneous,
+    // Fractal value noise, what is below threshold is empty space between the clouds
+    Noise { frequency: f32, octaves: u32, threshold: f32 },
+    // Falls off with the height over surface_radius around the center of the shape, one below it
+    Exponential { surface_radius: f32, scale_height: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Medium {
+    pub shape: MediumShape,
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    pub emission: Vec3,  // Radiance of the absorbing part, for glowing nebulae
+    pub phase: Phase,
+    pub density: Density,
+}
+
+/*
+ * Outcome of delta tracking a ray up to the surface it hits
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collision {
+    Passed,
+    Scattered { position: Vec3, phase: Phase },
+    Absorbed,
+}
+
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+pub fn sample_henyey_greenstein(g: f32, r1: f32) -> f32 {
+    if g.abs() < 1.0e-3 {
+        return 1.0 - 2.0 * r1;
+    }
+    let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+    ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+}
+
+pub fn rayleigh(cos_theta: f32) -> f32 {
+    3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta)
+}
+
+/*
+ * Inverts the cubic of the Rayleigh CDF with Cardano's formula
+ */
+pub fn sample_rayleigh(r1: f32) -> f32 {
+    let z = 2.0 * (2.0 * r1 - 1.0);
+    let u = (z + (z * z + 1.0).sqrt()).cbrt();
+    (u - 1.0 / u).clamp(-1.0, 1.0)
+}
+
+impl Phase {
+    /*
+     * Also the pdf of sample, both are sampled exactly
+     */
+    pub fn eval(&self, cos_theta: f32) -> f32 {
+        match *self {
+            Phase::HenyeyGreenstein(g) => henyey_greenstein(cos_theta, g),
+            Phase::Rayleigh => rayleigh(cos_theta),
+        }
+    }
+
+    pub fn sample(&self, direction: Vec3, rng: &mut Rng) -> Vec3 {
+        let r1 = rng.rand();
+        let r2 = rng.rand();
+
+        let cos_theta = match *self {
+            Phase::HenyeyGreenstein(g) => sample_henyey_greenstein(g, r1),
+            Phase::Rayleigh => sample_rayleigh(r1),
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = TWO_PI * r2;
+
+        let (t, b) = onb(direction);
+        (t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + direction * cos_theta).normalize()
+    }
+}
+
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+    // pcg3d by Jarzynski and Olano
+    let mut v = [x as u32, y as u32, z as u32];
+    for c in v.iter_mut() {
+        *c = c.wrapping_mul(1664525).wrapping_add(1013904223);
+    }
+    v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[2]));
+    v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+    v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+    for c in v.iter_mut() {
+        *c ^= *c >> 16;
+    }
+    v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[2]));
+    v[0] as f32 / u32::MAX as f32
+}
+
+pub fn value_noise(p: Vec3) -> f32 {
+    let cell = p.floor();
+    let f = p - cell;
+    let w = f * f * (Vec3::splat(3.0) - 2.0 * f);
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let plane = |dz: i32| lerp(
+        lerp(hash(x, y, z + dz), hash(x + 1, y, z + dz), w.x),
+        lerp(hash(x, y + 1, z + dz), hash(x + 1, y + 1, z + dz), w.x),
+        w.y,
+    );
+    lerp(plane(0), plane(1), w.z)
+}
+
+/*
+ * Octaves of value noise halving in amplitude, normalized to [0, 1]
+ */
+pub fn fbm(p: Vec3, octaves: u32) -> f32 {
+    let (mut sum, mut amplitude, mut total, mut q) = (0.0, 1.0, 0.0, p);
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * value_noise(q);
+        total += amplitude;
+        amplitude *= 0.5;
+        q *= 2.0;
+    }
+    sum / total
+}
+
+impl MediumShape {
+    pub fn center(&self) -> Vec3 {
+        match *self {
+            MediumShape::Sphere { center, .. } => center,
+            MediumShape::Box { min, max } => (min + max) * 0.5,
+        }
+    }
+
+    /*
+     * Distances where the ray enters and leaves the shape, the entry is zero from the inside
+     */
+    pub fn interval(&self, r: &Ray) -> Option<(f32, f32)> {
+        let (t0, t1) = match *self {
+            MediumShape::Sphere { center, radius } => {
+                let op = center - r.origin;
+                let b = op.dot(r.direction);
+                let det = b * b - op.dot(op) + radius * radius;
+                if det < 0.0 {
+                    return None;
+                }
+                let det = det.sqrt();
+                (b - det, b + det)
+            }
+            MediumShape::Box { min, max } => {
+                let inv = Vec3::ONE / r.direction;
+                let a = (min - r.origin) * inv;
+                let b = (max - r.origin) * inv;
+                (a.min(b).max_element(), a.max(b).min_element())
+            }
+        };
+
+        if t1 <= t0.max(0.0) { None } else { Some((t0.max(0.0), t1)) }
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        match *self {
+            MediumShape::Sphere { center, radius } => (p - center).length_squared() <= radius * radius,
+            MediumShape::Box { min, max } => p.cmpge(min).all() && p.cmple(max).all(),
+        }
+    }
+}
+
+impl Medium {
+    pub fn homogeneous(shape: MediumShape, sigma_a: Vec3, sigma_s: Vec3, g: f32) -> Self {
+        Self { shape, sigma_a, sigma_s, emission: Vec3::ZERO, phase: Phase::HenyeyGreenstein(g), density: Density::Homogeneous }
+    }
+
+    pub fn density(&self, p: Vec3) -> f32 {
+        if !self.shape.contains(p) {
+            return 0.0;
+        }
+        match self.density {
+            Density::Homogeneous => 1.0,
+            Density::Noise { frequency, octaves, threshold } => {
+                ((fbm(p * frequency, octaves) - threshold) / (1.0 - threshold).max(1.0e-4)).clamp(0.0, 1.0)
+            }
+            Density::Exponential { surface_radius, scale_height } => {
+                let height = (p - self.shape.center()).length() - surface_radius;
+                (-height.max(0.0) / scale_height).exp()
+            }
+        }
+    }
+
+    pub fn majorant(&self) -> f32 {
+        (self.sigma_a + self.sigma_s).max_element()
+    }
+}
+
+/*
+ * Span of the ray inside any of the media before max_dist and the sum of their majorants
+ */
+fn extent(media: &[Medium], r: &Ray, max_dist: f32) -> Option<(f32, f32, f32)> {
+    let mut span = (max_dist, 0.0f32);
+    let mut majorant = 0.0;
+    for medium in media.iter() {
+        if let Some((t0, t1)) = medium.shape.interval(r) {
+            if t0 < max_dist {
+                span = (span.0.min(t0), span.1.max(t1.min(max_dist)));
+                majorant += medium.majorant();
+            }
+        }
+    }
+
+    if majorant > 0.0 && span.0 < span.1 { Some((span.0, span.1, majorant)) } else { None }
+}
+
+/*
+ * Ratio tracking, the transmittance along the ray up to max_dist
+ */
+pub fn transmittance(media: &[Medium], r: &Ray, max_dist: f32, rng: &mut Rng) -> Vec3 {
+    let (mut t, t_max, majorant) = match extent(media, r, max_dist) {
+        Some(e) => e,
+        None => return Vec3::ONE,
+    };
+
+    let mut tr = Vec3::ONE;
+    for _ in 0..MAX_TRACKING_STEPS {
+        t -= (1.0 - rng.rand()).ln() / majorant;
+        if t >= t_max {
+            break;
+        }
+
+        let p = r.origin + r.direction * t;
+        let sigma_t = media.iter().fold(Vec3::ZERO, |s, m| s + (m.sigma_a + m.sigma_s) * m.density(p));
+        tr *= Vec3::ONE - sigma_t / majorant;
+    }
+    tr
+}
+
+/*
+ * Delta tracking with the null collisions weighted by throughput like spectral tracking (Kutz et al. 2017),
+ * so coloured media need no hero channel. Emission is collected at every tentative collision.
+ */
+pub fn delta_track(media: &[Medium], r: &Ray, max_dist: f32, throughput: &mut Vec3, radiance: &mut Vec3, rng: &mut Rng) -> Collision {
+    let (mut t, t_max, majorant) = match extent(media, r, max_dist) {
+        Some(e) => e,
+        None => return Collision::Passed,
+    };
+
+    for _ in 0..MAX_TRACKING_STEPS {
+        t -= (1.0 - rng.rand()).ln() / majorant;
+        if t >= t_max {
+            return Collision::Passed;
+        }
+
+        let p = r.origin + r.direction * t;
+        let mut sigma_t = Vec3::ZERO;
+        let mut emitted = Vec3::ZERO;
+        for m in media.iter() {
+            let d = m.density(p);
+            sigma_t += (m.sigma_a + m.sigma_s) * d;
+            emitted += m.sigma_a * m.emission * d;
+        }
+        *radiance += *throughput * emitted / majorant;
+
+        // Each medium scatters with the share of its coefficient, the rest of the majorant are null collisions
+        let sigma_n = Vec3::splat(majorant) - sigma_t;
+        let p_null = (sigma_n * *throughput).max_element();
+        let p_scatter: f32 = media.iter().map(|m| (m.sigma_s * m.density(p) * *throughput).max_element()).sum();
+        if p_null + p_scatter <= 0.0 {
+            return Collision::Absorbed;
+        }
+
+        let mut x = rng.rand() * (p_null + p_scatter);
+        if x < p_null {
+            *throughput *= sigma_n * (p_null + p_scatter) / (majorant * p_null);
+            continue;
+        }
+        x -= p_null;
+
+        for m in media.iter() {
+            let sigma_s = m.sigma_s * m.density(p);
+            let p_m = (sigma_s * *throughput).max_element();
+            if p_m <= 0.0 {
+                continue;
+            }
+            if x < p_m {
+                *throughput *= sigma_s * (p_null + p_scatter) / (majorant * p_m);
+                return Collision::Scattered { position: p, phase: m.phase };
+            }
+            x -= p_m;
+        }
+        // Rounding left x past the last medium
+        return Collision::Absorbed;
+    }
+
+    Collision::Absorbed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integrate(f: impl Fn(f32) -> f32) -> f32 {
+        let n = 4096;
+        (0..n).map(|i| f(-1.0 + (i as f32 + 0.5) * 2.0 / n as f32)).sum::<f32>() * 2.0 / n as f32
+    }
+
+    #[test]
+    fn phase_functions_are_normalized() {
+        for g in [-0.7, 0.0, 0.3, 0.76].iter() {
+            let total = TWO_PI * integrate(|c| henyey_greenstein(c, *g));
+            assert!((total - 1.0).abs() < 2.0e-3, "g: {}, {}", g, total);
+        }
+        assert!((TWO_PI * integrate(rayleigh) - 1.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn sampled_cosines_follow_the_phase_functions() {
+        // Share of the samples in each cosine bin against the integral of the pdf over it
+        let bins = 8;
+        for phase in [Phase::HenyeyGreenstein(0.6), Phase::HenyeyGreenstein(-0.3), Phase::Rayleigh].iter() {
+            let mut rng = Rng::new(1, 2, 3);
+            let mut counts = vec![0usize; bins];
+            let n = 200_000;
+            for _ in 0..n {
+                let c = phase.sample(Vec3::Z, &mut rng).z;
+                counts[(((c + 1.0) * 0.5 * bins as f32) as usize).min(bins - 1)] += 1;
+            }
+
+            for (i, count) in counts.iter().enumerate() {
+                let (lo, hi) = (-1.0 + 2.0 * i as f32 / bins as f32, -1.0 + 2.0 * (i + 1) as f32 / bins as f32);
+                let steps = 256;
+                let expected = (0..steps).map(|j| phase.eval(lo + (j as f32 + 0.5) * (hi - lo) / steps as f32)).sum::<f32>() * (hi - lo) / steps as f32 * TWO_PI;
+                let share = *count as f32 / n as f32;
+                assert!((share - expected).abs() < 0.01, "{:?} bin {}: {} vs {}", phase, i, share, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn densities_stay_in_range() {
+        let shape = MediumShape::Sphere { center: Vec3::ZERO, radius: 2.0 };
+        let nebula = Medium { density: Density::Noise { frequency: 3.0, octaves: 4, threshold: 0.4 }, ..Medium::homogeneous(shape, Vec3::ONE, Vec3::ONE, 0.0) };
+        let atmosphere = Medium { density: Density::Exponential { surface_radius: 1.0, scale_height: 0.1 }, ..nebula };
+
+        let mut rng = Rng::new(4, 5, 6);
+        for _ in 0..1000 {
+            let p = Vec3::new(rng.rand(), rng.rand(), rng.rand()) * 4.0 - Vec3::splat(2.0);
+            let d = nebula.density(p);
+            assert!(d >= 0.0 && d <= 1.0, "{}", d);
+        }
+        assert_eq!(atmosphere.density(Vec3::new(0.5, 0.0, 0.0)), 1.0);
+        assert!((atmosphere.density(Vec3::new(1.1, 0.0, 0.0)) - (-1.0f32).exp()).abs() < 1.0e-5);
+        assert_eq!(atmosphere.density(Vec3::new(2.5, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn optical_depth_of_an_exponential_atmosphere() {
+        // Straight up from the surface τ = σ H (1 - exp(-(R_atm - R) / H))
+        let (radius, height, top, sigma) = (1.0, 0.05, 1.3, 4.0);
+        let atmosphere = Medium {
+            density: Density::Exponential { surface_radius: radius, scale_height: height },
+            ..Medium::homogeneous(MediumShape::Sphere { center: Vec3::ZERO, radius: top }, Vec3::splat(sigma), Vec3::ZERO, 0.0)
+        };
+        let up = Ray { origin: Vec3::new(0.0, radius, 0.0), direction: Vec3::Y };
+
+        let n = 20_000;
+        let mut rng = Rng::new(7, 8, 9);
+        let mean = (0..n).map(|_| transmittance(&[atmosphere], &up, 100.0, &mut rng).x).sum::<f32>() / n as f32;
+        let expected = (-sigma * height * (1.0 - (-(top - radius) / height).exp())).exp();
+        assert!((mean - expected).abs() < 0.01, "{} vs {}", mean, expected);
+    }
+}
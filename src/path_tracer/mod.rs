@@ -0,0 +1,167 @@
+use bevy::{prelude::*, render::{camera::PerspectiveProjection, pipeline::PipelineDescriptor, render_graph::RenderGraph}};
+use bevy_rapier3d::na::ComplexField;
+use crate::{camera::PlayerCamera, shaders::ShaderCache};
+
+use bevy::core::Byteable;
+use crate::utils::reflection::Reflectable;
+use bevy::render::renderer::{RenderResource, RenderResources};
+use bevy::reflect::*;
+
+pub mod sampling;
+pub mod scene;
+pub mod disney;
+pub mod lambert;
+pub mod reference;
+pub mod gpu_scene;
+pub mod acceleration;
+pub mod accumulation;
+pub mod quality;
+pub mod denoise;
+pub mod environment;
+pub mod tonemapping;
+pub mod offline;
+pub mod medium;
+
+pub struct PathTraceScreen;
+
+crate::resource!{
+    #[uuid = "4b816f2b-f19f-4ec5-b4e4-9bb094905679"]
+    struct PathTracer {
+        width: f32,
+        height: f32,
+        time: f32,
+        samples: i32,
+        pathlenght: i32,
+        camera_position: Vec3,
+        camera_right: Vec3,
+        camera_up: Vec3,
+        camera_forward: Vec3,
+        fov: f32,
+        focal_dist: f32,
+        aperture: f32,
+        bg_color: Vec3,
+        num_of_lights: i32,
+        top_bvh_index: i32,
+        bvh: Handle<Texture>,
+        vertex_indices: Handle<Texture>,
+        vertices: Handle<Texture>,
+        normals: Handle<Texture>,
+        materials: Handle<Texture>,
+        transforms: Handle<Texture>,
+        lights: Handle<Texture>,
+        frame: i32,
+        accum: Handle<Texture>,
+        render_scale: f32,
+        use_env_map: i32,
+        hdr_multiplier: f32,
+        hdr_resolution: f32,
+        hdr: Handle<Texture>,
+        hdr_marginal_dist: Handle<Texture>,
+        hdr_cond_dist: Handle<Texture>,
+        tile_offset: Vec2,
+        inv_num_tiles: Vec2,
+        num_of_media: i32,
+        media: Handle<Texture>
+    }
+}
+
+crate::resource!{
+    #[uuid = "0e4a5c39-8d2b-4f61-a7c3-52f19b6d8e04"]
+    struct PathTracerOutput {
+        inv_sample_counter: f32,
+        accum: Handle<Texture>,
+        render_scale: f32,
+        exposure: Handle<Texture>,
+        auto_exposure: i32,
+        exposure_ev: f32,
+        tonemapper: i32,
+        white_point: f32,
+        white_balance: Mat4
+    }
+}
+
+/*
+ * Quad showing the accumulated samples, the tracing itself happens in accumulation::setup_accumulation
+ */
+pub fn path_trace(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    shader_cache: ResMut<ShaderCache>,
+	pipelines: ResMut<Assets<PipelineDescriptor>>,
+	render_graph: ResMut<RenderGraph>,
+	shaders: ResMut<Assets<Shader>>,
+) {
+    let mut entity = commands.spawn();
+
+    entity
+        .insert_bundle(MeshBundle {
+            mesh: meshes.add(Mesh::from(shape::Quad { size: Vec2::new(2.0, 2.0), flip: false })),
+            ..Default::default()
+        })
+        .insert(PathTraceScreen);
+    
+    crate::shaders::add_shader::<PathTracerOutput>(&mut entity, asset_server, shader_cache, pipelines, render_graph, shaders);
+}
+
+/*
+ * Settings of the quality controller, held while the accumulation is capped since nothing is traced then
+ */
+pub fn update_pt(
+    time: Res<Time>,
+    windows: Res<Windows>,
+    accumulation: Res<accumulation::Accumulation>,
+    mut controller: ResMut<quality::QualityController>,
+    mut query: Query<&mut PathTracer>,
+) {
+    // The frame time is held at the refresh interval with vsync
+    controller.refresh_interval = windows.get_primary().filter(|w| w.vsync()).map(|_| quality::REFRESH_INTERVAL);
+
+    let quality = if accumulation.done() {
+        controller.hold();
+        controller.quality
+    } else {
+        controller.update(time.delta_seconds())
+    };
+
+    for mut pt in query.iter_mut() {
+        // The accumulation targets are in physical pixels
+        if let Some(window) = windows.get_primary() {
+            pt.width = window.physical_width() as f32;
+            pt.height = window.physical_height() as f32;
+        }
+        pt.time = time.seconds_since_startup() as f32;
+        pt.samples = quality.samples as i32;
+        pt.pathlenght = quality.pathlenght as i32;
+        pt.render_scale = quality.render_scale;
+    }
+}
+/*
+ * Camera of the shaders from the player camera, fov is horizontal there
+ */
+pub fn update_pt_camera(
+    camera_query: Query<(&GlobalTransform, &PerspectiveProjection), With<PlayerCamera>>,
+    mut query: Query<&mut PathTracer>,
+) {
+    if let Ok((transform, projection)) = camera_query.single() {
+        for mut pt in query.iter_mut() {
+            pt.camera_position = transform.translation;
+            pt.camera_right = transform.rotation * Vec3::X;
+            pt.camera_up = transform.rotation * Vec3::Y;
+            pt.camera_forward = transform.rotation * -Vec3::Z;
+            pt.fov = 2.0 * ((projection.fov * 0.5).tan() * projection.aspect_ratio).atan();
+        }
+    }
+}
+
+//     let mut camera_rotation = None;
+
+//     if let Ok(ct) = set.q0().single() {
+//         camera_rotation = Some(ct.);
+//     }
+//     if let Some(ct) = camera_rotation {
+//         if let Ok(mut screen_transform) = set.q1_mut().single_mut() {
+//             screen_transform.rotation = ct;
+//         }
+//     }
+// } 
\ No newline at end of file
@@ -0,0 +1,618 @@
+use std::{cell::RefCell, sync::{mpsc, Arc, Mutex}};
+
+use image::{codecs::png::PngEncoder, ColorType, ImageResult};
+
+use bevy::{app::AppExit, prelude::*, render::{render_graph::{Node, RenderGraph, ResourceSlots}, renderer::{BufferId, BufferInfo, BufferMapMode, BufferUsage, RenderContext, RenderResourceContext}, texture::{Extent3d, TEXTURE_ASSET_INDEX}}};
+
+use crate::{actions::ActionState, color};
+use super::{accumulation::{self, Accumulation}, environment::{Environment, EnvironmentSource}, gpu_scene::GpuScene, reference::{self, Image}, scene::Camera, tonemapping::{self, Tonemapping}, PathTracer};
+
+/*
+ * Stills of the current view at any resolution and a fixed sample count, saved as an 8 bit PNG developed like
+ * PathTracerOutput.frag and an EXR of the linear radiance.
+ * The GPU renders the image a window sized tile at a time through the accumulation passes and reads every
+ * tile back once it has all its samples. The CPU one hands the uploaded scene to reference::render on a thread,
+ * it only has the background color for the environment.
+ */
+
+pub const STILL_READBACK_NODE: &str = "still_readback";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Gpu,
+    Cpu,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StillSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub pathlenght: i32,
+    pub backend: Backend,
+    pub path: String,   //Without the extension, the keybinding numbers its stills
+}
+
+impl Default for StillSettings {
+    fn default() -> Self {
+        Self {
+            width: 3840,
+            height: 2160,
+            samples: 1024,
+            pathlenght: 16,
+            backend: Backend::Gpu,
+            path: "still".to_string(),
+        }
+    }
+}
+
+/*
+ * Rows of the image from the top, the last ones cropped to it
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub fn tiles(width: u32, height: u32, tile_width: u32, tile_height: u32) -> Vec<Tile> {
+    let columns = (width + tile_width - 1) / tile_width;
+    let rows = (height + tile_height - 1) / tile_height;
+    (0..rows).flat_map(|j| (0..columns).map(move |i| Tile {
+        x: i * tile_width,
+        y: j * tile_height,
+        width: tile_width.min(width - i * tile_width),
+        height: tile_height.min(height - j * tile_height),
+    })).collect()
+}
+
+/*
+ * tileOffset and invNumTiles of a tile traced in the top left tile_width x tile_height pixels of the target,
+ * relative to the image with y upwards like the coords of PathTracer.frag
+ */
+pub fn tile_uniforms(tile: &Tile, width: u32, height: u32, tile_width: u32, tile_height: u32) -> (Vec2, Vec2) {
+    let offset = Vec2::new(tile.x as f32 / width as f32, (height as f32 - (tile.y + tile_height) as f32) / height as f32);
+    (offset, Vec2::new(tile_width as f32 / width as f32, tile_height as f32 / height as f32))
+}
+
+/*
+ * 8 bit RGB, rows from the top
+ */
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> ImageResult<Vec<u8>> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).encode(rgb, width, height, ColorType::Rgb8)?;
+    Ok(png)
+}
+
+fn exr_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/*
+ * Scanline OpenEXR with 32 bit float channels and no compression
+ */
+pub fn encode_exr(image: &Image) -> Vec<u8> {
+    let (width, height) = (image.width as i32, image.height as i32);
+    let mut exr = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    // Channels sorted by name
+    let mut channels = Vec::new();
+    for name in ["B", "G", "R"].iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&2i32.to_le_bytes()); // FLOAT
+        channels.extend_from_slice(&[0, 0, 0, 0]);      // pLinear and reserved
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+
+    let window: Vec<u8> = [0, 0, width - 1, height - 1].iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+    exr_attribute(&mut exr, "channels", "chlist", &channels);
+    exr_attribute(&mut exr, "compression", "compression", &[0]);
+    exr_attribute(&mut exr, "dataWindow", "box2i", &window);
+    exr_attribute(&mut exr, "displayWindow", "box2i", &window);
+    exr_attribute(&mut exr, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut exr, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    exr_attribute(&mut exr, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut exr, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    exr.push(0);
+
+    // A scanline per block
+    let line = 8 + width as usize * 3 * 4;
+    let table_end = exr.len() + height as usize * 8;
+    for y in 0..height as usize {
+        exr.extend_from_slice(&((table_end + y * line) as u64).to_le_bytes());
+    }
+    for (y, row) in image.pixels.chunks(image.width as usize).enumerate() {
+        exr.extend_from_slice(&(y as i32).to_le_bytes());
+        exr.extend_from_slice(&((line - 8) as i32).to_le_bytes());
+        for channel in [2, 1, 0].iter() {
+            for p in row {
+                exr.extend_from_slice(&p[*channel].to_le_bytes());
+            }
+        }
+    }
+    exr
+}
+
+/*
+ * 8 bit sRGB like PathTracerOutput.frag shows it, auto exposure meters the whole image at once
+ */
+pub fn develop(image: &Image, tonemapping: &Tonemapping) -> Vec<u8> {
+    let ev100 = if tonemapping.auto_exposure {
+        let luminances: Vec<f32> = image.pixels.iter().map(|p| color::relative_luminance(*p)).collect();
+        let metering = &tonemapping.metering;
+        tonemapping::metered_ev100(&tonemapping::histogram(&luminances, metering), metering).unwrap_or(0.0) - tonemapping.exposure
+    } else {
+        tonemapping.exposure
+    };
+    let exposure = tonemapping::ev100_to_exposure(ev100);
+    let white_balance = tonemapping.white_balance.map(color::white_balance).unwrap_or(Mat4::IDENTITY);
+
+    let mut rgb = Vec::with_capacity(image.pixels.len() * 3);
+    for p in image.pixels.iter() {
+        let c = color::tonemap(white_balance.transform_vector3(*p * exposure), tonemapping.tonemapper, tonemapping.white_point);
+        rgb.extend([c.x, c.y, c.z].iter().map(|v| (color::linear_to_srgb(*v) * 255.0).round() as u8));
+    }
+    rgb
+}
+
+pub fn save_still(image: &Image, path: &str, tonemapping: &Tonemapping) -> std::io::Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    save_image(image, &format!("{}.png", path), tonemapping)?;
+    save_image(image, &format!("{}.exr", path), tonemapping)
+}
+
+/*
+ * Format by extension, a .png is developed with the tonemapping, the others keep the linear radiance
+ */
+pub fn save_image(image: &Image, path: &str, tonemapping: &Tonemapping) -> std::io::Result<()> {
+    match std::path::Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("png") => {
+            let png = encode_png(image.width, image.height, &develop(image, tonemapping)).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            std::fs::write(path, png)
+        },
+        Some("exr") => std::fs::write(path, encode_exr(image)),
+        Some("pfm") => image.save_pfm(path),
+        Some("ppm") => image.save_ppm(path),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Can not save {}, use .png, .exr, .pfm or .ppm", path))),
+    }
+}
+
+/*
+ * Copy of a target the readback node makes, and what it read back a frame later
+ */
+#[derive(Debug, Default)]
+struct Readback {
+    request: Option<(HandleUntyped, u32, u32)>,
+    texels: Option<Vec<[f32; 4]>>,  //Rows from the top
+}
+
+enum Job {
+    Gpu {
+        tiles: Vec<Tile>,
+        tile: usize,
+        tile_size: (u32, u32),
+        image: Image,
+        max_samples: u32,           //Of the accumulation before the render
+        copying: Option<u32>,       //Samples in the copied target
+    },
+    Cpu(Mutex<mpsc::Receiver<Image>>),
+}
+
+struct Still {
+    settings: StillSettings,
+    path: String,
+    camera: Camera,
+    job: Job,
+}
+
+#[derive(Default)]
+pub struct OfflineRender {
+    pub settings: StillSettings,
+    pub pending: bool,
+    pub exit_when_done: bool,       //Set by --still
+    count: u32,
+    still: Option<Still>,
+    readback: Arc<Mutex<Readback>>,
+}
+
+impl OfflineRender {
+    /*
+     * --still <path> [--still-size <width>x<height>] [--still-samples <n>] [--still-cpu] renders the first view and exits
+     */
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let value = |flag: &str| args.iter().position(|a| a == flag).map(|i| args.get(i + 1).ok_or(format!("{} needs a value", flag)));
+
+        let mut offline = Self::default();
+        let path = match value("--still") {
+            Some(path) => path?,
+            None => return Ok(offline),
+        };
+
+        offline.pending = true;
+        offline.exit_when_done = true;
+        offline.settings.path = path.trim_end_matches(".png").trim_end_matches(".exr").to_string();
+        if let Some(size) = value("--still-size") {
+            let size = size?;
+            let mut dimensions = size.split('x').map(|v| v.parse::<u32>());
+            match (dimensions.next(), dimensions.next(), dimensions.next()) {
+                (Some(Ok(width)), Some(Ok(height)), None) if width > 0 && height > 0 => {
+                    offline.settings.width = width;
+                    offline.settings.height = height;
+                }
+                _ => return Err(format!("Invalid still size {}, expected <width>x<height>", size)),
+            }
+        }
+        if let Some(samples) = value("--still-samples") {
+            let samples = samples?;
+            offline.settings.samples = samples.parse().ok().filter(|s| *s > 0).ok_or(format!("Invalid sample count {}", samples))?;
+        }
+        if args.iter().any(|a| a == "--still-cpu") {
+            offline.settings.backend = Backend::Cpu;
+        }
+        Ok(offline)
+    }
+
+    pub fn busy(&self) -> bool {
+        self.still.is_some()
+    }
+}
+
+/*
+ * Copies the target requested by capture_still into a buffer, reads it back the next frame once the GPU is done with it
+ */
+#[derive(Default)]
+pub struct StillReadbackNode {
+    pending: Option<(BufferId, u32, u32, usize)>,
+}
+
+impl Node for StillReadbackNode {
+    fn update(
+        &mut self,
+        world: &World,
+        render_context: &mut dyn RenderContext,
+        _input: &ResourceSlots,
+        _output: &mut ResourceSlots,
+    ) {
+        let offline = match world.get_resource::<OfflineRender>() {
+            Some(offline) => offline,
+            None => return,
+        };
+        let mut readback = offline.readback.lock().unwrap();
+
+        if let Some((buffer, width, height, bytes_per_row)) = self.pending.take() {
+            let resources = render_context.resources();
+            let texels = RefCell::new(Vec::with_capacity((width * height) as usize));
+            resources.map_buffer(buffer, BufferMapMode::Read);
+            resources.read_mapped_buffer(buffer, 0..(bytes_per_row * height as usize) as u64, &|data: &[u8], _: &dyn RenderResourceContext| {
+                let mut texels = texels.borrow_mut();
+                for row in data.chunks(bytes_per_row) {
+                    texels.extend(row[..width as usize * 16].chunks(16).map(|t| {
+                        let c = |i: usize| f32::from_le_bytes([t[i], t[i + 1], t[i + 2], t[i + 3]]);
+                        [c(0), c(4), c(8), c(12)]
+                    }));
+                }
+            });
+            resources.unmap_buffer(buffer);
+            resources.remove_buffer(buffer);
+            readback.texels = Some(texels.into_inner());
+        }
+
+        if let Some((handle, width, height)) = readback.request.take() {
+            let resources = render_context.resources();
+            let texture = match resources.get_asset_resource_untyped(handle.clone_weak(), TEXTURE_ASSET_INDEX).and_then(|r| r.get_texture()) {
+                Some(texture) => texture,
+                None => {
+                    readback.request = Some((handle, width, height));
+                    return;
+                }
+            };
+            let bytes_per_row = resources.get_aligned_texture_size(width as usize * 16);
+            let buffer = resources.create_buffer(BufferInfo {
+                size: bytes_per_row * height as usize,
+                buffer_usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            });
+            render_context.copy_texture_to_buffer(texture, [0, 0, 0], 0, buffer, 0, bytes_per_row as u32, Extent3d::new(width, height, 1));
+            self.pending = Some((buffer, width, height, bytes_per_row));
+        }
+    }
+}
+
+/*
+ * After both accumulation passes, whichever traced the last samples of the tile
+ */
+pub fn setup_offline_render(mut render_graph: ResMut<RenderGraph>) {
+    render_graph.add_node(STILL_READBACK_NODE, StillReadbackNode::default());
+    render_graph.add_node_edge(accumulation::ACCUM_PASS_A, STILL_READBACK_NODE).unwrap();
+    render_graph.add_node_edge(accumulation::ACCUM_PASS_B, STILL_READBACK_NODE).unwrap();
+}
+
+pub fn request_still(
+    actions: Res<ActionState>,
+    mut offline: ResMut<OfflineRender>,
+) {
+    if actions.just_pressed("render_still") && !offline.busy() {
+        offline.pending = true;
+    }
+}
+
+fn start(offline: &mut OfflineRender, camera: Camera, tile_size: (u32, u32), scene: &GpuScene, environment: &Environment, accumulation: &mut Accumulation) {
+    let settings = offline.settings.clone();
+    let path = if offline.exit_when_done { settings.path.clone() } else { format!("{}_{:03}", settings.path, offline.count) };
+    offline.count += 1;
+    offline.pending = false;
+
+    let job = match settings.backend {
+        Backend::Gpu => {
+            let tiles = tiles(settings.width, settings.height, tile_size.0, tile_size.1);
+            info!("Rendering {} at {}x{} in {} tiles of {} samples", path, settings.width, settings.height, tiles.len(), settings.samples);
+            let max_samples = accumulation.max_samples;
+            accumulation.max_samples = settings.samples;
+            accumulation.reset();
+            Job::Gpu { tiles, tile: 0, tile_size, image: Image::new(settings.width, settings.height), max_samples, copying: None }
+        }
+        Backend::Cpu => {
+            let background = match environment.source {
+                EnvironmentSource::Background(color) => color * environment.multiplier,
+                _ => Vec3::ZERO,
+            };
+            let scene = scene.data.to_scene(camera, background, settings.pathlenght.max(1) as usize);
+            let (width, height, samples) = (settings.width, settings.height, settings.samples);
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = sender.send(reference::render(&scene, width, height, samples));
+            });
+            info!("Rendering {} at {}x{} with {} samples on the CPU", path, settings.width, settings.height, settings.samples);
+            Job::Cpu(Mutex::new(receiver))
+        }
+    };
+    offline.still = Some(Still { settings, path, camera, job });
+}
+
+/*
+ * Runs after the camera and the quality controller and before the environment and the accumulation.
+ * Starts requested renders once the scene is uploaded, then holds the camera and traces the current tile.
+ */
+pub fn trace_still(
+    windows: Res<Windows>,
+    gpu_scene: Res<GpuScene>,
+    environment: Res<Environment>,
+    mut offline: ResMut<OfflineRender>,
+    mut accumulation: ResMut<Accumulation>,
+    mut tracers: Query<&mut PathTracer>,
+) {
+    if offline.pending && !offline.busy() && gpu_scene.revision > 0 {
+        let window = windows.get_primary().map(|w| (w.physical_width().max(1), w.physical_height().max(1)));
+        if let (Some(pt), Some(tile_size)) = (tracers.iter_mut().next(), window) {
+            let camera = Camera {
+                up: pt.camera_up,
+                right: pt.camera_right,
+                forward: pt.camera_forward,
+                position: pt.camera_position,
+                fov: pt.fov,
+                focal_dist: pt.focal_dist,
+                aperture: pt.aperture,
+            };
+            start(&mut offline, camera, tile_size, &gpu_scene, &environment, &mut accumulation);
+        }
+    }
+
+    let still = match &offline.still {
+        Some(still) => still,
+        None => return,
+    };
+    if let Job::Gpu { tiles, tile, tile_size, .. } = &still.job {
+        let (tile_offset, inv_num_tiles) = tile_uniforms(&tiles[*tile], still.settings.width, still.settings.height, tile_size.0, tile_size.1);
+        let remaining = still.settings.samples.saturating_sub(accumulation.samples).max(1) as i32;
+        for mut pt in tracers.iter_mut() {
+            pt.camera_position = still.camera.position;
+            pt.camera_right = still.camera.right;
+            pt.camera_up = still.camera.up;
+            pt.camera_forward = still.camera.forward;
+            pt.fov = still.camera.fov;
+            pt.width = tile_size.0 as f32;
+            pt.height = tile_size.1 as f32;
+            pt.samples = pt.samples.min(remaining);
+            pt.pathlenght = still.settings.pathlenght;
+            pt.render_scale = 1.0;
+            pt.tile_offset = tile_offset;
+            pt.inv_num_tiles = inv_num_tiles;
+        }
+    }
+}
+
+fn whole_image(tracers: &mut Query<&mut PathTracer>) {
+    for mut pt in tracers.iter_mut() {
+        pt.tile_offset = Vec2::ZERO;
+        pt.inv_num_tiles = Vec2::ONE;
+    }
+}
+
+/*
+ * Runs after accumulation::accumulate, reads back finished tiles and saves the still
+ */
+pub fn capture_still(
+    windows: Res<Windows>,
+    tonemapping: Res<Tonemapping>,
+    mut offline: ResMut<OfflineRender>,
+    mut accumulation: ResMut<Accumulation>,
+    mut tracers: Query<&mut PathTracer>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let readback = offline.readback.clone();
+    let still = match &mut offline.still {
+        Some(still) => still,
+        None => return,
+    };
+
+    let image = match &mut still.job {
+        Job::Gpu { tiles, tile, tile_size, image, max_samples, copying } => {
+            let window = windows.get_primary().map(|w| (w.physical_width(), w.physical_height()));
+            if window.map_or(true, |(w, h)| w < tile_size.0 || h < tile_size.1) {
+                warn!("The window shrank below the tiles, {} aborted", still.path);
+                accumulation.max_samples = *max_samples;
+                offline.still = None;
+                whole_image(&mut tracers);
+                return;
+            }
+
+            let mut readback = readback.lock().unwrap();
+            match *copying {
+                None if accumulation.done() => {
+                    readback.request = Some((accumulation.latest().clone_weak(), tile_size.0, tile_size.1));
+                    *copying = Some(accumulation.samples);
+                    None
+                }
+                Some(samples) => match readback.texels.take() {
+                    Some(texels) => {
+                        let t = tiles[*tile];
+                        for y in 0..t.height {
+                            for x in 0..t.width {
+                                let c = texels[(y * tile_size.0 + x) as usize];
+                                image.pixels[((t.y + y) * image.width + t.x + x) as usize] = Vec3::new(c[0], c[1], c[2]) / samples as f32;
+                            }
+                        }
+                        *tile += 1;
+                        *copying = None;
+                        info!("Tile {}/{}", tile, tiles.len());
+
+                        if *tile == tiles.len() {
+                            accumulation.max_samples = *max_samples;
+                            Some(std::mem::replace(image, Image::new(0, 0)))
+                        } else {
+                            None
+                        }
+                    }
+                    None => None,
+                },
+                None => None,
+            }
+        }
+        Job::Cpu(receiver) => {
+            let received = receiver.lock().unwrap().try_recv();
+            match received {
+                Ok(image) => Some(image),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    warn!("The CPU render of {} failed", still.path);
+                    offline.still = None;
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Some(image) = image {
+        match save_still(&image, &still.path, &tonemapping) {
+            Ok(()) => info!("Saved {0}.png and {0}.exr", still.path),
+            Err(e) => warn!("Could not save {}: {}", still.path, e),
+        }
+        offline.still = None;
+        whole_image(&mut tracers);
+        if offline.exit_when_done {
+            exit.send(AppExit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        let (width, height, tile_width, tile_height) = (1000, 700, 320, 256);
+        let tiles = tiles(width, height, tile_width, tile_height);
+        assert_eq!(tiles.len(), 4 * 3);
+
+        let mut covered = vec![0; (width * height) as usize];
+        for t in tiles.iter() {
+            let (offset, inv_num_tiles) = tile_uniforms(t, width, height, tile_width, tile_height);
+            for y in 0..t.height {
+                for x in 0..t.width {
+                    covered[((t.y + y) * width + t.x + x) as usize] += 1;
+
+                    // Same as PathTracer.frag for the fragment at (x, y) of the target
+                    let local = Vec2::new((x as f32 + 0.5) / tile_width as f32, 1.0 - (y as f32 + 0.5) / tile_height as f32);
+                    let coords = offset + local * inv_num_tiles;
+                    let pixel = Vec2::new(coords.x * width as f32 - 0.5, (1.0 - coords.y) * height as f32 - 0.5);
+                    assert!((pixel - Vec2::new((t.x + x) as f32, (t.y + y) as f32)).abs().max_element() < 1.0e-2);
+                }
+            }
+        }
+        assert!(covered.iter().all(|c| *c == 1));
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let (width, height) = (300, 90);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let png = encode_png(width, height, &rgb).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let decoded = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap().to_rgb8();
+        assert_eq!((width, height), decoded.dimensions());
+        assert_eq!(rgb, decoded.into_raw());
+    }
+
+    #[test]
+    fn images_are_saved_by_extension() {
+        let image = Image::new(4, 2);
+        let path = |extension: &str| std::env::temp_dir().join(format!("images_are_saved_by_extension.{}", extension)).to_str().unwrap().to_string();
+
+        save_image(&image, &path("png"), &Tonemapping::default()).unwrap();
+        assert_eq!(&std::fs::read(path("png")).unwrap()[..8], b"\x89PNG\r\n\x1a\n");
+        save_image(&image, &path("exr"), &Tonemapping::default()).unwrap();
+        assert_eq!(&std::fs::read(path("exr")).unwrap()[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        assert!(save_image(&image, &path("jpg"), &Tonemapping::default()).is_err());
+    }
+
+    #[test]
+    fn exr_scanlines_hold_the_linear_values() {
+        let mut image = Image::new(3, 2);
+        image.pixels[4] = Vec3::new(1.5, 250.0, 0.25);
+        let exr = encode_exr(&image);
+        assert_eq!(&exr[..4], &[0x76, 0x2f, 0x31, 0x01]);
+
+        let last = b"screenWindowWidth\0float\0";
+        let header_end = exr.windows(last.len()).position(|w| w == last).unwrap() + last.len() + 4 + 4;
+        assert_eq!(exr[header_end], 0);
+        let offset = |y: usize| u64::from_le_bytes(exr[header_end + 1 + y * 8..header_end + 9 + y * 8].try_into().unwrap()) as usize;
+        let line = offset(1);
+        assert_eq!(line + 8 + 3 * 3 * 4, exr.len());
+        assert_eq!(i32::from_le_bytes(exr[line..line + 4].try_into().unwrap()), 1);
+
+        // B G R planes of the second row, pixel 1
+        let value = |channel: usize| f32::from_le_bytes(exr[line + 8 + (channel * 3 + 1) * 4..line + 12 + (channel * 3 + 1) * 4].try_into().unwrap());
+        assert_eq!([value(2), value(1), value(0)], [1.5, 250.0, 0.25]);
+    }
+
+    #[test]
+    fn still_flags_are_parsed() {
+        let offline = OfflineRender::from_args(&args("game --still shots/moon.png --still-size 800x600 --still-samples 64 --still-cpu")).unwrap();
+        assert!(offline.pending && offline.exit_when_done);
+        assert_eq!(offline.settings, StillSettings { width: 800, height: 600, samples: 64, backend: Backend::Cpu, path: "shots/moon".to_string(), ..Default::default() });
+
+        assert!(!OfflineRender::from_args(&args("game")).unwrap().pending);
+        assert!(OfflineRender::from_args(&args("game --still a --still-size 800")).is_err());
+        assert!(OfflineRender::from_args(&args("game --still")).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * Keeps the path tracer inside a frame time budget by trading samples, path length and render scale.
+ * The cost of a frame is assumed to grow with samples * pathlenght * render_scale², the controller corrects
+ * a share of the log error of the measured frame time on that cost and picks the best settings under it.
+ * Settings are only changed outside of a deadband and after the previous change has shown up in the
+ * measurements, so the discrete steps don't make it oscillate.
+ * With vsync every frame that makes the refresh measures the refresh interval whatever it cost, so those frames
+ * only tell there is headroom: the controller then steps up until a frame misses the refresh, and stays below the
+ * cost that missed.
+ */
+
+pub static REFRESH_INTERVAL: f32 = 1.0 / 60.0;  //s, winit doesn't report the refresh rate of the monitor
+static TARGET_FRAME_TIME: f32 = 0.8 / 60.0;     //s, a margin under the refresh interval wider than the deadband
+static PROBE_STEP: f32 = 1.1;                   //Cost growth per step while the frames make the refresh
+static SETTLE_FRAMES: u32 = 2;               //Frames in flight after a change, not measured
+static MEASURE_FRAMES: u32 = 4;              //Frames averaged per decision
+static RENDER_SCALE_STEP: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    pub samples: u32,
+    pub pathlenght: u32,
+    pub render_scale: f32,
+}
+
+impl Quality {
+    pub fn cost(&self) -> f32 {
+        (self.samples * self.pathlenght) as f32 * self.render_scale * self.render_scale
+    }
+}
+
+#[derive(Debug)]
+pub struct QualityController {
+    pub target_frame_time: f32,
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub min_pathlenght: u32,
+    pub max_pathlenght: u32,
+    pub min_render_scale: f32,
+    pub max_render_scale: f32,
+    pub gain: f32,      //Share of the log error corrected per decision, below 1 can settle further under the budget
+    pub deadband: f32,  //Relative frame time error left alone
+    pub refresh_interval: Option<f32>,  //Set with vsync
+    pub quality: Quality,
+
+    settle: u32,
+    sum: f32,
+    count: u32,
+    missed_cost: f32,  //Lowest cost that missed the refresh
+}
+
+impl Default for QualityController {
+    fn default() -> Self {
+        let mut controller = Self {
+            target_frame_time: TARGET_FRAME_TIME,
+            min_samples: 1,
+            max_samples: 64,
+            min_pathlenght: 2,
+            max_pathlenght: 10,
+            min_render_scale: 0.25,
+            max_render_scale: 1.0,
+            gain: 1.0,
+            deadband: 0.1,
+            refresh_interval: None,
+            quality: Quality { samples: 1, pathlenght: 2, render_scale: 1.0 },
+            settle: 0,
+            sum: 0.0,
+            count: 0,
+            missed_cost: f32::INFINITY,
+        };
+        controller.quality = controller.quality_for(controller.quality.cost());
+        controller
+    }
+}
+
+impl QualityController {
+    /*
+     * Best settings costing at most `budget`: the render scale goes first, then the path length, then the samples
+     */
+    pub fn quality_for(&self, budget: f32) -> Quality {
+        let min_samples = self.min_samples.max(1);
+        let max_samples = self.max_samples.max(min_samples);
+        let min_pathlenght = self.min_pathlenght.max(1);
+        let max_pathlenght = self.max_pathlenght.max(min_pathlenght);
+        let max_scale = self.max_render_scale.max(self.min_render_scale);
+
+        let base = (min_samples * min_pathlenght) as f32;
+        if budget < base * max_scale * max_scale {
+            let scale = (budget / base).max(0.0).sqrt();
+            let scale = ((scale / RENDER_SCALE_STEP).floor() * RENDER_SCALE_STEP).max(self.min_render_scale).min(max_scale);
+            return Quality { samples: min_samples, pathlenght: min_pathlenght, render_scale: scale };
+        }
+
+        let remaining = budget / (max_scale * max_scale);
+        let pathlenght = ((remaining / min_samples as f32) as u32).max(min_pathlenght).min(max_pathlenght);
+        let samples = ((remaining / pathlenght as f32) as u32).max(min_samples).min(max_samples);
+        Quality { samples, pathlenght, render_scale: max_scale }
+    }
+
+    /*
+     * The next settings costing more than the current ones, the current ones at the maximum
+     */
+    fn step_up(&self) -> Quality {
+        let max_scale = self.max_render_scale.max(self.min_render_scale);
+        let max_cost = (self.max_samples.max(1) * self.max_pathlenght.max(1)) as f32 * max_scale * max_scale;
+
+        let mut budget = self.quality.cost().max(f32::MIN_POSITIVE);
+        loop {
+            budget *= PROBE_STEP;
+            let next = self.quality_for(budget);
+            if next != self.quality || budget > max_cost {
+                return next;
+            }
+        }
+    }
+
+    fn change(&mut self, next: Quality) {
+        if next != self.quality {
+            self.quality = next;
+            self.settle = SETTLE_FRAMES;
+        }
+    }
+
+    /*
+     * Forgets the frames measured so far, for frames that didn't trace anything, and the cost that missed the refresh
+     */
+    pub fn hold(&mut self) {
+        self.settle = SETTLE_FRAMES;
+        self.sum = 0.0;
+        self.count = 0;
+        self.missed_cost = f32::INFINITY;
+    }
+
+    pub fn update(&mut self, frame_time: f32) -> Quality {
+        if self.settle > 0 {
+            self.settle -= 1;
+            return self.quality;
+        }
+        if !frame_time.is_finite() || frame_time <= 0.0 {
+            return self.quality;
+        }
+
+        self.sum += frame_time;
+        self.count += 1;
+        if self.count < MEASURE_FRAMES {
+            return self.quality;
+        }
+
+        let measured = self.sum / self.count as f32;
+        self.sum = 0.0;
+        self.count = 0;
+
+        if let Some(interval) = self.refresh_interval {
+            if measured <= interval * (1.0 + self.deadband) {
+                let next = self.step_up();
+                if next.cost() < self.missed_cost {
+                    self.change(next);
+                }
+                return self.quality;
+            }
+            self.missed_cost = self.missed_cost.min(self.quality.cost());
+        }
+
+        if (measured / self.target_frame_time - 1.0).abs() <= self.deadband {
+            return self.quality;
+        }
+
+        let error = (self.target_frame_time / measured).ln();
+        let next = self.quality_for(self.quality.cost() * (self.gain * error).exp());
+        self.change(next);
+        self.quality
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * Fixed overhead plus a cost per unit of work, the GPU finishes a frame late and the timings jitter
+     */
+    fn simulate(controller: &mut QualityController, overhead: f32, per_unit: f32, frames: usize) -> Vec<(Quality, f32)> {
+        let mut history = vec![];
+        let mut in_flight = controller.quality;
+        for i in 0..frames {
+            let jitter = 1.0 + 0.02 * ((i * 7919 % 13) as f32 / 6.0 - 1.0);
+            let frame_time = (overhead + per_unit * in_flight.cost()) * jitter;
+            in_flight = controller.quality;
+            controller.update(frame_time);
+            history.push((controller.quality, frame_time));
+        }
+        history
+    }
+
+    #[test]
+    fn converges_without_oscillating() {
+        for start in [Quality { samples: 1, pathlenght: 2, render_scale: 0.25 }, Quality { samples: 64, pathlenght: 10, render_scale: 1.0 }].iter() {
+            let mut controller = QualityController { quality: *start, ..Default::default() };
+            let history = simulate(&mut controller, 0.002, 0.0004, 400);
+
+            let settled = &history[200..];
+            assert!(settled.iter().all(|(q, _)| *q == settled[0].0), "{:?}", settled[0]);
+
+            let frame_time = settled.iter().map(|(_, t)| *t).sum::<f32>() / settled.len() as f32;
+            assert!(frame_time <= controller.target_frame_time * (1.0 + controller.deadband), "{}", frame_time);
+            assert!(frame_time > controller.target_frame_time * 0.75, "{}", frame_time);
+
+            // Every change goes the same way, nothing overshoots to come back
+            let costs: Vec<f32> = history.iter().map(|(q, _)| q.cost()).collect();
+            let rising = costs.windows(2).any(|w| w[1] > w[0]);
+            let falling = costs.windows(2).any(|w| w[1] < w[0]);
+            assert!(!(rising && falling), "{:?}", costs);
+        }
+    }
+
+    #[test]
+    fn settings_stay_inside_their_clamps() {
+        let mut controller = QualityController { min_pathlenght: 0, ..Default::default() };
+        simulate(&mut controller, 0.0, 1.0, 100);
+        assert_eq!(controller.quality, Quality { samples: 1, pathlenght: 1, render_scale: 0.25 });
+
+        let mut controller = QualityController::default();
+        simulate(&mut controller, 0.001, 1.0e-9, 200);
+        assert_eq!(controller.quality, Quality { samples: 64, pathlenght: 10, render_scale: 1.0 });
+    }
+
+    #[test]
+    fn held_frames_are_not_measured() {
+        let mut controller = QualityController::default();
+        let quality = controller.quality;
+
+        // A measurement cut short by the hold is dropped as well
+        for _ in 0..MEASURE_FRAMES - 1 {
+            controller.update(1.0);
+        }
+        controller.hold();
+        for _ in 0..SETTLE_FRAMES {
+            assert_eq!(controller.update(1.0), quality);
+        }
+        for _ in 0..MEASURE_FRAMES {
+            let target_frame_time = controller.target_frame_time;
+            assert_eq!(controller.update(target_frame_time), quality);
+        }
+    }
+
+    #[test]
+    fn rises_under_vsync_and_stays_below_a_missed_refresh() {
+        let mut controller = QualityController { refresh_interval: Some(REFRESH_INTERVAL), ..Default::default() };
+        let start = controller.quality;
+
+        // The frame time is a whole number of refresh intervals
+        let mut history = vec![];
+        let mut in_flight = controller.quality;
+        for _ in 0..600 {
+            let work = 0.002 + 0.0004 * in_flight.cost();
+            let frame_time = (work / REFRESH_INTERVAL).ceil() * REFRESH_INTERVAL;
+            in_flight = controller.quality;
+            controller.update(frame_time);
+            history.push((controller.quality, work));
+        }
+
+        let settled = &history[400..];
+        assert!(settled.iter().all(|(q, _)| *q == settled[0].0), "{:?}", settled[0]);
+        assert!(settled[0].0.cost() > start.cost());
+        assert!(settled.iter().all(|(_, work)| *work <= REFRESH_INTERVAL), "{:?}", settled[0]);
+        assert!(settled[0].1 > 0.5 * REFRESH_INTERVAL, "{:?}", settled[0]);
+    }
+}
@@ -0,0 +1,397 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use bevy::math::Vec3;
+use rayon::prelude::*;
+
+use super::disney::{disney_eval, disney_sample};
+use super::lambert::{lambert_eval, lambert_sample};
+use super::medium::{delta_track, transmittance, Collision, Phase};
+use super::sampling::*;
+use super::scene::{Bsdf, Camera, LightSampleRec, Ray, Scene, State};
+
+/*
+ * CPU version of lib/pathtrace/common/pathtrace.glsl, a ground truth for the shaders and for rendering without a GPU
+ */
+
+fn get_materials(scene: &Scene, state: &mut State) {
+    let mut mat = scene.materials[state.mat_id];
+    mat.roughness = mat.roughness.max(0.001);
+
+    state.mat = mat;
+    state.eta = if state.normal.dot(state.ffnormal) > 0.0 { 1.0 / mat.ior } else { mat.ior };
+}
+
+fn bsdf_sample(scene: &Scene, state: &State, v: Vec3, n: Vec3, rng: &mut Rng) -> (Vec3, Vec3, f32) {
+    match scene.bsdf {
+        Bsdf::Disney => disney_sample(state, v, n, rng),
+        Bsdf::Lambert => lambert_sample(state, v, n, rng),
+    }
+}
+
+fn bsdf_eval(scene: &Scene, state: &State, v: Vec3, n: Vec3, l: Vec3) -> (Vec3, f32) {
+    match scene.bsdf {
+        Bsdf::Disney => disney_eval(state, v, n, l),
+        Bsdf::Lambert => lambert_eval(state, v, n, l),
+    }
+}
+
+/*
+ * One light picked at random, weighted against the sampling of eval except for distant lights.
+ * Eval gives the scattered share towards the light and its pdf.
+ */
+fn sample_lights(scene: &Scene, position: Vec3, rng: &mut Rng, eval: impl Fn(Vec3) -> (Vec3, f32)) -> Vec3 {
+    if scene.lights.is_empty() {
+        return Vec3::ZERO;
+    }
+
+    let index = ((rng.rand() * scene.lights.len() as f32) as usize).min(scene.lights.len() - 1);
+    let light = &scene.lights[index];
+    let light_sample = sample_one_light(light, position, scene.lights.len(), rng);
+
+    // Quad lights only emit on one side
+    if light_sample.direction.dot(light_sample.normal) >= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let shadow_ray = Ray { origin: position, direction: light_sample.direction };
+    if scene.any_hit(&shadow_ray, light_sample.dist - EPS) {
+        return Vec3::ZERO;
+    }
+
+    let (f, pdf) = eval(light_sample.direction);
+    if pdf <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let tr = transmittance(&scene.media, &shadow_ray, light_sample.dist - EPS, rng);
+    let weight = if light.area > 0.0 { power_heuristic(light_sample.pdf, pdf) } else { 1.0 };
+    weight * f * tr * light_sample.emission / light_sample.pdf
+}
+
+pub fn direct_light(scene: &Scene, r: &Ray, state: &State, rng: &mut Rng) -> Vec3 {
+    sample_lights(scene, state.fhp + state.normal * EPS, rng, |l| {
+        let (f, pdf) = bsdf_eval(scene, state, -r.direction, state.ffnormal, l);
+        (f * state.ffnormal.dot(l).abs(), pdf)
+    })
+}
+
+/*
+ * Same for a scattering event inside a medium, the phase function takes the place of the BSDF
+ */
+pub fn medium_light(scene: &Scene, r: &Ray, position: Vec3, phase: Phase, rng: &mut Rng) -> Vec3 {
+    sample_lights(scene, position, rng, |l| {
+        let p = phase.eval(r.direction.dot(l));
+        (Vec3::splat(p), p)
+    })
+}
+
+fn exp3(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.exp(), v.y.exp(), v.z.exp())
+}
+
+fn ln3(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.ln(), v.y.ln(), v.z.ln())
+}
+
+pub fn path_trace(scene: &Scene, mut r: Ray, rng: &mut Rng) -> Vec3 {
+    let mut radiance = Vec3::ZERO;
+    let mut throughput = Vec3::ONE;
+    let mut state = State::default();
+    let mut light_sample = LightSampleRec::default();
+    let mut bsdf_pdf = 0.0;
+    let mut absorption = Vec3::ZERO;
+
+    for depth in 0..scene.max_depth {
+        state.depth = depth;
+
+        let hit = scene.closest_hit(&r, &mut state, &mut light_sample);
+
+        // Media in front of the surface, or of the background when nothing was hit
+        let max_dist = if hit { state.hit_dist } else { INFINITY };
+        match delta_track(&scene.media, &r, max_dist, &mut throughput, &mut radiance, rng) {
+            Collision::Passed => {}
+            Collision::Absorbed => return radiance,
+            Collision::Scattered { position, phase } => {
+                radiance += medium_light(scene, &r, position, phase, rng) * throughput;
+
+                let l = phase.sample(r.direction, rng);
+                // The phase function is sampled exactly, its value and pdf cancel
+                bsdf_pdf = phase.eval(r.direction.dot(l));
+                r = Ray { origin: position, direction: l };
+                continue;
+            }
+        }
+
+        if !hit {
+            return radiance + scene.background * throughput;
+        }
+
+        if state.is_emitter {
+            return radiance + emitter_sample(depth, &light_sample, bsdf_pdf) * throughput;
+        }
+
+        get_materials(scene, &mut state);
+
+        // Reset absorption when the ray is going out of the surface
+        if state.normal.dot(state.ffnormal) > 0.0 {
+            absorption = Vec3::ZERO;
+        }
+
+        radiance += state.mat.emission * throughput;
+
+        throughput *= exp3(-absorption * state.hit_dist);
+
+        radiance += direct_light(scene, &r, &state, rng) * throughput;
+
+        let (f, l, pdf) = bsdf_sample(scene, &state, -r.direction, state.ffnormal, rng);
+
+        // Absorb only while the ray is inside the object
+        if state.ffnormal.dot(l) < 0.0 {
+            absorption = -ln3(state.mat.extinction) / state.mat.at_distance;
+        }
+
+        if pdf <= 0.0 {
+            break;
+        }
+        throughput *= f * state.ffnormal.dot(l).abs() / pdf;
+        bsdf_pdf = pdf;
+
+        if let Some(rr_depth) = scene.rr_depth {
+            if depth >= rr_depth {
+                let q = (throughput.max_element() + 0.001).min(0.95);
+                if rng.rand() > q {
+                    break;
+                }
+                throughput /= q;
+            }
+        }
+
+        r = Ray { origin: state.fhp + l * EPS, direction: l };
+    }
+
+    radiance
+}
+
+/*
+ * Jittered ray through a pixel like tiled.glsl, y grows upwards
+ */
+pub fn camera_ray(camera: &Camera, x: u32, y: u32, width: u32, height: u32, rng: &mut Rng) -> Ray {
+    let r1 = 2.0 * rng.rand();
+    let r2 = 2.0 * rng.rand();
+
+    // Tent filter
+    let jitter_x = if r1 < 1.0 { r1.sqrt() - 1.0 } else { 1.0 - (2.0 - r1).sqrt() };
+    let jitter_y = if r2 < 1.0 { r2.sqrt() - 1.0 } else { 1.0 - (2.0 - r2).sqrt() };
+
+    let scale = (camera.fov * 0.5).tan();
+    let dx = ((x as f32 + 0.5) / width as f32 * 2.0 - 1.0 + jitter_x / (width as f32 * 0.5)) * scale;
+    let dy = ((y as f32 + 0.5) / height as f32 * 2.0 - 1.0 + jitter_y / (height as f32 * 0.5)) * height as f32 / width as f32 * scale;
+    let direction = (dx * camera.right + dy * camera.up + camera.forward).normalize();
+
+    let focal_point = camera.focal_dist * direction;
+    let cam_r1 = rng.rand() * TWO_PI;
+    let cam_r2 = rng.rand() * camera.aperture;
+    let aperture_pos = (cam_r1.cos() * camera.right + cam_r1.sin() * camera.up) * cam_r2.sqrt();
+
+    Ray { origin: camera.position + aperture_pos, direction: (focal_point - aperture_pos).normalize() }
+}
+
+/*
+ * Linear radiance, rows from the top
+ */
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, pixels: vec![Vec3::ZERO; (width * height) as usize] }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Vec3 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn mean(&self) -> Vec3 {
+        self.pixels.iter().fold(Vec3::ZERO, |s, p| s + *p) / self.pixels.len().max(1) as f32
+    }
+
+    /*
+     * Portable float map, keeps the full range for comparisons
+     */
+    pub fn save_pfm(&self, path: &str) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        // PFM stores the bottom row first
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for p in row {
+                for c in [p.x, p.y, p.z].iter() {
+                    out.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()
+    }
+
+    /*
+     * 8 bit preview tonemapped like tonemap.glsl
+     */
+    pub fn save_ppm(&self, path: &str) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for p in self.pixels.iter() {
+            let luminance = 0.3 * p.x + 0.6 * p.y + 0.1 * p.z;
+            let c = *p / (1.0 + luminance / 1.5);
+            for v in [c.x, c.y, c.z].iter() {
+                out.write_all(&[(v.max(0.0).powf(1.0 / 2.2).min(1.0) * 255.0).round() as u8])?;
+            }
+        }
+        out.flush()
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        if path.ends_with(".pfm") { self.save_pfm(path) } else { self.save_ppm(path) }
+    }
+}
+
+/*
+ * Every sample of a pixel is its own frame of the RNG, the pixels are traced in parallel
+ */
+pub fn render(scene: &Scene, width: u32, height: u32, samples: u32) -> Image {
+    let pixels = (0..width * height).into_par_iter().map(|i| {
+        let (x, row) = (i % width, i / width);
+        let y = height - 1 - row;
+
+        let (mut sum, mut accepted) = (Vec3::ZERO, 0);
+        for frame in 0..samples {
+            let mut rng = Rng::new(x, y, frame);
+            let ray = camera_ray(&scene.camera, x, y, width, height, &mut rng);
+            let c = path_trace(scene, ray, &mut rng);
+            // A NaN would poison the whole pixel, rejected samples don't count towards the average either
+            if c.x.is_finite() && c.y.is_finite() && c.z.is_finite() {
+                sum += c;
+                accepted += 1;
+            }
+        }
+        sum / accepted.max(1) as f32
+    }).collect();
+
+    Image { width, height, pixels }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::medium::{Medium, MediumShape};
+    use super::super::scene::{Light, Material, Object, Shape};
+
+    fn scene(objects: Vec<Object>, lights: Vec<Light>, camera: Camera, background: Vec3) -> Scene {
+        Scene {
+            camera,
+            objects,
+            materials: vec![Material::diffuse(Vec3::splat(0.5)), Material::diffuse(Vec3::ONE)],
+            lights,
+            media: vec![],
+            background,
+            max_depth: 4,
+            rr_depth: None,
+            bsdf: Bsdf::Lambert,
+        }
+    }
+
+    #[test]
+    fn lambert_furnace() {
+        // A convex diffuse object under a uniform sky reflects exactly its albedo
+        let s = scene(
+            vec![Object { shape: Shape::Sphere { center: Vec3::ZERO, radius: 1.0 }, material: 0 }],
+            vec![],
+            Camera::look_at(Vec3::new(0.0, 0.0, -4.0), Vec3::ZERO, 0.2),
+            Vec3::ONE,
+        );
+
+        let image = render(&s, 8, 8, 16);
+        for p in image.pixels.iter() {
+            assert!((*p - Vec3::splat(0.5)).abs().max_element() < 1.0e-4, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn sphere_light_over_a_floor() {
+        // Directly under a sphere light sin²α = (r/d)², a white lambertian floor reflects L (r/d)²
+        let (r, d, emission) = (0.25, 2.0, 64.0);
+        let floor = Object { shape: Shape::Rect { position: Vec3::new(-50.0, 0.0, -50.0), u: Vec3::new(0.0, 0.0, 100.0), v: Vec3::new(100.0, 0.0, 0.0) }, material: 1 };
+
+        let mut s = scene(
+            vec![floor],
+            vec![Light::sphere(Vec3::new(0.0, d, 0.0), r, Vec3::splat(emission))],
+            Camera::look_at(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 0.0, 1.0e-4), 0.01),
+            Vec3::ZERO,
+        );
+        s.max_depth = 2;
+
+        let expected = emission * (r / d) * (r / d);
+        let mean = render(&s, 8, 8, 128).mean();
+        assert!((mean.x - expected).abs() / expected < 0.05, "{:?}, expected {}", mean, expected);
+
+        // Light sampling alone, without the BSDF hits, falls short by the MIS weight of the BSDF strategy
+        s.max_depth = 1;
+        assert!(render(&s, 8, 8, 128).mean().x < mean.x);
+    }
+
+    #[test]
+    fn transmittance_through_a_homogeneous_slab() {
+        // Beer–Lambert, exp(-σt d) in each channel
+        let d = 1.5;
+        let (sigma_a, sigma_s) = (Vec3::new(0.2, 0.5, 1.0), Vec3::new(0.4, 0.3, 0.2));
+        let slab = MediumShape::Box { min: Vec3::new(-50.0, -50.0, 0.0), max: Vec3::new(50.0, 50.0, d) };
+        let ray = Ray { origin: Vec3::new(0.0, 0.0, -1.0), direction: Vec3::Z };
+        let n = 20_000;
+        let mut rng = Rng::new(1, 2, 3);
+
+        // Ratio tracking of the shadow rays
+        let media = [Medium::homogeneous(slab, sigma_a, sigma_s, 0.5)];
+        let tr = (0..n).fold(Vec3::ZERO, |s, _| s + transmittance(&media, &ray, INFINITY, &mut rng)) / n as f32;
+        let expected = exp3(-(sigma_a + sigma_s) * d);
+        assert!((tr - expected).abs().max_element() < 0.01, "{:?}, expected {:?}", tr, expected);
+
+        // Delta tracking through a grey slab, the majorant is the extinction so rays pass with probability exp(-σt d)
+        let grey = [Medium::homogeneous(slab, Vec3::splat(0.3), Vec3::splat(0.5), 0.0)];
+        let passed = (0..n).filter(|_| {
+            let (mut throughput, mut radiance) = (Vec3::ONE, Vec3::ZERO);
+            delta_track(&grey, &ray, INFINITY, &mut throughput, &mut radiance, &mut rng) == Collision::Passed
+        }).count();
+        let expected = (-0.8 * d).exp();
+        assert!((passed as f32 / n as f32 - expected).abs() < 0.01, "{}, expected {}", passed as f32 / n as f32, expected);
+
+        // The integrator looking through a coloured absorber at a white sky
+        let mut s = scene(vec![], vec![], Camera::look_at(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.1), Vec3::ONE);
+        s.media = vec![Medium::homogeneous(slab, sigma_a, Vec3::ZERO, 0.0)];
+        let mean = render(&s, 8, 8, 256).mean();
+        let expected = exp3(-sigma_a * d);
+        assert!((mean - expected).abs().max_element() < 0.01, "{:?}, expected {:?}", mean, expected);
+    }
+
+    #[test]
+    fn scattering_furnace() {
+        // Without absorption every path leaves the cloud again and sees the sky
+        let mut s = scene(vec![], vec![], Camera::look_at(Vec3::new(0.0, 0.0, -4.0), Vec3::ZERO, 0.4), Vec3::ONE);
+        s.media = vec![Medium::homogeneous(MediumShape::Sphere { center: Vec3::ZERO, radius: 1.0 }, Vec3::ZERO, Vec3::splat(2.0), 0.6)];
+        s.max_depth = 64;
+
+        let image = render(&s, 8, 8, 16);
+        for p in image.pixels.iter() {
+            assert!((*p - Vec3::ONE).abs().max_element() < 1.0e-4, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn cornell_box_renders_finite_and_lit() {
+        let image = render(&Scene::cornell(0.0), 16, 12, 4);
+        assert!(image.pixels.iter().all(|p| p.x.is_finite() && p.min_element() >= 0.0));
+        assert!(image.mean().max_element() > 0.0);
+    }
+}
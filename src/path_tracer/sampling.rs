@@ -0,0 +1,246 @@
+use bevy::math::Vec3;
+
+use super::scene::{Light, LightSampleRec, LightType};
+
+/*
+ * Mirrors lib/pathtrace/common/sampling.glsl and the RNG of globals.glsl
+ */
+
+pub static PI: f32 = std::f32::consts::PI;
+pub static TWO_PI: f32 = 2.0 * std::f32::consts::PI;
+pub static INFINITY: f32 = 1000000.0;
+pub static EPS: f32 = 0.001;
+
+/*
+ * pcg4d by Moroz Mykhailo, seeded per pixel and frame like InitRNG so both sides draw the same numbers
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Rng {
+    seed: [u32; 4],
+}
+
+impl Rng {
+    pub fn new(x: u32, y: u32, frame: u32) -> Self {
+        Self { seed: [x, y, frame, x.wrapping_add(y)] }
+    }
+
+    fn pcg4d(&mut self) {
+        let v = &mut self.seed;
+        for c in v.iter_mut() {
+            *c = c.wrapping_mul(1664525).wrapping_add(1013904223);
+        }
+        v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[3]));
+        v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+        v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+        v[3] = v[3].wrapping_add(v[1].wrapping_mul(v[2]));
+        for c in v.iter_mut() {
+            *c ^= *c >> 16;
+        }
+        v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[3]));
+        v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+        v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+        v[3] = v[3].wrapping_add(v[1].wrapping_mul(v[2]));
+    }
+
+    pub fn rand(&mut self) -> f32 {
+        self.pcg4d();
+        self.seed[0] as f32 / u32::MAX as f32
+    }
+}
+
+pub fn mix3(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    a + (b - a) * t
+}
+
+pub fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    i - 2.0 * n.dot(i) * n
+}
+
+/*
+ * Zero on total internal reflection like GLSL
+ */
+pub fn refract(i: Vec3, n: Vec3, eta: f32) -> Vec3 {
+    let d = n.dot(i);
+    let k = 1.0 - eta * eta * (1.0 - d * d);
+    if k < 0.0 {
+        Vec3::ZERO
+    } else {
+        eta * i - (eta * d + k.sqrt()) * n
+    }
+}
+
+pub fn importance_sample_gtr1(rgh: f32, r1: f32, r2: f32) -> Vec3 {
+    let a = rgh.max(0.001);
+    let a2 = a * a;
+
+    let phi = r1 * TWO_PI;
+
+    let cos_theta = ((1.0 - a2.powf(1.0 - r1)) / (1.0 - a2)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt().clamp(0.0, 1.0);
+
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn importance_sample_gtr2(rgh: f32, r1: f32, r2: f32) -> Vec3 {
+    let a = rgh.max(0.001);
+
+    let phi = r1 * TWO_PI;
+
+    let cos_theta = ((1.0 - r2) / (1.0 + (a * a - 1.0) * r2)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt().clamp(0.0, 1.0);
+
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn schlick_fresnel(u: f32) -> f32 {
+    let m = (1.0 - u).clamp(0.0, 1.0);
+    let m2 = m * m;
+    m2 * m2 * m
+}
+
+pub fn dielectric_fresnel(cos_theta_i: f32, eta: f32) -> f32 {
+    let sin_theta_t_sq = eta * eta * (1.0 - cos_theta_i * cos_theta_i);
+
+    // Total internal reflection
+    if sin_theta_t_sq > 1.0 {
+        return 1.0;
+    }
+
+    let cos_theta_t = (1.0 - sin_theta_t_sq).max(0.0).sqrt();
+
+    let rs = (eta * cos_theta_t - cos_theta_i) / (eta * cos_theta_t + cos_theta_i);
+    let rp = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+
+    0.5 * (rs * rs + rp * rp)
+}
+
+pub fn gtr1(n_dot_h: f32, a: f32) -> f32 {
+    if a >= 1.0 {
+        return 1.0 / PI;
+    }
+    let a2 = a * a;
+    let t = 1.0 + (a2 - 1.0) * n_dot_h * n_dot_h;
+    (a2 - 1.0) / (PI * a2.ln() * t)
+}
+
+pub fn gtr2(n_dot_h: f32, a: f32) -> f32 {
+    let a2 = a * a;
+    let t = 1.0 + (a2 - 1.0) * n_dot_h * n_dot_h;
+    a2 / (PI * t * t)
+}
+
+pub fn smith_g_ggx(n_dot_v: f32, alpha_g: f32) -> f32 {
+    let a = alpha_g * alpha_g;
+    let b = n_dot_v * n_dot_v;
+    1.0 / (n_dot_v + (a + b - a * b).sqrt())
+}
+
+pub fn cosine_sample_hemisphere(r1: f32, r2: f32) -> Vec3 {
+    let r = r1.sqrt();
+    let phi = TWO_PI * r2;
+    let (x, y) = (r * phi.cos(), r * phi.sin());
+    Vec3::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+pub fn uniform_sample_hemisphere(r1: f32, r2: f32) -> Vec3 {
+    let r = (1.0 - r1 * r1).max(0.0).sqrt();
+    let phi = TWO_PI * r2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), r1)
+}
+
+pub fn uniform_sample_sphere(r1: f32, r2: f32) -> Vec3 {
+    let z = 1.0 - 2.0 * r1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = TWO_PI * r2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn power_heuristic(a: f32, b: f32) -> f32 {
+    let t = a * a;
+    t / (b * b + t)
+}
+
+/*
+ * Tangent and bitangent completing n
+ */
+pub fn onb(n: Vec3) -> (Vec3, Vec3) {
+    let up = if n.z.abs() < 0.999 { Vec3::Z } else { Vec3::X };
+    let t = up.cross(n).normalize();
+    (t, n.cross(t))
+}
+
+/*
+ * Samples the hemisphere of the light facing the surface, assumes the light is seen from the outside
+ */
+pub fn sample_sphere_light(light: &Light, surface_pos: Vec3, num_lights: usize, rng: &mut Rng) -> LightSampleRec {
+    let r1 = rng.rand();
+    let r2 = rng.rand();
+
+    let to_surface = (surface_pos - light.position).normalize();
+    let (t, b) = onb(to_surface);
+    let d = uniform_sample_hemisphere(r1, r2);
+    let sampled_dir = t * d.x + b * d.y + to_surface * d.z;
+
+    let light_surface_pos = light.position + sampled_dir * light.radius;
+
+    let direction = light_surface_pos - surface_pos;
+    let dist = direction.length();
+    let direction = direction / dist;
+    let normal = (light_surface_pos - light.position).normalize();
+
+    LightSampleRec {
+        normal,
+        emission: light.emission * num_lights as f32,
+        direction,
+        dist,
+        pdf: dist * dist / (light.area * 0.5 * normal.dot(direction).abs()),
+    }
+}
+
+pub fn sample_rect_light(light: &Light, surface_pos: Vec3, num_lights: usize, rng: &mut Rng) -> LightSampleRec {
+    let r1 = rng.rand();
+    let r2 = rng.rand();
+
+    let light_surface_pos = light.position + light.u * r1 + light.v * r2;
+    let direction = light_surface_pos - surface_pos;
+    let dist = direction.length();
+    let direction = direction / dist;
+    let normal = light.u.cross(light.v).normalize();
+
+    LightSampleRec {
+        normal,
+        emission: light.emission * num_lights as f32,
+        direction,
+        dist,
+        pdf: dist * dist / (light.area * normal.dot(direction).abs()),
+    }
+}
+
+pub fn sample_distant_light(light: &Light, surface_pos: Vec3, num_lights: usize) -> LightSampleRec {
+    LightSampleRec {
+        direction: light.position.normalize(),
+        normal: (surface_pos - light.position).normalize(),
+        emission: light.emission * num_lights as f32,
+        dist: INFINITY,
+        pdf: 1.0,
+    }
+}
+
+pub fn sample_one_light(light: &Light, surface_pos: Vec3, num_lights: usize, rng: &mut Rng) -> LightSampleRec {
+    match light.kind {
+        LightType::Rect => sample_rect_light(light, surface_pos, num_lights, rng),
+        LightType::Sphere => sample_sphere_light(light, surface_pos, num_lights, rng),
+        LightType::Distant => sample_distant_light(light, surface_pos, num_lights),
+    }
+}
+
+/*
+ * A light hit by a BSDF sample, weighted against the chance of having sampled it directly
+ */
+pub fn emitter_sample(depth: usize, light: &LightSampleRec, bsdf_pdf: f32) -> Vec3 {
+    if depth == 0 {
+        light.emission
+    } else {
+        power_heuristic(bsdf_pdf, light.pdf) * light.emission
+    }
+}
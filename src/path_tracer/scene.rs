@@ -0,0 +1,408 @@
+use bevy::math::Vec3;
+
+use super::medium::Medium;
+use super::sampling::{onb, EPS, INFINITY};
+
+/*
+ * Mirrors the structs of lib/pathtrace/common/globals.glsl and the intersection functions of intersection.glsl
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+/*
+ * Disney principled parameters, laid out in seven vec4 by GetMaterials
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub albedo: Vec3,
+    pub specular: f32,
+    pub emission: Vec3,
+    pub anisotropic: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub subsurface: f32,
+    pub specular_tint: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub spec_trans: f32,
+    pub ior: f32,
+    pub at_distance: f32,
+    pub extinction: Vec3,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            albedo: Vec3::ONE,
+            specular: 0.5,
+            emission: Vec3::ZERO,
+            anisotropic: 0.0,
+            metallic: 0.0,
+            roughness: 0.5,
+            subsurface: 0.0,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.0,
+            clearcoat: 0.0,
+            clearcoat_gloss: 0.0,
+            spec_trans: 0.0,
+            ior: 1.45,
+            at_distance: 1.0,
+            extinction: Vec3::ONE,
+        }
+    }
+}
+
+impl Material {
+    pub fn diffuse(albedo: Vec3) -> Self {
+        Self { albedo, specular: 0.0, roughness: 1.0, ..Default::default() }
+    }
+
+    pub fn glass(ior: f32) -> Self {
+        Self { spec_trans: 1.0, roughness: 0.001, ior, ..Default::default() }
+    }
+}
+
+/*
+ * Same order as QUAD_LIGHT, SPHERE_LIGHT and DISTANT_LIGHT
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightType {
+    Rect = 0,
+    Sphere = 1,
+    Distant = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub position: Vec3,  // Direction towards the light for distant lights
+    pub emission: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub radius: f32,
+    pub area: f32,
+    pub kind: LightType,
+}
+
+impl Light {
+    pub fn sphere(position: Vec3, radius: f32, emission: Vec3) -> Self {
+        Self { position, emission, u: Vec3::ZERO, v: Vec3::ZERO, radius, area: 4.0 * std::f32::consts::PI * radius * radius, kind: LightType::Sphere }
+    }
+
+    /*
+     * Emits to the side of u × v
+     */
+    pub fn rect(position: Vec3, u: Vec3, v: Vec3, emission: Vec3) -> Self {
+        Self { position, emission, u, v, radius: 0.0, area: u.cross(v).length(), kind: LightType::Rect }
+    }
+
+    pub fn distant(direction: Vec3, emission: Vec3) -> Self {
+        Self { position: direction, emission, u: Vec3::ZERO, v: Vec3::ZERO, radius: 0.0, area: 0.0, kind: LightType::Distant }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LightSampleRec {
+    pub normal: Vec3,
+    pub emission: Vec3,
+    pub direction: Vec3,
+    pub dist: f32,
+    pub pdf: f32,
+}
+
+/*
+ * Fields of the GLSL State struct the integrator uses
+ */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct State {
+    pub depth: usize,
+    pub eta: f32,
+    pub hit_dist: f32,
+
+    pub fhp: Vec3,
+    pub normal: Vec3,
+    pub ffnormal: Vec3,
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+
+    pub is_emitter: bool,
+
+    pub mat_id: usize,
+    pub mat: Material,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sphere { center: Vec3, radius: f32 },
+    Rect { position: Vec3, u: Vec3, v: Vec3 },
+    Triangle { a: Vec3, b: Vec3, c: Vec3 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Object {
+    pub shape: Shape,
+    pub material: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub up: Vec3,
+    pub right: Vec3,
+    pub forward: Vec3,
+    pub position: Vec3,
+    pub fov: f32,  // Horizontal, rad
+    pub focal_dist: f32,
+    pub aperture: f32,
+}
+
+impl Camera {
+    pub fn look_at(position: Vec3, target: Vec3, fov: f32) -> Self {
+        let forward = (target - position).normalize();
+        let right = forward.cross(Vec3::Y).normalize();
+        let up = right.cross(forward).normalize();
+        Self { up, right, forward, position, fov, focal_dist: 1.0, aperture: 0.0 }
+    }
+}
+
+/*
+ * Which BSDF the integrator samples, the shaders pick one at compile time
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bsdf {
+    Disney,
+    Lambert,
+}
+
+#[derive(Debug, Clone)]
+pub struct Scene {
+    pub camera: Camera,
+    pub objects: Vec<Object>,
+    pub materials: Vec<Material>,
+    pub lights: Vec<Light>,
+    pub media: Vec<Medium>,
+    pub background: Vec3,  // bgColor
+    pub max_depth: usize,
+    pub rr_depth: Option<usize>,
+    pub bsdf: Bsdf,
+}
+
+pub fn sphere_intersect(radius: f32, position: Vec3, r: &Ray) -> f32 {
+    let op = position - r.origin;
+    let eps = 0.001;
+    let b = op.dot(r.direction);
+    let det = b * b - op.dot(op) + radius * radius;
+    if det < 0.0 {
+        return INFINITY;
+    }
+
+    let det = det.sqrt();
+    let t1 = b - det;
+    if t1 > eps {
+        return t1;
+    }
+
+    let t2 = b + det;
+    if t2 > eps {
+        return t2;
+    }
+
+    INFINITY
+}
+
+/*
+ * u and v are scaled by their inverse squared length and the plane is the normal with its distance like in ClosestHit
+ */
+pub fn rect_intersect(position: Vec3, u: Vec3, v: Vec3, plane: (Vec3, f32), r: &Ray) -> f32 {
+    let n = plane.0;
+    let dt = r.direction.dot(n);
+    let t = (plane.1 - n.dot(r.origin)) / dt;
+    if t > EPS {
+        let p = r.origin + r.direction * t;
+        let vi = p - position;
+        let a1 = u.dot(vi);
+        if a1 >= 0.0 && a1 <= 1.0 {
+            let a2 = v.dot(vi);
+            if a2 >= 0.0 && a2 <= 1.0 {
+                return t;
+            }
+        }
+    }
+
+    INFINITY
+}
+
+fn rect(position: Vec3, u: Vec3, v: Vec3, r: &Ray) -> (f32, Vec3) {
+    let normal = u.cross(v).normalize();
+    let plane = (normal, normal.dot(position));
+    (rect_intersect(position, u / u.dot(u), v / v.dot(v), plane, r), normal)
+}
+
+/*
+ * Möller–Trumbore, returns the distance and the barycentrics of b and c
+ */
+pub fn triangle_intersect(a: Vec3, b: Vec3, c: Vec3, r: &Ray) -> (f32, f32, f32) {
+    let (e1, e2) = (b - a, c - a);
+    let p = r.direction.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < 1.0e-9 {
+        return (INFINITY, 0.0, 0.0);
+    }
+
+    let inv = 1.0 / det;
+    let s = r.origin - a;
+    let u = s.dot(p) * inv;
+    if u < 0.0 || u > 1.0 {
+        return (INFINITY, 0.0, 0.0);
+    }
+
+    let q = s.cross(e1);
+    let v = r.direction.dot(q) * inv;
+    if v < 0.0 || u + v > 1.0 {
+        return (INFINITY, 0.0, 0.0);
+    }
+
+    let t = e2.dot(q) * inv;
+    if t > EPS { (t, u, v) } else { (INFINITY, 0.0, 0.0) }
+}
+
+impl Shape {
+    /*
+     * Distance and geometric normal
+     */
+    pub fn intersect(&self, r: &Ray) -> (f32, Vec3) {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                let t = sphere_intersect(radius, center, r);
+                (t, (r.origin + r.direction * t - center) / radius)
+            }
+            Shape::Rect { position, u, v } => rect(position, u, v, r),
+            Shape::Triangle { a, b, c } => (triangle_intersect(a, b, c, r).0, (b - a).cross(c - a).normalize()),
+        }
+    }
+}
+
+impl Scene {
+    /*
+     * Lights are tested first and flag the state as an emitter like ClosestHit
+     */
+    pub fn closest_hit(&self, r: &Ray, state: &mut State, light_sample: &mut LightSampleRec) -> bool {
+        let mut t = INFINITY;
+        let mut normal = Vec3::ZERO;
+        state.is_emitter = false;
+
+        for light in self.lights.iter() {
+            match light.kind {
+                LightType::Rect => {
+                    let (d, n) = rect(light.position, light.u, light.v, r);
+                    // Hide backfacing quad lights
+                    if n.dot(r.direction) > 0.0 || d >= t {
+                        continue;
+                    }
+                    t = d;
+                    light_sample.pdf = t * t / (light.area * (-r.direction).dot(n));
+                    light_sample.emission = light.emission;
+                    state.is_emitter = true;
+                }
+                LightType::Sphere => {
+                    let d = sphere_intersect(light.radius, light.position, r);
+                    if d >= t {
+                        continue;
+                    }
+                    t = d;
+                    let hit = r.origin + r.direction * t;
+                    let cos_theta = (-r.direction).dot((hit - light.position).normalize());
+                    light_sample.pdf = t * t / (light.area * cos_theta * 0.5);
+                    light_sample.emission = light.emission;
+                    state.is_emitter = true;
+                }
+                LightType::Distant => {}
+            }
+        }
+
+        let mut hit_object = None;
+        for (i, object) in self.objects.iter().enumerate() {
+            let (d, n) = object.shape.intersect(r);
+            if d < t {
+                t = d;
+                normal = n;
+                hit_object = Some(i);
+            }
+        }
+
+        if t >= INFINITY {
+            return false;
+        }
+
+        state.hit_dist = t;
+        state.fhp = r.origin + r.direction * t;
+
+        if let Some(i) = hit_object {
+            state.is_emitter = false;
+            state.mat_id = self.objects[i].material;
+            state.normal = normal.normalize();
+            state.ffnormal = if state.normal.dot(r.direction) <= 0.0 { state.normal } else { -state.normal };
+
+            let (tangent, bitangent) = onb(state.normal);
+            state.tangent = tangent;
+            state.bitangent = bitangent;
+        }
+
+        true
+    }
+
+    pub fn any_hit(&self, r: &Ray, max_dist: f32) -> bool {
+        let light_hit = self.lights.iter().any(|light| {
+            let d = match light.kind {
+                LightType::Rect => rect(light.position, light.u, light.v, r).0,
+                LightType::Sphere => sphere_intersect(light.radius, light.position, r),
+                LightType::Distant => INFINITY,
+            };
+            d > 0.0 && d < max_dist
+        });
+
+        light_hit || self.objects.iter().any(|o| {
+            let d = o.shape.intersect(r).0;
+            d > 0.0 && d < max_dist
+        })
+    }
+
+    /*
+     * The box of PathTracer.frag with its glass sphere and the light sphere from initLightSphere
+     */
+    pub fn cornell(time: f32) -> Self {
+        let white = Vec3::new(0.7295, 0.7355, 0.729) * 0.7;
+        let green = Vec3::new(0.117, 0.4125, 0.115) * 0.7;
+        let red = Vec3::new(0.611, 0.0555, 0.062) * 0.7;
+
+        let (w, h, d) = (5.59, 5.49, 8.0);
+        let wall = |position: Vec3, u: Vec3, v: Vec3, material: usize| Object { shape: Shape::Rect { position, u, v }, material };
+
+        let light = Vec3::new(3.0 + 2.0 * time.sin(), 2.8 + 2.0 * (time * 0.9).sin().powi(2), 3.0 + 4.0 * (time * 0.7).cos());
+
+        Self {
+            camera: Camera::look_at(Vec3::new(2.78, 2.73, -8.0), Vec3::new(2.78, 2.73, 0.0), 2.0 * (1.0f32 / 3.0).atan()),
+            objects: vec![
+                wall(Vec3::new(0.0, 0.0, -d), Vec3::new(w, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0 * d), 0),
+                wall(Vec3::new(0.0, h, -d), Vec3::new(w, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0 * d), 0),
+                wall(Vec3::new(0.0, 0.0, d), Vec3::new(w, 0.0, 0.0), Vec3::new(0.0, h, 0.0), 0),
+                wall(Vec3::new(0.0, 0.0, -d), Vec3::new(0.0, h, 0.0), Vec3::new(0.0, 0.0, 2.0 * d), 1),
+                wall(Vec3::new(w, 0.0, -d), Vec3::new(0.0, h, 0.0), Vec3::new(0.0, 0.0, 2.0 * d), 2),
+                Object { shape: Shape::Sphere { center: Vec3::new(1.5, 1.0, 2.7), radius: 1.0 }, material: 0 },
+                Object { shape: Shape::Sphere { center: Vec3::new(4.0, 1.0, 4.0), radius: 1.0 }, material: 3 },
+            ],
+            materials: vec![Material::diffuse(white), Material::diffuse(green), Material::diffuse(red), Material::glass(1.5)],
+            lights: vec![Light::sphere(light, 0.5, Vec3::splat(20.0))],
+            media: vec![],
+            background: Vec3::ZERO,
+            max_depth: 6,
+            rr_depth: Some(3),
+            bsdf: Bsdf::Disney,
+        }
+    }
+}
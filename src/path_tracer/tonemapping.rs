@@ -0,0 +1,325 @@
+use bevy::{prelude::*, reflect::TypeUuid, render::{camera::ActiveCameras, pipeline::PipelineDescriptor, render_graph::{RenderGraph, RenderResourcesNode}, renderer::{RenderResource, RenderResources}}};
+
+use crate::{actions::ActionState, color::{self, Tonemapper}, utils::reflection::Reflectable};
+use super::{accumulation::{self, TargetTextureNode}, denoise, PathTracerOutput};
+
+/*
+ * Auto exposure metered like a camera: a histogram of the EV100 of a grid of samples of the displayed image,
+ * the darkest and brightest parts left out and the rest averaged. The exposure is the saturation based one
+ * of that average (Lagarde & de Rousiers, Moving Frostbite to PBR) and eases towards it over a few frames.
+ * Exposure/Exposure.frag does all of it in a single fragment, PathTracerOutput.frag applies the exposure,
+ * the white balance and the tonemapper.
+ */
+
+pub const HISTOGRAM_BINS: usize = 64;   //HISTOGRAM_BINS in Exposure.frag, the first one gathers what is too dark to meter
+pub static METERING_GRID: [u32; 2] = [64, 36];
+
+static ISO: f32 = 100.0;
+static CALIBRATION: f32 = 12.5;         //K of reflected light meters
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoExposure {
+    pub min_ev: f32,        //EV100 range of the histogram, the exposure stays within it
+    pub max_ev: f32,
+    pub low_percent: f32,   //Darkest samples left out
+    pub high_percent: f32,  //Samples above are highlights and left out
+    pub speed_up: f32,      //1/s towards brighter scenes
+    pub speed_down: f32,    //1/s towards darker scenes, eyes take longer
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            min_ev: -8.0,
+            max_ev: 20.0,
+            low_percent: 50.0,
+            high_percent: 95.0,
+            speed_up: 3.0,
+            speed_down: 1.0,
+        }
+    }
+}
+
+pub fn luminance_to_ev100(luminance: f32) -> f32 {
+    (luminance * ISO / CALIBRATION).log2()
+}
+
+/*
+ * Scale putting the luminance metered at `ev100` at 1 / 1.2 of the white of the sensor
+ */
+pub fn ev100_to_exposure(ev100: f32) -> f32 {
+    1.0 / (1.2 * ev100.exp2())
+}
+
+pub fn histogram_bin(luminance: f32, settings: &AutoExposure) -> usize {
+    let ev = luminance_to_ev100(luminance);
+    if !(luminance > 0.0) || ev < settings.min_ev {
+        return 0;
+    }
+    let t = ((ev - settings.min_ev) / (settings.max_ev - settings.min_ev)).min(1.0);
+    1 + ((t * (HISTOGRAM_BINS - 1) as f32) as usize).min(HISTOGRAM_BINS - 2)
+}
+
+fn bin_ev(bin: usize, settings: &AutoExposure) -> f32 {
+    settings.min_ev + (bin as f32 - 0.5) / (HISTOGRAM_BINS - 1) as f32 * (settings.max_ev - settings.min_ev)
+}
+
+pub fn histogram(luminances: &[f32], settings: &AutoExposure) -> [f32; HISTOGRAM_BINS] {
+    let mut bins = [0.0; HISTOGRAM_BINS];
+    for l in luminances {
+        bins[histogram_bin(*l, settings)] += 1.0;
+    }
+    bins
+}
+
+/*
+ * Average EV100 between the percentiles, None when nothing is bright enough to meter
+ */
+pub fn metered_ev100(histogram: &[f32; HISTOGRAM_BINS], settings: &AutoExposure) -> Option<f32> {
+    let total: f32 = histogram[1..].iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let (low, high) = (total * settings.low_percent * 0.01, total * settings.high_percent * 0.01);
+    let mut below = 0.0;
+    let mut sum = 0.0;
+    let mut weights = 0.0;
+    for (bin, count) in histogram.iter().enumerate().skip(1) {
+        // Part of the bin between the percentiles
+        let w = (below + count).max(low).min(high) - below.max(low).min(high);
+        sum += w * bin_ev(bin, settings);
+        weights += w;
+        below += count;
+    }
+
+    let ev = if weights > 0.0 { sum / weights } else { bin_ev(HISTOGRAM_BINS - 1, settings) };
+    Some(ev.max(settings.min_ev).min(settings.max_ev))
+}
+
+pub fn adapt(current: f32, target: f32, delta_time: f32, settings: &AutoExposure) -> f32 {
+    let speed = if target > current { settings.speed_up } else { settings.speed_down };
+    current + (target - current) * (1.0 - (-delta_time * speed).exp())
+}
+
+#[derive(Debug)]
+pub struct Tonemapping {
+    pub tonemapper: Tonemapper,
+    pub white_point: f32,               //Luminance Reinhard maps to white
+    pub auto_exposure: bool,
+    pub exposure: f32,                  //Compensation in stops with auto exposure, EV100 without
+    pub metering: AutoExposure,
+    pub white_balance: Option<f32>,     //Temperature shown as white, None leaves the D65 white of sRGB
+    frame: u32,
+    reset: bool,
+}
+
+impl Default for Tonemapping {
+    fn default() -> Self {
+        Self {
+            tonemapper: Tonemapper::AgX,
+            white_point: 4.0,
+            auto_exposure: true,
+            exposure: 0.0,
+            metering: AutoExposure::default(),
+            white_balance: None,
+            frame: 0,
+            reset: true,
+        }
+    }
+}
+
+pub const EXPOSURE_TEXTURE_A: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d1b);
+pub const EXPOSURE_TEXTURE_B: HandleUntyped = HandleUntyped::weak_from_u64(Texture::TYPE_UUID, 0x6a2f_91c4_0b3e_7d1c);
+
+pub const EXPOSURE_TEXTURE_A_NODE: &str = "exposure_target_a";
+pub const EXPOSURE_TEXTURE_B_NODE: &str = "exposure_target_b";
+pub const EXPOSURE_CAMERA: &str = "exposure_camera";
+static EXPOSURE_PASSES: [&str; 2] = ["exposure_pass_a", "exposure_pass_b"];
+
+#[derive(Default)]
+pub struct ExposurePass<const I: u32>;
+
+crate::resource!{
+    #[uuid = "5c8e2b17-93fa-4d06-8e41-a7d3b0f6c259"]
+    struct Exposure {
+        color: Handle<Texture>,
+        previous: Handle<Texture>,
+        inv_sample_counter: f32,
+        render_scale: f32,
+        reset: i32,
+        delta_time: f32,
+        min_ev: f32,
+        max_ev: f32,
+        low_percent: f32,
+        high_percent: f32,
+        speed_up: f32,
+        speed_down: f32
+    }
+}
+
+/*
+ * 1x1 targets holding the adapted EV100, A for even frames
+ */
+fn target(frame: u32) -> &'static HandleUntyped {
+    if frame % 2 == 0 { &EXPOSURE_TEXTURE_A } else { &EXPOSURE_TEXTURE_B }
+}
+
+fn add_pass<const I: u32>(commands: &mut Commands, render_graph: &mut RenderGraph, quad: Handle<Mesh>, pipeline: Handle<PipelineDescriptor>) {
+    let node = if I == 0 { EXPOSURE_TEXTURE_A_NODE } else { EXPOSURE_TEXTURE_B_NODE };
+    let name = EXPOSURE_PASSES[I as usize];
+
+    accumulation::add_target_pass::<ExposurePass<I>>(commands, render_graph, name, EXPOSURE_CAMERA, &["color_attachment"], quad, pipeline)
+        .insert(Exposure {
+            previous: target(I + 1).clone_weak().typed(),
+            ..Default::default()
+        });
+    render_graph.add_node_edge("Exposure", name).unwrap();
+    render_graph.add_slot_edge(node, TargetTextureNode::TEXTURE, name, "color_attachment").unwrap();
+
+    // Meters the image the output quad shows, whichever pass drew it
+    render_graph.add_node_edge(accumulation::ACCUM_PASS_A, name).unwrap();
+    render_graph.add_node_edge(accumulation::ACCUM_PASS_B, name).unwrap();
+    for pass in denoise::DENOISE_PASSES.iter() {
+        render_graph.add_node_edge(*pass, name).unwrap();
+    }
+}
+
+pub fn setup_exposure(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pipelines: ResMut<Assets<PipelineDescriptor>>,
+    mut render_graph: ResMut<RenderGraph>,
+    mut active_cameras: ResMut<ActiveCameras>,
+    shaders: ResMut<Assets<Shader>>,
+) {
+    let pipeline = pipelines.add(accumulation::target_pipeline("Exposure", 1, shaders, asset_server));
+    let quad = meshes.add(Mesh::from(shape::Quad { size: Vec2::new(2.0, 2.0), flip: false }));
+
+    render_graph.add_system_node("Exposure", RenderResourcesNode::<Exposure>::new(true));
+    render_graph.add_node(EXPOSURE_TEXTURE_A_NODE, TargetTextureNode::fixed(EXPOSURE_TEXTURE_A.clone_weak(), 1, 1));
+    render_graph.add_node(EXPOSURE_TEXTURE_B_NODE, TargetTextureNode::fixed(EXPOSURE_TEXTURE_B.clone_weak(), 1, 1));
+    render_graph.add_node_edge(EXPOSURE_TEXTURE_A_NODE, "Exposure").unwrap();
+    render_graph.add_node_edge(EXPOSURE_TEXTURE_B_NODE, "Exposure").unwrap();
+    render_graph.add_node_edge(accumulation::ACCUM_TEXTURE_A_NODE, "Exposure").unwrap();
+    render_graph.add_node_edge(accumulation::ACCUM_TEXTURE_B_NODE, "Exposure").unwrap();
+    render_graph.add_node_edge(denoise::DENOISE_TEXTURE_A_NODE, "Exposure").unwrap();
+    render_graph.add_node_edge(denoise::DENOISE_TEXTURE_B_NODE, "Exposure").unwrap();
+    accumulation::target_camera(&mut commands, &mut active_cameras, &mut render_graph, EXPOSURE_CAMERA);
+
+    add_pass::<0>(&mut commands, &mut render_graph, quad.clone(), pipeline.clone());
+    add_pass::<1>(&mut commands, &mut render_graph, quad, pipeline);
+}
+
+pub fn cycle_tonemapper(
+    actions: Res<ActionState>,
+    mut tonemapping: ResMut<Tonemapping>,
+) {
+    if actions.just_pressed("cycle_tonemapper") {
+        tonemapping.tonemapper = tonemapping.tonemapper.next();
+        info!("Tonemapper {:?}", tonemapping.tonemapper);
+    }
+}
+
+/*
+ * Runs after denoise::denoise, meters whatever the output quad shows this frame
+ */
+pub fn expose(
+    time: Res<Time>,
+    mut tonemapping: ResMut<Tonemapping>,
+    mut passes: Query<(&mut Exposure, &mut Visible, Option<&ExposurePass<0>>)>,
+    mut outputs: Query<&mut PathTracerOutput>,
+) {
+    let frame = tonemapping.frame;
+    let reset = tonemapping.reset;
+    if tonemapping.auto_exposure {
+        tonemapping.frame += 1;
+        tonemapping.reset = false;
+    } else {
+        // Jumps straight to the metered exposure when turned back on
+        tonemapping.reset = true;
+    }
+
+    let shown = outputs.iter_mut().next().map(|output| (output.accum.clone_weak(), output.inv_sample_counter, output.render_scale));
+    let (color, inv_sample_counter, render_scale) = match shown {
+        Some(shown) => shown,
+        None => return,
+    };
+
+    let metering = tonemapping.metering;
+    for (mut pass, mut visible, pass_a) in passes.iter_mut() {
+        visible.is_visible = tonemapping.auto_exposure && pass_a.is_some() == (frame % 2 == 0);
+        pass.color = color.clone_weak();
+        pass.inv_sample_counter = inv_sample_counter;
+        pass.render_scale = render_scale;
+        pass.reset = reset as i32;
+        pass.delta_time = time.delta_seconds();
+        pass.min_ev = metering.min_ev;
+        pass.max_ev = metering.max_ev;
+        pass.low_percent = metering.low_percent;
+        pass.high_percent = metering.high_percent;
+        pass.speed_up = metering.speed_up;
+        pass.speed_down = metering.speed_down;
+    }
+
+    let white_balance = tonemapping.white_balance.map(color::white_balance).unwrap_or(Mat4::IDENTITY);
+    for mut output in outputs.iter_mut() {
+        output.exposure = target(frame).clone_weak().typed();
+        output.auto_exposure = tonemapping.auto_exposure as i32;
+        output.exposure_ev = tonemapping.exposure;
+        output.tonemapper = tonemapping.tonemapper as i32;
+        output.white_point = tonemapping.white_point;
+        output.white_balance = white_balance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grey_is_metered_at_its_ev100() {
+        let settings = AutoExposure::default();
+        let luminance = 0.3;
+        let bins = histogram(&vec![luminance; 1000], &settings);
+        let ev = metered_ev100(&bins, &settings).unwrap();
+
+        // Within a bin
+        let bin_width = (settings.max_ev - settings.min_ev) / (HISTOGRAM_BINS - 1) as f32;
+        assert!((ev - luminance_to_ev100(luminance)).abs() <= bin_width);
+
+        // Middle grey of a camera, 0.18 / 1.2 * 12.5 / 100 * 1 / 0.18
+        let exposed = luminance * ev100_to_exposure(luminance_to_ev100(luminance));
+        assert!((exposed - 1.0 / 9.6).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn highlights_and_black_are_left_out() {
+        let settings = AutoExposure::default();
+        let mut scene = vec![0.0; 5000];                // Space
+        scene.extend(vec![0.5; 970]);
+        scene.extend(vec![1.0e5; 30]);                  // A star
+        let with_star = metered_ev100(&histogram(&scene, &settings), &settings).unwrap();
+        let without = metered_ev100(&histogram(&vec![0.5; 1000], &settings), &settings).unwrap();
+        assert!((with_star - without).abs() < 1.0e-3);
+
+        assert_eq!(metered_ev100(&histogram(&vec![0.0; 100], &settings), &settings), None);
+        assert_eq!(histogram_bin(f32::NAN, &settings), 0);
+        assert_eq!(histogram_bin(1.0e30, &settings), HISTOGRAM_BINS - 1);
+    }
+
+    #[test]
+    fn adaptation_is_faster_towards_bright() {
+        let settings = AutoExposure::default();
+        let brighter = adapt(0.0, 10.0, 0.1, &settings);
+        let darker = adapt(10.0, 0.0, 0.1, &settings);
+        assert!(brighter > 10.0 - darker);
+
+        let mut ev = 0.0;
+        for _ in 0..600 {
+            ev = adapt(ev, 5.0, 1.0 / 60.0, &settings);
+        }
+        assert!((ev - 5.0).abs() < 1.0e-3);
+    }
+}
@@ -8,6 +8,7 @@ use bevy::tasks::ComputeTaskPool;
 use bevy_rapier3d::physics::ColliderBundle;
 use bevy_rapier3d::prelude::*;
 use crate::utils::reflection::Reflectable;
+use crate::constants::{GLOBAL_SCALE, MAX_MASS, MAX_SPEED, MIN_FORCE, MIN_VELOCITY, PHYSICS_C};
 
 pub struct Identity {
     pub id: Uuid
@@ -25,6 +26,64 @@ pub struct Force {
     pub force: Vec3
 }
 
+/*
+ * Time in seconds measured by a clock moving with the entity
+ */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProperTime {
+    pub τ: f64
+}
+
+/*
+ * Adds velocities relativistically instead of linearly, speeds are still clamped to MAX_SPEED
+ */
+pub struct Relativity {
+    pub enabled: bool
+}
+
+impl Default for Relativity {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+pub fn lorentz_factor(v: f64) -> f64 {
+    let β = (v / PHYSICS_C).min(1.0 - f64::EPSILON);
+    1.0 / (1.0 - β * β).sqrt()
+}
+
+/*
+ * Velocity of a body moving at v in a frame that moves at u, both in m/s
+ */
+pub fn velocity_addition(u: DVec3, v: DVec3) -> DVec3 {
+    let c2 = PHYSICS_C * PHYSICS_C;
+    let γ = lorentz_factor(u.length());
+    let uv = u.dot(v);
+
+    (u + v / γ + u * (γ / (1.0 + γ)) * uv / c2) / (1.0 + uv / c2)
+}
+
+pub fn clamp_speed(v: DVec3, max: f64) -> DVec3 {
+    let s = v.length();
+    if s > max {
+        v * (max / s)
+    } else {
+        v
+    }
+}
+
+fn to_dvec3(v: Vec3) -> DVec3 {
+    DVec3::new(v.x as f64, v.y as f64, v.z as f64)
+}
+
+fn linvel(rbv: &RigidBodyVelocity) -> Vec3 {
+    Vec3::new(rbv.linvel.x, rbv.linvel.y, rbv.linvel.z)
+}
+
+fn to_vec3(v: DVec3) -> Vec3 {
+    Vec3::new(v.x as f32, v.y as f32, v.z as f32)
+}
+
 crate::resource!{
     #[uuid = "11c82e72-b7b5-433f-8fa1-440796c714aa"]
     struct BlackBody {
@@ -98,18 +157,34 @@ pub fn gravity(
 pub fn impulse(
     time: Res<Time>,
     thread_pool: Res<ComputeTaskPool>,
+    relativity: Option<Res<Relativity>>,
     // mut impulses: EventReader<ImpulseEvent>,
     mut query: Query<(&mut RigidBodyVelocity, &mut RigidBodyActivation, &RigidBodyMassProps, &mut Force), (With<RigidBodyVelocity>, With<RigidBodyActivation>, With<RigidBodyMassProps>, With<Force>)>,
     // thread_pool: Res<ComputeTaskPool>,
 ) {
+    let relativistic = relativity.map_or(false, |r| r.enabled);
     // let mut impulse = Vec3::ZERO;
     // for event in impulses.iter() {
     //     impulse += **event;
     // }
     // if impulse.length_squared() > 1E-6 {
         query.par_for_each_mut(&thread_pool, num_cpus::get(), |(mut rbv, mut rba, rbm, mut f)| {
+            if (f.force.length() as f64) < MIN_FORCE {
+                f.force = Vec3::ZERO;
+                return;
+            }
+
             let impulse = f.force * time.delta_seconds();
+            let before = linvel(&rbv);
             rbv.apply_impulse(rbm, impulse.into());
+
+            if relativistic {
+                // The impulse changes the velocity in the frame moving with the body
+                let after = linvel(&rbv);
+                let u = to_dvec3(before) * GLOBAL_SCALE as f64;
+                let dv = to_dvec3(after - before) * GLOBAL_SCALE as f64;
+                rbv.linvel = to_vec3(velocity_addition(u, dv) / GLOBAL_SCALE as f64).into();
+            }
             rba.wake_up(true);
 
             f.force = Vec3::ZERO;
@@ -117,6 +192,44 @@ pub fn impulse(
     // }
 }
 
+/*
+ * Enforces the physical limits from constants.rs on all massive bodies
+ */
+pub fn limits(
+    mut query: Query<(&mut RigidBodyVelocity, &mut Mass, Entity)>,
+) {
+    for (mut rbv, mut m, e) in query.iter_mut() {
+        if !m.mass.is_finite() || m.mass > MAX_MASS {
+            warn!("Mass of {:?} out of range: {}", e, m.mass);
+            m.mass = if m.mass.is_finite() { MAX_MASS } else { 0.0 };
+        }
+
+        let v = to_dvec3(linvel(&rbv)) * GLOBAL_SCALE as f64;
+        if !v.length().is_finite() {
+            warn!("Veclocity of {:?} is not finite", e);
+            rbv.linvel = Vec3::ZERO.into();
+        } else if v.length() < MIN_VELOCITY {
+            rbv.linvel = Vec3::ZERO.into();
+        } else if v.length() > MAX_SPEED {
+            rbv.linvel = to_vec3(clamp_speed(v, MAX_SPEED) / GLOBAL_SCALE as f64).into();
+        }
+    }
+}
+
+/*
+ * Advances the clock of every entity by the time dilated frame time
+ */
+pub fn proper_time(
+    time: Res<Time>,
+    mut query: Query<(&RigidBodyVelocity, &mut ProperTime)>,
+) {
+    let dt = time.delta_seconds_f64();
+    for (rbv, mut t) in query.iter_mut() {
+        let v = to_dvec3(linvel(&rbv)).length() * GLOBAL_SCALE as f64;
+        t.τ += dt / lorentz_factor(v);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ForceEvent {
     force: Vec3,
@@ -166,4 +279,40 @@ impl Deref for ForceEvent {
 //     let res = top / bottom ;
 
 //     return res;
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lorentz_factor_at_rest_and_near_c() {
+        assert_eq!(1.0, lorentz_factor(0.0));
+        assert!((lorentz_factor(0.6 * PHYSICS_C) - 1.25).abs() < 1.0e-9);
+        assert!(lorentz_factor(PHYSICS_C).is_finite());
+    }
+
+    #[test]
+    fn velocity_addition_is_galilean_at_low_speed() {
+        let w = velocity_addition(DVec3::new(10.0, 0.0, 0.0), DVec3::new(0.0, 5.0, 0.0));
+        assert!((w - DVec3::new(10.0, 5.0, 0.0)).length() < 1.0e-6);
+    }
+
+    #[test]
+    fn velocity_addition_stays_below_c() {
+        let u = DVec3::new(0.9 * PHYSICS_C, 0.0, 0.0);
+        let w = velocity_addition(u, DVec3::new(0.9 * PHYSICS_C, 0.0, 0.0));
+        assert!(w.length() < PHYSICS_C);
+        assert!((w.x - 1.8 / 1.81 * PHYSICS_C).abs() < 1.0);
+
+        let w = velocity_addition(u, DVec3::new(0.0, 0.9 * PHYSICS_C, 0.0));
+        assert!(w.length() < PHYSICS_C);
+    }
+
+    #[test]
+    fn clamp_keeps_direction() {
+        let v = clamp_speed(DVec3::new(3.0, 4.0, 0.0) * MAX_SPEED, MAX_SPEED);
+        assert!((v.length() - MAX_SPEED).abs() < 1.0e-6);
+        assert!((v.normalize() - DVec3::new(0.6, 0.8, 0.0)).length() < 1.0e-12);
+    }
+}
@@ -0,0 +1,477 @@
+use bevy::math::IVec3;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::constants::CHUNK_SIZE;
+use crate::procedual::terrain::ActiveSurface;
+use crate::replay::Tick;
+
+static STANDARD_GRAVITY: f32 = 9.80665; //m/s2
+static PUSH_OUT_ITERATIONS: usize = 4;
+static GROUND_PROBE: f32 = 0.05;
+static STEP_SETTLE_SAMPLES: usize = 8;
+static AIR_CONTROL: f32 = 2.0;          //1/s
+static MAX_PITCH: f32 = 1.55;           //rad
+static LOOK_SPEED: f32 = 0.04;          //rad per unit of the look axes
+static STREAM_RADIUS: i32 = 1;          // Chunks generated around the chunk of a character on every axis
+
+/*
+ * Solid voxel cells, a voxel at cell c is a unit cube centered on c
+ */
+#[derive(Default)]
+pub struct VoxelOccupancy {
+    pub cells: HashSet<IVec3>,
+    pub chunks: HashMap<IVec3, Vec<IVec3>>, // Solid cells of the chunks already generated by origin
+    pub on_surface: bool,
+}
+
+impl VoxelOccupancy {
+    pub fn is_solid(&self, cell: IVec3) -> bool {
+        self.cells.contains(&cell)
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.chunks.clear();
+    }
+
+    /*
+     * Keeps the chunks within STREAM_RADIUS of the chunk of any center, generate returns the solid cells of the chunk at an origin
+     */
+    pub fn stream(&mut self, centers: &[Vec3], generate: impl Fn(IVec3) -> Vec<IVec3>) {
+        let cs = CHUNK_SIZE as i32;
+        let keys: Vec<IVec3> = centers.iter()
+            .map(|p| IVec3::new((p.x / cs as f32).floor() as i32, (p.y / cs as f32).floor() as i32, (p.z / cs as f32).floor() as i32))
+            .collect();
+        let near = |origin: IVec3| keys.iter().any(|k| {
+            let d = (origin / cs - *k).abs();
+            d.x.max(d.y).max(d.z) <= STREAM_RADIUS
+        });
+
+        let evicted: Vec<IVec3> = self.chunks.keys().filter(|origin| !near(**origin)).copied().collect();
+        for origin in evicted {
+            for cell in self.chunks.remove(&origin).unwrap_or_default() {
+                self.cells.remove(&cell);
+            }
+        }
+
+        for key in keys.iter() {
+            for dx in -STREAM_RADIUS..=STREAM_RADIUS {
+                for dy in -STREAM_RADIUS..=STREAM_RADIUS {
+                    for dz in -STREAM_RADIUS..=STREAM_RADIUS {
+                        let origin = (*key + IVec3::new(dx, dy, dz)) * cs;
+                        if self.chunks.contains_key(&origin) {
+                            continue;
+                        }
+
+                        let cells = generate(origin);
+                        self.cells.extend(cells.iter().copied());
+                        self.chunks.insert(origin, cells);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CharacterInput {
+    pub movement: Vec2, // x strafes right, y walks forward
+    pub look: Vec2,     // Radians of yaw and pitch
+    pub jump: bool,
+    pub crouch: bool,
+}
+
+/*
+ * Kinematic capsule standing on its feet at position, up is opposite to the local gravity
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CharacterController {
+    pub position: Vec3,
+    pub veclocity: Vec3,
+    pub up: Vec3,
+    pub frame: Quat,  // Rotates Y onto up
+    pub yaw: f32,
+    pub pitch: f32,
+
+    pub radius: f32,
+    pub stand_height: f32,
+    pub crouch_height: f32,
+    pub eye_offset: f32,  // Eyes are this far below the top of the capsule
+    pub walk_speed: f32,
+    pub crouch_speed: f32,
+    pub jump_speed: f32,
+    pub step_height: f32,
+
+    pub grounded: bool,
+    pub crouching: bool,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            veclocity: Vec3::ZERO,
+            up: Vec3::Y,
+            frame: Quat::IDENTITY,
+            yaw: 0.0,
+            pitch: 0.0,
+
+            radius: 0.3,
+            stand_height: 1.8,
+            crouch_height: 1.0,
+            eye_offset: 0.1,
+            walk_speed: 4.0,
+            crouch_speed: 2.0,
+            jump_speed: 5.0,
+            step_height: 1.0,
+
+            grounded: false,
+            crouching: false,
+        }
+    }
+}
+
+/*
+ * Closest points between a segment and an axis aligned box, alternating projections converge quickly for convex shapes
+ */
+fn closest_segment_box(a: Vec3, b: Vec3, min: Vec3, max: Vec3) -> (Vec3, Vec3) {
+    let ab = b - a;
+    let len2 = ab.length_squared();
+    let project = |p: Vec3| if len2 > 0.0 { a + ab * ((p - a).dot(ab) / len2).clamp(0.0, 1.0) } else { a };
+
+    let mut on_segment = project((min + max) * 0.5);
+    let mut on_box = on_segment.max(min).min(max);
+    for _ in 0..3 {
+        on_segment = project(on_box);
+        on_box = on_segment.max(min).min(max);
+    }
+
+    (on_segment, on_box)
+}
+
+impl CharacterController {
+    pub fn height(&self) -> f32 {
+        if self.crouching { self.crouch_height } else { self.stand_height }
+    }
+
+    pub fn eye(&self) -> Vec3 {
+        self.position + self.up * (self.height() - self.eye_offset)
+    }
+
+    pub fn look_rotation(&self) -> Quat {
+        self.frame * Quat::from_rotation_y(self.yaw) * Quat::from_rotation_x(self.pitch)
+    }
+
+    fn segment(&self, position: Vec3, height: f32) -> (Vec3, Vec3) {
+        (position + self.up * self.radius, position + self.up * (height - self.radius))
+    }
+
+    fn cells(&self, position: Vec3, height: f32) -> impl Iterator<Item = IVec3> {
+        let (a, b) = self.segment(position, height);
+        let r = Vec3::splat(self.radius + 0.5);
+        let min = (a.min(b) - r).round();
+        let max = (a.max(b) + r).round();
+
+        let (min, max) = (IVec3::new(min.x as i32, min.y as i32, min.z as i32), IVec3::new(max.x as i32, max.y as i32, max.z as i32));
+        (min.x..=max.x).flat_map(move |x| (min.y..=max.y).flat_map(move |y| (min.z..=max.z).map(move |z| IVec3::new(x, y, z))))
+    }
+
+    pub fn overlaps(&self, position: Vec3, height: f32, solid: &dyn Fn(IVec3) -> bool) -> bool {
+        let (a, b) = self.segment(position, height);
+        self.cells(position, height).filter(|c| solid(*c)).any(|c| {
+            let center = Vec3::new(c.x as f32, c.y as f32, c.z as f32);
+            let (p, q) = closest_segment_box(a, b, center - Vec3::splat(0.5), center + Vec3::splat(0.5));
+            p.distance_squared(q) < self.radius * self.radius
+        })
+    }
+
+    /*
+     * Moves the capsule out of all solid voxels it penetrates
+     */
+    fn push_out(&self, mut position: Vec3, height: f32, solid: &dyn Fn(IVec3) -> bool) -> Vec3 {
+        for _ in 0..PUSH_OUT_ITERATIONS {
+            let mut moved = false;
+            for c in self.cells(position, height).filter(|c| solid(*c)) {
+                let (a, b) = self.segment(position, height);
+                let center = Vec3::new(c.x as f32, c.y as f32, c.z as f32);
+                let (p, q) = closest_segment_box(a, b, center - Vec3::splat(0.5), center + Vec3::splat(0.5));
+
+                let d = p.distance(q);
+                if d < self.radius {
+                    let normal = if d > 1.0e-5 { (p - q) / d } else { self.up };
+                    position += normal * (self.radius - d);
+                    moved = true;
+                }
+            }
+
+            if !moved {
+                break;
+            }
+        }
+
+        position
+    }
+
+    /*
+     * Advances the controller by dt seconds, gravity is the local gravity acceleration
+     */
+    pub fn step(&mut self, input: &CharacterInput, gravity: Vec3, dt: f32, solid: &dyn Fn(IVec3) -> bool) {
+        let g = gravity.length();
+        if g > 0.0 {
+            let up = -gravity / g;
+            self.frame = (Quat::from_rotation_arc(self.up, up) * self.frame).normalize();
+            self.up = up;
+        }
+        let up = self.up;
+
+        self.yaw -= input.look.x;
+        self.pitch = (self.pitch - input.look.y).clamp(-MAX_PITCH, MAX_PITCH);
+
+        if input.crouch {
+            self.crouching = true;
+        } else if self.crouching && !self.overlaps(self.position, self.stand_height, solid) {
+            self.crouching = false;
+        }
+        let height = self.height();
+
+        // Walking happens in the plane perpendicular to up
+        let heading = self.frame * Quat::from_rotation_y(self.yaw);
+        let forward = heading * -Vec3::Z;
+        let right = heading * Vec3::X;
+
+        let mut movement = input.movement;
+        if movement.length() > 1.0 {
+            movement = movement.normalize();
+        }
+        let speed = if self.crouching { self.crouch_speed } else { self.walk_speed };
+        let wish = (forward * movement.y + right * movement.x) * speed;
+
+        let mut vertical_speed = self.veclocity.dot(up);
+        let horizontal = self.veclocity - up * vertical_speed;
+        let horizontal = horizontal + (wish - horizontal) * if self.grounded { 1.0 } else { (AIR_CONTROL * dt).min(1.0) };
+
+        vertical_speed -= g * dt;
+        if self.grounded && input.jump && !self.crouching {
+            vertical_speed = self.jump_speed;
+        }
+
+        // Horizontal move, stepping up over obstacles up to step_height
+        let start = self.position;
+        let delta = horizontal * dt;
+        let progress = |p: Vec3| (p - start).dot(delta);
+
+        let mut position = self.push_out(start + delta, height, solid);
+        if self.grounded && delta.length_squared() > 0.0 && progress(position) < 0.9 * delta.length_squared() {
+            let raised = start + up * self.step_height;
+            if !self.overlaps(raised, height, solid) {
+                let stepped = self.push_out(raised + delta, height, solid);
+                if progress(stepped) > progress(position) {
+                    // Settle back down onto the step
+                    let mut settled = stepped;
+                    for i in 1..=STEP_SETTLE_SAMPLES {
+                        let candidate = stepped - up * (self.step_height * i as f32 / STEP_SETTLE_SAMPLES as f32);
+                        if self.overlaps(candidate, height, solid) {
+                            break;
+                        }
+                        settled = candidate;
+                    }
+                    position = settled;
+                }
+            }
+        }
+
+        // Vertical move
+        let target = position + up * vertical_speed * dt;
+        let resolved = self.push_out(target, height, solid);
+        let correction = (resolved - target).dot(up);
+        if (vertical_speed < 0.0 && correction > 0.0) || (vertical_speed > 0.0 && correction < 0.0) {
+            vertical_speed = 0.0;
+        }
+
+        self.position = resolved;
+        self.grounded = self.overlaps(resolved - up * GROUND_PROBE, height, solid);
+        if self.grounded && vertical_speed < 0.0 {
+            vertical_speed = 0.0;
+        }
+
+        self.veclocity = horizontal + up * vertical_speed;
+    }
+}
+
+/*
+ * Generates the chunks around the characters on the simulation tick, unlike the rendered chunks from chunks::load_chunk
+ * these do not depend on when a background task finishes so a replay collides with the same cells
+ */
+pub fn stream_occupancy(
+    state: Res<crate::state::GameState>,
+    surface: Option<Res<ActiveSurface>>,
+    query: Query<&CharacterController>,
+    mut occupancy: ResMut<VoxelOccupancy>,
+) {
+    // The voxel frame moves with the surface, cells generated for another surface are meaningless
+    let on_surface = surface.is_some();
+    if on_surface != occupancy.on_surface || surface.as_ref().map_or(false, |s| s.is_changed()) {
+        occupancy.clear();
+        occupancy.on_surface = on_surface;
+    }
+
+    let surface = surface.map(|s| *s);
+    let centers: Vec<Vec3> = query.iter().map(|c| c.position).collect();
+
+    occupancy.stream(&centers, |origin| {
+        let chunk = crate::chunks::generate_chunk(state.seed, origin.as_f32(), CHUNK_SIZE as u64, 2, surface);
+        chunk.voxels.iter().map(|voxel| {
+            let c = (chunk.position + voxel.position).round();
+            IVec3::new(c.x as i32, c.y as i32, c.z as i32)
+        }).collect()
+    });
+}
+
+pub fn spawn_player(mut commands: Commands) {
+    commands.spawn().insert(CharacterController {
+        position: Vec3::new(0.0, crate::constants::CHUNK_SIZE as f32 + 2.0, 0.0),
+        ..Default::default()
+    });
+}
+
+/*
+ * Drives the character from the action map once per simulation tick and moves the PlayerCamera to its eyes
+ */
+pub fn player_controller(
+    tick: Res<Tick>,
+    actions: Res<crate::actions::ActionState>,
+    occupancy: Res<VoxelOccupancy>,
+    surface: Option<Res<ActiveSurface>>,
+    mut query: Query<&mut CharacterController>,
+    mut camera_query: Query<&mut crate::camera::PlayerCamera, With<crate::camera::PlayerCamera>>
+) {
+    let input = CharacterInput {
+        look: Vec2::new(actions.axis("look_x"), -actions.axis("look_y")) * LOOK_SPEED,
+        movement: Vec2::new(actions.axis("move_x"), actions.axis("move_y")),
+        jump: actions.pressed("jump"),
+        crouch: actions.pressed("crouch"),
+    };
+
+    let solid = |c: IVec3| occupancy.is_solid(c);
+    let dt = tick.seconds;
+
+    for mut controller in query.iter_mut() {
+        // On a planet gravity points to its center, elsewhere down
+        let gravity = match surface.as_ref() {
+            Some(s) => (s.center() - controller.position).normalize_or_zero() * s.settings.gravity as f32,
+            None => -Vec3::Y * STANDARD_GRAVITY
+        };
+
+        controller.step(&input, gravity, dt, &solid);
+
+        if let Ok(mut pc) = camera_query.single_mut() {
+            pc.position = controller.eye();
+            pc.rotation = controller.look_rotation();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DT: f32 = 1.0 / 60.0;
+
+    fn floor(c: IVec3) -> bool {
+        c.y <= 0
+    }
+
+    fn run(controller: &mut CharacterController, input: &CharacterInput, solid: &dyn Fn(IVec3) -> bool, seconds: f32) {
+        for _ in 0..(seconds / DT) as usize {
+            controller.step(input, -Vec3::Y * STANDARD_GRAVITY, DT, solid);
+        }
+    }
+
+    fn standing() -> CharacterController {
+        CharacterController { position: Vec3::new(0.0, 0.5, 0.0), grounded: true, ..Default::default() }
+    }
+
+    #[test]
+    fn falls_and_lands_on_the_floor() {
+        let mut c = CharacterController { position: Vec3::new(0.0, 3.0, 0.0), ..Default::default() };
+        run(&mut c, &CharacterInput::default(), &floor, 2.0);
+
+        assert!(c.grounded);
+        assert!((c.position.y - 0.5).abs() < 0.05, "y: {}", c.position.y);
+    }
+
+    #[test]
+    fn jumping_leaves_the_ground() {
+        let mut c = standing();
+        run(&mut c, &CharacterInput::default(), &floor, 0.5);
+        c.step(&CharacterInput { jump: true, ..Default::default() }, -Vec3::Y * STANDARD_GRAVITY, DT, &floor);
+
+        assert!(!c.grounded);
+        assert!(c.position.y > 0.5);
+    }
+
+    #[test]
+    fn steps_up_single_voxels() {
+        let solid = |c: IVec3| c.y <= 0 || (c.x == 2 && c.y == 1);
+        let mut c = standing();
+        // Yaw a quarter turn to walk along +x
+        c.yaw = -std::f32::consts::FRAC_PI_2;
+
+        let input = CharacterInput { movement: Vec2::new(0.0, 1.0), ..Default::default() };
+        for _ in 0..120 {
+            c.step(&input, -Vec3::Y * STANDARD_GRAVITY, DT, &solid);
+            if c.position.x >= 2.0 {
+                break;
+            }
+        }
+
+        assert!(c.position.x >= 2.0, "x: {}", c.position.x);
+        assert!((c.position.y - 1.5).abs() < 0.1, "y: {}", c.position.y);
+    }
+
+    #[test]
+    fn walls_block_walking() {
+        let solid = |c: IVec3| c.y <= 0 || (c.x == 2 && c.y <= 2);
+        let mut c = standing();
+        c.yaw = -std::f32::consts::FRAC_PI_2;
+
+        run(&mut c, &CharacterInput { movement: Vec2::new(0.0, 1.0), ..Default::default() }, &solid, 2.0);
+        assert!(c.position.x < 1.5 - c.radius + 0.01, "x: {}", c.position.x);
+    }
+
+    #[test]
+    fn cannot_stand_up_under_a_low_ceiling() {
+        let solid = |c: IVec3| c.y <= 0 || c.y == 2;
+        let mut c = standing();
+        c.step(&CharacterInput { crouch: true, ..Default::default() }, -Vec3::Y * STANDARD_GRAVITY, DT, &solid);
+        c.step(&CharacterInput::default(), -Vec3::Y * STANDARD_GRAVITY, DT, &solid);
+
+        assert!(c.crouching);
+    }
+
+    #[test]
+    fn up_follows_gravity() {
+        let mut c = standing();
+        c.step(&CharacterInput::default(), Vec3::X * STANDARD_GRAVITY, DT, &|_: IVec3| false);
+
+        assert!((c.up - -Vec3::X).length() < 1.0e-5);
+        assert!((c.frame * Vec3::Y - c.up).length() < 1.0e-5);
+    }
+
+    #[test]
+    fn streams_every_axis_and_evicts_far_chunks() {
+        let cs = CHUNK_SIZE as f32;
+        let mut occupancy = VoxelOccupancy::default();
+        occupancy.stream(&[Vec3::ZERO], |origin| vec![origin]);
+
+        assert!(occupancy.is_solid(IVec3::new(0, 0, -(CHUNK_SIZE as i32))));
+        assert!(occupancy.is_solid(IVec3::new(0, 0, CHUNK_SIZE as i32)));
+
+        occupancy.stream(&[Vec3::new(0.0, 0.0, 10.0 * cs)], |origin| vec![origin]);
+
+        assert!(!occupancy.is_solid(IVec3::ZERO));
+        assert_eq!(occupancy.chunks.len(), 27);
+        assert_eq!(occupancy.cells.len(), 27);
+    }
+}
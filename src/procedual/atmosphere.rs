@@ -0,0 +1,263 @@
+use bevy::prelude::*;
+use bevy::reflect::*;
+use bevy::render::renderer::RenderResources;
+
+use crate::constants::PHYSICS_GRAVITY;
+use crate::utils::reflection::Reflectable;
+
+static GAS_CONSTANT: f64 = 8.314462618;   //J/(mol K)
+static BOLTZMANN_CONSTANT: f64 = 1.380649e-23;
+static AVOGADRO_CONSTANT: f64 = 6.02214076e23;
+
+static EARTH_SURFACE_PRESSURE: f64 = 101325.0; //Pa
+static EARTH_SURFACE_GRAVITY: f64 = 9.80665;   //m/s2
+static EARTH_SURFACE_TEMPERATURE: f64 = 288.0; //K
+static WATER_TRIPLE_POINT_PRESSURE: f64 = 611.657; //Pa
+
+static EARTH_RAYLEIGH: [f64; 3] = [5.802e-6, 13.558e-6, 33.1e-6]; //1/m at sea level for red, green and blue
+static EARTH_MIE: f64 = 3.996e-6;                                  //1/m at sea level
+static EARTH_RAYLEIGH_SCALE_HEIGHT: f64 = 8.5e3;                   //m
+static EARTH_MIE_SCALE_HEIGHT: f64 = 1.2e3;                        //m
+
+// A gas is kept over geological time if the escape velocity is this many times its thermal velocity
+static JEANS_RETENTION: f64 = 6.0;
+
+crate::resource!{
+    #[uuid = "6f0b6c8e-41a5-4c57-a2a6-5f3e0d2c9b14"]
+    struct AtmosphereScattering {
+        planet_radius: f32,
+        atmosphere_radius: f32,
+        rayleigh_scale_height: f32,
+        mie_scale_height: f32,
+        rayleigh: Vec3,
+        mie: f32,
+        mie_g: f32
+    }
+}
+
+/*
+ * Molar fractions of the atmosphere, sums to 1 if there is an atmosphere
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Composition {
+    pub h2: f64,
+    pub he: f64,
+    pub n2: f64,
+    pub o2: f64,
+    pub co2: f64,
+    pub h2o: f64,
+    pub ch4: f64,
+}
+
+impl Composition {
+    // Molar masses in kg/mol
+    const MOLAR_MASS: [f64; 7] = [0.002016, 0.004003, 0.028014, 0.031998, 0.04401, 0.018015, 0.016043];
+
+    fn fractions(&self) -> [f64; 7] {
+        [self.h2, self.he, self.n2, self.o2, self.co2, self.h2o, self.ch4]
+    }
+
+    fn from_fractions(f: [f64; 7]) -> Self {
+        Self { h2: f[0], he: f[1], n2: f[2], o2: f[3], co2: f[4], h2o: f[5], ch4: f[6] }
+    }
+
+    /*
+     * Removes the gases that are light enough to escape and renormalizes the rest
+     */
+    fn retained(&self, escape_velocity: f64, temperature: f64) -> Self {
+        let mut f = self.fractions();
+        for (i, m) in Self::MOLAR_MASS.iter().enumerate() {
+            if escape_velocity < JEANS_RETENTION * thermal_velocity(temperature, *m) {
+                f[i] = 0.0;
+            }
+        }
+
+        let sum: f64 = f.iter().sum();
+        if sum <= 0.0 {
+            return Self::default();
+        }
+
+        Self::from_fractions([f[0] / sum, f[1] / sum, f[2] / sum, f[3] / sum, f[4] / sum, f[5] / sum, f[6] / sum])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fractions().iter().all(|x| *x <= 0.0)
+    }
+
+    pub fn mean_molar_mass(&self) -> f64 {
+        self.fractions().iter().zip(Self::MOLAR_MASS.iter()).map(|(f, m)| f * m).sum()
+    }
+
+    /*
+     * Fraction of the atmosphere absorbing thermal infrared, CO2 and CH4 are weighted by how strong absorbers they are
+     */
+    fn greenhouse_gases(&self) -> f64 {
+        self.co2 + self.h2o + 5.0 * self.ch4
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Atmosphere {
+    pub surface_pressure: f64, //Pa
+    pub composition: Composition,
+    pub greenhouse: f64,       //Surface temperature / equilibrium temperature
+    pub scale_height: f64,     //m
+}
+
+pub fn surface_gravity(m: f64, r: f64) -> f64 {
+    PHYSICS_GRAVITY * m / (r * r)
+}
+
+pub fn escape_velocity(m: f64, r: f64) -> f64 {
+    (2.0 * PHYSICS_GRAVITY * m / r).sqrt()
+}
+
+/*
+ * Root mean square speed of a gas with molar mass m (kg/mol)
+ */
+pub fn thermal_velocity(t: f64, m: f64) -> f64 {
+    (3.0 * BOLTZMANN_CONSTANT * t / (m / AVOGADRO_CONSTANT)).sqrt()
+}
+
+/*
+ * Height where the pressure has dropped by a factor e, m is the mean molar mass (kg/mol)
+ */
+pub fn scale_height(t: f64, m: f64, g: f64) -> f64 {
+    GAS_CONSTANT * t / (m * g)
+}
+
+/*
+ * Grey atmosphere greenhouse factor from the infrared optical depth.
+ * The optical depth grows sublinearly with pressure, fitted to Earth (τ ≈ 0.83) and Venus.
+ */
+fn greenhouse_factor(pressure: f64, composition: &Composition) -> f64 {
+    let τ = 8.0 * ((pressure / EARTH_SURFACE_PRESSURE) * composition.greenhouse_gases()).sqrt();
+    (1.0 + 0.75 * τ).powf(0.25)
+}
+
+impl Atmosphere {
+    /*
+     * Generates an atmosphere from the planet mass (Kg), radius (m) and equilibrium temperature (K)
+     */
+    pub fn create(mass: f64, radius: f64, temperature: f64, gas: bool, seed: u64) -> Self {
+        let g = surface_gravity(mass, radius);
+        let v_esc = escape_velocity(mass, radius);
+        let volatiles = crate::noise::noise_1d(mass as u64, seed) as f64 / u64::MAX as f64;
+
+        let (pressure, composition) = if gas {
+            // Gas giants have no surface, use the 1 bar level as reference
+            (1.0e5, Composition { h2: 0.86, he: 0.136, ch4: 0.004, ..Default::default() })
+        } else {
+            // With an atmosphere mass proportional to the planet mass the pressure scales with g²
+            let pressure = EARTH_SURFACE_PRESSURE * (g / EARTH_SURFACE_GRAVITY).powi(2) * 3.0 * volatiles;
+            let composition = if temperature > 350.0 {
+                Composition { co2: 0.965, n2: 0.035, ..Default::default() }
+            } else if temperature > 200.0 {
+                Composition { n2: 0.97 - 0.2 * volatiles, co2: 0.02 + 0.2 * volatiles, h2o: 0.01, ..Default::default() }
+            } else {
+                Composition { n2: 0.95, ch4: 0.05, ..Default::default() }
+            };
+            (pressure, composition)
+        };
+
+        let composition = composition.retained(v_esc, temperature);
+        if composition.is_empty() || pressure <= 0.0 {
+            // Without an atmosphere the surface is at the equilibrium temperature
+            return Self { greenhouse: 1.0, ..Default::default() };
+        }
+
+        let greenhouse = greenhouse_factor(pressure, &composition);
+        let surface_temperature = temperature * greenhouse;
+
+        Self {
+            surface_pressure: pressure,
+            composition,
+            greenhouse,
+            scale_height: scale_height(surface_temperature, composition.mean_molar_mass(), g)
+        }
+    }
+
+    pub fn surface_temperature(&self, equilibrium_temperature: f64) -> f64 {
+        equilibrium_temperature * self.greenhouse.max(1.0)
+    }
+
+    /*
+     * Rayleigh and Mie parameters for the path tracer, scaled from Earth by the surface number density
+     */
+    pub fn scattering(&self, planet_radius: f64, surface_temperature: f64) -> AtmosphereScattering {
+        let density = (self.surface_pressure / EARTH_SURFACE_PRESSURE) * (EARTH_SURFACE_TEMPERATURE / surface_temperature.max(1.0));
+        let mie_scale_height = self.scale_height * EARTH_MIE_SCALE_HEIGHT / EARTH_RAYLEIGH_SCALE_HEIGHT;
+
+        AtmosphereScattering {
+            planet_radius: planet_radius as f32,
+            // Above ten scale heights the density is negligible
+            atmosphere_radius: (planet_radius + 10.0 * self.scale_height) as f32,
+            rayleigh_scale_height: self.scale_height as f32,
+            mie_scale_height: mie_scale_height as f32,
+            rayleigh: Vec3::new(
+                (EARTH_RAYLEIGH[0] * density) as f32,
+                (EARTH_RAYLEIGH[1] * density) as f32,
+                (EARTH_RAYLEIGH[2] * density) as f32
+            ),
+            mie: (EARTH_MIE * density) as f32,
+            mie_g: 0.76
+        }
+    }
+}
+
+/*
+ * Score in [0, 1] for how well a planet could support liquid water based life
+ */
+pub fn habitability(star_supports_life: bool, gas: bool, mass: f64, radius: f64, atmosphere: &Atmosphere, surface_temperature: f64) -> f64 {
+    if !star_supports_life || gas || atmosphere.surface_pressure < WATER_TRIPLE_POINT_PRESSURE {
+        return 0.0;
+    }
+
+    let gaussian = |x: f64, μ: f64, σ: f64| (-(x - μ) * (x - μ) / (2.0 * σ * σ)).exp();
+
+    let temperature = gaussian(surface_temperature, EARTH_SURFACE_TEMPERATURE, 30.0);
+    let pressure = gaussian((atmosphere.surface_pressure / EARTH_SURFACE_PRESSURE).ln(), 0.0, 1.5);
+    let gravity = gaussian(surface_gravity(mass, radius) / EARTH_SURFACE_GRAVITY, 1.0, 0.75);
+
+    (temperature * pressure * gravity).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EARTH_MASS: f64 = 5.9722e24;
+    static EARTH_RADIUS: f64 = 6.3781e6;
+
+    #[test]
+    fn earth_scale_height() {
+        let h = scale_height(288.0, 0.029, EARTH_SURFACE_GRAVITY);
+        assert!((h - 8.4e3).abs() < 200.0, "h: {}", h);
+    }
+
+    #[test]
+    fn greenhouse_warms_surface() {
+        for seed in 0..16 {
+            let atmosphere = Atmosphere::create(EARTH_MASS, EARTH_RADIUS, 400.0, false, seed);
+            assert!(atmosphere.composition.co2 > 0.9);
+            assert!(atmosphere.greenhouse > 1.0, "greenhouse: {}", atmosphere.greenhouse);
+            assert!(atmosphere.surface_temperature(400.0) > 400.0);
+        }
+
+        let airless = Atmosphere::create(1.0e20, 2.0e5, 400.0, false, 42);
+        assert_eq!(1.0, airless.greenhouse);
+        assert_eq!(400.0, airless.surface_temperature(400.0));
+    }
+
+    #[test]
+    fn small_bodies_loose_their_atmosphere() {
+        let atmosphere = Atmosphere::create(1.0e20, 2.0e5, 400.0, false, 42);
+        assert_eq!(0.0, atmosphere.surface_pressure);
+    }
+
+    #[test]
+    fn gas_giants_keep_hydrogen() {
+        let atmosphere = Atmosphere::create(318.0 * EARTH_MASS, 11.2 * EARTH_RADIUS, 110.0, true, 42);
+        assert!(atmosphere.composition.h2 > 0.5);
+    }
+}
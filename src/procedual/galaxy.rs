@@ -0,0 +1,349 @@
+use bevy::math::{DVec3, Vec3A};
+use bevy::prelude::*;
+use bevy::reflect::*;
+use bevy::render::mesh::VertexAttributeValues;
+use bevy::render::pipeline::{PipelineDescriptor, PrimitiveTopology, RenderPipeline};
+use bevy::render::render_graph::RenderGraph;
+use bevy::render::renderer::RenderResources;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::HashMap;
+use futures_lite::future;
+
+use crate::constants::GLOBAL_SCALE;
+use crate::shaders::ShaderCache;
+use crate::utils::reflection::Reflectable;
+
+use super::solar_system::{Star, StarSystem, AU};
+
+static LIGHT_YEAR: f64 = 9.4607e15; //m
+
+static GALAXY_SCALE_LENGTH: f64 = 11000.0 * LIGHT_YEAR;
+static GALAXY_SCALE_HEIGHT: f64 = 1000.0 * LIGHT_YEAR;
+static GALAXY_BULGE_RADIUS: f64 = 3000.0 * LIGHT_YEAR;
+static GALAXY_SOLAR_RADIUS: f64 = 26000.0 * LIGHT_YEAR; // Distance from the galactic center to the world origin
+
+static GALAXY_ARMS: f64 = 4.0;
+static GALAXY_ARM_PITCH: f64 = 0.22; // rad
+static GALAXY_ARM_WIDTH: f64 = 4.0;  // Higher is narrower
+static GALAXY_INTER_ARM_DENSITY: f64 = 0.3;
+
+static SECTOR_SIZE: f64 = 10.0 * LIGHT_YEAR;
+static SECTORS_LOADED_RADIUS: i64 = 2;
+static MAX_STARS_PER_SECTOR: u64 = 64;
+
+static STAR_SYSTEM_VICINITY: f64 = 1000.0 * AU;
+
+pub type SectorKey = (i64, i64, i64);
+
+crate::resource!{
+    #[uuid = "0c0a3a4e-3f6a-4bde-9a3c-1f4a8f5d2b71"]
+    struct StarField {
+        point_size: f32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GalaxyStar {
+    pub id: u64,
+    pub seed: u64,
+    pub position: DVec3,  //m, relative to the world origin
+    pub temperature: f64, //Kelvin
+    pub luminosity: f64,  //Watt
+}
+
+#[derive(Debug, Clone)]
+pub struct Sector {
+    pub key: SectorKey,
+    pub stars: Vec<GalaxyStar>
+}
+
+#[derive(Default)]
+pub struct LoadedSectors {
+    pub sectors: HashMap<SectorKey, Entity>
+}
+
+#[derive(Default)]
+pub struct ActiveStarSystem {
+    pub star: Option<GalaxyStar>,
+    pub entities: Vec<Entity>
+}
+
+pub struct StarFieldPipeline(pub RenderPipeline);
+
+/*
+ * Relative star density in [0, 1] at a position relative to the galactic center.
+ * An exponential disk modulated by logarithmic spiral arms, plus a central bulge.
+ */
+pub fn galaxy_density(p: DVec3) -> f64 {
+    let r = (p.x * p.x + p.z * p.z).sqrt();
+    let θ = p.z.atan2(p.x);
+
+    let disk = (-r / GALAXY_SCALE_LENGTH).exp() * (-p.y.abs() / GALAXY_SCALE_HEIGHT).exp();
+
+    let spiral = θ - (r / GALAXY_SCALE_LENGTH).max(1.0e-3).ln() / GALAXY_ARM_PITCH.tan();
+    let arms = (0.5 + 0.5 * (GALAXY_ARMS * spiral).cos()).powf(GALAXY_ARM_WIDTH);
+
+    let bulge = (-(r * r + p.y * p.y) / (2.0 * GALAXY_BULGE_RADIUS * GALAXY_BULGE_RADIUS)).exp();
+
+    (disk * (GALAXY_INTER_ARM_DENSITY + (1.0 - GALAXY_INTER_ARM_DENSITY) * arms) + bulge).clamp(0.0, 1.0)
+}
+
+fn galaxy_center() -> DVec3 {
+    DVec3::new(-GALAXY_SOLAR_RADIUS, 0.0, 0.0)
+}
+
+pub fn sector_key(p: DVec3) -> SectorKey {
+    (
+        (p.x / SECTOR_SIZE).floor() as i64,
+        (p.y / SECTOR_SIZE).floor() as i64,
+        (p.z / SECTOR_SIZE).floor() as i64,
+    )
+}
+
+pub fn sector_origin(key: SectorKey) -> DVec3 {
+    DVec3::new(key.0 as f64, key.1 as f64, key.2 as f64) * SECTOR_SIZE
+}
+
+fn to_world(p: Vec3) -> DVec3 {
+    DVec3::new(p.x as f64, p.y as f64, p.z as f64) * GLOBAL_SCALE as f64
+}
+
+fn to_render(p: DVec3) -> Vec3 {
+    let p = p / GLOBAL_SCALE as f64;
+    Vec3::new(p.x as f32, p.y as f32, p.z as f32)
+}
+
+/*
+ * Every sector has a fixed number of candidate star slots, a slot holds a star if its noise is below the galaxy density
+ */
+pub fn generate_sector(key: SectorKey, seed: u64) -> Sector {
+    let origin = sector_origin(key);
+    let (x, y, z) = (key.0 as u64, key.1 as u64, key.2 as u64);
+
+    let mut stars = Vec::new();
+    for i in 0..MAX_STARS_PER_SECTOR {
+        let slot_seed = seed.wrapping_add(i);
+        let offset = DVec3::new(
+            crate::noise::noise_3d_f64_normalized(x, y, z, slot_seed.wrapping_add(1)),
+            crate::noise::noise_3d_f64_normalized(x, y, z, slot_seed.wrapping_add(2)),
+            crate::noise::noise_3d_f64_normalized(x, y, z, slot_seed.wrapping_add(3)),
+        );
+        let position = origin + offset * SECTOR_SIZE;
+
+        if crate::noise::noise_3d_f64_normalized(x, y, z, slot_seed) >= galaxy_density(position - galaxy_center()) {
+            continue;
+        }
+
+        let star_seed = crate::noise::noise_3d(x, y, z, slot_seed.wrapping_add(4));
+        let star = Star::create(0, 0, 0, star_seed);
+
+        stars.push(GalaxyStar {
+            id: star.id,
+            seed: star_seed,
+            position,
+            temperature: star.temperature,
+            luminosity: star.luminosity,
+        });
+    }
+
+    Sector {
+        key,
+        stars
+    }
+}
+
+fn sector_mesh(sector: &Sector) -> Mesh {
+    let origin = sector_origin(sector.key);
+
+    let positions: Vec<[f32; 3]> = sector.stars.iter().map(|s| to_render(s.position - origin).into()).collect();
+    let temperatures: Vec<f32> = sector.stars.iter().map(|s| s.temperature as f32).collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::PointList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute("Vertex_Temperature", VertexAttributeValues::Float(temperatures));
+
+    mesh
+}
+
+pub fn setup_star_field(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    shader_cache: ResMut<ShaderCache>,
+    pipelines: ResMut<Assets<PipelineDescriptor>>,
+    render_graph: ResMut<RenderGraph>,
+    shaders: ResMut<Assets<Shader>>,
+) {
+    if let Some(pipeline) = crate::shaders::loader::setup_material::<StarField>(asset_server, shader_cache, pipelines, render_graph, shaders) {
+        commands.insert_resource(StarFieldPipeline(pipeline));
+    } else {
+        info!("No shaders found for {}", StarField::struct_name());
+    }
+
+    commands.insert_resource(LoadedSectors::default());
+    commands.insert_resource(ActiveStarSystem::default());
+}
+
+/*
+ * Starts generating sectors around the camera and despawns the ones that are out of range
+ */
+pub fn stream_sectors(
+    mut commands: Commands,
+    camera_query: Query<&Transform, With<crate::camera::PlayerCamera>>,
+    thread_pool: Res<AsyncComputeTaskPool>,
+    state: Res<crate::state::GameState>,
+    mut loaded: ResMut<LoadedSectors>,
+) {
+    if let Ok(t) = camera_query.single() {
+        let center = sector_key(to_world(t.translation));
+
+        for dx in -SECTORS_LOADED_RADIUS..=SECTORS_LOADED_RADIUS {
+            for dy in -SECTORS_LOADED_RADIUS..=SECTORS_LOADED_RADIUS {
+                for dz in -SECTORS_LOADED_RADIUS..=SECTORS_LOADED_RADIUS {
+                    let key = (center.0 + dx, center.1 + dy, center.2 + dz);
+                    if loaded.sectors.contains_key(&key) {
+                        continue;
+                    }
+
+                    let seed = state.seed;
+                    let entity = commands
+                        .spawn()
+                        .insert(thread_pool.spawn(async move {
+                            return generate_sector(key, seed);
+                        }))
+                        .id();
+
+                    loaded.sectors.insert(key, entity);
+                }
+            }
+        }
+
+        let out_of_range: Vec<SectorKey> = loaded.sectors
+            .keys()
+            .filter(|k| {
+                (k.0 - center.0).abs() > SECTORS_LOADED_RADIUS + 1 ||
+                (k.1 - center.1).abs() > SECTORS_LOADED_RADIUS + 1 ||
+                (k.2 - center.2).abs() > SECTORS_LOADED_RADIUS + 1
+            })
+            .cloned()
+            .collect();
+
+        for key in out_of_range {
+            if let Some(entity) = loaded.sectors.remove(&key) {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+    }
+}
+
+pub fn create_sectors(
+    mut commands: Commands,
+    mut sector_tasks: Query<(Entity, &mut Task<Sector>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    pipeline: Option<Res<StarFieldPipeline>>,
+) {
+    for (entity, mut task) in sector_tasks.iter_mut() {
+        if let Some(sector) = future::block_on(future::poll_once(&mut *task)) {
+            let pos = to_render(sector_origin(sector.key));
+
+            let mut entity_commands = commands.entity(entity);
+            entity_commands.remove::<Task<Sector>>();
+
+            if let (Some(pipeline), false) = (pipeline.as_ref(), sector.stars.is_empty()) {
+                entity_commands
+                    .insert_bundle(MeshBundle {
+                        mesh: meshes.add(sector_mesh(&sector)),
+                        render_pipelines: RenderPipelines::from_pipelines(vec![pipeline.0.clone()]),
+                        transform: Transform::from_translation(pos),
+                        global_transform: GlobalTransform::from_translation(pos),
+                        ..Default::default()
+                    })
+                    .insert(StarField { point_size: 2.0 });
+            } else {
+                entity_commands
+                    .insert(Transform::from_translation(pos))
+                    .insert(GlobalTransform::from_translation(pos));
+            }
+
+            entity_commands.insert(sector);
+        }
+    }
+}
+
+/*
+ * Instantiates the full star system of the nearest star when the camera gets close and despawns it when leaving
+ */
+pub fn instantiate_star_systems(
+    mut commands: Commands,
+    camera_query: Query<&Transform, With<crate::camera::PlayerCamera>>,
+    sectors: Query<&Sector>,
+    mut active: ResMut<ActiveStarSystem>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    shader_cache: ResMut<ShaderCache>,
+    pipelines: ResMut<Assets<PipelineDescriptor>>,
+    render_graph: ResMut<RenderGraph>,
+    shaders: ResMut<Assets<Shader>>,
+    asset_server: ResMut<AssetServer>,
+) {
+    if let Ok(t) = camera_query.single() {
+        let camera = to_world(t.translation);
+
+        if let Some(star) = active.star {
+            // Hysteresis so the system does not flicker at the border of the vicinity
+            if star.position.distance(camera) > 1.5 * STAR_SYSTEM_VICINITY {
+                for entity in active.entities.drain(..) {
+                    commands.entity(entity).despawn_recursive();
+                }
+                active.star = None;
+            }
+            return;
+        }
+
+        let nearest = sectors
+            .iter()
+            .flat_map(|s| s.stars.iter())
+            .map(|s| (s, s.position.distance(camera)))
+            .filter(|(_, d)| *d < STAR_SYSTEM_VICINITY)
+            .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap());
+
+        if let Some((star, _)) = nearest {
+            let mut system = StarSystem::create(0, 0, 0, star.seed);
+            //TODO: Floating origin, f32 positions loose precision far away from the world origin
+            system.translate(Vec3A::new(star.position.x as f32, star.position.y as f32, star.position.z as f32));
+
+            active.entities = super::solar_system::spawn_star_system(&mut commands, &mut materials, &mut meshes, shader_cache, pipelines, render_graph, shaders, asset_server, system);
+            active.star = Some(*star);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sectors_are_deterministic() {
+        let a = super::generate_sector((0, 0, 0), 42);
+        let b = super::generate_sector((0, 0, 0), 42);
+
+        assert_eq!(a.stars.len(), b.stars.len());
+        for (s1, s2) in a.stars.iter().zip(b.stars.iter()) {
+            assert_eq!(s1.id, s2.id);
+            assert_eq!(s1.position, s2.position);
+        }
+    }
+
+    #[test]
+    fn stars_are_inside_their_sector() {
+        let key = (3, -1, 7);
+        let sector = super::generate_sector(key, 1337);
+        for star in sector.stars {
+            assert_eq!(key, super::sector_key(star.position));
+        }
+    }
+
+    #[test]
+    fn density_is_higher_in_the_core() {
+        let core = super::galaxy_density(bevy::math::DVec3::ZERO);
+        let rim = super::galaxy_density(bevy::math::DVec3::new(5.0 * super::GALAXY_SCALE_LENGTH, 0.0, 0.0));
+        assert!(core > rim);
+    }
+}
@@ -1 +1,5 @@
-pub mod solar_system;
\ No newline at end of file
+pub mod solar_system;
+pub mod galaxy;
+pub mod stellar;
+pub mod atmosphere;
+pub mod terrain;
\ No newline at end of file
@@ -7,12 +7,15 @@ use bevy::render::render_graph::RenderGraph;
 use crate::constants::{GLOBAL_SCALE, PHYSICS_GRAVITY};
 use crate::shaders::ShaderCache;
 
-static SUN_MASS: f64 = 1.989e30;        //Kg
-static SUN_RADIUS: f64 = 6.963400e8;   //m
-static SUN_LUMINOSITY: f64 = 3.828e26;  //W
+use super::atmosphere::Atmosphere;
+use super::stellar::{Classification, EvolutionStage};
+
+pub static SUN_MASS: f64 = 1.989e30;        //Kg
+pub static SUN_RADIUS: f64 = 6.963400e8;   //m
+pub static SUN_LUMINOSITY: f64 = 3.828e26;  //W
 static SUN_TEMPERATURE: f64 = 5778.0;   //K
 
-static AU: f64 = 1.496e11;
+pub static AU: f64 = 1.496e11;
 static EARTH_MASS: f64 = 5.9722e24;
 static EARTH_RADIUS: f64 = 6.3781e6;
 
@@ -22,7 +25,15 @@ static MAX_STAR_MASS: f64 = 265.0 * SUN_MASS;
 static MAX_SOLID_PLANET_MASS: f64 = 50.0 * EARTH_MASS;
 static MIN_GAS_PLANET_MASS: f64 = 125.0 * EARTH_MASS;
 
-static σ: f64 = 5.670374419e-8;
+static MAX_MOONS: u64 = 8;
+static MAX_RING_PARTICLES: u64 = 64;
+static MAX_BELT_ASTEROIDS: u64 = 128;
+
+static MIN_MOON_DENSITY: f64 = 1.5;  // g/cm3
+static MAX_MOON_DENSITY: f64 = 3.5;  // g/cm3
+static RING_DENSITY: f64 = 0.917;    // g/cm3, water ice
+
+pub static σ: f64 = 5.670374419e-8;
 // static ASTOID_MIN_DENSITY: f64 = 1.38; // g/cm3
 // static ASTOID_MEAN_DENSITY: f64 = 2.0; // g/cm3
 // static ASTOID_MAX_DENSITY: f64 = 5.32; // g/cm3
@@ -37,7 +48,7 @@ fn astroid_radious(x: f64) -> f64 {
 }
 
 fn astroid_density(x: u64, y: u64, z: u64, seed: u64) -> f64 {
-    let x = (crate::noise::noise_3d(x, y, z, seed) as f64) / (f64::MAX as f64);
+    let x = (crate::noise::noise_3d(x, y, z, seed) as f64) / (u64::MAX as f64);
     if x <= 0.5 {
         return 1.38e6 + x * 1.24e6;
     }
@@ -51,14 +62,51 @@ fn mass_from_volume_density(r: f64, d: f64) -> f64  {
     return d*v;
 } 
 
+/*
+ * Speed of a circular orbit at distance r around a body of mass m
+ */
+fn orbital_speed(m: f64, r: f64) -> f64 {
+    (PHYSICS_GRAVITY * m / r).sqrt()
+}
+
+/*
+ * Radius of the region where a body of mass m orbiting a body of mass big_m at distance a dominates gravity
+ */
+fn hill_radius(a: f64, m: f64, big_m: f64) -> f64 {
+    a * (m / (3.0 * big_m)).cbrt()
+}
+
+/*
+ * Distance from a body of radius r where a satellite gets torn apart by tidal forces
+ */
+fn roche_limit(r: f64, density: f64, satellite_density: f64) -> f64 {
+    2.44 * r * (density / satellite_density).cbrt()
+}
+
+/*
+ * Position and velocity of a circular orbit in the xz-plane at angle θ around a moving center
+ */
+fn circular_orbit(center: Vec3A, center_veclocity: Vec3A, m: f64, r: f64, θ: f64) -> (Vec3A, Vec3A) {
+    let direction = Vec3A::new(θ.cos() as f32, 0.0, θ.sin() as f32);
+    let tangent = Vec3A::new(-θ.sin() as f32, 0.0, θ.cos() as f32);
+
+    let position = center + direction * r as f32;
+    let veclocity = center_veclocity + tangent * orbital_speed(m, r) as f32;
+
+    (position, veclocity)
+}
+
 fn planet_density_distribution(d: f64) -> f64 {
     let s = d / AU;
 
     return -0.0038 * s * s * s + 0.1236 * s * s - 1.5275 * s + 6.7796;
 }
 
+/*
+ * Equilibrium temperature of a planet without atmosphere
+ */
 fn planet_temperature(l: f64, a: f64, d2: f64) -> f64 {
-    return (l * (1.0 - a))/(16.0 * std::f64::consts::PI  * σ * d2);
+    return ((l * (1.0 - a))/(16.0 * std::f64::consts::PI  * σ * d2)).powf(0.25);
 }
 
 fn star_luminosity(m: f64) -> f64 {
@@ -111,18 +159,13 @@ fn luminosity_at(p: Vec3A, sun_p: Vec3A, m: f64, r: f64) -> f64 {
     AU * star_luminosity(m)
 }
 
-fn habital_zone(m: f64) -> (f64, f64) {
-    //TODO: Does not take account for atmosspherical pressure
-    let au = scale_luminosity(m);
-    (0.7 * au, 1.5 * au)
-}
-
-fn planet_radius(m: f64) -> f64 {
+pub fn planet_radius(m: f64) -> f64 {
+    let m_norm = m / EARTH_MASS;
     if m < MAX_SOLID_PLANET_MASS {
-        return EARTH_RADIUS * m.powf(0.56);
+        return EARTH_RADIUS * m_norm.powf(0.56);
     }
     //  else { // if m > MIN_GAS_PLANET_MASS {
-    return EARTH_RADIUS * m.powf(0.02);
+    return EARTH_RADIUS * m_norm.powf(0.02);
     // }
  
     // return 0.0;
@@ -148,41 +191,52 @@ pub struct Star {
     pub radius: f64,      //m
     pub temperature: f64, //Kelvin
     pub luminosity: f64,  //Watt
+    pub lifetime: f64,    //years on the main sequence
+    pub stage: EvolutionStage,
     // pub color: Color,
     // pub cycle: f64                //% of max luminosity
 }
 
 impl Star {
-    fn create(x: u64, y: u64, z: u64, seed: u64) -> Self {
+    pub fn create(x: u64, y: u64, z: u64, seed: u64) -> Self {
         let id = crate::noise::noise_3d(x, y, z, seed);
         let mass = MAX_STAR_MASS * ((crate::noise::noise_3d(x, y, z, id) as f64) / u64::MAX as f64) + MIN_STAR_MASS;
         let position = Vec3A::new(x as f32, y as f32, z as f32);
-        let age = crate::noise::noise_3d_f64_normalized(x, y, z, mass as u64);
-        let radius = star_radius(mass);
-        let luminosity = star_luminosity(mass);
-        let temperature = star_temperature(mass, radius);
-        let color = Color::WHITE;//crate::physics::plancks_law_rgb(temperature);
+        let lifetime = super::stellar::main_sequence_lifetime(mass);
+        let age = (2.0 * lifetime).min(super::stellar::UNIVERSE_AGE) * crate::noise::noise_3d_f64_normalized(x, y, z, mass as u64);
+        let state = super::stellar::evolve(mass, star_radius(mass), star_luminosity(mass), age);
 
         Self {
             id,
             age,
             position,
-            mass: mass,
-            radius,
-            temperature,
-            luminosity,
+            mass: state.mass,
+            radius: state.radius,
+            temperature: state.temperature,
+            luminosity: state.luminosity,
+            lifetime,
+            stage: state.stage,
             // color
         }
     }
 
+    pub fn classification(&self) -> Classification {
+        Classification::create(self.stage, self.mass, self.temperature, self.age / self.lifetime)
+    }
+
+    pub fn color(&self) -> Color {
+        self.classification().color()
+    }
+
     fn luminosity_at(&mut self, p: Vec3A) -> f64 {
-        return luminosity_at(p, self.position, self.mass, self.radius);
+        let d = p.distance(self.position) as f64;
+        return self.luminosity / (4.0 * std::f64::consts::PI * d * d);
     }
 
     fn pbr(&self) -> StandardMaterial {
         return StandardMaterial {
-            // base_color: self.color,
-            // emissive: self.color,
+            base_color: self.color(),
+            emissive: self.color(),
             double_sided: true,
             reflectance: 0.0,
             roughness: 1.0,
@@ -201,8 +255,10 @@ pub struct Planet {
     pub veclocity: Vec3A,
     pub albedo: f64,
     pub radius: f64,      //m
-    pub temperature: f64, //Kelvin
+    pub temperature: f64, //Kelvin, at the surface
     pub density: f64,     //Kg/m3
+    pub atmosphere: Atmosphere,
+    pub habitability: f64,
 }
 
 impl Planet {
@@ -226,9 +282,14 @@ impl Planet {
 
         
         let radius = planet_radius(mass);
-        let temperature = planet_temperature(star.luminosity, albedo, (d*d) as f64);
-
-        let veclocity = (position - star.position).any_orthonormal_vector() * ((PHYSICS_GRAVITY * star.mass) as f32) / d;
+        let equilibrium_temperature = planet_temperature(star.luminosity, albedo, (d*d) as f64);
+        let atmosphere = Atmosphere::create(mass, radius, equilibrium_temperature, gas, seed.wrapping_add(id));
+        let temperature = atmosphere.surface_temperature(equilibrium_temperature);
+        let habitability = super::atmosphere::habitability(star.classification().supports_life(), gas, mass, radius, &atmosphere, temperature);
+
+        // Counterclockwise around +Y in the xz-plane like circular_orbit
+        let tangent = (position - star.position).cross(Vec3A::Y).normalize();
+        let veclocity = tangent * orbital_speed(star.mass, d as f64) as f32;
         
         //TODO:
         assert!(veclocity.dot(position - star.position) < 0.0001, "velocity should be orhogonal");
@@ -238,11 +299,13 @@ impl Planet {
             gas,
             albedo,
             position,
-            mass: SUN_MASS,
+            mass,
             veclocity: veclocity,
             radius: radius,
             temperature: temperature,
-            density
+            density,
+            atmosphere,
+            habitability
         };
     }
 
@@ -258,14 +321,167 @@ impl Planet {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Moon {
+    pub id: u64,
+    pub planet: u64,
+    pub position: Vec3A,
+    pub mass: f64,        //Kg
+    pub veclocity: Vec3A,
+    pub albedo: f64,
+    pub radius: f64,      //m
+    pub density: f64,     //g/cm3
+}
+
+impl Moon {
+    fn create(planet: &Planet, i: u64, orbit: f64, seed: u64) -> Self {
+        let id = crate::noise::noise_3d(planet.id, i, 0, seed);
+        let albedo = crate::noise::noise_3d_f64_normalized(planet.id, i, 1, seed);
+
+        let density = MIN_MOON_DENSITY + (MAX_MOON_DENSITY - MIN_MOON_DENSITY) * crate::noise::noise_3d_f64_normalized(planet.id, i, 2, seed);
+        let radius = planet.radius * (0.02 + 0.25 * crate::noise::noise_3d_f64_normalized(planet.id, i, 3, seed));
+        let mass = mass_from_volume_density(radius, density * 1000.0);
+
+        let θ = std::f64::consts::TAU * crate::noise::noise_3d_f64_normalized(planet.id, i, 4, seed);
+        let (position, veclocity) = circular_orbit(planet.position, planet.veclocity, planet.mass, orbit, θ);
+
+        Self {
+            id,
+            planet: planet.id,
+            position,
+            mass,
+            veclocity,
+            albedo,
+            radius,
+            density
+        }
+    }
+
+    fn pbr(&self) -> StandardMaterial {
+        return StandardMaterial {
+            base_color: Color::GRAY,
+            double_sided: true,
+            reflectance: self.albedo as f32,
+           
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Asteroid {
+    pub id: u64,
+    pub position: Vec3A,
+    pub mass: f64,        //Kg
+    pub veclocity: Vec3A,
+    pub radius: f64,      //m
+    pub density: f64,     //g/cm3
+}
+
+impl Asteroid {
+    /*
+     * Creates an asteroid orbiting a body of mass m in a circular orbit with a radius between inner and outer
+     */
+    fn create(center: Vec3A, center_veclocity: Vec3A, m: f64, inner: f64, outer: f64, i: u64, seed: u64) -> Self {
+        let id = crate::noise::noise_1d(i, seed);
+
+        let radius = astroid_radious(crate::noise::noise_3d_f64_normalized(i, 0, 0, seed));
+        let density = astroid_density(i, 1, 0, seed); // g/m3
+        let mass = mass_from_volume_density(radius, density / 1000.0);
+
+        let orbit = inner + (outer - inner) * crate::noise::noise_3d_f64_normalized(i, 2, 0, seed);
+        let θ = std::f64::consts::TAU * crate::noise::noise_3d_f64_normalized(i, 3, 0, seed);
+        let (position, veclocity) = circular_orbit(center, center_veclocity, m, orbit, θ);
+
+        Self {
+            id,
+            position,
+            mass,
+            veclocity,
+            radius,
+            density: density / 1.0e6
+        }
+    }
+
+    fn pbr(&self) -> StandardMaterial {
+        return StandardMaterial {
+            base_color: Color::DARK_GRAY,
+            double_sided: true,
+            roughness: 1.0,
+           
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ring {
+    pub planet: u64,
+    pub inner_radius: f64, //m
+    pub outer_radius: f64, //m
+    pub particles: Vec<Asteroid>
+}
+
+impl Ring {
+    /*
+     * Rings lie inside the Roche limit of the planet where ice can not clump together into moons
+     */
+    fn create(planet: &Planet, seed: u64) -> Self {
+        let inner_radius = 1.2 * planet.radius;
+        let outer_radius = roche_limit(planet.radius, planet.density, RING_DENSITY).max(inner_radius);
+
+        let nbr_particles = crate::noise::noise_1d(planet.id, seed) % MAX_RING_PARTICLES;
+        let particles = (0..nbr_particles)
+            .map(|i| {
+                let mut particle = Asteroid::create(planet.position, planet.veclocity, planet.mass, inner_radius, outer_radius, i, seed.wrapping_add(planet.id));
+                particle.density = RING_DENSITY;
+                particle.mass = mass_from_volume_density(particle.radius, RING_DENSITY * 1000.0);
+                particle
+            })
+            .collect();
+
+        Self {
+            planet: planet.id,
+            inner_radius,
+            outer_radius,
+            particles
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsteroidBelt {
+    pub inner_radius: f64, //m
+    pub outer_radius: f64, //m
+    pub asteroids: Vec<Asteroid>
+}
+
+impl AsteroidBelt {
+    fn create(star: &Star, inner_radius: f64, outer_radius: f64, seed: u64) -> Self {
+        let nbr_asteroids = crate::noise::noise_1d(inner_radius as u64, seed) % MAX_BELT_ASTEROIDS;
+        let asteroids = (0..nbr_asteroids)
+            .map(|i| Asteroid::create(star.position, Vec3A::ZERO, star.mass, inner_radius, outer_radius, i, seed))
+            .collect();
+
+        Self {
+            inner_radius,
+            outer_radius,
+            asteroids
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct StarSystem {
     pub star: Star,
-    pub planets: Vec<Planet>
+    pub planets: Vec<Planet>,
+    pub moons: Vec<Moon>,
+    pub rings: Vec<Ring>,
+    pub belts: Vec<AsteroidBelt>
 }
 
 impl StarSystem {
-    fn create(x: u64, y: u64, z: u64, seed: u64) -> Self {
+    pub fn create(x: u64, y: u64, z: u64, seed: u64) -> Self {
         let star= Star::create(x, y, z, seed);
         let star_r = star.radius;
 
@@ -275,16 +491,131 @@ impl StarSystem {
         let mut d = star_r + 0.05 * AU;
         for i in 0..nbr_planets {
             d += crate::noise::noise_3d_f64_normalized(x, y, z, seed + i + 1337) * AU;
-            planets.push(Planet::create(&star, x + (AU * star_r / SUN_RADIUS) as u64, y, z, seed));
+            planets.push(Planet::create(&star, x + d as u64, y, z, seed.wrapping_add(i)));
+        }
+
+        let mut moons = Vec::new();
+        let mut rings = Vec::new();
+        for planet in planets.iter() {
+            moons.append(&mut Self::create_moons(&star, planet, seed));
+
+            if planet.gas && crate::noise::noise_1d(planet.id, seed.wrapping_add(2)) % 2 == 0 {
+                rings.push(Ring::create(planet, seed));
+            }
+        }
+
+        let mut belts = Vec::new();
+        for (i, pair) in planets.windows(2).enumerate() {
+            if crate::noise::noise_3d(x, y, z, seed.wrapping_add(i as u64 + 7331)) % 4 != 0 {
+                continue;
+            }
+
+            let a1 = star.position.distance(pair[0].position) as f64;
+            let a2 = star.position.distance(pair[1].position) as f64;
+
+            let d1 = a1 + hill_radius(a1, pair[0].mass, star.mass);
+            let d2 = a2 - hill_radius(a2, pair[1].mass, star.mass);
+            if d2 > d1 {
+                let gap = d2 - d1;
+                belts.push(AsteroidBelt::create(&star, d1 + 0.35 * gap, d1 + 0.65 * gap, seed.wrapping_add(i as u64)));
+            }
         }
 
         return Self {
             star,
-            planets
+            planets,
+            moons,
+            rings,
+            belts
+        }
+    }
+
+    /*
+     * Moves every body in the system, systems are generated around the origin and placed in the galaxy afterwards
+     */
+    pub fn translate(&mut self, offset: Vec3A) {
+        self.star.position += offset;
+        self.planets.iter_mut().for_each(|p| p.position += offset);
+        self.moons.iter_mut().for_each(|m| m.position += offset);
+        self.rings.iter_mut().flat_map(|r| r.particles.iter_mut()).for_each(|p| p.position += offset);
+        self.belts.iter_mut().flat_map(|b| b.asteroids.iter_mut()).for_each(|a| a.position += offset);
+    }
+
+    /*
+     * Moons are placed outside the Roche limit and inside half of the Hill radius, where prograde orbits are stable
+     */
+    fn create_moons(star: &Star, planet: &Planet, seed: u64) -> Vec<Moon> {
+        let a = star.position.distance(planet.position) as f64;
+        let max_orbit = 0.5 * hill_radius(a, planet.mass, star.mass);
+        let mut orbit = roche_limit(planet.radius, planet.density, MAX_MOON_DENSITY).max(2.0 * planet.radius);
+
+        let nbr_moons = crate::noise::noise_1d(planet.id, seed.wrapping_add(1)) % MAX_MOONS;
+
+        let mut moons = Vec::new();
+        for i in 0..nbr_moons {
+            orbit *= 1.4 + crate::noise::noise_3d_f64_normalized(planet.id, i, 5, seed);
+            if orbit > max_orbit {
+                break;
+            }
+
+            moons.push(Moon::create(planet, i, orbit, seed));
         }
+
+        moons
     }
 }
 
+fn spawn_body<T: bevy::ecs::component::Component>(
+    commands: &mut Commands,
+    body: T,
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+    position: Vec3A,
+    veclocity: Vec3A,
+    mass: f64,
+    radius: f64,
+    density: f64,
+) -> Entity {
+    let pos: Vec3 = (position / GLOBAL_SCALE).into();
+    commands
+        .spawn()
+        .insert(body)
+        .insert_bundle(PbrBundle {
+            mesh,
+            material,
+            global_transform: GlobalTransform::from_translation(pos),
+            transform: Transform::from_translation(pos),
+            ..Default::default()
+        })
+        .insert(bevy_frustum_culling::aabb::Aabb::default())
+        .insert_bundle(bevy_rapier3d::physics::RigidBodyBundle {
+            position: pos.into(),
+            velocity: bevy_rapier3d::prelude::RigidBodyVelocity { 
+                linvel: (veclocity / GLOBAL_SCALE).into(),
+                angvel: Vec3A::ZERO.into()
+            },
+            forces: bevy_rapier3d::prelude::RigidBodyForces { gravity_scale: 0.0, ..Default::default() },
+            activation: bevy_rapier3d::prelude::RigidBodyActivation::cannot_sleep(),
+            ccd: bevy_rapier3d::prelude::RigidBodyCcd { ccd_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .insert_bundle(bevy_rapier3d::physics::ColliderBundle {
+            shape: bevy_rapier3d::prelude::ColliderShape::ball((radius as f32) / GLOBAL_SCALE),
+            collider_type: bevy_rapier3d::prelude::ColliderType::Solid,
+            flags: bevy_rapier3d::prelude::ColliderFlags { active_events: bevy_rapier3d::prelude::ActiveEvents::CONTACT_EVENTS, ..Default::default() },
+            position: ((position / GLOBAL_SCALE).into(), Quat::from_rotation_x(0.0)).into(),
+            material: bevy_rapier3d::prelude::ColliderMaterial { friction: 0.7, restitution: 0.3, ..Default::default() },
+            mass_properties: bevy_rapier3d::prelude::ColliderMassProps::Density(density as f32),
+            ..Default::default()
+        })
+        .insert(crate::physics::Mass { mass })
+        .insert(crate::physics::Force { force: Vec3::ZERO })
+        .insert(crate::physics::ProperTime::default())
+        .insert(bevy_rapier3d::physics::RigidBodyPositionSync::Discrete)
+        .insert(crate::physics::Identity { id: bevy::reflect::Uuid::new_v4() })
+        .id()
+}
+
 pub fn render_solar_system(
     mut commands: Commands,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -295,13 +626,29 @@ pub fn render_solar_system(
     shaders: ResMut<Assets<Shader>>,
     asset_server: ResMut<AssetServer>,
 ) {
-    let mut system = StarSystem::create(0, 0, 0, 42);
-    system.planets[0].position = Vec3A::new(50.0, 0.0, 0.0) * GLOBAL_SCALE;
+    let system = StarSystem::create(0, 0, 0, 42);
     // system.planets[0].radius *= 10.0;
     // sun.radius = Some(1.0e2);
     // system.star.position = Vec3A::new(2.0 * system.star.radius as f32, 0.0, -AU as f32) ;
 
-    info!("system.star.temperature: {}", system.star.temperature);
+    spawn_star_system(&mut commands, &mut materials, &mut meshes, shader_cache, pipelines, render_graph, shaders, asset_server, system);
+}
+
+/*
+ * Spawns the star and all bodies orbiting it, returns the spawned entities so the system can be despawned again
+ */
+pub fn spawn_star_system(
+    commands: &mut Commands,
+    materials: &mut Assets<StandardMaterial>,
+    meshes: &mut Assets<Mesh>,
+    shader_cache: ResMut<ShaderCache>,
+    pipelines: ResMut<Assets<PipelineDescriptor>>,
+    render_graph: ResMut<RenderGraph>,
+    shaders: ResMut<Assets<Shader>>,
+    asset_server: ResMut<AssetServer>,
+    system: StarSystem,
+) -> Vec<Entity> {
+    info!("system.star.temperature: {}, class: {}", system.star.temperature, system.star.classification());
 
     let mut pos = system.star.position.into();
     pos = pos / GLOBAL_SCALE;
@@ -340,45 +687,68 @@ pub fn render_solar_system(
 
     crate::shaders::add_shader::<crate::physics::BlackBody>(&mut star_entity, asset_server, shader_cache, pipelines, render_graph, shaders);
 
+    let mut entities = vec![star_entity.id()];
+
     for planet in system.planets {
-        let mut pos = planet.position.into();
-        pos = pos / GLOBAL_SCALE;
-        commands
-            .spawn()
-            .insert(planet)
-            .insert_bundle(PbrBundle {
-                mesh: meshes.add(Mesh::from(shape::Icosphere { radius: (planet.radius as f32) / GLOBAL_SCALE * 1000.0, subdivisions: 4 })),
-                material:  materials.add(planet.pbr()),
-                global_transform: GlobalTransform::from_translation(pos),
-                transform: Transform::from_translation(pos),
-                ..Default::default()
-            })
-            .insert(bevy_frustum_culling::aabb::Aabb::default())
-            .insert_bundle(bevy_rapier3d::physics::RigidBodyBundle {
-                position: pos.into(),
-                velocity: bevy_rapier3d::prelude::RigidBodyVelocity { 
-                    linvel: (planet.veclocity / GLOBAL_SCALE).into(),
-                    angvel: Vec3A::ZERO.into()
-                },
-                forces: bevy_rapier3d::prelude::RigidBodyForces { gravity_scale: 0.0, ..Default::default() },
-                activation: bevy_rapier3d::prelude::RigidBodyActivation::cannot_sleep(),
-                ccd: bevy_rapier3d::prelude::RigidBodyCcd { ccd_enabled: false, ..Default::default() },
-                ..Default::default()
-            })
-        .insert_bundle(bevy_rapier3d::physics::ColliderBundle {
-            shape: bevy_rapier3d::prelude::ColliderShape::ball((planet.radius as f32) / GLOBAL_SCALE),
-            collider_type: bevy_rapier3d::prelude::ColliderType::Sensor,
-            position: ((planet.position / GLOBAL_SCALE).into(), Quat::from_rotation_x(0.0)).into(),
-            material: bevy_rapier3d::prelude::ColliderMaterial { friction: 0.7, restitution: 0.3, ..Default::default() },
-            mass_properties: bevy_rapier3d::prelude::ColliderMassProps::Density(planet.density as f32),
-            ..Default::default()
-        })
-        .insert(crate::physics::Mass { mass: planet.mass })
-        .insert(crate::physics::Force { force: Vec3::ZERO })
-        .insert(bevy_rapier3d::physics::RigidBodyPositionSync::Discrete)
-        .insert(crate::physics::Identity { id: bevy::reflect::Uuid::new_v4() })
-        ;
+        let entity = spawn_body(
+            commands,
+            planet,
+            meshes.add(Mesh::from(shape::Icosphere { radius: (planet.radius as f32) / GLOBAL_SCALE * super::terrain::PLANET_VISUAL_SCALE, subdivisions: 4 })),
+            materials.add(planet.pbr()),
+            planet.position,
+            planet.veclocity,
+            planet.mass,
+            planet.radius,
+            planet.density
+        );
+
+        if planet.atmosphere.surface_pressure > 0.0 {
+            commands.entity(entity).insert(planet.atmosphere.scattering(planet.radius, planet.temperature));
+        }
+
+        commands.entity(entity).insert(super::terrain::PlanetTerrain::create(&planet, Vec3::from(system.star.position) / GLOBAL_SCALE));
+
+        entities.push(entity);
+    }
+
+    for moon in system.moons {
+        entities.push(spawn_body(
+            commands,
+            moon,
+            meshes.add(Mesh::from(shape::Icosphere { radius: (moon.radius as f32) / GLOBAL_SCALE * 1000.0, subdivisions: 3 })),
+            materials.add(moon.pbr()),
+            moon.position,
+            moon.veclocity,
+            moon.mass,
+            moon.radius,
+            moon.density
+        ));
+    }
+
+    let asteroids = system.rings
+        .into_iter()
+        .flat_map(|r| r.particles)
+        .chain(system.belts.into_iter().flat_map(|b| b.asteroids));
+
+    for asteroid in asteroids {
+        entities.push(spawn_asteroid(commands, materials, meshes, asteroid));
     }
+
+    entities
+}
+
+pub(crate) fn spawn_asteroid(commands: &mut Commands, materials: &mut Assets<StandardMaterial>, meshes: &mut Assets<Mesh>, asteroid: Asteroid) -> Entity {
+    spawn_body(
+        commands,
+        asteroid,
+        meshes.add(Mesh::from(shape::Icosphere { radius: (asteroid.radius as f32) / GLOBAL_SCALE * 1000.0, subdivisions: 1 })),
+        materials.add(asteroid.pbr()),
+        asteroid.position,
+        asteroid.veclocity,
+        asteroid.mass,
+        asteroid.radius,
+        asteroid.density
+    )
 }
 
 // fn create_planet(
@@ -0,0 +1,301 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::constants::{PHYSICS_C, PHYSICS_GRAVITY};
+
+use super::solar_system::{SUN_MASS, SUN_RADIUS, σ};
+
+pub static SUN_MAIN_SEQUENCE_LIFETIME: f64 = 1.0e10; //years
+pub static UNIVERSE_AGE: f64 = 1.38e10;              //years
+
+static GIANT_PHASE: f64 = 0.1; // Fraction of the main sequence lifetime spent as a giant
+
+static WHITE_DWARF_MAX_PROGENITOR: f64 = 8.0;   //Sun masses
+static NEUTRON_STAR_MAX_PROGENITOR: f64 = 20.0; //Sun masses
+static SUPERGIANT_MIN_MASS: f64 = 8.0;          //Sun masses
+
+static NEUTRON_STAR_MASS: f64 = 1.4 * SUN_MASS;
+static NEUTRON_STAR_RADIUS: f64 = 1.1e4; //m
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpectralClass {
+    O,
+    B,
+    A,
+    F,
+    G,
+    K,
+    M
+}
+
+impl SpectralClass {
+    /*
+     * Returns the class and the subclass (0 hottest, 9 coolest) for an effective temperature
+     */
+    pub fn from_temperature(t: f64) -> (Self, u8) {
+        let (class, hot, cold) = match t {
+            t if t >= 30000.0 => (SpectralClass::O, 50000.0, 30000.0),
+            t if t >= 10000.0 => (SpectralClass::B, 30000.0, 10000.0),
+            t if t >= 7500.0 => (SpectralClass::A, 10000.0, 7500.0),
+            t if t >= 6000.0 => (SpectralClass::F, 7500.0, 6000.0),
+            t if t >= 5200.0 => (SpectralClass::G, 6000.0, 5200.0),
+            t if t >= 3700.0 => (SpectralClass::K, 5200.0, 3700.0),
+            _ => (SpectralClass::M, 3700.0, 2400.0),
+        };
+
+        let subclass = (10.0 * (hot - t) / (hot - cold)).clamp(0.0, 9.0) as u8;
+
+        (class, subclass)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuminosityClass {
+    Ia,
+    Ib,
+    II,
+    III,
+    IV,
+    V,
+    VII
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvolutionStage {
+    MainSequence,
+    Giant,
+    WhiteDwarf,
+    NeutronStar,
+    BlackHole
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Classification {
+    pub stage: EvolutionStage,
+    pub spectral: Option<(SpectralClass, u8)>,
+    pub luminosity: Option<LuminosityClass>,
+    pub temperature: f64, //Kelvin, of the evolved star
+}
+
+impl Classification {
+    /*
+     * age_fraction is the age of the star divided by its main sequence lifetime
+     */
+    pub fn create(stage: EvolutionStage, mass: f64, temperature: f64, age_fraction: f64) -> Self {
+        let m_norm = mass / SUN_MASS;
+
+        let luminosity = match stage {
+            EvolutionStage::MainSequence if age_fraction > 0.9 => Some(LuminosityClass::IV),
+            EvolutionStage::MainSequence => Some(LuminosityClass::V),
+            EvolutionStage::Giant if m_norm >= 25.0 => Some(LuminosityClass::Ia),
+            EvolutionStage::Giant if m_norm >= SUPERGIANT_MIN_MASS => Some(LuminosityClass::Ib),
+            EvolutionStage::Giant if m_norm >= 3.0 => Some(LuminosityClass::II),
+            EvolutionStage::Giant => Some(LuminosityClass::III),
+            EvolutionStage::WhiteDwarf => Some(LuminosityClass::VII),
+            EvolutionStage::NeutronStar | EvolutionStage::BlackHole => None,
+        };
+
+        let spectral = match stage {
+            EvolutionStage::NeutronStar | EvolutionStage::BlackHole => None,
+            _ => Some(SpectralClass::from_temperature(temperature)),
+        };
+
+        Self {
+            stage,
+            spectral,
+            luminosity,
+            temperature
+        }
+    }
+
+    /*
+     * Only long lived main sequence stars give life enough time to evolve
+     */
+    pub fn supports_life(&self) -> bool {
+        match (self.stage, self.spectral) {
+            (EvolutionStage::MainSequence, Some((SpectralClass::F, _))) |
+            (EvolutionStage::MainSequence, Some((SpectralClass::G, _))) |
+            (EvolutionStage::MainSequence, Some((SpectralClass::K, _))) |
+            (EvolutionStage::MainSequence, Some((SpectralClass::M, _))) => true,
+            _ => false
+        }
+    }
+
+    /*
+     * Black body color of the evolved star, the same as the star sprites of the galaxy
+     */
+    pub fn color(&self) -> Color {
+        match self.stage {
+            // Black holes have no temperature to take the color from
+            EvolutionStage::BlackHole => Color::BLACK,
+            _ => {
+                let c = crate::color::blackbody_rgb(self.temperature as f32);
+                Color::rgb_linear(c.x, c.y, c.z)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.stage, self.spectral, self.luminosity) {
+            (EvolutionStage::BlackHole, _, _) => write!(f, "BH"),
+            (EvolutionStage::NeutronStar, _, _) => write!(f, "NS"),
+            (EvolutionStage::WhiteDwarf, Some((c, _)), _) => write!(f, "D{:?}", c),
+            (_, Some((c, s)), Some(l)) => write!(f, "{:?}{}{:?}", c, s, l),
+            _ => write!(f, "?"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StellarState {
+    pub stage: EvolutionStage,
+    pub mass: f64,        //Kg
+    pub radius: f64,      //m
+    pub luminosity: f64,  //Watt
+    pub temperature: f64, //Kelvin
+}
+
+/*
+ * Main sequence lifetime in years, more massive stars burn through their hydrogen faster
+ */
+pub fn main_sequence_lifetime(m: f64) -> f64 {
+    SUN_MAIN_SEQUENCE_LIFETIME * (m / SUN_MASS).powf(-2.5)
+}
+
+fn stefan_boltzmann_luminosity(r: f64, t: f64) -> f64 {
+    4.0 * std::f64::consts::PI * r * r * σ * t.powi(4)
+}
+
+fn stefan_boltzmann_temperature(r: f64, l: f64) -> f64 {
+    (l / (4.0 * std::f64::consts::PI * r * r * σ)).powf(0.25)
+}
+
+/*
+ * Evolves a star from its zero age main sequence mass, radius and luminosity to the given age in years
+ */
+pub fn evolve(mass: f64, radius: f64, luminosity: f64, age: f64) -> StellarState {
+    let m_norm = mass / SUN_MASS;
+    let lifetime = main_sequence_lifetime(mass);
+    let age_fraction = age / lifetime;
+
+    if age_fraction < 1.0 {
+        // Stars slowly brighten and swell while fusing hydrogen
+        let radius = radius * (1.0 + 0.2 * age_fraction);
+        let luminosity = luminosity * (1.0 + 0.4 * age_fraction);
+
+        return StellarState {
+            stage: EvolutionStage::MainSequence,
+            mass,
+            radius,
+            luminosity,
+            temperature: stefan_boltzmann_temperature(radius, luminosity)
+        };
+    }
+
+    if age_fraction < 1.0 + GIANT_PHASE {
+        let progress = (age_fraction - 1.0) / GIANT_PHASE;
+        let max_expansion = if m_norm >= SUPERGIANT_MIN_MASS { 1000.0 } else { 100.0 };
+
+        let radius = radius * (10.0 + (max_expansion - 10.0) * progress);
+        let temperature = 5000.0 - 1500.0 * progress;
+
+        return StellarState {
+            stage: EvolutionStage::Giant,
+            mass,
+            radius,
+            luminosity: stefan_boltzmann_luminosity(radius, temperature),
+            temperature
+        };
+    }
+
+    let cooling_time = age - (1.0 + GIANT_PHASE) * lifetime;
+
+    if m_norm < WHITE_DWARF_MAX_PROGENITOR {
+        let mass = (0.109 * m_norm + 0.394) * SUN_MASS;
+        let radius = 0.0126 * SUN_RADIUS * (mass / SUN_MASS).powf(-1.0 / 3.0);
+        let temperature = 1.0e5 * (1.0 + cooling_time / 1.0e7).powf(-0.4);
+
+        return StellarState {
+            stage: EvolutionStage::WhiteDwarf,
+            mass,
+            radius,
+            luminosity: stefan_boltzmann_luminosity(radius, temperature),
+            temperature
+        };
+    }
+
+    if m_norm < NEUTRON_STAR_MAX_PROGENITOR {
+        let temperature = 1.0e6 * (1.0 + cooling_time / 1.0e5).powf(-0.5);
+
+        return StellarState {
+            stage: EvolutionStage::NeutronStar,
+            mass: NEUTRON_STAR_MASS,
+            radius: NEUTRON_STAR_RADIUS,
+            luminosity: stefan_boltzmann_luminosity(NEUTRON_STAR_RADIUS, temperature),
+            temperature
+        };
+    }
+
+    let mass = 0.5 * mass;
+
+    StellarState {
+        stage: EvolutionStage::BlackHole,
+        mass,
+        radius: 2.0 * PHYSICS_GRAVITY * mass / (PHYSICS_C * PHYSICS_C),
+        luminosity: 0.0,
+        temperature: 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_is_g2v() {
+        let state = evolve(SUN_MASS, SUN_RADIUS, 3.828e26, 0.0);
+        let class = Classification::create(state.stage, state.mass, state.temperature, 0.46);
+
+        assert_eq!(EvolutionStage::MainSequence, state.stage);
+        assert_eq!("G2V", format!("{}", class));
+        assert!(class.supports_life());
+    }
+
+    #[test]
+    fn color_follows_the_classification() {
+        let m = Classification::create(EvolutionStage::MainSequence, 0.2 * SUN_MASS, 3000.0, 0.1).color();
+        let o = Classification::create(EvolutionStage::MainSequence, 40.0 * SUN_MASS, 40000.0, 0.1).color();
+        assert!(m.r() > m.b() && o.b() > o.r(), "{:?} {:?}", m, o);
+
+        let black_hole = Classification::create(EvolutionStage::BlackHole, 40.0 * SUN_MASS, 0.0, 2.0);
+        assert_eq!(Color::BLACK, black_hole.color());
+    }
+
+    #[test]
+    fn sun_main_sequence_lifetime() {
+        assert!((main_sequence_lifetime(SUN_MASS) - SUN_MAIN_SEQUENCE_LIFETIME).abs() < 1.0);
+        assert!(main_sequence_lifetime(10.0 * SUN_MASS) < main_sequence_lifetime(SUN_MASS));
+    }
+
+    #[test]
+    fn remnants_by_mass() {
+        let old = UNIVERSE_AGE;
+        assert_eq!(EvolutionStage::WhiteDwarf, evolve(2.0 * SUN_MASS, SUN_RADIUS, 1.0e27, old).stage);
+        assert_eq!(EvolutionStage::NeutronStar, evolve(12.0 * SUN_MASS, SUN_RADIUS, 1.0e30, old).stage);
+        assert_eq!(EvolutionStage::BlackHole, evolve(40.0 * SUN_MASS, SUN_RADIUS, 1.0e31, old).stage);
+    }
+
+    #[test]
+    fn giants_are_larger_and_cooler() {
+        let lifetime = main_sequence_lifetime(SUN_MASS);
+        let ms = evolve(SUN_MASS, SUN_RADIUS, 3.828e26, 0.5 * lifetime);
+        let giant = evolve(SUN_MASS, SUN_RADIUS, 3.828e26, 1.05 * lifetime);
+
+        assert_eq!(EvolutionStage::Giant, giant.stage);
+        assert!(giant.radius > 10.0 * ms.radius);
+        assert!(giant.temperature < ms.temperature);
+    }
+}
@@ -0,0 +1,520 @@
+use bevy::math::{DQuat, DVec3};
+use bevy::prelude::*;
+use bevy::reflect::*;
+use bevy::render::mesh::Indices;
+use bevy::render::pipeline::{PipelineDescriptor, PrimitiveTopology, RenderPipeline};
+use bevy::render::render_graph::RenderGraph;
+use bevy::render::renderer::RenderResources;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::constants::GLOBAL_SCALE;
+use crate::shaders::ShaderCache;
+use crate::utils::reflection::Reflectable;
+
+use super::solar_system::Planet;
+
+pub static PLANET_VISUAL_SCALE: f32 = 1000.0; // Planets are rendered larger than they are to be visible at system scale
+
+static PATCH_RESOLUTION: usize = 17;   // Vertices per side of a patch
+static MAX_LOD: u8 = 14;
+static LOD_SPLIT_FACTOR: f64 = 2.0;    // A patch is split when the camera is closer than this many patch sizes
+static MAX_PATCHES_PER_FRAME: usize = 8;
+
+static EARTH_SURFACE_GRAVITY: f64 = 9.80665; //m/s2
+static EARTH_MAX_RELIEF: f64 = 1.0e4;        //m
+static LAPSE_RATE: f64 = 6.5e-3;             //K/m
+
+static VOXEL_TRANSITION_ALTITUDE: f64 = 2.0e3; //m
+static VOXEL_SIZE: f64 = 1.0;                  //m
+
+crate::resource!{
+    #[uuid = "2a8f61c3-94c4-4a0e-8f0d-6b1d4e7c5a90"]
+    struct PlanetSurface {
+        ambient: f32,
+        light_position: Vec3
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CubeFace {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ
+}
+
+impl CubeFace {
+    pub const ALL: [CubeFace; 6] = [CubeFace::PosX, CubeFace::NegX, CubeFace::PosY, CubeFace::NegY, CubeFace::PosZ, CubeFace::NegZ];
+
+    /*
+     * Normal and the u, v axes of the face, u × v = normal so triangles wind counter clockwise seen from outside
+     */
+    fn axes(&self) -> (DVec3, DVec3, DVec3) {
+        match self {
+            CubeFace::PosX => (DVec3::X, -DVec3::Z, DVec3::Y),
+            CubeFace::NegX => (-DVec3::X, DVec3::Z, DVec3::Y),
+            CubeFace::PosY => (DVec3::Y, DVec3::X, -DVec3::Z),
+            CubeFace::NegY => (-DVec3::Y, DVec3::X, DVec3::Z),
+            CubeFace::PosZ => (DVec3::Z, DVec3::X, DVec3::Y),
+            CubeFace::NegZ => (-DVec3::Z, -DVec3::X, DVec3::Y),
+        }
+    }
+}
+
+/*
+ * A node in the quadtree of a cube face, x and y are in [0, 2^lod)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PatchKey {
+    pub face: CubeFace,
+    pub lod: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl PatchKey {
+    fn children(&self) -> [PatchKey; 4] {
+        let (lod, x, y) = (self.lod + 1, 2 * self.x, 2 * self.y);
+        [
+            PatchKey { face: self.face, lod, x, y },
+            PatchKey { face: self.face, lod, x: x + 1, y },
+            PatchKey { face: self.face, lod, x, y: y + 1 },
+            PatchKey { face: self.face, lod, x: x + 1, y: y + 1 },
+        ]
+    }
+
+    /*
+     * Direction from the planet center to a point on the patch, s and t are in [0, 1]
+     */
+    fn direction(&self, s: f64, t: f64) -> DVec3 {
+        let (normal, u, v) = self.face.axes();
+        let n = (1u32 << self.lod) as f64;
+
+        let a = -1.0 + 2.0 * (self.x as f64 + s) / n;
+        let b = -1.0 + 2.0 * (self.y as f64 + t) / n;
+
+        cube_to_sphere(normal + u * a + v * b)
+    }
+}
+
+/*
+ * Maps a point on the unit cube to the unit sphere with less distortion at the corners than normalizing
+ */
+pub fn cube_to_sphere(p: DVec3) -> DVec3 {
+    let (x2, y2, z2) = (p.x * p.x, p.y * p.y, p.z * p.z);
+    DVec3::new(
+        p.x * (1.0 - y2 / 2.0 - z2 / 2.0 + y2 * z2 / 3.0).sqrt(),
+        p.y * (1.0 - z2 / 2.0 - x2 / 2.0 + z2 * x2 / 3.0).sqrt(),
+        p.z * (1.0 - x2 / 2.0 - y2 / 2.0 + x2 * y2 / 3.0).sqrt(),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Biome {
+    Ocean,
+    Ice,
+    Snow,
+    Rock,
+    Desert,
+    Forest,
+    Tundra,
+    Gas
+}
+
+impl Biome {
+    pub fn color(&self) -> Color {
+        match self {
+            Biome::Ocean => Color::rgb(0.05, 0.15, 0.45),
+            Biome::Ice => Color::rgb(0.8, 0.9, 0.95),
+            Biome::Snow => Color::rgb(0.95, 0.95, 0.97),
+            Biome::Rock => Color::rgb(0.45, 0.42, 0.4),
+            Biome::Desert => Color::rgb(0.85, 0.72, 0.45),
+            Biome::Forest => Color::rgb(0.15, 0.4, 0.12),
+            Biome::Tundra => Color::rgb(0.45, 0.45, 0.3),
+            Biome::Gas => Color::rgb(0.8, 0.7, 0.55),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainSettings {
+    pub seed: i32,
+    pub radius: f64,      //m
+    pub amplitude: f64,   //m, maximum height above and depth below sea level
+    pub frequency: f64,
+    pub temperature: f64, //Kelvin, mean surface temperature
+    pub gravity: f64,     //m/s2 at the surface
+    pub ocean: bool,
+    pub gas: bool,
+}
+
+impl TerrainSettings {
+    pub fn create(planet: &Planet) -> Self {
+        let g = super::atmosphere::surface_gravity(planet.mass, planet.radius);
+        let liquid_water = planet.atmosphere.surface_pressure > 611.657 && planet.temperature > 240.0 && planet.temperature < 373.0;
+
+        Self {
+            seed: planet.id as i32,
+            radius: planet.radius,
+            // Weaker gravity allows higher mountains
+            amplitude: if planet.gas { 0.0 } else { EARTH_MAX_RELIEF * EARTH_SURFACE_GRAVITY / g },
+            frequency: 2.0,
+            temperature: planet.temperature,
+            gravity: g,
+            ocean: !planet.gas && liquid_water,
+            gas: planet.gas,
+        }
+    }
+
+    /*
+     * Height above sea level in meters in a direction from the planet center
+     */
+    pub fn height(&self, dir: DVec3) -> f64 {
+        if self.gas {
+            return 0.0;
+        }
+
+        let p = dir * self.frequency;
+        let n = unsafe { simdnoise::scalar::fbm_3d(p.x as f32, p.y as f32, p.z as f32, 2.0, 0.5, 8, self.seed) };
+        self.amplitude * n.clamp(-1.0, 1.0) as f64
+    }
+
+    /*
+     * Mean temperature falls towards the poles and with altitude
+     */
+    pub fn local_temperature(&self, dir: DVec3, height: f64) -> f64 {
+        let sin_latitude = dir.y.clamp(-1.0, 1.0);
+        self.temperature + 15.0 - 45.0 * sin_latitude * sin_latitude - LAPSE_RATE * height.max(0.0)
+    }
+
+    pub fn biome(&self, dir: DVec3, height: f64) -> Biome {
+        if self.gas {
+            return Biome::Gas;
+        }
+
+        let t = self.local_temperature(dir, height);
+
+        if height < 0.0 && self.ocean {
+            return if t < 271.0 { Biome::Ice } else { Biome::Ocean };
+        }
+
+        if t < 263.0 {
+            Biome::Snow
+        } else if height > 0.6 * self.amplitude {
+            Biome::Rock
+        } else if t > 303.0 {
+            Biome::Desert
+        } else if t > 283.0 {
+            Biome::Forest
+        } else {
+            Biome::Tundra
+        }
+    }
+
+    pub fn color(&self, dir: DVec3, height: f64) -> Color {
+        let biome = self.biome(dir, height);
+        match biome {
+            // Gas giants are colored in bands of latitude
+            Biome::Gas => {
+                let band = 0.5 + 0.5 * (dir.y * 12.0 + 0.3 * (dir.x * 3.0).sin()).sin() as f32;
+                let c = biome.color();
+                Color::rgb(c.r() * (0.7 + 0.3 * band), c.g() * (0.6 + 0.4 * band), c.b() * (0.5 + 0.5 * band))
+            }
+            _ => biome.color()
+        }
+    }
+
+    /*
+     * Approximate edge length of a patch in meters
+     */
+    fn patch_size(&self, lod: u8) -> f64 {
+        std::f64::consts::FRAC_PI_2 * self.radius / (1u32 << lod) as f64
+    }
+}
+
+/*
+ * Selects the quadtree patches to render for a camera position relative to the planet center in meters
+ */
+pub fn select_patches(settings: &TerrainSettings, camera: DVec3) -> Vec<PatchKey> {
+    fn subdivide(settings: &TerrainSettings, camera: DVec3, key: PatchKey, patches: &mut Vec<PatchKey>) {
+        let dir = key.direction(0.5, 0.5);
+        let center = dir * (settings.radius + settings.height(dir));
+
+        if key.lod < MAX_LOD && center.distance(camera) < LOD_SPLIT_FACTOR * settings.patch_size(key.lod) {
+            for child in key.children().iter() {
+                subdivide(settings, camera, *child, patches);
+            }
+        } else {
+            patches.push(key);
+        }
+    }
+
+    let mut patches = Vec::new();
+    for face in CubeFace::ALL.iter() {
+        subdivide(settings, camera, PatchKey { face: *face, lod: 0, x: 0, y: 0 }, &mut patches);
+    }
+
+    patches
+}
+
+/*
+ * Mesh of a patch relative to the planet center, scale is render units per meter
+ */
+pub fn patch_mesh(settings: &TerrainSettings, key: PatchKey, scale: f64) -> Mesh {
+    let n = PATCH_RESOLUTION;
+    let step = 1.0 / (n - 1) as f64;
+
+    let mut positions: Vec<[f32; 3]> = Vec::with_capacity(n * n);
+    let mut colors: Vec<[f32; 4]> = Vec::with_capacity(n * n);
+    for j in 0..n {
+        for i in 0..n {
+            let dir = key.direction(i as f64 * step, j as f64 * step);
+            let height = settings.height(dir);
+
+            // The ocean surface is flat at sea level
+            let surface = if settings.ocean { height.max(0.0) } else { height };
+            let p = dir * (settings.radius + surface) * scale;
+
+            positions.push([p.x as f32, p.y as f32, p.z as f32]);
+            colors.push(settings.color(dir, height).into());
+        }
+    }
+
+    let mut indices: Vec<u32> = Vec::with_capacity(6 * (n - 1) * (n - 1));
+    for j in 0..(n - 1) {
+        for i in 0..(n - 1) {
+            let a = (j * n + i) as u32;
+            let b = a + 1;
+            let c = a + n as u32;
+            let d = c + 1;
+            indices.extend_from_slice(&[a, b, c, b, d, c]);
+        }
+    }
+
+    let mut normals = vec![Vec3::ZERO; n * n];
+    for t in indices.chunks(3) {
+        let (a, b, c) = (Vec3::from(positions[t[0] as usize]), Vec3::from(positions[t[1] as usize]), Vec3::from(positions[t[2] as usize]));
+        let normal = (b - a).cross(c - a);
+        for i in t.iter() {
+            normals[*i as usize] += normal;
+        }
+    }
+    let normals: Vec<[f32; 3]> = normals.iter().map(|n| n.normalize_or_zero().into()).collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.set_indices(Some(Indices::U32(indices)));
+
+    mesh
+}
+
+pub struct PlanetTerrain {
+    pub settings: TerrainSettings,
+    pub light_position: Vec3, // Position of the star in render units
+    pub patches: HashMap<PatchKey, Entity>
+}
+
+impl PlanetTerrain {
+    pub fn create(planet: &Planet, light_position: Vec3) -> Self {
+        Self {
+            settings: TerrainSettings::create(planet),
+            light_position,
+            patches: HashMap::default()
+        }
+    }
+}
+
+/*
+ * Close to the surface the voxel chunks take over, voxel space is a local frame tangent to the surface
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveSurface {
+    pub planet: Entity,
+    pub settings: TerrainSettings,
+    pub frame_origin: Vec3,     // Camera position in render units when the surface was entered
+    pub surface_origin: DVec3,  //m, relative to the planet center
+    pub rotation: DQuat,        // From voxel space to planet space
+}
+
+impl ActiveSurface {
+    pub fn create(planet: Entity, settings: TerrainSettings, frame_origin: Vec3, camera: DVec3) -> Self {
+        let up = camera.normalize();
+
+        Self {
+            planet,
+            settings,
+            frame_origin,
+            surface_origin: up * (settings.radius + settings.height(up)),
+            rotation: DQuat::from_rotation_arc(DVec3::Y, up),
+        }
+    }
+
+    /*
+     * Planet center in voxel space, gravity points towards it
+     */
+    pub fn center(&self) -> Vec3 {
+        let local = self.rotation.conjugate() * -self.surface_origin / VOXEL_SIZE;
+        self.frame_origin + local.as_f32()
+    }
+
+    /*
+     * Biome of the voxel at a position in render units, None if it is above ground
+     */
+    pub fn voxel(&self, p: Vec3) -> Option<Biome> {
+        let local = (p - self.frame_origin).as_f64() * VOXEL_SIZE;
+        let world = self.surface_origin + self.rotation * local;
+
+        let r = world.length();
+        let dir = world / r;
+        let height = self.settings.height(dir);
+
+        if r <= self.settings.radius + height {
+            Some(self.settings.biome(dir, height))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct TerrainPipeline(pub RenderPipeline);
+
+pub fn setup_terrain(
+    mut commands: Commands,
+    asset_server: ResMut<AssetServer>,
+    shader_cache: ResMut<ShaderCache>,
+    pipelines: ResMut<Assets<PipelineDescriptor>>,
+    render_graph: ResMut<RenderGraph>,
+    shaders: ResMut<Assets<Shader>>,
+) {
+    if let Some(pipeline) = crate::shaders::loader::setup_material::<PlanetSurface>(asset_server, shader_cache, pipelines, render_graph, shaders) {
+        commands.insert_resource(TerrainPipeline(pipeline));
+    } else {
+        info!("No shaders found for {}", PlanetSurface::struct_name());
+    }
+}
+
+/*
+ * Refines the quadtree of every planet around the camera and switches to voxels close to the surface
+ */
+pub fn update_terrain(
+    mut commands: Commands,
+    camera_query: Query<&Transform, With<crate::camera::PlayerCamera>>,
+    mut planets: Query<(Entity, &GlobalTransform, &mut PlanetTerrain, &mut Visible)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    pipeline: Option<Res<TerrainPipeline>>,
+    surface: Option<Res<ActiveSurface>>,
+) {
+    let (camera, pipeline) = match (camera_query.single(), pipeline) {
+        (Ok(camera), Some(pipeline)) => (camera, pipeline),
+        _ => return
+    };
+
+    let scale = (PLANET_VISUAL_SCALE / GLOBAL_SCALE) as f64;
+    let mut spawned = 0;
+
+    for (entity, transform, mut terrain, mut visible) in planets.iter_mut() {
+        // The terrain patches replace the plain sphere of the planet
+        visible.is_visible = false;
+
+        let local = (camera.translation - transform.translation).as_f64() / scale;
+        let wanted: HashSet<PatchKey> = select_patches(&terrain.settings, local).into_iter().collect();
+
+        let stale: Vec<PatchKey> = terrain.patches.keys().filter(|k| !wanted.contains(k)).cloned().collect();
+        for key in stale {
+            if let Some(patch) = terrain.patches.remove(&key) {
+                commands.entity(patch).despawn_recursive();
+            }
+        }
+
+        for key in wanted {
+            if spawned >= MAX_PATCHES_PER_FRAME || terrain.patches.contains_key(&key) {
+                continue;
+            }
+
+            let patch = commands
+                .spawn_bundle(MeshBundle {
+                    mesh: meshes.add(patch_mesh(&terrain.settings, key, scale)),
+                    render_pipelines: RenderPipelines::from_pipelines(vec![pipeline.0.clone()]),
+                    ..Default::default()
+                })
+                .insert(PlanetSurface { ambient: 0.1, light_position: terrain.light_position })
+                .id();
+
+            commands.entity(entity).push_children(&[patch]);
+            terrain.patches.insert(key, patch);
+            spawned += 1;
+        }
+
+        let dir = local.normalize();
+        let altitude = local.length() - (terrain.settings.radius + terrain.settings.height(dir));
+
+        match surface.as_ref() {
+            Some(s) if s.planet == entity && altitude > 2.0 * VOXEL_TRANSITION_ALTITUDE => {
+                commands.remove_resource::<ActiveSurface>();
+            }
+            None if !terrain.settings.gas && altitude < VOXEL_TRANSITION_ALTITUDE => {
+                commands.insert_resource(ActiveSurface::create(entity, terrain.settings, camera.translation, local));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::DVec3;
+
+    use super::*;
+
+    fn settings() -> TerrainSettings {
+        TerrainSettings {
+            seed: 42,
+            radius: 6.3781e6,
+            amplitude: 1.0e4,
+            frequency: 2.0,
+            temperature: 288.0,
+            gravity: 9.81,
+            ocean: true,
+            gas: false,
+        }
+    }
+
+    #[test]
+    fn cube_to_sphere_is_unit_length() {
+        for face in CubeFace::ALL.iter() {
+            let key = PatchKey { face: *face, lod: 2, x: 1, y: 3 };
+            for (s, t) in [(0.0, 0.0), (0.5, 0.25), (1.0, 1.0)].iter() {
+                assert!((key.direction(*s, *t).length() - 1.0).abs() < 1.0e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn far_away_camera_selects_root_patches() {
+        let patches = select_patches(&settings(), DVec3::new(1.0e12, 0.0, 0.0));
+        assert_eq!(6, patches.len());
+        assert!(patches.iter().all(|p| p.lod == 0));
+    }
+
+    #[test]
+    fn close_camera_refines_patches() {
+        let s = settings();
+        let dir = DVec3::X;
+        let camera = dir * (s.radius + s.height(dir) + 100.0);
+        let patches = select_patches(&s, camera);
+        assert!(patches.iter().any(|p| p.lod == MAX_LOD));
+    }
+
+    #[test]
+    fn planet_surface_shaders_exist() {
+        let name = PlanetSurface::struct_name();
+        let folder = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/shaders").join(name);
+
+        assert!(folder.join(format!("{}.vert", name)).exists());
+        assert!(folder.join(format!("{}.frag", name)).exists());
+    }
+}
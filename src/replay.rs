@@ -0,0 +1,504 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+
+use bevy::app::AppExit;
+use bevy::core::FixedTimestep;
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::actions::ActionState;
+use crate::constants::PHYSICS_TICKS;
+use crate::player::{CharacterController, VoxelOccupancy};
+use crate::state::GameState;
+
+static FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+static FNV_PRIME: u64 = 0x100000001b3;
+
+/*
+ * Fixed simulation step, everything that has to replay identically runs once per tick with this dt
+ */
+pub struct Tick {
+    pub tick: u64,
+    pub seconds: f32,
+}
+
+impl Default for Tick {
+    fn default() -> Self {
+        Self { tick: 0, seconds: PHYSICS_TICKS as f32 }
+    }
+}
+
+/*
+ * Actions as the simulation saw them at a tick, held until the next frame of the recording
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct InputFrame {
+    pub tick: u64,
+    pub pressed: Vec<String>,
+    pub just_pressed: Vec<String>,
+    pub axes: BTreeMap<String, f32>,
+}
+
+impl InputFrame {
+    pub fn capture(tick: u64, state: &ActionState) -> Self {
+        let mut pressed: Vec<String> = state.pressed.iter().cloned().collect();
+        let mut just_pressed: Vec<String> = state.just_pressed.iter().cloned().collect();
+        pressed.sort();
+        just_pressed.sort();
+
+        Self {
+            tick,
+            pressed,
+            just_pressed,
+            axes: state.axes.iter().filter(|(_, v)| **v != 0.0).map(|(k, v)| (k.clone(), *v)).collect(),
+        }
+    }
+
+    /*
+     * just_pressed only fires on the tick the frame was recorded at
+     */
+    pub fn apply(&self, tick: u64, state: &mut ActionState) {
+        state.pressed = self.pressed.iter().cloned().collect();
+        state.just_pressed = if tick == self.tick { self.just_pressed.iter().cloned().collect() } else { Default::default() };
+        state.axes = self.axes.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    }
+
+    fn same_input(&self, other: &InputFrame) -> bool {
+        self.pressed == other.pressed && self.axes == other.axes
+    }
+}
+
+/*
+ * Seeds and hashes do not fit into the signed integers of toml
+ */
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:016x}", v))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        let s = String::deserialize(d)?;
+        u64::from_str_radix(&s, 16).map_err(serde::de::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(v: &Option<u64>, s: S) -> Result<S::Ok, S::Error> {
+            match v {
+                Some(v) => super::serialize(v, s),
+                None => s.serialize_none()
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+            let s = Option::<String>::deserialize(d)?;
+            s.map(|s| u64::from_str_radix(&s, 16).map_err(serde::de::Error::custom)).transpose()
+        }
+    }
+}
+
+/*
+ * Frames are only stored when the input changes
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Recording {
+    #[serde(with = "hex")]
+    pub seed: u64,
+    pub tick_seconds: f32,
+    pub last_tick: u64,
+    #[serde(default, with = "hex::option", skip_serializing_if = "Option::is_none")]
+    pub final_hash: Option<u64>,
+    #[serde(default)]
+    pub frames: Vec<InputFrame>,
+}
+
+impl Recording {
+    pub fn new(seed: u64, tick_seconds: f32) -> Self {
+        Self { seed, tick_seconds, ..Default::default() }
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        toml::from_str(source).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        Self::parse(&fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let source = toml::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(path, source).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn push(&mut self, frame: InputFrame) {
+        self.last_tick = frame.tick;
+        let changed = match self.frames.last() {
+            Some(last) => !frame.just_pressed.is_empty() || !last.same_input(&frame),
+            None => true
+        };
+        if changed {
+            self.frames.push(frame);
+        }
+    }
+}
+
+pub struct InputRecorder {
+    pub path: String,
+    pub recording: Recording,
+}
+
+pub struct InputReplay {
+    pub recording: Recording,
+    next: usize,
+    held: InputFrame,
+}
+
+impl InputReplay {
+    pub fn new(recording: Recording) -> Self {
+        Self { recording, next: 0, held: InputFrame::default() }
+    }
+
+    pub fn finished(&self, tick: u64) -> bool {
+        tick > self.recording.last_tick
+    }
+}
+
+/*
+ * Hash of the simulation state after the tick, compared bit for bit so any divergence shows
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WorldHash {
+    pub tick: u64,
+    pub hash: u64,
+}
+
+/*
+ * FNV-1a, unlike DefaultHasher the result is fixed across runs, Rust versions and platforms so recordings stay valid
+ */
+pub struct WorldHasher(u64);
+
+impl Default for WorldHasher {
+    fn default() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for WorldHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+pub fn advance_tick(mut tick: ResMut<Tick>) {
+    tick.tick += 1;
+}
+
+pub fn record_input(
+    tick: Res<Tick>,
+    actions: Res<ActionState>,
+    recorder: Option<ResMut<InputRecorder>>,
+) {
+    if let Some(mut recorder) = recorder {
+        recorder.recording.push(InputFrame::capture(tick.tick, &actions));
+    }
+}
+
+/*
+ * Overrides whatever actions::update_actions read from the devices
+ */
+pub fn replay_input(
+    tick: Res<Tick>,
+    mut actions: ResMut<ActionState>,
+    replay: Option<ResMut<InputReplay>>,
+) {
+    if let Some(mut replay) = replay {
+        while replay.next < replay.recording.frames.len() && replay.recording.frames[replay.next].tick <= tick.tick {
+            replay.held = replay.recording.frames[replay.next].clone();
+            replay.next += 1;
+        }
+        replay.held.apply(tick.tick, &mut actions);
+    }
+}
+
+/*
+ * Slices of integers hash their bytes in native order, the bits are written one by one instead
+ */
+fn hash_bits<const N: usize>(values: &[[u32; N]], hasher: &mut WorldHasher) {
+    hasher.write_usize(values.len());
+    for v in values.iter().flat_map(|v| v.iter()) {
+        hasher.write_u32(*v);
+    }
+}
+
+pub fn hash_world(
+    tick: Res<Tick>,
+    state: Res<GameState>,
+    occupancy: Res<VoxelOccupancy>,
+    players: Query<&CharacterController>,
+    bodies: Query<(&RigidBodyPosition, &RigidBodyVelocity)>,
+    mut world_hash: ResMut<WorldHash>,
+) {
+    let mut hasher = WorldHasher::default();
+    tick.tick.hash(&mut hasher);
+    state.seed.hash(&mut hasher);
+
+    let mut chunks: Vec<(i32, i32, i32)> = occupancy.chunks.keys().map(|c| (c.x, c.y, c.z)).collect();
+    chunks.sort();
+    chunks.hash(&mut hasher);
+    occupancy.cells.len().hash(&mut hasher);
+
+    let mut controllers: Vec<[u32; 9]> = players.iter().map(|c| {
+        let (p, v) = (c.position, c.veclocity);
+        [p.x.to_bits(), p.y.to_bits(), p.z.to_bits(), v.x.to_bits(), v.y.to_bits(), v.z.to_bits(), c.yaw.to_bits(), c.pitch.to_bits(), c.crouching as u32]
+    }).collect();
+    controllers.sort();
+    hash_bits(&controllers, &mut hasher);
+
+    let mut rigid_bodies: Vec<[u32; 13]> = bodies.iter().map(|(position, veclocity)| {
+        let (p, r): (Vec3, Quat) = position.position.into();
+        let (v, w) = (veclocity.linvel, veclocity.angvel);
+        [
+            p.x.to_bits(), p.y.to_bits(), p.z.to_bits(),
+            r.x.to_bits(), r.y.to_bits(), r.z.to_bits(), r.w.to_bits(),
+            v.x.to_bits(), v.y.to_bits(), v.z.to_bits(),
+            w.x.to_bits(), w.y.to_bits(), w.z.to_bits(),
+        ]
+    }).collect();
+    rigid_bodies.sort();
+    hash_bits(&rigid_bodies, &mut hasher);
+
+    *world_hash = WorldHash { tick: tick.tick, hash: hasher.finish() };
+}
+
+/*
+ * Writes the recording with the final hash when the app exits
+ */
+pub fn save_recording(
+    mut exit_events: EventReader<AppExit>,
+    world_hash: Res<WorldHash>,
+    recorder: Option<ResMut<InputRecorder>>,
+) {
+    if exit_events.iter().next().is_none() {
+        return;
+    }
+
+    if let Some(mut recorder) = recorder {
+        recorder.recording.last_tick = world_hash.tick;
+        recorder.recording.final_hash = Some(world_hash.hash);
+        match recorder.recording.save(&recorder.path) {
+            Ok(_) => info!("Saved recording of {} ticks to {}", world_hash.tick, recorder.path),
+            Err(e) => error!("Could not save the recording {}", e)
+        }
+    }
+}
+
+/*
+ * The deterministic part of the game, one run is one tick
+ */
+pub fn simulation_systems() -> SystemSet {
+    SystemSet::new()
+        .with_system(record_input.system().label("record_input"))
+        .with_system(replay_input.system().label("replay_input"))
+        .with_system(crate::player::stream_occupancy.system().label("stream_occupancy"))
+        .with_system(crate::player::player_controller.system().label("player_controller").after("record_input").after("replay_input").after("stream_occupancy"))
+        .with_system(hash_world.system().label("hash_world").after("player_controller"))
+        .with_system(advance_tick.system().after("hash_world"))
+}
+
+fn every_update() -> ShouldRun {
+    ShouldRun::Yes
+}
+
+/*
+ * Starts recording to the path after --record
+ */
+pub fn start_recording(mut commands: Commands, state: Res<GameState>, tick: Res<Tick>) {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.iter().position(|a| a == "--record").and_then(|i| args.get(i + 1)) {
+        info!("Recording input to {}", path);
+        commands.insert_resource(InputRecorder { path: path.clone(), recording: Recording::new(state.seed, tick.seconds) });
+    }
+}
+
+#[derive(Default)]
+pub struct SimulationPlugin {
+    pub headless: bool,
+}
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<Tick>()
+            .init_resource::<GameState>()
+            .init_resource::<WorldHash>()
+            .init_resource::<VoxelOccupancy>()
+            .add_startup_system(crate::player::spawn_player.system());
+
+        if self.headless {
+            app.add_system_set(simulation_systems().with_run_criteria(every_update.system()));
+        } else {
+            app.add_startup_system(start_recording.system())
+                .add_system_set(simulation_systems().with_run_criteria(FixedTimestep::step(PHYSICS_TICKS)).after("actions"))
+                .add_system_to_stage(CoreStage::Last, save_recording.system());
+        }
+    }
+}
+
+/*
+ * Without window or devices every update is one tick of the recorded input
+ */
+pub fn headless_app(recording: &Recording) -> App {
+    let mut builder = App::build();
+    builder.insert_resource(GameState { seed: recording.seed })
+        .insert_resource(Tick { tick: 0, seconds: recording.tick_seconds })
+        .init_resource::<ActionState>()
+        .insert_resource(InputReplay::new(recording.clone()))
+        .add_plugin(SimulationPlugin { headless: true });
+    builder.app
+}
+
+pub fn replay(recording: &Recording) -> WorldHash {
+    let mut app = headless_app(recording);
+    loop {
+        app.update();
+        let hash = *app.world.get_resource::<WorldHash>().unwrap();
+        if hash.tick >= recording.last_tick {
+            return hash;
+        }
+    }
+}
+
+/*
+ * Replays a recording and checks it ends in the recorded world state
+ */
+pub fn run_headless_replay(path: &str) -> Result<WorldHash, String> {
+    let recording = Recording::load(path)?;
+    let hash = replay(&recording);
+
+    match recording.final_hash {
+        None => Err(format!("{} has no final hash to compare against, was the recording saved on exit?", path)),
+        Some(expected) if expected != hash.hash => Err(format!("World state diverged after {} ticks, expected {:016x} got {:016x}", hash.tick, expected, hash.hash)),
+        Some(_) => Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tick: u64, pressed: &[&str], just_pressed: &[&str], axes: &[(&str, f32)]) -> InputFrame {
+        InputFrame {
+            tick,
+            pressed: pressed.iter().map(|s| s.to_string()).collect(),
+            just_pressed: just_pressed.iter().map(|s| s.to_string()).collect(),
+            axes: axes.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+
+    fn session() -> Recording {
+        let mut recording = Recording::new(u64::MAX - 42, PHYSICS_TICKS as f32);
+        for tick in 0..90 {
+            let f = match tick {
+                0..=29 => frame(tick, &[], &[], &[("move_y", 1.0)]),
+                30 => frame(tick, &["jump"], &["jump"], &[("move_y", 1.0), ("look_x", 0.5)]),
+                31..=59 => frame(tick, &["jump"], &[], &[("move_x", -0.25)]),
+                _ => frame(tick, &[], &[], &[]),
+            };
+            recording.push(f);
+        }
+        recording
+    }
+
+    #[test]
+    fn only_changes_are_recorded() {
+        let recording = session();
+        assert_eq!(recording.frames.len(), 4);
+        assert_eq!(recording.last_tick, 89);
+    }
+
+    #[test]
+    fn recording_survives_toml() {
+        let mut recording = session();
+        recording.final_hash = Some(u64::MAX);
+
+        let source = toml::to_string(&recording).unwrap();
+        assert_eq!(Recording::parse(&source).unwrap(), recording);
+    }
+
+    #[test]
+    fn held_frames_fire_just_pressed_once() {
+        let f = frame(30, &["jump"], &["jump"], &[]);
+        let mut state = ActionState::default();
+
+        f.apply(30, &mut state);
+        assert!(state.just_pressed("jump"));
+        f.apply(31, &mut state);
+        assert!(state.pressed("jump") && !state.just_pressed("jump"));
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let recording = session();
+        let a = replay(&recording);
+        let b = replay(&recording);
+        assert_eq!(a, b);
+        assert_eq!(a.tick, recording.last_tick);
+
+        let mut changed = recording.clone();
+        changed.frames[0].axes.insert("move_y".to_string(), 0.5);
+        assert_ne!(replay(&changed).hash, a.hash);
+    }
+
+    #[test]
+    fn replay_needs_a_final_hash() {
+        let path = std::env::temp_dir().join("replay_needs_a_final_hash.toml");
+        let path = path.to_str().unwrap();
+
+        let mut recording = session();
+        recording.save(path).unwrap();
+        assert!(run_headless_replay(path).is_err());
+
+        recording.final_hash = Some(replay(&recording).hash);
+        recording.save(path).unwrap();
+        assert!(run_headless_replay(path).is_ok());
+    }
+
+    #[test]
+    fn world_hasher_is_fnv1a() {
+        let mut hasher = WorldHasher::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+
+        let mut hasher = WorldHasher::default();
+        1u32.hash(&mut hasher);
+        let mut bytes = WorldHasher::default();
+        bytes.write(&[1, 0, 0, 0]);
+        assert_eq!(hasher.finish(), bytes.finish());
+    }
+}
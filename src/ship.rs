@@ -0,0 +1,390 @@
+use bevy::math::{DQuat, DVec3};
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::rapier::math::{Point, Vector};
+
+use crate::constants::GLOBAL_SCALE;
+use crate::physics::{Force, Identity, Mass, ProperTime};
+
+static ALLOCATION_ITERATIONS: usize = 256;
+static STANDARD_GRAVITY: f64 = 9.80665; //m/s2
+
+/*
+ * A thruster pushes the ship along direction at position, both in the ship frame (m)
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Thruster {
+    pub position: DVec3,
+    pub direction: DVec3,
+    pub max_thrust: f64, //N
+    pub throttle: f64,   //[0, 1]
+}
+
+impl Thruster {
+    pub fn new(position: DVec3, direction: DVec3, max_thrust: f64) -> Self {
+        Self { position, direction: direction.normalize(), max_thrust, throttle: 0.0 }
+    }
+
+    pub fn torque(&self) -> DVec3 {
+        self.position.cross(self.direction) * self.max_thrust
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssistMode {
+    Off,
+    RateDamping,
+    Prograde,
+    Retrograde
+}
+
+impl AssistMode {
+    fn next(&self) -> Self {
+        match self {
+            AssistMode::Off => AssistMode::RateDamping,
+            AssistMode::RateDamping => AssistMode::Prograde,
+            AssistMode::Prograde => AssistMode::Retrograde,
+            AssistMode::Retrograde => AssistMode::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlightAssist {
+    pub mode: AssistMode,
+    pub damping: f64,   //1/s
+    pub stiffness: f64, //1/s2
+}
+
+impl Default for FlightAssist {
+    fn default() -> Self {
+        Self { mode: AssistMode::RateDamping, damping: 2.0, stiffness: 1.0 }
+    }
+}
+
+impl FlightAssist {
+    /*
+     * Angular acceleration in the ship frame added to the pilot input, ω and v are in the ship frame
+     */
+    pub fn angular_acceleration(&self, ω: DVec3, v: DVec3) -> DVec3 {
+        let hold = |target: DVec3| {
+            // Forward is -Z, rotate it onto the target
+            let error = (-DVec3::Z).cross(target.normalize());
+            error * self.stiffness - ω * self.damping
+        };
+
+        match self.mode {
+            AssistMode::Off => DVec3::ZERO,
+            AssistMode::RateDamping => -ω * self.damping,
+            AssistMode::Prograde if v.length() > 0.0 => hold(v),
+            AssistMode::Retrograde if v.length() > 0.0 => hold(-v),
+            _ => -ω * self.damping,
+        }
+    }
+}
+
+pub struct Ship {
+    pub dry_mass: f64,          //Kg
+    pub fuel: f64,              //Kg
+    pub exhaust_veclocity: f64, //m/s
+    pub inertia: DVec3,         //Kg m2, principal moments per unit mass
+    pub thrusters: Vec<Thruster>,
+    pub assist: FlightAssist,
+    pub max_acceleration: f64,  //m/s2 requested at full input
+    pub max_angular_acceleration: f64, //rad/s2
+}
+
+impl Ship {
+    /*
+     * A box shaped ship with a main engine and 12 RCS thrusters in pairs, so every axis can be translated and rotated
+     */
+    pub fn create(dry_mass: f64, fuel: f64, size: DVec3, main_thrust: f64, rcs_thrust: f64) -> Self {
+        let half = [0.5 * size.x, 0.5 * size.y, 0.5 * size.z];
+        let axes = [DVec3::X, DVec3::Y, DVec3::Z];
+
+        let mut thrusters = vec![Thruster::new(DVec3::new(0.0, 0.0, half[2]), -DVec3::Z, main_thrust)];
+        for k in 0..3 {
+            let j = (k + 1) % 3;
+            let lever = axes[j] * half[j];
+            for s in [1.0, -1.0].iter() {
+                thrusters.push(Thruster::new(lever, axes[k] * *s, rcs_thrust));
+                thrusters.push(Thruster::new(-lever, axes[k] * *s, rcs_thrust));
+            }
+        }
+
+        // Solid box
+        let inertia = DVec3::new(
+            (size.y * size.y + size.z * size.z) / 12.0,
+            (size.x * size.x + size.z * size.z) / 12.0,
+            (size.x * size.x + size.y * size.y) / 12.0,
+        );
+
+        Self {
+            dry_mass,
+            fuel,
+            exhaust_veclocity: 300.0 * STANDARD_GRAVITY,
+            inertia,
+            thrusters,
+            assist: FlightAssist::default(),
+            max_acceleration: 10.0,
+            max_angular_acceleration: 0.5,
+        }
+    }
+
+    pub fn mass(&self) -> f64 {
+        self.dry_mass + self.fuel
+    }
+
+    pub fn inertia_tensor(&self) -> DVec3 {
+        self.inertia * self.mass()
+    }
+
+    /*
+     * Force and torque in the ship frame at the current throttles
+     */
+    pub fn thrust(&self) -> (DVec3, DVec3) {
+        self.thrusters.iter().fold((DVec3::ZERO, DVec3::ZERO), |(f, τ), t| {
+            (f + t.direction * t.max_thrust * t.throttle, τ + t.torque() * t.throttle)
+        })
+    }
+
+    /*
+     * Consumes the fuel for dt seconds of thrust, throttles are scaled down if the tank runs dry
+     */
+    pub fn burn(&mut self, dt: f64) {
+        let flow: f64 = self.thrusters.iter().map(|t| t.max_thrust * t.throttle).sum::<f64>() / self.exhaust_veclocity;
+        let needed = flow * dt;
+
+        if needed > self.fuel {
+            let scale = if needed > 0.0 { self.fuel / needed } else { 0.0 };
+            for t in self.thrusters.iter_mut() {
+                t.throttle *= scale;
+            }
+            self.fuel = 0.0;
+        } else {
+            self.fuel -= needed;
+        }
+    }
+
+    /*
+     * Sets the throttles that best achieve a linear and angular acceleration in the ship frame
+     */
+    pub fn allocate(&mut self, acceleration: DVec3, angular_acceleration: DVec3) {
+        let throttles = allocate(&self.thrusters, self.mass(), self.inertia_tensor(), acceleration, angular_acceleration);
+        for (t, throttle) in self.thrusters.iter_mut().zip(throttles) {
+            t.throttle = throttle;
+        }
+    }
+}
+
+fn effect(t: &Thruster, mass: f64, inertia: DVec3) -> [f64; 6] {
+    let a = t.direction * t.max_thrust / mass;
+    let α = t.torque() / inertia;
+    [a.x, a.y, a.z, α.x, α.y, α.z]
+}
+
+/*
+ * Box constrained least squares over the throttles in [0, 1] by cyclic coordinate descent.
+ * The problem is convex so this converges to the best achievable combination.
+ */
+pub fn allocate(thrusters: &[Thruster], mass: f64, inertia: DVec3, acceleration: DVec3, angular_acceleration: DVec3) -> Vec<f64> {
+    let columns: Vec<[f64; 6]> = thrusters.iter().map(|t| effect(t, mass, inertia)).collect();
+    let mut throttles = vec![0.0; thrusters.len()];
+    let mut residual = [
+        acceleration.x, acceleration.y, acceleration.z,
+        angular_acceleration.x, angular_acceleration.y, angular_acceleration.z
+    ];
+
+    for _ in 0..ALLOCATION_ITERATIONS {
+        for (i, c) in columns.iter().enumerate() {
+            let norm: f64 = c.iter().map(|x| x * x).sum();
+            if norm <= 0.0 {
+                continue;
+            }
+
+            let dot: f64 = c.iter().zip(residual.iter()).map(|(a, b)| a * b).sum();
+            let throttle = (throttles[i] + dot / norm).clamp(0.0, 1.0);
+            let delta = throttle - throttles[i];
+
+            for (r, x) in residual.iter_mut().zip(c.iter()) {
+                *r -= x * delta;
+            }
+            throttles[i] = throttle;
+        }
+    }
+
+    throttles
+}
+
+fn to_dvec3(v: Vec3) -> DVec3 {
+    DVec3::new(v.x as f64, v.y as f64, v.z as f64)
+}
+
+fn to_vec3(v: DVec3) -> Vec3 {
+    Vec3::new(v.x as f32, v.y as f32, v.z as f32)
+}
+
+pub fn spawn_ship(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let size = DVec3::new(10.0, 5.0, 20.0);
+    let ship = Ship::create(2.0e4, 1.0e4, size, 5.0e5, 2.0e4);
+    let inertia = ship.inertia_tensor();
+    let mass = ship.mass();
+
+    let half = to_vec3(size * 0.5) / GLOBAL_SCALE;
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Box::new(2.0 * half.x, 2.0 * half.y, 2.0 * half.z))),
+            material: materials.add(Color::SILVER.into()),
+            ..Default::default()
+        })
+        .insert_bundle(RigidBodyBundle {
+            forces: RigidBodyForces { gravity_scale: 0.0, ..Default::default() },
+            activation: RigidBodyActivation::cannot_sleep(),
+            ..Default::default()
+        })
+        .insert_bundle(ColliderBundle {
+            shape: ColliderShape::cuboid(half.x, half.y, half.z),
+            // Mass and inertia are in SI units, the force from the thrusters is scaled to render units instead
+            mass_properties: ColliderMassProps::MassProperties(Box::new(MassProperties::new(
+                Point::origin(),
+                mass as f32,
+                Vector::new(inertia.x as f32, inertia.y as f32, inertia.z as f32)
+            ))),
+            flags: ColliderFlags { active_events: ActiveEvents::CONTACT_EVENTS, ..Default::default() },
+            ..Default::default()
+        })
+        .insert(ship)
+        .insert(Mass { mass })
+        .insert(Force { force: Vec3::ZERO })
+        .insert(ProperTime::default())
+        .insert(RigidBodyPositionSync::Discrete)
+        .insert(crate::camera::CameraTarget)
+        .insert(Identity { id: bevy::reflect::Uuid::new_v4() });
+}
+
+/*
+ * Turns the pilot input and the flight assist into thruster throttles, force and torque
+ */
+pub fn ship_control(
+    time: Res<Time>,
+    actions: Res<crate::actions::ActionState>,
+    mut query: Query<(&mut Ship, &mut Mass, &mut Force, &mut RigidBodyVelocity, &RigidBodyMassProps, &RigidBodyPosition)>,
+) {
+    let dt = time.delta_seconds_f64();
+
+    for (mut ship, mut mass, mut force, mut rbv, rbm, rbp) in query.iter_mut() {
+        if actions.just_pressed("toggle_assist") {
+            ship.assist.mode = ship.assist.mode.next();
+            info!("Flight assist: {:?}", ship.assist.mode);
+        }
+
+        let (_, rotation): (Vec3, Quat) = rbp.position.into();
+        let rotation = DQuat::from_xyzw(rotation.x as f64, rotation.y as f64, rotation.z as f64, rotation.w as f64);
+        let inverse = rotation.conjugate();
+
+        let v = inverse * to_dvec3(Vec3::new(rbv.linvel.x, rbv.linvel.y, rbv.linvel.z)) * GLOBAL_SCALE as f64;
+        let ω = inverse * to_dvec3(Vec3::new(rbv.angvel.x, rbv.angvel.y, rbv.angvel.z));
+
+        let linear = DVec3::new(
+            actions.axis("move_x") as f64,
+            actions.axis("move_z") as f64,
+            -actions.axis("move_y") as f64,
+        ) * ship.max_acceleration;
+
+        let angular_input = DVec3::new(
+            actions.axis("look_y") as f64,
+            -actions.axis("look_x") as f64,
+            actions.axis("roll") as f64,
+        ) * ship.max_angular_acceleration;
+
+        // The assist only acts on the axes the pilot leaves alone
+        let assist = ship.assist.angular_acceleration(ω, v);
+        let angular = DVec3::new(
+            if angular_input.x != 0.0 { angular_input.x } else { assist.x },
+            if angular_input.y != 0.0 { angular_input.y } else { assist.y },
+            if angular_input.z != 0.0 { angular_input.z } else { assist.z },
+        );
+
+        ship.allocate(linear, angular);
+        ship.burn(dt);
+
+        let (f, τ) = ship.thrust();
+        force.force += to_vec3(rotation * f) / GLOBAL_SCALE;
+        rbv.apply_torque_impulse(rbm, to_vec3(rotation * τ * dt).into());
+        mass.mass = ship.mass();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ship() -> Ship {
+        Ship::create(2.0e4, 1.0e4, DVec3::new(10.0, 5.0, 20.0), 5.0e5, 2.0e4)
+    }
+
+    fn achieved(ship: &Ship) -> (DVec3, DVec3) {
+        let (f, τ) = ship.thrust();
+        (f / ship.mass(), τ / ship.inertia_tensor())
+    }
+
+    #[test]
+    fn forward_acceleration_has_no_torque() {
+        let mut ship = ship();
+        ship.allocate(-DVec3::Z * 5.0, DVec3::ZERO);
+
+        let (a, α) = achieved(&ship);
+        assert!((a - -DVec3::Z * 5.0).length() < 1.0e-3, "a: {:?}", a);
+        assert!(α.length() < 1.0e-3, "α: {:?}", α);
+    }
+
+    #[test]
+    fn yaw_has_no_net_force() {
+        let mut ship = ship();
+        ship.allocate(DVec3::ZERO, DVec3::Y * 0.05);
+
+        let (a, α) = achieved(&ship);
+        assert!(a.length() < 1.0e-3, "a: {:?}", a);
+        assert!((α - DVec3::Y * 0.05).length() < 1.0e-3, "α: {:?}", α);
+    }
+
+    #[test]
+    fn combined_request_is_achieved() {
+        let mut ship = ship();
+        let (a0, α0) = (DVec3::new(0.2, -0.1, -3.0), DVec3::new(0.01, -0.02, 0.03));
+        ship.allocate(a0, α0);
+
+        let (a, α) = achieved(&ship);
+        assert!((a - a0).length() < 1.0e-3, "a: {:?}", a);
+        assert!((α - α0).length() < 1.0e-3, "α: {:?}", α);
+    }
+
+    #[test]
+    fn impossible_requests_saturate() {
+        let mut ship = ship();
+        ship.allocate(-DVec3::Z * 1.0e3, DVec3::ZERO);
+
+        assert!(ship.thrusters.iter().all(|t| t.throttle >= 0.0 && t.throttle <= 1.0));
+        assert_eq!(1.0, ship.thrusters[0].throttle);
+    }
+
+    #[test]
+    fn burning_consumes_fuel_until_empty() {
+        let mut ship = ship();
+        ship.allocate(-DVec3::Z * 1.0e3, DVec3::ZERO);
+
+        let fuel = ship.fuel;
+        ship.burn(1.0);
+        assert!(ship.fuel < fuel);
+
+        ship.burn(1.0e6);
+        assert_eq!(0.0, ship.fuel);
+        ship.allocate(-DVec3::Z * 1.0e3, DVec3::ZERO);
+        ship.burn(1.0);
+        assert!(ship.thrust().0.length() == 0.0);
+    }
+}
@@ -0,0 +1,380 @@
+use bevy::math::DVec3;
+use bevy::prelude::*;
+use bevy::reflect::Uuid;
+use bevy::render::pipeline::PrimitiveTopology;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy_rapier3d::prelude::*;
+use futures_lite::future;
+
+use crate::constants::{GRAVITY_MAX_DISTANCE, GRAVITY_MIN_DISTANCE, GRAVITY_MIN_MASS, GRAVITY_MIN_MASS_PROD, PHYSICS_GRAVITY, PHYSICS_TICKS};
+use crate::physics::{Identity, Mass};
+
+static MARKER_RADIUS: f32 = 0.01;
+
+/*
+ * State of a body for the predictor, in the same units as physics::gravity (render units and seconds)
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct TrajectoryBody {
+    pub id: Uuid,
+    pub mass: f64,
+    pub position: DVec3,
+    pub veclocity: DVec3,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClosestApproach {
+    pub other: Uuid,
+    pub step: usize,
+    pub position: DVec3,
+    pub distance: f64,
+}
+
+/*
+ * The body whose gravity dominates changes from `from` to `to`
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SoiTransition {
+    pub from: Option<Uuid>,
+    pub to: Option<Uuid>,
+    pub step: usize,
+    pub position: DVec3,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    pub id: Uuid,
+    pub points: Vec<DVec3>,
+    pub closest_approaches: Vec<ClosestApproach>,
+    pub soi_transitions: Vec<SoiTransition>,
+}
+
+/*
+ * Acceleration of every body, mirrors the cutoffs of physics::gravity
+ */
+fn accelerations(bodies: &[TrajectoryBody]) -> Vec<DVec3> {
+    bodies.iter().map(|b2| {
+        bodies.iter()
+            .filter(|b1| b1.id != b2.id && b1.mass > GRAVITY_MIN_MASS && b1.mass * b2.mass > GRAVITY_MIN_MASS_PROD)
+            .fold(DVec3::ZERO, |a, b1| {
+                let r2 = b1.position.distance_squared(b2.position);
+                if r2 >= GRAVITY_MIN_DISTANCE && r2 <= GRAVITY_MAX_DISTANCE {
+                    a + (b1.position - b2.position).normalize() * PHYSICS_GRAVITY * b1.mass / r2
+                } else {
+                    a
+                }
+            })
+    }).collect()
+}
+
+/*
+ * The heavier attractor pulling hardest on a body, the body orbits it
+ */
+fn parent(bodies: &[TrajectoryBody], i: usize) -> Option<usize> {
+    bodies.iter()
+        .enumerate()
+        .filter(|(j, b)| *j != i && b.mass > GRAVITY_MIN_MASS && b.mass > bodies[i].mass)
+        .map(|(j, b)| (j, b.mass / b.position.distance_squared(bodies[i].position).max(GRAVITY_MIN_DISTANCE)))
+        .fold(None, |best: Option<(usize, f64)>, (j, pull)| match best {
+            Some((_, p)) if p >= pull => best,
+            _ => Some((j, pull))
+        })
+        .map(|(j, _)| j)
+}
+
+/*
+ * Laplace sphere of influence a·(m/M)^(2/5) of every body relative to its parent, unbounded for bodies without one
+ */
+fn soi_radii(bodies: &[TrajectoryBody]) -> Vec<f64> {
+    (0..bodies.len()).map(|i| match parent(bodies, i) {
+        Some(p) => bodies[i].position.distance(bodies[p].position) * (bodies[i].mass / bodies[p].mass).powf(0.4),
+        None => f64::INFINITY
+    }).collect()
+}
+
+/*
+ * The attractor with the innermost sphere of influence containing the body, the one pulling hardest if they are the same size
+ */
+fn dominant_attractor(bodies: &[TrajectoryBody], radii: &[f64], i: usize) -> Option<usize> {
+    bodies.iter()
+        .enumerate()
+        .filter(|(j, b)| *j != i && b.mass > GRAVITY_MIN_MASS && b.position.distance(bodies[i].position) < radii[*j])
+        .map(|(j, b)| (j, radii[j], b.mass / b.position.distance_squared(bodies[i].position).max(GRAVITY_MIN_DISTANCE)))
+        .fold(None, |best: Option<(usize, f64, f64)>, (j, r, pull)| match best {
+            Some((_, br, bp)) if br < r || (br == r && bp >= pull) => best,
+            _ => Some((j, r, pull))
+        })
+        .map(|(j, _, _)| j)
+}
+
+/*
+ * Forward simulates all bodies for a number of steps of dt seconds with velocity Verlet.
+ * Returns one trajectory per body in the same order, including the starting position.
+ */
+pub fn predict(bodies: &[TrajectoryBody], steps: usize, dt: f64) -> Vec<Trajectory> {
+    let mut bodies = bodies.to_vec();
+    let n = bodies.len();
+
+    let mut trajectories: Vec<Trajectory> = bodies.iter().map(|b| Trajectory {
+        id: b.id,
+        points: vec![b.position],
+        closest_approaches: Vec::new(),
+        soi_transitions: Vec::new(),
+    }).collect();
+
+    // Distance to every other body over the last two steps, a local minimum is a closest approach
+    let distances = |bodies: &[TrajectoryBody]| -> Vec<Vec<f64>> {
+        bodies.iter().map(|a| bodies.iter().map(|b| a.position.distance(b.position)).collect()).collect()
+    };
+    let mut previous = distances(&bodies);
+    let mut approaching = vec![vec![false; n]; n];
+    let radii = soi_radii(&bodies);
+    let mut dominant: Vec<Option<usize>> = (0..n).map(|i| dominant_attractor(&bodies, &radii, i)).collect();
+
+    let mut a = accelerations(&bodies);
+    for step in 1..=steps {
+        for (b, a) in bodies.iter_mut().zip(a.iter()) {
+            b.veclocity += *a * (0.5 * dt);
+            b.position += b.veclocity * dt;
+        }
+
+        a = accelerations(&bodies);
+        for (b, a) in bodies.iter_mut().zip(a.iter()) {
+            b.veclocity += *a * (0.5 * dt);
+        }
+
+        let current = distances(&bodies);
+        let radii = soi_radii(&bodies);
+        for i in 0..n {
+            trajectories[i].points.push(bodies[i].position);
+
+            for j in 0..n {
+                if i == j || bodies[j].mass <= GRAVITY_MIN_MASS {
+                    continue;
+                }
+
+                if approaching[i][j] && current[i][j] > previous[i][j] {
+                    let position = trajectories[i].points[step - 1];
+                    trajectories[i].closest_approaches.push(ClosestApproach {
+                        other: bodies[j].id,
+                        step: step - 1,
+                        position,
+                        distance: previous[i][j],
+                    });
+                }
+                approaching[i][j] = current[i][j] < previous[i][j];
+            }
+
+            let d = dominant_attractor(&bodies, &radii, i);
+            if d != dominant[i] {
+                trajectories[i].soi_transitions.push(SoiTransition {
+                    from: dominant[i].map(|j| bodies[j].id),
+                    to: d.map(|j| bodies[j].id),
+                    step,
+                    position: bodies[i].position,
+                });
+                dominant[i] = d;
+            }
+        }
+        previous = current;
+    }
+
+    trajectories
+}
+
+pub struct TrajectoryPredictor {
+    pub steps: usize,
+    pub dt: f64,
+    pub entities: Vec<Entity>,
+    pub pending: bool,
+}
+
+impl Default for TrajectoryPredictor {
+    fn default() -> Self {
+        Self {
+            steps: 2000,
+            dt: 10.0 * PHYSICS_TICKS,
+            entities: Vec::new(),
+            pending: false,
+        }
+    }
+}
+
+/*
+ * Starts a new prediction on a background task from the current state of all massive bodies
+ */
+pub fn predict_trajectories(
+    mut commands: Commands,
+    thread_pool: Res<AsyncComputeTaskPool>,
+    mut predictor: ResMut<TrajectoryPredictor>,
+    query: Query<(&Mass, &RigidBodyPosition, &RigidBodyVelocity, &Identity)>,
+) {
+    if predictor.pending {
+        return;
+    }
+
+    let bodies: Vec<TrajectoryBody> = query.iter().map(|(m, p, v, e)| {
+        let (t, _): (Vec3, Quat) = p.position.into();
+        TrajectoryBody {
+            id: e.id,
+            mass: m.mass,
+            position: DVec3::new(t.x as f64, t.y as f64, t.z as f64),
+            veclocity: DVec3::new(v.linvel.x as f64, v.linvel.y as f64, v.linvel.z as f64),
+        }
+    }).collect();
+
+    let (steps, dt) = (predictor.steps, predictor.dt);
+    commands.spawn().insert(thread_pool.spawn(async move {
+        predict(&bodies, steps, dt)
+    }));
+    predictor.pending = true;
+}
+
+fn line_mesh(points: &[DVec3]) -> Mesh {
+    let positions: Vec<[f32; 3]> = points.iter().map(|p| [p.x as f32, p.y as f32, p.z as f32]).collect();
+    let normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+    let uvs = vec![[0.0, 0.0]; positions.len()];
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineStrip);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+
+    mesh
+}
+
+fn spawn_marker(commands: &mut Commands, mesh: Handle<Mesh>, material: Handle<StandardMaterial>, position: DVec3) -> Entity {
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh,
+            material,
+            transform: Transform::from_translation(Vec3::new(position.x as f32, position.y as f32, position.z as f32)),
+            ..Default::default()
+        })
+        .id()
+}
+
+/*
+ * Replaces the previous paths with the finished prediction
+ */
+pub fn render_trajectories(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut Task<Vec<Trajectory>>)>,
+    mut predictor: ResMut<TrajectoryPredictor>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (task_entity, mut task) in tasks.iter_mut() {
+        if let Some(trajectories) = future::block_on(future::poll_once(&mut *task)) {
+            commands.entity(task_entity).despawn();
+
+            for entity in predictor.entities.drain(..) {
+                commands.entity(entity).despawn();
+            }
+
+            let path = materials.add(StandardMaterial { base_color: Color::CYAN, unlit: true, ..Default::default() });
+            let approach = materials.add(StandardMaterial { base_color: Color::RED, unlit: true, ..Default::default() });
+            let transition = materials.add(StandardMaterial { base_color: Color::YELLOW, unlit: true, ..Default::default() });
+            let marker = meshes.add(Mesh::from(shape::Icosphere { radius: MARKER_RADIUS, subdivisions: 1 }));
+
+            let mut entities = Vec::new();
+            for trajectory in trajectories {
+                entities.push(commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: meshes.add(line_mesh(&trajectory.points)),
+                        material: path.clone(),
+                        ..Default::default()
+                    })
+                    .id());
+
+                for a in trajectory.closest_approaches.iter() {
+                    entities.push(spawn_marker(&mut commands, marker.clone(), approach.clone(), a.position));
+                }
+
+                for t in trajectory.soi_transitions.iter() {
+                    entities.push(spawn_marker(&mut commands, marker.clone(), transition.clone(), t.position));
+                }
+            }
+
+            predictor.entities = entities;
+            predictor.pending = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(mass: f64, position: DVec3, veclocity: DVec3) -> TrajectoryBody {
+        TrajectoryBody { id: Uuid::new_v4(), mass, position, veclocity }
+    }
+
+    #[test]
+    fn circular_orbit_keeps_its_radius() {
+        let m = 1.0e12;
+        let r = 10.0;
+        let v = (PHYSICS_GRAVITY * m / r).sqrt();
+        let period = 2.0 * std::f64::consts::PI * r / v;
+
+        let bodies = [
+            body(m, DVec3::ZERO, DVec3::ZERO),
+            body(1.0, DVec3::new(r, 0.0, 0.0), DVec3::new(0.0, 0.0, v)),
+        ];
+        let steps = 2000;
+        let trajectories = predict(&bodies, steps, period / steps as f64);
+
+        assert_eq!(steps + 1, trajectories[1].points.len());
+        for p in trajectories[1].points.iter() {
+            assert!((p.length() - r).abs() < 0.01 * r, "r: {}", p.length());
+        }
+        // Back where it started after one period
+        assert!(trajectories[1].points[steps].distance(bodies[1].position) < 0.05 * r);
+    }
+
+    #[test]
+    fn flyby_has_a_closest_approach() {
+        let bodies = [
+            body(1.0e12, DVec3::ZERO, DVec3::ZERO),
+            body(1.0, DVec3::new(-50.0, 0.0, 5.0), DVec3::new(5.0, 0.0, 0.0)),
+        ];
+        let trajectories = predict(&bodies, 2000, 0.01);
+
+        let approaches = &trajectories[1].closest_approaches;
+        assert_eq!(1, approaches.len());
+        assert_eq!(bodies[0].id, approaches[0].other);
+        assert!(approaches[0].distance < 5.0);
+    }
+
+    #[test]
+    fn passing_between_attractors_changes_soi() {
+        let bodies = [
+            body(1.0e12, DVec3::ZERO, DVec3::ZERO),
+            body(1.0e12, DVec3::new(100.0, 0.0, 0.0), DVec3::ZERO),
+            body(1.0, DVec3::new(10.0, 0.0, 0.0), DVec3::new(20.0, 0.0, 0.0)),
+        ];
+        let trajectories = predict(&bodies, 300, 0.01);
+
+        let transitions = &trajectories[2].soi_transitions;
+        assert!(transitions.iter().any(|t| t.from == Some(bodies[0].id) && t.to == Some(bodies[1].id)));
+    }
+
+    #[test]
+    fn soi_is_the_innermost_laplace_sphere() {
+        let bodies = [
+            body(1.0e12, DVec3::ZERO, DVec3::ZERO),
+            body(1.0e9, DVec3::new(1000.0, 0.0, 0.0), DVec3::ZERO),
+            // The star pulls harder, but the probe is inside the 63 units sphere of influence of the planet
+            body(1.0, DVec3::new(1050.0, 0.0, 0.0), DVec3::ZERO),
+            body(1.0, DVec3::new(1100.0, 0.0, 0.0), DVec3::ZERO),
+        ];
+        let radii = soi_radii(&bodies);
+
+        assert!((radii[1] - 1000.0 * 1.0e-3f64.powf(0.4)).abs() < 1.0e-6);
+        assert_eq!(f64::INFINITY, radii[0]);
+        assert_eq!(Some(1), dominant_attractor(&bodies, &radii, 2));
+        assert_eq!(Some(0), dominant_attractor(&bodies, &radii, 3));
+        assert_eq!(Some(0), dominant_attractor(&bodies, &radii, 1));
+        assert_eq!(None, dominant_attractor(&bodies, &radii, 0));
+    }
+}